@@ -0,0 +1,595 @@
+//! Framed request/response protocol of the USB crypto service
+//!
+//! Every frame on the wire is COBS encoded and terminated by a 0x00 byte, so
+//! payloads may contain any byte value. Before encoding a CRC-16/CCITT-FALSE
+//! of the payload is appended (little endian).
+//!
+//! Request payload:  | opcode | seq | AAD length (u16 LE) | AAD | data |
+//! Response payload: | status | seq | data |
+//!
+//! `seq` is chosen by the host and echoed back so it can match responses.
+//! Encrypt answers with `nonce || ciphertext || tag` and Decrypt takes the same,
+//! the nonce length depends on the [`Algorithm`] of the session.
+//!
+//! Used by both the firmware and the host CLI so the two cannot drift apart.
+#![no_std]
+
+use core::convert::TryFrom;
+
+pub const VERSION: u8 = 3;
+/// Largest AAD accepted in a request
+pub const MAX_AAD: usize = 64;
+/// Largest plaintext accepted by Encrypt
+pub const MAX_DATA: usize = 256;
+/// Longest run of a Benchmark request
+pub const BENCH_MAX: u16 = 4096;
+pub const TAG_LEN: usize = 16;
+/// Longest nonce of all algorithms, see [`Algorithm::nonce_len`]
+pub const MAX_NONCE: usize = 24;
+/// Most bytes an encrypted message grows by
+pub const MAX_OVERHEAD: usize = MAX_NONCE + TAG_LEN;
+pub const REQUEST_HEADER: usize = 4;
+pub const RESPONSE_HEADER: usize = 2;
+/// Largest decoded payload, without the CRC
+pub const MAX_PAYLOAD: usize = REQUEST_HEADER + MAX_AAD + MAX_DATA + MAX_OVERHEAD;
+/// Largest frame on the wire, including the delimiter
+pub const MAX_FRAME: usize = cobs_max_len(MAX_PAYLOAD + 2) + 1;
+/// Active slot reported by GetInfo when no stored key is in use
+pub const NO_SLOT: u8 = 0xFF;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Opcode {
+    Encrypt = 0x01,
+    Decrypt = 0x02,
+    /// Data is a 32 byte key, kept in RAM only
+    SetKey = 0x03,
+    /// Answers with `version | key set (u8) | boot counter (u32 LE) |
+    /// max data (u16 LE) | active slot (u8, 0xFF if none) | used slots (u16 LE) |
+    /// algorithm (u8)`
+    GetInfo = 0x04,
+    /// Data is `slot | key id (u32 LE) | key (32 bytes)`, stores the key in
+    /// flash wrapped with a device-unique key
+    Provision = 0x05,
+    /// Data is `slot`, makes the key stored there the active one
+    LoadKey = 0x06,
+    /// Data is `slot` to destroy one stored key, empty to erase all of them
+    Zeroize = 0x07,
+    /// Data is the [`Algorithm`] used by Encrypt and Decrypt from now on
+    SetAlgorithm = 0x08,
+    /// Data is `algorithm | length (u16 LE)`, encrypts that many bytes with a
+    /// throwaway key and answers with `cycles (u32 LE) | length (u16 LE)`,
+    /// the length is at most [`BENCH_MAX`]
+    Benchmark = 0x09,
+}
+
+impl TryFrom<u8> for Opcode {
+    type Error = Status;
+
+    fn try_from(byte: u8) -> Result<Self, Status> {
+        match byte {
+            0x01 => Ok(Opcode::Encrypt),
+            0x02 => Ok(Opcode::Decrypt),
+            0x03 => Ok(Opcode::SetKey),
+            0x04 => Ok(Opcode::GetInfo),
+            0x05 => Ok(Opcode::Provision),
+            0x06 => Ok(Opcode::LoadKey),
+            0x07 => Ok(Opcode::Zeroize),
+            0x08 => Ok(Opcode::SetAlgorithm),
+            0x09 => Ok(Opcode::Benchmark),
+            _ => Err(Status::UnknownOpcode),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    Ok = 0x00,
+    /// Bad COBS encoding, CRC mismatch or frame too long
+    BadFrame = 0x01,
+    UnknownOpcode = 0x02,
+    /// Payload, AAD or key has the wrong length
+    BadLength = 0x03,
+    /// Decryption failed, the message or AAD was tampered with or the key is wrong
+    AuthFailed = 0x04,
+    /// No key has been set yet
+    NoKey = 0x05,
+    /// The device can not produce unique nonces anymore
+    NonceExhausted = 0x06,
+    /// Key slot holds no key
+    SlotEmpty = 0x07,
+    /// Key slot has already been written, zeroize all slots to reuse it
+    SlotInUse = 0x08,
+    /// Writing the key table to flash failed
+    StorageFailed = 0x09,
+    /// Unknown algorithm
+    BadAlgorithm = 0x0A,
+}
+
+impl TryFrom<u8> for Status {
+    type Error = ();
+
+    fn try_from(byte: u8) -> Result<Self, ()> {
+        match byte {
+            0x00 => Ok(Status::Ok),
+            0x01 => Ok(Status::BadFrame),
+            0x02 => Ok(Status::UnknownOpcode),
+            0x03 => Ok(Status::BadLength),
+            0x04 => Ok(Status::AuthFailed),
+            0x05 => Ok(Status::NoKey),
+            0x06 => Ok(Status::NonceExhausted),
+            0x07 => Ok(Status::SlotEmpty),
+            0x08 => Ok(Status::SlotInUse),
+            0x09 => Ok(Status::StorageFailed),
+            0x0A => Ok(Status::BadAlgorithm),
+            _ => Err(()),
+        }
+    }
+}
+
+/// AEAD used for Encrypt and Decrypt, all take 32 byte keys and produce 16
+/// byte tags
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Algorithm {
+    /// Reduced-round ChaCha, fastest but with a smaller security margin
+    ChaCha8Poly1305 = 0x00,
+    ChaCha12Poly1305 = 0x01,
+    /// RFC 8439, the default
+    #[default]
+    ChaCha20Poly1305 = 0x02,
+    /// 24 byte nonces, long enough to be picked at random
+    XChaCha20Poly1305 = 0x03,
+    Aes256Gcm = 0x04,
+}
+
+impl Algorithm {
+    pub const ALL: [Algorithm; 5] = [
+        Algorithm::ChaCha8Poly1305,
+        Algorithm::ChaCha12Poly1305,
+        Algorithm::ChaCha20Poly1305,
+        Algorithm::XChaCha20Poly1305,
+        Algorithm::Aes256Gcm,
+    ];
+
+    pub fn nonce_len(self) -> usize {
+        match self {
+            Algorithm::XChaCha20Poly1305 => 24,
+            _ => 12,
+        }
+    }
+
+    /// Bytes an encrypted message grows by
+    pub fn overhead(self) -> usize {
+        self.nonce_len() + TAG_LEN
+    }
+}
+
+impl TryFrom<u8> for Algorithm {
+    type Error = Status;
+
+    fn try_from(byte: u8) -> Result<Self, Status> {
+        Algorithm::ALL
+            .iter()
+            .copied()
+            .find(|algorithm| *algorithm as u8 == byte)
+            .ok_or(Status::BadAlgorithm)
+    }
+}
+
+pub struct Request<'a> {
+    pub opcode: Opcode,
+    pub seq: u8,
+    pub aad: &'a [u8],
+    pub data: &'a [u8],
+}
+
+impl<'a> Request<'a> {
+    pub fn decode(payload: &'a [u8]) -> Result<Self, Status> {
+        if payload.len() < REQUEST_HEADER {
+            return Err(Status::BadLength);
+        }
+        let opcode = Opcode::try_from(payload[0])?;
+        let aad_len = usize::from(u16::from_le_bytes([payload[2], payload[3]]));
+        let rest = &payload[REQUEST_HEADER..];
+        if aad_len > MAX_AAD || aad_len > rest.len() {
+            return Err(Status::BadLength);
+        }
+        let (aad, data) = rest.split_at(aad_len);
+        Ok(Request {
+            opcode,
+            seq: payload[1],
+            aad,
+            data,
+        })
+    }
+
+    /// Writes the payload into `out`, returns its length
+    pub fn encode(&self, out: &mut [u8]) -> Option<usize> {
+        let len = REQUEST_HEADER + self.aad.len() + self.data.len();
+        if self.aad.len() > MAX_AAD || len > out.len() {
+            return None;
+        }
+        out[0] = self.opcode as u8;
+        out[1] = self.seq;
+        out[2..4].copy_from_slice(&(self.aad.len() as u16).to_le_bytes());
+        out[REQUEST_HEADER..REQUEST_HEADER + self.aad.len()].copy_from_slice(self.aad);
+        out[REQUEST_HEADER + self.aad.len()..len].copy_from_slice(self.data);
+        Some(len)
+    }
+}
+
+pub struct Response<'a> {
+    pub status: Status,
+    pub seq: u8,
+    pub data: &'a [u8],
+}
+
+impl<'a> Response<'a> {
+    pub fn decode(payload: &'a [u8]) -> Option<Self> {
+        if payload.len() < RESPONSE_HEADER {
+            return None;
+        }
+        Some(Response {
+            status: Status::try_from(payload[0]).ok()?,
+            seq: payload[1],
+            data: &payload[RESPONSE_HEADER..],
+        })
+    }
+
+    /// Writes the payload into `out`, returns its length
+    pub fn encode(&self, out: &mut [u8]) -> Option<usize> {
+        let len = RESPONSE_HEADER + self.data.len();
+        if len > out.len() {
+            return None;
+        }
+        out[0] = self.status as u8;
+        out[1] = self.seq;
+        out[RESPONSE_HEADER..len].copy_from_slice(self.data);
+        Some(len)
+    }
+}
+
+/// CRC-16/CCITT-FALSE (poly 0x1021, init 0xFFFF)
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFF_u16;
+    for byte in data {
+        crc ^= u16::from(*byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Worst case COBS output length for `len` input bytes
+pub const fn cobs_max_len(len: usize) -> usize {
+    len + len / 254 + 1
+}
+
+/// COBS encodes `input` into `output` (no delimiter), returns the length
+pub fn cobs_encode(input: &[u8], output: &mut [u8]) -> Option<usize> {
+    if output.len() < cobs_max_len(input.len()) {
+        return None;
+    }
+    let mut code_index = 0;
+    let mut out = 1;
+    let mut code = 1_u8;
+    for &byte in input {
+        if byte != 0 {
+            output[out] = byte;
+            out += 1;
+            code += 1;
+        }
+        if byte == 0 || code == 0xFF {
+            output[code_index] = code;
+            code_index = out;
+            out += 1;
+            code = 1;
+        }
+    }
+    output[code_index] = code;
+    Some(out)
+}
+
+/// Decodes a COBS encoded block (without delimiter), returns the length
+pub fn cobs_decode(input: &[u8], output: &mut [u8]) -> Option<usize> {
+    let mut i = 0;
+    let mut out = 0;
+    while i < input.len() {
+        let code = usize::from(input[i]);
+        i += 1;
+        let end = i + code.checked_sub(1)?;
+        if end > input.len() || out + (end - i) > output.len() {
+            return None;
+        }
+        for &byte in &input[i..end] {
+            if byte == 0 {
+                return None;
+            }
+            output[out] = byte;
+            out += 1;
+        }
+        i = end;
+        // Every block but the last and the full ones ends with a zero
+        if code != 0xFF && i < input.len() {
+            *output.get_mut(out)? = 0;
+            out += 1;
+        }
+    }
+    Some(out)
+}
+
+/// Appends the CRC, COBS encodes and terminates `payload`, returns the length
+pub fn encode_frame(payload: &[u8], out: &mut [u8]) -> Option<usize> {
+    let mut buf = [0; MAX_PAYLOAD + 2];
+    let len = payload.len();
+    if len > MAX_PAYLOAD {
+        return None;
+    }
+    buf[..len].copy_from_slice(payload);
+    buf[len..len + 2].copy_from_slice(&crc16(payload).to_le_bytes());
+    let encoded = cobs_encode(&buf[..len + 2], out)?;
+    *out.get_mut(encoded)? = 0;
+    Some(encoded + 1)
+}
+
+/// Decodes a frame (without its delimiter) into `out` and checks the CRC,
+/// returns the payload length
+pub fn decode_frame(frame: &[u8], out: &mut [u8]) -> Result<usize, Status> {
+    let len = cobs_decode(frame, out).ok_or(Status::BadFrame)?;
+    if len < 2 {
+        return Err(Status::BadFrame);
+    }
+    let len = len - 2;
+    let crc = u16::from_le_bytes([out[len], out[len + 1]]);
+    if crc != crc16(&out[..len]) {
+        return Err(Status::BadFrame);
+    }
+    Ok(len)
+}
+
+/// Collects bytes until a delimiter, oversized frames are dropped whole
+pub struct FrameReader {
+    buf: [u8; MAX_FRAME],
+    len: usize,
+    overflow: bool,
+}
+
+impl Default for FrameReader {
+    fn default() -> Self {
+        FrameReader {
+            buf: [0; MAX_FRAME],
+            len: 0,
+            overflow: false,
+        }
+    }
+}
+
+impl FrameReader {
+    /// Returns the encoded frame (without delimiter) once it is complete, or
+    /// [`Status::BadFrame`] if it did not fit
+    pub fn push(&mut self, byte: u8) -> Option<Result<&[u8], Status>> {
+        if byte == 0 {
+            let len = self.len;
+            let overflow = self.overflow;
+            self.len = 0;
+            self.overflow = false;
+            return match (overflow, len) {
+                (true, _) => Some(Err(Status::BadFrame)),
+                // Back to back delimiters, nothing to report
+                (false, 0) => None,
+                (false, len) => Some(Ok(&self.buf[..len])),
+            };
+        }
+        if self.len == self.buf.len() {
+            self.overflow = true;
+        } else {
+            self.buf[self.len] = byte;
+            self.len += 1;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes `payload` as a frame and decodes it again, checking that the
+    /// delimiter is the only zero on the wire
+    fn round_trip(payload: &[u8]) {
+        let mut frame = [0; MAX_FRAME];
+        let len = encode_frame(payload, &mut frame).unwrap();
+        assert_eq!(frame[len - 1], 0);
+        assert!(!frame[..len - 1].contains(&0));
+        let mut decoded = [0; MAX_PAYLOAD + 2];
+        let decoded_len = decode_frame(&frame[..len - 1], &mut decoded).unwrap();
+        assert_eq!(&decoded[..decoded_len], payload);
+    }
+
+    /// `len` bytes that are never zero
+    fn nonzero(len: usize) -> [u8; MAX_PAYLOAD] {
+        let mut payload = [0; MAX_PAYLOAD];
+        for (i, byte) in payload[..len].iter_mut().enumerate() {
+            *byte = (i % 255) as u8 + 1;
+        }
+        payload
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        round_trip(&[]);
+        round_trip(&[0x42]);
+        round_trip(b"\x01\x00\x02\x00\x00\x03");
+        let mut every_byte = [0; 256];
+        for (i, byte) in every_byte.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        round_trip(&every_byte);
+        round_trip(&nonzero(MAX_PAYLOAD));
+    }
+
+    #[test]
+    fn zero_runs() {
+        let mut encoded = [0; 8];
+        assert_eq!(cobs_encode(&[0, 0, 0], &mut encoded), Some(4));
+        assert_eq!(encoded[..4], [1, 1, 1, 1]);
+        let mut decoded = [0xAA; 3];
+        assert_eq!(cobs_decode(&encoded[..4], &mut decoded), Some(3));
+        assert_eq!(decoded, [0, 0, 0]);
+
+        round_trip(&[0; 300]);
+        let mut mixed = [0; 300];
+        mixed[100] = 7;
+        mixed[299] = 9;
+        round_trip(&mixed);
+    }
+
+    #[test]
+    fn full_blocks() {
+        let mut encoded = [0; cobs_max_len(MAX_PAYLOAD)];
+        let mut decoded = [0; MAX_PAYLOAD];
+        for &len in &[253, 254, 255, MAX_PAYLOAD] {
+            let input = &nonzero(len)[..len];
+            let encoded_len = cobs_encode(input, &mut encoded).unwrap();
+            assert!(encoded_len <= cobs_max_len(len));
+            assert!(!encoded[..encoded_len].contains(&0));
+            assert_eq!(
+                cobs_decode(&encoded[..encoded_len], &mut decoded),
+                Some(len)
+            );
+            assert_eq!(&decoded[..len], input);
+        }
+
+        // 254 non-zero bytes fill a block, which takes no implied zero
+        let len = cobs_encode(&nonzero(254)[..254], &mut encoded).unwrap();
+        assert_eq!(len, 256);
+        assert_eq!(encoded[0], 0xFF);
+        assert_eq!(encoded[255], 0x01);
+        // The 255th starts the next block
+        let len = cobs_encode(&nonzero(255)[..255], &mut encoded).unwrap();
+        assert_eq!(len, 257);
+        assert_eq!(encoded[255], 0x02);
+
+        // A full block followed by a zero
+        let mut input = nonzero(255);
+        input[254] = 0;
+        round_trip(&input[..255]);
+    }
+
+    #[test]
+    fn bad_crc_is_rejected() {
+        let payload = b"\x01\x02\x00\x00hello";
+        let mut buf = [0; 16];
+        buf[..payload.len()].copy_from_slice(payload);
+        let crc = crc16(payload) ^ 0x0001;
+        buf[payload.len()..payload.len() + 2].copy_from_slice(&crc.to_le_bytes());
+
+        let mut frame = [0; 32];
+        let len = cobs_encode(&buf[..payload.len() + 2], &mut frame).unwrap();
+        let mut out = [0; 32];
+        assert_eq!(decode_frame(&frame[..len], &mut out), Err(Status::BadFrame));
+    }
+
+    #[test]
+    fn crc_matches_the_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn truncated_frames_are_rejected() {
+        let mut frame = [0; MAX_FRAME];
+        let len = encode_frame(b"\x04\x07\x00\x00", &mut frame).unwrap() - 1;
+        let mut out = [0; MAX_PAYLOAD + 2];
+        for cut in 0..len {
+            assert_eq!(decode_frame(&frame[..cut], &mut out), Err(Status::BadFrame));
+        }
+
+        // The code byte promises more than there is
+        assert_eq!(cobs_decode(&[5, 1, 2], &mut out), None);
+        // A zero code byte is never valid
+        assert_eq!(cobs_decode(&[0], &mut out), None);
+        // Does not fit the output
+        assert_eq!(cobs_decode(&[4, 1, 2, 3], &mut out[..2]), None);
+
+        assert_eq!(
+            Request::decode(&[0x04, 0, 0]).err(),
+            Some(Status::BadLength)
+        );
+        // AAD longer than what follows the header
+        assert_eq!(
+            Request::decode(&[0x01, 0, 3, 0, 0xAA]).err(),
+            Some(Status::BadLength)
+        );
+        assert!(Response::decode(&[0x00]).is_none());
+    }
+
+    #[test]
+    fn unknown_opcodes_are_rejected() {
+        for byte in [0x00, 0x0A, 0x7F, 0xFF].iter() {
+            assert_eq!(Opcode::try_from(*byte), Err(Status::UnknownOpcode));
+            assert_eq!(
+                Request::decode(&[*byte, 0, 0, 0]).err(),
+                Some(Status::UnknownOpcode)
+            );
+        }
+        assert_eq!(Status::try_from(0x0B), Err(()));
+        assert!(Response::decode(&[0x0B, 0]).is_none());
+        assert_eq!(Algorithm::try_from(0x05), Err(Status::BadAlgorithm));
+    }
+
+    #[test]
+    fn requests_and_responses_round_trip() {
+        let request = Request {
+            opcode: Opcode::Encrypt,
+            seq: 9,
+            aad: b"header",
+            data: b"\x00secret\x00",
+        };
+        let mut payload = [0; MAX_PAYLOAD];
+        let len = request.encode(&mut payload).unwrap();
+        let decoded = Request::decode(&payload[..len]).unwrap();
+        assert_eq!(decoded.opcode, Opcode::Encrypt);
+        assert_eq!(decoded.seq, 9);
+        assert_eq!(decoded.aad, b"header");
+        assert_eq!(decoded.data, b"\x00secret\x00");
+
+        let too_long = Request {
+            aad: &[0; MAX_AAD + 1],
+            ..request
+        };
+        assert_eq!(too_long.encode(&mut payload), None);
+
+        let response = Response {
+            status: Status::AuthFailed,
+            seq: 200,
+            data: &[1, 2, 3],
+        };
+        let len = response.encode(&mut payload).unwrap();
+        let decoded = Response::decode(&payload[..len]).unwrap();
+        assert_eq!(decoded.status, Status::AuthFailed);
+        assert_eq!(decoded.seq, 200);
+        assert_eq!(decoded.data, [1, 2, 3]);
+    }
+
+    #[test]
+    fn reader_splits_frames_and_drops_oversized_ones() {
+        let mut reader = FrameReader::default();
+        assert_eq!(reader.push(0), None);
+        for byte in [3, 1, 2].iter() {
+            assert_eq!(reader.push(*byte), None);
+        }
+        assert_eq!(reader.push(0), Some(Ok(&[3, 1, 2][..])));
+
+        for _ in 0..MAX_FRAME + 1 {
+            assert_eq!(reader.push(1), None);
+        }
+        assert_eq!(reader.push(0), Some(Err(Status::BadFrame)));
+        // Back to normal after the delimiter
+        reader.push(1);
+        assert_eq!(reader.push(0), Some(Ok(&[1][..])));
+    }
+}
@@ -0,0 +1,245 @@
+//! Client for the USB crypto service, over any byte stream
+//!
+//! Normally that stream is the CDC-ACM port of the device, but anything that
+//! implements `Read + Write` works, the tests use a pseudo-terminal.
+use crypto_protocol::{
+    self as protocol, Algorithm, FrameReader, Opcode, Request, Response, Status,
+};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+/// USB IDs of `with_hal/src/bin/serial_rust_crypto.rs`, shared with other
+/// hobby CDC-ACM devices
+pub const VID: u16 = 0x16c0;
+pub const PID: u16 = 0x27dd;
+/// Product string of the device, tells it apart from others with its IDs
+pub const PRODUCT: &str = "AEAD over USB";
+/// Size of a key as taken by SetKey and Provision
+pub const KEY_LEN: usize = 32;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Serial(serialport::Error),
+    /// No port with the device's VID, PID and product string is connected
+    NotFound,
+    /// The device answered with an error status
+    Status(Status),
+    /// The response could not be decoded
+    Malformed,
+    /// Request does not fit in a frame
+    TooLarge,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Serial(e) => write!(f, "serial port error: {}", e),
+            Error::NotFound => write!(
+                f,
+                "no {:?} device with ID {:04x}:{:04x} found",
+                PRODUCT, VID, PID
+            ),
+            Error::Status(status) => write!(f, "device answered {:?}", status),
+            Error::Malformed => write!(f, "malformed response"),
+            Error::TooLarge => write!(f, "request too large"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<serialport::Error> for Error {
+    fn from(e: serialport::Error) -> Self {
+        Error::Serial(e)
+    }
+}
+
+/// Answer to GetInfo
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Info {
+    pub version: u8,
+    pub key_set: bool,
+    /// Boot counter used as nonce prefix, `u32::MAX` once it ran out
+    pub boot: u32,
+    /// Largest plaintext a single Encrypt accepts
+    pub max_data: usize,
+    /// Slot of the active key, `None` if it was set with SetKey or there is none
+    pub slot: Option<u8>,
+    /// Bitmap of the slots holding a provisioned key
+    pub used_slots: u16,
+    pub algorithm: Algorithm,
+}
+
+/// Path of the first serial port with the device's VID, PID and product
+/// string. Ports whose product string can not be read are matched by their
+/// IDs alone.
+pub fn find_port() -> Result<String, Error> {
+    serialport::available_ports()?
+        .into_iter()
+        .find(|port| match &port.port_type {
+            serialport::SerialPortType::UsbPort(usb) => {
+                usb.vid == VID
+                    && usb.pid == PID
+                    && usb
+                        .product
+                        .as_deref()
+                        .is_none_or(|product| product == PRODUCT)
+            }
+            _ => false,
+        })
+        .map(|port| port.port_name)
+        .ok_or(Error::NotFound)
+}
+
+/// Opens the serial port at `path`, the baud rate is ignored by CDC-ACM
+pub fn open(path: &str) -> Result<Device<Box<dyn serialport::SerialPort>>, Error> {
+    let port = serialport::new(path, 115_200)
+        .timeout(Duration::from_secs(2))
+        .open()?;
+    Ok(Device::new(port))
+}
+
+pub struct Device<P> {
+    port: P,
+    seq: u8,
+    reader: FrameReader,
+}
+
+impl<P: Read + Write> Device<P> {
+    pub fn new(port: P) -> Self {
+        Device {
+            port,
+            seq: 0,
+            reader: FrameReader::default(),
+        }
+    }
+
+    pub fn release(self) -> P {
+        self.port
+    }
+
+    /// Sends one request and waits for the response with the same sequence
+    /// number, returns its data
+    pub fn request(&mut self, opcode: Opcode, aad: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+        self.seq = self.seq.wrapping_add(1);
+        let request = Request {
+            opcode,
+            seq: self.seq,
+            aad,
+            data,
+        };
+        let mut payload = [0; protocol::MAX_PAYLOAD];
+        let mut frame = [0; protocol::MAX_FRAME];
+        let len = request
+            .encode(&mut payload)
+            .and_then(|len| protocol::encode_frame(&payload[..len], &mut frame))
+            .ok_or(Error::TooLarge)?;
+        self.port.write_all(&frame[..len])?;
+        self.port.flush()?;
+
+        loop {
+            let mut payload = [0; protocol::MAX_PAYLOAD + 2];
+            let len = self.read_frame(&mut payload)?;
+            let response = Response::decode(&payload[..len]).ok_or(Error::Malformed)?;
+            // Answers to earlier, abandoned requests are skipped
+            if response.seq != self.seq {
+                continue;
+            }
+            return match response.status {
+                Status::Ok => Ok(response.data.to_vec()),
+                status => Err(Error::Status(status)),
+            };
+        }
+    }
+
+    /// Reads until a whole frame arrived and decodes it into `out`
+    fn read_frame(&mut self, out: &mut [u8]) -> Result<usize, Error> {
+        let mut byte = [0];
+        loop {
+            self.port.read_exact(&mut byte)?;
+            if let Some(frame) = self.reader.push(byte[0]) {
+                return frame
+                    .and_then(|frame| protocol::decode_frame(frame, out))
+                    .map_err(|_| Error::Malformed);
+            }
+        }
+    }
+
+    /// Replaces the key of the device, it is kept in RAM until reset
+    pub fn set_key(&mut self, key: &[u8; KEY_LEN]) -> Result<(), Error> {
+        self.request(Opcode::SetKey, &[], key).map(|_| ())
+    }
+
+    /// Returns `nonce || ciphertext || tag`, `data` is at most
+    /// [`protocol::MAX_DATA`] bytes
+    pub fn encrypt(&mut self, aad: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+        self.request(Opcode::Encrypt, aad, data)
+    }
+
+    /// Takes the output of [`Device::encrypt`] and the same AAD
+    pub fn decrypt(&mut self, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, Error> {
+        self.request(Opcode::Decrypt, aad, sealed)
+    }
+
+    /// Stores `key` as `id` in a free slot of the device's key table
+    pub fn provision(&mut self, slot: u8, id: u32, key: &[u8; KEY_LEN]) -> Result<(), Error> {
+        let mut data = vec![slot];
+        data.extend_from_slice(&id.to_le_bytes());
+        data.extend_from_slice(key);
+        self.request(Opcode::Provision, &[], &data).map(|_| ())
+    }
+
+    /// Makes the key stored in `slot` the active one
+    pub fn load_key(&mut self, slot: u8) -> Result<(), Error> {
+        self.request(Opcode::LoadKey, &[], &[slot]).map(|_| ())
+    }
+
+    /// Destroys the key in `slot`, or every stored key for `None`
+    pub fn zeroize(&mut self, slot: Option<u8>) -> Result<(), Error> {
+        let data = slot.as_slice();
+        self.request(Opcode::Zeroize, &[], data).map(|_| ())
+    }
+
+    /// Selects the AEAD for the following Encrypt and Decrypt requests
+    pub fn set_algorithm(&mut self, algorithm: Algorithm) -> Result<(), Error> {
+        self.request(Opcode::SetAlgorithm, &[], &[algorithm as u8])
+            .map(|_| ())
+    }
+
+    /// Encrypts `len` bytes on the device, returns the cycles it took
+    pub fn benchmark(&mut self, algorithm: Algorithm, len: u16) -> Result<u32, Error> {
+        let mut data = vec![algorithm as u8];
+        data.extend_from_slice(&len.to_le_bytes());
+        let data = self.request(Opcode::Benchmark, &[], &data)?;
+        if data.len() < 6 {
+            return Err(Error::Malformed);
+        }
+        Ok(u32::from_le_bytes([data[0], data[1], data[2], data[3]]))
+    }
+
+    pub fn info(&mut self) -> Result<Info, Error> {
+        let data = self.request(Opcode::GetInfo, &[], &[])?;
+        if data.len() < 12 {
+            return Err(Error::Malformed);
+        }
+        Ok(Info {
+            version: data[0],
+            key_set: data[1] != 0,
+            boot: u32::from_le_bytes([data[2], data[3], data[4], data[5]]),
+            max_data: usize::from(u16::from_le_bytes([data[6], data[7]])),
+            slot: Some(data[8]).filter(|slot| *slot != protocol::NO_SLOT),
+            used_slots: u16::from_le_bytes([data[9], data[10]]),
+            algorithm: Algorithm::try_from(data[11]).map_err(|_| Error::Malformed)?,
+        })
+    }
+}
@@ -0,0 +1,5 @@
+//! Host side of the USB crypto service in `with_hal/src/bin/serial_rust_crypto.rs`
+pub mod device;
+pub mod stream;
+
+pub use device::{find_port, open, Device, Error, Info};
@@ -0,0 +1,255 @@
+//! Command line client for the USB crypto device
+//!
+//! Keys are 32 bytes written as 64 hex digits, `crypto keygen` makes one.
+//! `crypto provision` stores a key in one of the device's flash slots, the
+//! first one is used after every reset. `--key` instead loads a key into RAM
+//! only and `--slot` switches to another stored key before an operation.
+use clap::{Parser, Subcommand, ValueEnum};
+use crypto_host::device::{self, Device, KEY_LEN};
+use crypto_host::stream;
+use crypto_protocol::Algorithm;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Encrypt and decrypt with the STM32F4 USB crypto device"
+)]
+struct Cli {
+    /// Serial port of the device, found by its USB VID:PID and product when not given
+    #[arg(short, long, global = true)]
+    port: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Show the protocol version, key state and boot counter of the device
+    Info,
+    /// Generate a random key, no device needed
+    Keygen {
+        /// Key file to write instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Store a key in a free slot of the device's key table
+    Provision {
+        /// Slot to write, slots can only be written once until `zeroize --all`
+        #[arg(short, long)]
+        slot: u8,
+        /// Identifier stored alongside the key
+        #[arg(short, long, default_value_t = 0)]
+        id: u32,
+        /// Key file (64 hex digits)
+        #[arg(short, long)]
+        key: PathBuf,
+    },
+    /// Destroy stored keys
+    Zeroize {
+        /// Slot to destroy, it stays unusable until `zeroize --all`
+        #[arg(short, long, required_unless_present = "all", conflicts_with = "all")]
+        slot: Option<u8>,
+        /// Erase the whole key table
+        #[arg(long)]
+        all: bool,
+    },
+    /// Encrypt a file or stdin
+    Encrypt(Crypt),
+    /// Decrypt the output of `encrypt`
+    Decrypt(Crypt),
+    /// Measure the cycles per byte of every algorithm on the device
+    Bench {
+        /// Bytes encrypted per algorithm (at most 4096)
+        #[arg(
+            short,
+            long,
+            default_value_t = 1024,
+            value_parser = clap::value_parser!(u16).range(1..=i64::from(crypto_protocol::BENCH_MAX))
+        )]
+        len: u16,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Aead {
+    Chacha8,
+    Chacha12,
+    Chacha20,
+    Xchacha20,
+    Aes256gcm,
+}
+
+impl From<Aead> for Algorithm {
+    fn from(aead: Aead) -> Self {
+        match aead {
+            Aead::Chacha8 => Algorithm::ChaCha8Poly1305,
+            Aead::Chacha12 => Algorithm::ChaCha12Poly1305,
+            Aead::Chacha20 => Algorithm::ChaCha20Poly1305,
+            Aead::Xchacha20 => Algorithm::XChaCha20Poly1305,
+            Aead::Aes256gcm => Algorithm::Aes256Gcm,
+        }
+    }
+}
+
+#[derive(clap::Args)]
+struct Crypt {
+    /// Key file (64 hex digits) to load into the device's RAM first
+    #[arg(short, long, conflicts_with = "slot")]
+    key: Option<PathBuf>,
+    /// Stored key to use instead of the active one
+    #[arg(short, long)]
+    slot: Option<u8>,
+    /// Associated data, authenticated but not encrypted
+    #[arg(short, long, default_value = "")]
+    aad: String,
+    /// AEAD to use, decryption needs the one used for encryption
+    #[arg(long, value_enum, default_value_t = Aead::Chacha20)]
+    algorithm: Aead,
+    /// Output file instead of stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Input file, stdin when not given or `-`
+    input: Option<PathBuf>,
+}
+
+fn main() {
+    if let Err(e) = run(Cli::parse()) {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(cli: Cli) -> Result<(), Box<dyn Error>> {
+    if let Command::Keygen { output } = &cli.command {
+        let mut key = [0; KEY_LEN];
+        getrandom::getrandom(&mut key)?;
+        return write_output(output.as_deref(), format!("{}\n", to_hex(&key)).as_bytes());
+    }
+
+    let port = match cli.port {
+        Some(port) => port,
+        None => device::find_port()?,
+    };
+    let mut device = device::open(&port)?;
+
+    match cli.command {
+        Command::Info => {
+            let info = device.info()?;
+            println!("port:      {}", port);
+            println!("version:   {}", info.version);
+            println!("key set:   {}", info.key_set);
+            if info.boot == u32::MAX {
+                println!("boot:      exhausted, encryption disabled");
+            } else {
+                println!("boot:      {}", info.boot);
+            }
+            println!("max data:  {} bytes", info.max_data);
+            match info.slot {
+                Some(slot) => println!("active:    slot {}", slot),
+                None => println!("active:    none"),
+            }
+            let used: Vec<String> = (0..16)
+                .filter(|slot| info.used_slots & 1 << slot != 0)
+                .map(|slot| slot.to_string())
+                .collect();
+            println!("slots:     {}", used.join(" "));
+            println!("algorithm: {:?}", info.algorithm);
+        }
+        Command::Bench { len } => {
+            for algorithm in Algorithm::ALL.iter().copied() {
+                let cycles = device.benchmark(algorithm, len)?;
+                println!(
+                    "{:<18} {:>8} cycles  {:>7.2} cycles/byte",
+                    format!("{:?}", algorithm),
+                    cycles,
+                    f64::from(cycles) / f64::from(len)
+                );
+            }
+        }
+        Command::Provision { slot, id, key } => {
+            device.provision(slot, id, &read_key(&key)?)?;
+        }
+        Command::Zeroize { slot, .. } => {
+            // Without a slot `--all` was given
+            device.zeroize(slot)?;
+        }
+        Command::Encrypt(args) => {
+            let input = prepare(&mut device, &args)?;
+            let mut output = Vec::new();
+            stream::encrypt(&mut device, args.aad.as_bytes(), &input[..], &mut output)?;
+            write_output(args.output.as_deref(), &output)?;
+        }
+        Command::Decrypt(args) => {
+            let input = prepare(&mut device, &args)?;
+            // Buffered so nothing is written unless every chunk authenticates
+            let mut output = Vec::new();
+            stream::decrypt(&mut device, args.aad.as_bytes(), &input[..], &mut output)?;
+            write_output(args.output.as_deref(), &output)?;
+        }
+        Command::Keygen { .. } => unreachable!(),
+    }
+    Ok(())
+}
+
+/// Loads the key into the device if one was given and reads the whole input
+fn prepare<P: Read + Write>(
+    device: &mut Device<P>,
+    args: &Crypt,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    if args.aad.len() > stream::MAX_AAD {
+        return Err(format!("AAD is limited to {} bytes", stream::MAX_AAD).into());
+    }
+    if let Some(path) = &args.key {
+        device.set_key(&read_key(path)?)?;
+    }
+    if let Some(slot) = args.slot {
+        device.load_key(slot)?;
+    }
+    device.set_algorithm(args.algorithm.into())?;
+    let mut input = Vec::new();
+    match args.input.as_deref() {
+        Some(path) if path != Path::new("-") => File::open(path)?.read_to_end(&mut input)?,
+        _ => io::stdin().read_to_end(&mut input)?,
+    };
+    Ok(input)
+}
+
+fn write_output(path: Option<&Path>, data: &[u8]) -> Result<(), Box<dyn Error>> {
+    match path {
+        Some(path) => File::create(path)?.write_all(data)?,
+        None => {
+            let mut stdout = io::stdout();
+            stdout.write_all(data)?;
+            stdout.flush()?;
+        }
+    }
+    Ok(())
+}
+
+fn read_key(path: &Path) -> Result<[u8; KEY_LEN], Box<dyn Error>> {
+    let text = std::fs::read_to_string(path)?;
+    let text = text.trim();
+    let mut key = [0; KEY_LEN];
+    if text.len() != KEY_LEN * 2 || !text.is_ascii() {
+        return Err(format!(
+            "{} does not hold {} hex digits",
+            path.display(),
+            KEY_LEN * 2
+        )
+        .into());
+    }
+    for (byte, digits) in key.iter_mut().zip(text.as_bytes().chunks(2)) {
+        let digits = std::str::from_utf8(digits)?;
+        *byte = u8::from_str_radix(digits, 16)
+            .map_err(|_| format!("{} does not hold a hex key", path.display()))?;
+    }
+    Ok(key)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
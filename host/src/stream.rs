@@ -0,0 +1,113 @@
+//! Encryption of inputs larger than a single request
+//!
+//! The input is cut into chunks of [`CHUNK`] bytes that are sealed one by one.
+//! Each record on the output is `| length (u16 LE) | nonce || ciphertext || tag |`.
+//! The AAD of a chunk is the user AAD followed by the chunk index (u32 LE) and
+//! a flag set on the last chunk, so records can not be reordered, dropped or
+//! cut off at the end without decryption failing.
+use crate::device::{Device, Error};
+use crypto_protocol as protocol;
+use std::io::{self, Read, Write};
+
+/// Plaintext bytes per record
+pub const CHUNK: usize = protocol::MAX_DATA;
+/// Room taken from the AAD by the chunk index and last flag
+const CHUNK_AAD: usize = 5;
+/// Largest user AAD for streams
+pub const MAX_AAD: usize = protocol::MAX_AAD - CHUNK_AAD;
+
+fn chunk_aad(aad: &[u8], index: u32, last: bool) -> Result<Vec<u8>, Error> {
+    if aad.len() > MAX_AAD {
+        return Err(Error::TooLarge);
+    }
+    let mut chunk = aad.to_vec();
+    chunk.extend_from_slice(&index.to_le_bytes());
+    chunk.push(last as u8);
+    Ok(chunk)
+}
+
+/// Fills `buf` as far as `input` allows, returns the number of bytes read
+fn read_full(input: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match input.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+/// Reads the next record, `None` at the end of the input
+fn read_record(input: &mut impl Read) -> Result<Option<Vec<u8>>, Error> {
+    let mut len = [0; 2];
+    match read_full(input, &mut len)? {
+        0 => return Ok(None),
+        2 => {}
+        _ => return Err(Error::Malformed),
+    }
+    let mut record = vec![0; usize::from(u16::from_le_bytes(len))];
+    if read_full(input, &mut record)? != record.len() {
+        return Err(Error::Malformed);
+    }
+    Ok(Some(record))
+}
+
+/// Encrypts all of `input` into records on `output`, an empty input still
+/// produces one record
+pub fn encrypt<P: Read + Write>(
+    device: &mut Device<P>,
+    aad: &[u8],
+    mut input: impl Read,
+    mut output: impl Write,
+) -> Result<(), Error> {
+    let mut chunk = vec![0; CHUNK];
+    let mut next = vec![0; CHUNK];
+    let mut len = read_full(&mut input, &mut chunk)?;
+    let mut index = 0_u32;
+    loop {
+        // Read ahead to know if this is the last chunk
+        let next_len = if len == CHUNK {
+            read_full(&mut input, &mut next)?
+        } else {
+            0
+        };
+        let last = next_len == 0;
+        let sealed = device.encrypt(&chunk_aad(aad, index, last)?, &chunk[..len])?;
+        output.write_all(&(sealed.len() as u16).to_le_bytes())?;
+        output.write_all(&sealed)?;
+        if last {
+            return Ok(output.flush()?);
+        }
+        std::mem::swap(&mut chunk, &mut next);
+        len = next_len;
+        index = index.checked_add(1).ok_or(Error::TooLarge)?;
+    }
+}
+
+/// Decrypts records produced by [`encrypt`] with the same key and AAD
+///
+/// Chunks are written as soon as they are authenticated, if an error is
+/// returned everything written so far must be discarded
+pub fn decrypt<P: Read + Write>(
+    device: &mut Device<P>,
+    aad: &[u8],
+    mut input: impl Read,
+    mut output: impl Write,
+) -> Result<(), Error> {
+    let mut record = read_record(&mut input)?.ok_or(Error::Malformed)?;
+    let mut index = 0_u32;
+    loop {
+        let next = read_record(&mut input)?;
+        let last = next.is_none();
+        let plaintext = device.decrypt(&chunk_aad(aad, index, last)?, &record)?;
+        output.write_all(&plaintext)?;
+        match next {
+            Some(next) => record = next,
+            None => return Ok(output.flush()?),
+        }
+        index = index.checked_add(1).ok_or(Error::Malformed)?;
+    }
+}
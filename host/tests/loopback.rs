@@ -0,0 +1,384 @@
+//! Runs the client against a stand-in for the firmware on a pseudo-terminal
+#![cfg(unix)]
+
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::aead::generic_array::GenericArray;
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{ChaCha12Poly1305, ChaCha20Poly1305, ChaCha8Poly1305, XChaCha20Poly1305};
+use crypto_host::device::{Device, Error};
+use crypto_host::stream;
+use crypto_protocol::{
+    self as protocol, Algorithm, FrameReader, Opcode, Request, Response, Status, TAG_LEN,
+};
+use serialport::{SerialPort, TTYPort};
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::process::Command;
+use std::thread;
+
+const KEY: [u8; 32] = [0x42; 32];
+
+#[derive(Clone, Copy, Default, PartialEq)]
+enum FakeSlot {
+    #[default]
+    Free,
+    Used([u8; 32]),
+    Zeroized,
+}
+
+/// Evaluates `$op` with `$aead` set up for `$algorithm` and `$key`
+macro_rules! with_aead {
+    ($algorithm:expr, $key:expr, |$aead:ident| $op:expr) => {
+        match $algorithm {
+            Algorithm::ChaCha8Poly1305 => {
+                let $aead = ChaCha8Poly1305::new(GenericArray::from_slice($key));
+                $op
+            }
+            Algorithm::ChaCha12Poly1305 => {
+                let $aead = ChaCha12Poly1305::new(GenericArray::from_slice($key));
+                $op
+            }
+            Algorithm::ChaCha20Poly1305 => {
+                let $aead = ChaCha20Poly1305::new(GenericArray::from_slice($key));
+                $op
+            }
+            Algorithm::XChaCha20Poly1305 => {
+                let $aead = XChaCha20Poly1305::new(GenericArray::from_slice($key));
+                $op
+            }
+            Algorithm::Aes256Gcm => {
+                let $aead = Aes256Gcm::new(GenericArray::from_slice($key));
+                $op
+            }
+        }
+    };
+}
+
+/// Mirrors the request handling of `serial_rust_crypto.rs`, the key table is
+/// kept in memory and not wrapped
+#[derive(Default)]
+struct FakeDevice {
+    key: Option<[u8; 32]>,
+    algorithm: Algorithm,
+    counter: u64,
+    slot: Option<u8>,
+    slots: [FakeSlot; 16],
+}
+
+impl FakeDevice {
+    fn handle(&mut self, request: &Request) -> Result<Vec<u8>, Status> {
+        match request.opcode {
+            Opcode::Encrypt => {
+                let key = self.key.ok_or(Status::NoKey)?;
+                if request.data.len() > protocol::MAX_DATA {
+                    return Err(Status::BadLength);
+                }
+                self.counter += 1;
+                let mut nonce = vec![0; self.algorithm.nonce_len()];
+                let len = nonce.len();
+                nonce[len - 8..].copy_from_slice(&self.counter.to_be_bytes());
+                let mut body = request.data.to_vec();
+                let tag = with_aead!(self.algorithm, &key, |aead| aead
+                    .encrypt_in_place_detached(
+                        GenericArray::from_slice(&nonce),
+                        request.aad,
+                        &mut body
+                    )
+                    .map(|tag| tag.to_vec()))
+                .map_err(|_| Status::BadLength)?;
+                Ok([&nonce[..], &body, &tag].concat())
+            }
+            Opcode::Decrypt => {
+                let key = self.key.ok_or(Status::NoKey)?;
+                let nonce_len = self.algorithm.nonce_len();
+                if request.data.len() < nonce_len + TAG_LEN {
+                    return Err(Status::BadLength);
+                }
+                let (nonce, rest) = request.data.split_at(nonce_len);
+                let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+                let mut body = ciphertext.to_vec();
+                with_aead!(self.algorithm, &key, |aead| aead.decrypt_in_place_detached(
+                    GenericArray::from_slice(nonce),
+                    request.aad,
+                    &mut body,
+                    GenericArray::from_slice(tag),
+                ))
+                .map_err(|_| Status::AuthFailed)?;
+                Ok(body)
+            }
+            Opcode::SetAlgorithm => match request.data {
+                [algorithm] => {
+                    self.algorithm = Algorithm::try_from(*algorithm)?;
+                    Ok(Vec::new())
+                }
+                _ => Err(Status::BadLength),
+            },
+            Opcode::Benchmark => {
+                if request.data.len() != 3 {
+                    return Err(Status::BadLength);
+                }
+                Algorithm::try_from(request.data[0])?;
+                // No cycle counter here, any plausible number will do
+                let mut answer = 1000_u32.to_le_bytes().to_vec();
+                answer.extend_from_slice(&request.data[1..3]);
+                Ok(answer)
+            }
+            Opcode::SetKey => {
+                if request.data.len() != 32 {
+                    return Err(Status::BadLength);
+                }
+                let mut key = [0; 32];
+                key.copy_from_slice(request.data);
+                self.key = Some(key);
+                self.slot = None;
+                Ok(Vec::new())
+            }
+            Opcode::Provision => {
+                if request.data.len() != 37 {
+                    return Err(Status::BadLength);
+                }
+                let slot = self
+                    .slots
+                    .get_mut(usize::from(request.data[0]))
+                    .ok_or(Status::BadLength)?;
+                if *slot != FakeSlot::Free {
+                    return Err(Status::SlotInUse);
+                }
+                let mut key = [0; 32];
+                key.copy_from_slice(&request.data[5..]);
+                *slot = FakeSlot::Used(key);
+                Ok(Vec::new())
+            }
+            Opcode::LoadKey => match request.data {
+                [slot] => match self.slots.get(usize::from(*slot)) {
+                    Some(FakeSlot::Used(key)) => {
+                        self.key = Some(*key);
+                        self.slot = Some(*slot);
+                        Ok(Vec::new())
+                    }
+                    Some(_) => Err(Status::SlotEmpty),
+                    None => Err(Status::BadLength),
+                },
+                _ => Err(Status::BadLength),
+            },
+            Opcode::Zeroize => {
+                match request.data {
+                    [] => self.slots = [FakeSlot::Free; 16],
+                    [slot] => {
+                        *self
+                            .slots
+                            .get_mut(usize::from(*slot))
+                            .ok_or(Status::BadLength)? = FakeSlot::Zeroized;
+                        if self.slot != Some(*slot) {
+                            return Ok(Vec::new());
+                        }
+                    }
+                    _ => return Err(Status::BadLength),
+                }
+                self.key = None;
+                self.slot = None;
+                Ok(Vec::new())
+            }
+            Opcode::GetInfo => {
+                let mut info = vec![protocol::VERSION, self.key.is_some() as u8];
+                info.extend_from_slice(&7_u32.to_le_bytes());
+                info.extend_from_slice(&(protocol::MAX_DATA as u16).to_le_bytes());
+                info.push(self.slot.unwrap_or(protocol::NO_SLOT));
+                let used = (0..16)
+                    .filter(|slot| matches!(self.slots[*slot], FakeSlot::Used(_)))
+                    .fold(0_u16, |bits, slot| bits | 1 << slot);
+                info.extend_from_slice(&used.to_le_bytes());
+                info.push(self.algorithm as u8);
+                Ok(info)
+            }
+        }
+    }
+
+    /// Serves requests until the other end of the pseudo-terminal is closed
+    fn serve(mut self, mut port: TTYPort) {
+        let mut reader = FrameReader::default();
+        let mut byte = [0];
+        loop {
+            match port.read(&mut byte) {
+                Ok(1) => {}
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                _ => return,
+            }
+            let frame = match reader.push(byte[0]) {
+                Some(frame) => frame,
+                None => continue,
+            };
+            let mut payload = [0; protocol::MAX_PAYLOAD + 2];
+            let (status, seq, data) =
+                match frame.and_then(|frame| protocol::decode_frame(frame, &mut payload)) {
+                    Ok(len) => match Request::decode(&payload[..len]) {
+                        Ok(request) => match self.handle(&request) {
+                            Ok(data) => (Status::Ok, request.seq, data),
+                            Err(status) => (status, request.seq, Vec::new()),
+                        },
+                        Err(status) => (status, payload.get(1).copied().unwrap_or(0), Vec::new()),
+                    },
+                    Err(status) => (status, 0, Vec::new()),
+                };
+            let response = Response {
+                status,
+                seq,
+                data: &data,
+            };
+            let mut encoded = [0; protocol::MAX_PAYLOAD];
+            let mut out = [0; protocol::MAX_FRAME];
+            let len = response.encode(&mut encoded).unwrap();
+            let len = protocol::encode_frame(&encoded[..len], &mut out).unwrap();
+            if port.write_all(&out[..len]).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Client connected to a fresh fake device
+fn connect() -> Device<TTYPort> {
+    let (host, device) = TTYPort::pair().unwrap();
+    let fake = FakeDevice::default();
+    thread::spawn(move || fake.serve(device));
+    Device::new(host)
+}
+
+#[test]
+fn info() {
+    let mut device = connect();
+    let info = device.info().unwrap();
+    assert_eq!(info.version, protocol::VERSION);
+    assert!(!info.key_set);
+    assert_eq!(info.max_data, protocol::MAX_DATA);
+    device.set_key(&KEY).unwrap();
+    assert!(device.info().unwrap().key_set);
+}
+
+#[test]
+fn encrypt_needs_key() {
+    let mut device = connect();
+    assert!(matches!(
+        device.encrypt(b"", b"hello"),
+        Err(Error::Status(Status::NoKey))
+    ));
+}
+
+#[test]
+fn round_trip_with_aad() {
+    let mut device = connect();
+    device.set_key(&KEY).unwrap();
+    let sealed = device.encrypt(b"header", b"hello world").unwrap();
+    assert_eq!(
+        sealed.len(),
+        b"hello world".len() + Algorithm::default().overhead()
+    );
+    // Nonces must never repeat
+    assert_ne!(device.encrypt(b"header", b"hello world").unwrap(), sealed);
+    assert_eq!(device.decrypt(b"header", &sealed).unwrap(), b"hello world");
+    assert!(matches!(
+        device.decrypt(b"other", &sealed),
+        Err(Error::Status(Status::AuthFailed))
+    ));
+}
+
+#[test]
+fn every_algorithm() {
+    let mut device = connect();
+    device.set_key(&KEY).unwrap();
+    for algorithm in Algorithm::ALL.iter().copied() {
+        device.set_algorithm(algorithm).unwrap();
+        assert_eq!(device.info().unwrap().algorithm, algorithm);
+        let sealed = device.encrypt(b"aad", b"payload").unwrap();
+        assert_eq!(sealed.len(), b"payload".len() + algorithm.overhead());
+        assert_eq!(device.decrypt(b"aad", &sealed).unwrap(), b"payload");
+        assert!(device.benchmark(algorithm, 1024).is_ok());
+    }
+    assert!(matches!(
+        device.request(Opcode::SetAlgorithm, &[], &[0x7F]),
+        Err(Error::Status(Status::BadAlgorithm))
+    ));
+}
+
+#[test]
+fn provisioned_slots() {
+    let mut device = connect();
+    device.provision(3, 0x1234, &KEY).unwrap();
+    assert!(matches!(
+        device.provision(3, 0x1234, &KEY),
+        Err(Error::Status(Status::SlotInUse))
+    ));
+    let info = device.info().unwrap();
+    assert_eq!(info.used_slots, 1 << 3);
+    assert_eq!(info.slot, None);
+
+    device.load_key(3).unwrap();
+    assert_eq!(device.info().unwrap().slot, Some(3));
+    let sealed = device.encrypt(b"", b"factory keyed").unwrap();
+    // The same key through SetKey decrypts it
+    device.set_key(&KEY).unwrap();
+    assert_eq!(device.decrypt(b"", &sealed).unwrap(), b"factory keyed");
+
+    device.load_key(3).unwrap();
+    device.zeroize(Some(3)).unwrap();
+    assert!(matches!(
+        device.encrypt(b"", b"gone"),
+        Err(Error::Status(Status::NoKey))
+    ));
+    assert!(matches!(
+        device.load_key(3),
+        Err(Error::Status(Status::SlotEmpty))
+    ));
+    assert!(matches!(
+        device.provision(3, 0, &KEY),
+        Err(Error::Status(Status::SlotInUse))
+    ));
+    device.zeroize(None).unwrap();
+    device.provision(3, 0, &KEY).unwrap();
+}
+
+#[test]
+fn stream_round_trip() {
+    let mut device = connect();
+    device.set_key(&KEY).unwrap();
+    for len in [0, 1, stream::CHUNK, stream::CHUNK * 3 + 17] {
+        let input: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        let mut sealed = Vec::new();
+        stream::encrypt(&mut device, b"file", &input[..], &mut sealed).unwrap();
+        let mut output = Vec::new();
+        stream::decrypt(&mut device, b"file", &sealed[..], &mut output).unwrap();
+        assert_eq!(output, input);
+    }
+}
+
+#[test]
+fn stream_truncation_detected() {
+    let mut device = connect();
+    device.set_key(&KEY).unwrap();
+    let input = vec![0xAB; stream::CHUNK * 2 + 1];
+    let mut sealed = Vec::new();
+    stream::encrypt(&mut device, b"", &input[..], &mut sealed).unwrap();
+    // Drop the last record
+    let record = 2 + stream::CHUNK + Algorithm::default().overhead();
+    let mut output = Vec::new();
+    assert!(matches!(
+        stream::decrypt(&mut device, b"", &sealed[..record * 2], &mut output),
+        Err(Error::Status(Status::AuthFailed))
+    ));
+}
+
+#[test]
+fn cli_info() {
+    // The CLI opens the slave end by name, the fake device serves the master
+    let (master, slave) = TTYPort::pair().unwrap();
+    let fake = FakeDevice::default();
+    thread::spawn(move || fake.serve(master));
+    let output = Command::new(env!("CARGO_BIN_EXE_crypto"))
+        .args(["info", "--port"])
+        .arg(slave.name().unwrap())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("key set:   false"), "{}", stdout);
+}
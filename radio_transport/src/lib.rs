@@ -0,0 +1,219 @@
+//! Fragmentation and reassembly of messages larger than a single radio frame
+//!
+//! Every frame starts with a 3 byte header followed by a chunk of the message:
+//! | message id | fragment index | fragment count | data ... |
+//! All fragments but the last carry a full chunk, so the receiver knows where
+//! each one belongs and can tell duplicates apart from new data.
+//!
+//! The framing only depends on the [`FrameLink`] and [`Clock`] traits and not
+//! on the radio, so `tests/` runs it against a simulated lossy channel on the
+//! host. The firmware implements both traits for the nRF24L01 link.
+#![no_std]
+
+/// Largest payload a single nRF24L01 packet can carry
+pub const MAX_FRAME: usize = 32;
+
+pub const HEADER_LEN: usize = 3;
+/// Received fragments are tracked in a 64-bit bitmap
+pub const MAX_FRAGMENTS: usize = 64;
+/// Largest message that fits in [`MAX_FRAGMENTS`] full-size frames
+pub const MAX_MESSAGE: usize = MAX_FRAGMENTS * (MAX_FRAME - HEADER_LEN);
+
+/// A channel that moves single frames of at most `MTU` bytes
+pub trait FrameLink {
+    type Error;
+    /// Largest frame the link can carry, at most [`MAX_FRAME`]
+    const MTU: usize;
+
+    /// Sends one frame, returns `false` if the peer did not acknowledge it
+    fn send_frame(&mut self, frame: &[u8]) -> Result<bool, Self::Error>;
+    /// Copies the next frame into `buf` and returns its length, or `None` if
+    /// nothing has arrived yet
+    fn recv_frame(&mut self, buf: &mut [u8]) -> Result<Option<usize>, Self::Error>;
+}
+
+/// Monotonic millisecond counter, allowed to wrap around
+pub trait Clock {
+    fn now_ms(&mut self) -> u32;
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error<E> {
+    Link(E),
+    /// Message needs more than [`MAX_FRAGMENTS`] frames
+    TooLarge,
+    /// A fragment was not acknowledged after all attempts
+    Undelivered,
+    /// Received message does not fit in the caller's buffer
+    BufferTooSmall,
+}
+
+/// Message being put back together
+struct Reassembly {
+    active: bool,
+    id: u8,
+    count: u8,
+    received: u64,
+    len: usize,
+    /// Arrival time of the last fragment, used for the timeout
+    last_ms: u32,
+    /// Last message handed to the application and when, to drop late duplicates
+    completed: Option<(u8, u32)>,
+    data: [u8; MAX_MESSAGE],
+}
+
+pub struct Transport<L, C> {
+    link: L,
+    clock: C,
+    /// Attempts per fragment on top of the link's own retransmissions
+    pub retries: u8,
+    /// Partial messages are dropped after this long without a new fragment
+    pub timeout_ms: u32,
+    next_id: u8,
+    rx: Reassembly,
+}
+
+impl<L: FrameLink, C: Clock> Transport<L, C> {
+    pub fn new(link: L, clock: C) -> Self {
+        assert!(L::MTU > HEADER_LEN && L::MTU <= MAX_FRAME);
+        Transport {
+            link,
+            clock,
+            retries: 3,
+            timeout_ms: 500,
+            next_id: 0,
+            rx: Reassembly {
+                active: false,
+                id: 0,
+                count: 0,
+                received: 0,
+                len: 0,
+                last_ms: 0,
+                completed: None,
+                data: [0; MAX_MESSAGE],
+            },
+        }
+    }
+
+    pub fn link(&mut self) -> &mut L {
+        &mut self.link
+    }
+
+    pub fn release(self) -> (L, C) {
+        (self.link, self.clock)
+    }
+
+    /// Splits `message` into frames and sends them in order
+    pub fn send(&mut self, message: &[u8]) -> Result<(), Error<L::Error>> {
+        let chunk = L::MTU - HEADER_LEN;
+        let count = message.len().div_ceil(chunk).max(1);
+        if count > MAX_FRAGMENTS {
+            return Err(Error::TooLarge);
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        if message.is_empty() {
+            return self.send_fragment(id, 0, 1, &[]);
+        }
+        for (index, data) in message.chunks(chunk).enumerate() {
+            self.send_fragment(id, index as u8, count as u8, data)?;
+        }
+        Ok(())
+    }
+
+    fn send_fragment(
+        &mut self,
+        id: u8,
+        index: u8,
+        count: u8,
+        data: &[u8],
+    ) -> Result<(), Error<L::Error>> {
+        let mut frame = [0; MAX_FRAME];
+        frame[..HEADER_LEN].copy_from_slice(&[id, index, count]);
+        frame[HEADER_LEN..HEADER_LEN + data.len()].copy_from_slice(data);
+        let frame = &frame[..HEADER_LEN + data.len()];
+
+        for _ in 0..=self.retries {
+            if self.link.send_frame(frame).map_err(Error::Link)? {
+                return Ok(());
+            }
+        }
+        Err(Error::Undelivered)
+    }
+
+    /// Handles the next incoming frame and returns the length of the message
+    /// copied into `buf` once all of its fragments have arrived
+    pub fn recv(&mut self, buf: &mut [u8]) -> nb::Result<usize, Error<L::Error>> {
+        let mut frame = [0; MAX_FRAME];
+        let len = match self.link.recv_frame(&mut frame).map_err(Error::Link)? {
+            Some(len) => len,
+            None => return Err(nb::Error::WouldBlock),
+        };
+        let now = self.clock.now_ms();
+        let rx = &mut self.rx;
+
+        // Forget stale state, a partial message will never complete and a
+        // restarted sender is allowed to reuse the last message id
+        if rx.active && now.wrapping_sub(rx.last_ms) > self.timeout_ms {
+            rx.active = false;
+        }
+        if let Some((_, at)) = rx.completed {
+            if now.wrapping_sub(at) > self.timeout_ms {
+                rx.completed = None;
+            }
+        }
+
+        // Silently drop anything malformed
+        if len < HEADER_LEN {
+            return Err(nb::Error::WouldBlock);
+        }
+        let (id, index, count) = (frame[0], frame[1], frame[2]);
+        let data = &frame[HEADER_LEN..len];
+        let chunk = L::MTU - HEADER_LEN;
+        let is_last = index.wrapping_add(1) == count;
+        if count == 0
+            || usize::from(count) > MAX_FRAGMENTS
+            || index >= count
+            || data.len() > chunk
+            || (!is_last && data.len() != chunk)
+        {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        if !rx.active || rx.id != id {
+            if rx.completed.map(|(completed, _)| completed) == Some(id) {
+                // Late duplicate of a message that was already delivered
+                return Err(nb::Error::WouldBlock);
+            }
+            rx.active = true;
+            rx.id = id;
+            rx.count = count;
+            rx.received = 0;
+            rx.len = 0;
+        }
+        let bit = 1 << index;
+        if rx.count != count || rx.received & bit != 0 {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        let offset = usize::from(index) * chunk;
+        rx.data[offset..offset + data.len()].copy_from_slice(data);
+        if is_last {
+            rx.len = offset + data.len();
+        }
+        rx.received |= bit;
+        rx.last_ms = now;
+
+        if rx.received != u64::MAX >> (64 - u32::from(count)) {
+            return Err(nb::Error::WouldBlock);
+        }
+        rx.active = false;
+        rx.completed = Some((id, now));
+        if buf.len() < rx.len {
+            return Err(nb::Error::Other(Error::BufferTooSmall));
+        }
+        buf[..rx.len].copy_from_slice(&rx.data[..rx.len]);
+        Ok(rx.len)
+    }
+}
@@ -0,0 +1,228 @@
+//! Runs the transport over a simulated channel that loses, duplicates and
+//! reorders frames
+use radio_transport::{Clock, Error, FrameLink, Transport, MAX_FRAME, MAX_MESSAGE};
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::rc::Rc;
+
+/// Chances in percent, applied to every frame
+#[derive(Clone, Copy, Default)]
+struct Faults {
+    /// The frame never arrives
+    loss: u32,
+    /// The frame arrives but its acknowledgement does not, so it is sent again
+    ack_loss: u32,
+    /// The frame arrives twice
+    duplicate: u32,
+    /// The frame overtakes the ones already in flight
+    reorder: u32,
+}
+
+/// Frames in flight from the sender to the receiver
+struct Wire {
+    frames: VecDeque<Vec<u8>>,
+    faults: Faults,
+    /// Drops every frame while set
+    cut: bool,
+    /// xorshift32, so every run sees the same faults
+    seed: u32,
+}
+
+impl Wire {
+    fn new(faults: Faults) -> Rc<RefCell<Self>> {
+        Rc::new(RefCell::new(Wire {
+            frames: VecDeque::new(),
+            faults,
+            cut: false,
+            seed: 0x1234_5678,
+        }))
+    }
+
+    fn random(&mut self) -> u32 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        self.seed
+    }
+
+    fn chance(&mut self, percent: u32) -> bool {
+        self.random() % 100 < percent
+    }
+
+    fn deliver(&mut self, frame: &[u8]) {
+        let at = if self.chance(self.faults.reorder) {
+            self.random() as usize % (self.frames.len() + 1)
+        } else {
+            self.frames.len()
+        };
+        self.frames.insert(at, frame.to_vec());
+    }
+}
+
+/// One end of the wire, either side only uses its own direction
+struct End(Rc<RefCell<Wire>>);
+
+impl FrameLink for End {
+    type Error = Infallible;
+    const MTU: usize = MAX_FRAME;
+
+    fn send_frame(&mut self, frame: &[u8]) -> Result<bool, Infallible> {
+        let wire = &mut *self.0.borrow_mut();
+        if wire.cut || wire.chance(wire.faults.loss) {
+            return Ok(false);
+        }
+        wire.deliver(frame);
+        if wire.chance(wire.faults.duplicate) {
+            wire.deliver(frame);
+        }
+        Ok(!wire.chance(wire.faults.ack_loss))
+    }
+
+    fn recv_frame(&mut self, buf: &mut [u8]) -> Result<Option<usize>, Infallible> {
+        Ok(self.0.borrow_mut().frames.pop_front().map(|frame| {
+            buf[..frame.len()].copy_from_slice(&frame);
+            frame.len()
+        }))
+    }
+}
+
+#[derive(Clone)]
+struct FakeClock(Rc<Cell<u32>>);
+
+impl FakeClock {
+    fn advance(&self, ms: u32) {
+        self.0.set(self.0.get().wrapping_add(ms));
+    }
+}
+
+impl Clock for FakeClock {
+    fn now_ms(&mut self) -> u32 {
+        self.0.get()
+    }
+}
+
+type Endpoint = Transport<End, FakeClock>;
+
+fn pair(faults: Faults) -> (Endpoint, Endpoint, Rc<RefCell<Wire>>, FakeClock) {
+    let wire = Wire::new(faults);
+    let clock = FakeClock(Rc::new(Cell::new(0)));
+    let mut tx = Transport::new(End(wire.clone()), clock.clone());
+    // Lossy enough that the default retries give up now and then
+    tx.retries = 20;
+    let rx = Transport::new(End(wire.clone()), clock.clone());
+    (tx, rx, wire, clock)
+}
+
+/// Every message the receiver completes until the wire is empty
+fn drain(rx: &mut Endpoint, wire: &Rc<RefCell<Wire>>) -> Vec<Vec<u8>> {
+    let mut messages = Vec::new();
+    let mut buf = [0; MAX_MESSAGE];
+    while !wire.borrow().frames.is_empty() {
+        match rx.recv(&mut buf) {
+            Ok(len) => messages.push(buf[..len].to_vec()),
+            Err(nb::Error::WouldBlock) => {}
+            Err(nb::Error::Other(e)) => panic!("{:?}", e),
+        }
+    }
+    messages
+}
+
+fn message(len: usize, salt: u8) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i as u8).wrapping_mul(31) ^ salt)
+        .collect()
+}
+
+#[test]
+fn largest_message_survives_a_lossy_channel() {
+    let (mut tx, mut rx, wire, _) = pair(Faults {
+        loss: 20,
+        ack_loss: 20,
+        duplicate: 20,
+        reorder: 30,
+    });
+    for salt in 0..10 {
+        // 64 full fragments
+        let message = message(MAX_MESSAGE, salt);
+        tx.send(&message).unwrap();
+        assert_eq!(drain(&mut rx, &wire), vec![message]);
+    }
+}
+
+#[test]
+fn messages_of_every_length_arrive() {
+    let (mut tx, mut rx, wire, _) = pair(Faults {
+        loss: 10,
+        reorder: 50,
+        ..Faults::default()
+    });
+    for len in (0..=MAX_MESSAGE).step_by(7) {
+        let message = message(len, len as u8);
+        tx.send(&message).unwrap();
+        assert_eq!(drain(&mut rx, &wire), vec![message]);
+    }
+}
+
+#[test]
+fn duplicates_are_delivered_once() {
+    let (mut tx, mut rx, wire, _) = pair(Faults {
+        ack_loss: 50,
+        duplicate: 100,
+        reorder: 50,
+        ..Faults::default()
+    });
+    let message = message(200, 1);
+    tx.send(&message).unwrap();
+    // Every frame a second time, after the message was completed
+    let copies: Vec<_> = wire.borrow().frames.iter().cloned().collect();
+    wire.borrow_mut().frames.extend(copies);
+    assert_eq!(drain(&mut rx, &wire), vec![message]);
+}
+
+#[test]
+fn too_many_fragments_are_refused() {
+    let (mut tx, _, wire, _) = pair(Faults::default());
+    assert_eq!(tx.send(&[0; MAX_MESSAGE + 1]), Err(Error::TooLarge));
+    assert!(wire.borrow().frames.is_empty());
+}
+
+#[test]
+fn undelivered_fragment_is_reported() {
+    let (mut tx, _, wire, _) = pair(Faults::default());
+    wire.borrow_mut().cut = true;
+    assert_eq!(tx.send(b"nobody listens"), Err(Error::Undelivered));
+}
+
+#[test]
+fn partial_message_times_out() {
+    let (mut tx, mut rx, wire, clock) = pair(Faults::default());
+    // All but the last fragment of message id 0
+    let stale = message(100, 0xAA);
+    let chunk = MAX_FRAME - radio_transport::HEADER_LEN;
+    tx.send(&stale[..chunk * 3]).unwrap();
+    wire.borrow_mut().frames.pop_back();
+    assert!(drain(&mut rx, &wire).is_empty());
+
+    // A restarted sender reuses id 0 with the same fragment count, without
+    // the timeout the old fragments would be taken as duplicates
+    clock.advance(rx.timeout_ms + 1);
+    let mut restarted = Transport::new(End(wire.clone()), clock.clone());
+    let fresh = message(chunk * 3, 0x55);
+    restarted.send(&fresh).unwrap();
+    assert_eq!(drain(&mut rx, &wire), vec![fresh]);
+}
+
+#[test]
+fn partial_message_is_kept_until_the_timeout() {
+    let (mut tx, mut rx, wire, clock) = pair(Faults::default());
+    let message = message(100, 3);
+    tx.send(&message).unwrap();
+    let last = wire.borrow_mut().frames.pop_back().unwrap();
+    assert!(drain(&mut rx, &wire).is_empty());
+
+    // The last fragment shows up late, but in time
+    clock.advance(rx.timeout_ms);
+    wire.borrow_mut().frames.push_back(last);
+    assert_eq!(drain(&mut rx, &wire), vec![message]);
+}
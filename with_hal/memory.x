@@ -1,6 +1,26 @@
 /* Linker script for the STM32F401CCU6 */
 MEMORY
 {
-  FLASH : ORIGIN = 0x08000000, LENGTH = 256K
+  /* Sectors 0-4, 64K is tight for the crypto stack of serial_rust_crypto */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 128K
+  /* Sector 5 is shared by all persistent data and erased as a whole, which
+     wipes every region below together (see src/flash.rs) */
+  /* Provisioned keys (see src/keystore.rs) */
+  KEYS : ORIGIN = 0x08020000, LENGTH = 16K
+  /* Nonce boot counter (see src/nonce.rs) */
+  STORAGE : ORIGIN = 0x08024000, LENGTH = 16K
+  /* Replay floors of radio receivers (see src/radio/floors.rs) */
+  FLOORS : ORIGIN = 0x08028000, LENGTH = 32K
+  /* Read-only USB drive of usb_msc (see src/block/flash.rs) */
+  DISK : ORIGIN = 0x08030000, LENGTH = 64K
   RAM : ORIGIN = 0x20000000, LENGTH = 64K
 }
+
+_keys_start = ORIGIN(KEYS);
+_keys_end = ORIGIN(KEYS) + LENGTH(KEYS);
+_storage_start = ORIGIN(STORAGE);
+_storage_end = ORIGIN(STORAGE) + LENGTH(STORAGE);
+_floors_start = ORIGIN(FLOORS);
+_floors_end = ORIGIN(FLOORS) + LENGTH(FLOORS);
+_disk_start = ORIGIN(DISK);
+_disk_end = ORIGIN(DISK) + LENGTH(DISK);
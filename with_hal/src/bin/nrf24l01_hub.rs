@@ -0,0 +1,100 @@
+#![no_std]
+#![no_main]
+
+use stm32f4_playground as _; // Global logger + panicking-behavior
+use core::{cell::RefCell, ops::DerefMut};
+use cortex_m::interrupt::Mutex;
+use hal::gpio::{gpiob::PB0, Edge, ExtiPin, Input, PullUp};
+use hal::prelude::*;
+use hal::spi::{Mode, Phase, Polarity, Spi};
+use hal::stm32::{interrupt, Interrupt};
+use stm32f4_playground::radio::star::{self, Event};
+use stm32f4_playground::radio::{self, link::MAX_PAYLOAD, Hub, LinkConfig, Nrf24};
+use stm32f4_playground::uid;
+use stm32f4xx_hal as hal;
+
+static IRQ_PIN: Mutex<RefCell<Option<PB0<Input<PullUp>>>>> = Mutex::new(RefCell::new(None));
+
+/// Collects data from up to five nodes (see `nrf24l01_node.rs`) and answers
+/// each packet with a downlink message carried in the ACK
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    defmt::info!("Board {:?}", uid::hex().as_str());
+    if let Some(mut dp) = hal::stm32::Peripherals::take() {
+        // Enable the SYSCFG clock so the IRQ pin can be routed to EXTI
+        dp.RCC.apb2enr.modify(|_, w| w.syscfgen().enabled());
+        let rcc = dp.RCC.constrain();
+        let clocks = rcc.cfgr.sysclk(48.mhz()).freeze();
+
+        let gpiob = dp.GPIOB.split();
+        let gpioa = dp.GPIOA.split();
+
+        // SPI Setup
+        let sck = gpioa.pa5.into_alternate_af5();
+        let miso = gpioa.pa6.into_alternate_af5();
+        let mosi = gpioa.pa7.into_alternate_af5();
+        let spi = Spi::spi1(
+            dp.SPI1,
+            (sck, miso, mosi),
+            Mode {
+                polarity: Polarity::IdleLow,
+                phase: Phase::CaptureOnFirstTransition,
+            },
+            hal::time::KiloHertz(8000).into(),
+            clocks,
+        );
+        // CE and CSN pins for nrf24l01
+        let ce = gpioa.pa4.into_push_pull_output();
+        let csn = gpioa.pa3.into_push_pull_output();
+        // IRQ pin for nrf24l01, active low
+        let mut irq = gpiob.pb0.into_pull_up_input();
+        irq.make_interrupt_source(&mut dp.SYSCFG);
+        irq.trigger_on_edge(&mut dp.EXTI, Edge::FALLING);
+        irq.enable_interrupt(&mut dp.EXTI);
+        cortex_m::interrupt::free(|cs| IRQ_PIN.borrow(cs).replace(Some(irq)));
+        unsafe {
+            cortex_m::peripheral::NVIC::unmask(Interrupt::EXTI0);
+        }
+        // nrf24l01 setup
+        let mut hub = Hub::new(Nrf24::new(spi, ce, csn), &LinkConfig::default()).unwrap();
+        let mut data = [0; MAX_PAYLOAD];
+        let mut packets = [0_u32; 6];
+
+        defmt::info!("Hub listening on all six pipes");
+        loop {
+            match hub.poll(&mut data) {
+                Ok(Some(Event::Joined { slot, uid })) => {
+                    defmt::info!("Node {:?} joined in slot {:?}", uid, slot)
+                }
+                Ok(Some(Event::Data { slot, len })) => {
+                    defmt::info!("Slot {:?}: {:?}", slot, &data[..len]);
+                    let count = &mut packets[usize::from(slot)];
+                    *count += 1;
+                    match hub.send_to(slot, &count.to_le_bytes()) {
+                        Ok(()) => {}
+                        // The node gets a newer count with one of its next packets
+                        Err(star::Error::FifoFull) => {
+                            defmt::debug!("No room for a downlink to slot {:?}", slot)
+                        }
+                        Err(_) => defmt::warn!("Could not queue downlink for slot {:?}", slot),
+                    }
+                }
+                Ok(None) => radio::link::wait_irq(),
+                Err(star::Error::FifoFull) => defmt::warn!("No room to answer a join request"),
+                Err(_) => defmt::error!("Radio error"),
+            }
+        }
+    }
+    loop {}
+}
+
+/// Falling edge on the nrf24l01 IRQ pin
+#[interrupt]
+fn EXTI0() {
+    cortex_m::interrupt::free(|cs| {
+        if let Some(irq) = IRQ_PIN.borrow(cs).borrow_mut().deref_mut() {
+            irq.clear_interrupt_pending_bit();
+        }
+    });
+    radio::link::on_irq();
+}
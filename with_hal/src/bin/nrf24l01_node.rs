@@ -0,0 +1,67 @@
+#![no_std]
+#![no_main]
+
+use stm32f4_playground as _; // Global logger + panicking-behavior
+use hal::prelude::*;
+use hal::spi::{Mode, Phase, Polarity, Spi};
+use stm32f4_playground::radio::{link::MAX_PAYLOAD, LinkConfig, Node, Nrf24};
+use stm32f4_playground::uid;
+use stm32f4xx_hal as hal;
+
+/// Joins the network of `nrf24l01_hub.rs` and reports a counter every second
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    defmt::info!("Board {:?}", uid::hex().as_str());
+    if let (Some(dp), Some(cp)) = (
+        hal::stm32::Peripherals::take(),
+        cortex_m::peripheral::Peripherals::take(),
+    ) {
+        let rcc = dp.RCC.constrain();
+        let clocks = rcc.cfgr.sysclk(48.mhz()).freeze();
+
+        let gpioa = dp.GPIOA.split();
+
+        // Delay
+        let mut delay = hal::delay::Delay::new(cp.SYST, clocks);
+        // SPI Setup
+        let sck = gpioa.pa5.into_alternate_af5();
+        let miso = gpioa.pa6.into_alternate_af5();
+        let mosi = gpioa.pa7.into_alternate_af5();
+        let spi = Spi::spi1(
+            dp.SPI1,
+            (sck, miso, mosi),
+            Mode {
+                polarity: Polarity::IdleLow,
+                phase: Phase::CaptureOnFirstTransition,
+            },
+            hal::time::KiloHertz(8000).into(),
+            clocks,
+        );
+        // CE and CSN pins for nrf24l01
+        let ce = gpioa.pa4.into_push_pull_output();
+        let csn = gpioa.pa3.into_push_pull_output();
+        // nrf24l01 setup
+        let radio = Nrf24::new(spi, ce, csn);
+        let mut node = Node::new(radio, LinkConfig::default(), uid::short_id()).unwrap();
+
+        while node.slot().is_none() {
+            match node.join(10) {
+                Ok(slot) => defmt::info!("Joined as slot {:?}", slot),
+                Err(_) => defmt::warn!("No answer from the hub, retrying"),
+            }
+        }
+
+        let mut counter = 0_u32;
+        let mut downlink = [0; MAX_PAYLOAD];
+        loop {
+            match node.send(&counter.to_le_bytes(), &mut downlink) {
+                Ok(Some(len)) => defmt::info!("Delivered, downlink: {:?}", &downlink[..len]),
+                Ok(None) => defmt::warn!("Lost"),
+                Err(_) => defmt::error!("Radio error"),
+            }
+            counter = counter.wrapping_add(1);
+            delay.delay_ms(1000_u32);
+        }
+    }
+    loop {}
+}
@@ -2,27 +2,44 @@
 #![no_main]
 
 use stm32f4_playground as _; // Global logger + panicking-behavior
-use embedded_nrf24l01::{Configuration, CrcMode, DataRate, NRF24L01};
+use core::{cell::RefCell, ops::DerefMut};
+use cortex_m::interrupt::Mutex;
+use embedded_nrf24l01::NRF24L01;
+use hal::gpio::{gpiob::PB0, Edge, ExtiPin, Input, PullUp};
 use hal::prelude::*;
 use hal::spi::{Mode, Phase, Polarity, Spi};
+use hal::stm32::{interrupt, Interrupt};
+use chacha20poly1305::Key;
+use stm32f4_playground::flash::{Flash, Region};
+use stm32f4_playground::radio::{
+    self, fragment::MAX_MESSAGE, FlashFloors, Link, LinkConfig, SecureLink, Transport,
+};
+use stm32f4_playground::time::DwtClock;
+use stm32f4_playground::uid;
 use stm32f4xx_hal as hal;
 
+/// DEMO ONLY, NOT A SECRET: this key is compiled into every build and published
+/// with the source, anyone can read and forge frames under it. Replace it with
+/// a key of your own before sending anything that matters.
+const DEMO_KEY: &[u8; 32] = b"an example very very secret key.";
+
+static IRQ_PIN: Mutex<RefCell<Option<PB0<Input<PullUp>>>>> = Mutex::new(RefCell::new(None));
+
 #[cortex_m_rt::entry]
 fn main() -> ! {
-    if let (Some(dp), Some(cp)) = (
+    defmt::info!("Board {:?}", uid::hex().as_str());
+    if let (Some(mut dp), Some(mut cp)) = (
         hal::stm32::Peripherals::take(),
         cortex_m::peripheral::Peripherals::take(),
     ) {
+        // Enable the SYSCFG clock so the IRQ pin can be routed to EXTI
+        dp.RCC.apb2enr.modify(|_, w| w.syscfgen().enabled());
         let rcc = dp.RCC.constrain();
         let clocks = rcc.cfgr.sysclk(48.mhz()).freeze();
 
-        // let gpioc = dp.GPIOC.split();
+        let gpiob = dp.GPIOB.split();
         let gpioa = dp.GPIOA.split();
 
-        // On-board LED
-        // let mut led = gpioc.pc13.into_push_pull_output();
-        // Delay
-        let mut delay = hal::delay::Delay::new(cp.SYST, clocks);
         // SPI Setup
         let sck = gpioa.pa5.into_alternate_af5();
         let miso = gpioa.pa6.into_alternate_af5();
@@ -40,33 +57,47 @@ fn main() -> ! {
         // CE and CSN pins for nrf24l01
         let ce = gpioa.pa4.into_push_pull_output();
         let csn = gpioa.pa3.into_push_pull_output();
+        // IRQ pin for nrf24l01, active low
+        let mut irq = gpiob.pb0.into_pull_up_input();
+        irq.make_interrupt_source(&mut dp.SYSCFG);
+        irq.trigger_on_edge(&mut dp.EXTI, Edge::FALLING);
+        irq.enable_interrupt(&mut dp.EXTI);
+        cortex_m::interrupt::free(|cs| IRQ_PIN.borrow(cs).replace(Some(irq)));
+        unsafe {
+            cortex_m::peripheral::NVIC::unmask(Interrupt::EXTI0);
+        }
         // nrf24l01 setup
-        let mut radio = NRF24L01::new(ce, csn, spi).unwrap();
-        radio.set_frequency(8).unwrap();
-        radio.set_auto_retransmit(0, 0).unwrap();
-        radio.set_rf(&DataRate::R2Mbps, 3).unwrap();
-        radio
-            .set_pipes_rx_enable(&[true, false, false, false, false, false])
-            .unwrap();
-        radio.set_auto_ack(&[false; 6]).unwrap();
-        radio.set_crc(CrcMode::Disabled).unwrap();
-        radio.set_rx_addr(0, b"stm32").unwrap();
-        radio.set_tx_addr(b"stm32").unwrap();
-        let mut rx = radio.rx().unwrap();
+        let radio = NRF24L01::new(ce, csn, spi).unwrap();
+        let link = Link::new(radio, &LinkConfig::default()).unwrap();
+        let clock = DwtClock::new(&mut cp.DCB, &mut cp.DWT, clocks.sysclk());
+        // Floors of every sender, so a reset does not let replays back in
+        let floors = FlashFloors::new(Flash::new(dp.FLASH), Region::floors());
+        defmt::info!("Room for {:?} more floors", floors.free());
+        // Every frame is encrypted and authenticated with key id 0
+        let mut link = SecureLink::new(link, floors, 2, 0);
+        defmt::warn!("Using the built-in demo key, frames are neither secret nor authentic");
+        link.add_key(0, Key::from_slice(DEMO_KEY)).unwrap();
+        let mut transport = Transport::new(link, clock);
+        let mut data = [0; MAX_MESSAGE];
 
+        defmt::info!("Waiting...");
         loop {
-            defmt::info!("Waiting...");
-            if let Ok(_) = rx.can_read() {
-                // if let Ok(false) = rx.is_empty() {
-                if let Ok(data) = rx.read() {
-                    defmt::info!("Data received: {:?}", data.as_ref());
-                } else {
-                    defmt::info!("ERROR");
-                }
-                // }
+            match nb::block!(transport.recv(&mut data)) {
+                Ok(len) => defmt::info!("Data received: {:?}", &data[..len]),
+                Err(_) => defmt::info!("ERROR"),
             }
-            delay.delay_ms(1000_u32);
         }
     }
     loop {}
 }
+
+/// Falling edge on the nrf24l01 IRQ pin
+#[interrupt]
+fn EXTI0() {
+    cortex_m::interrupt::free(|cs| {
+        if let Some(irq) = IRQ_PIN.borrow(cs).borrow_mut().deref_mut() {
+            irq.clear_interrupt_pending_bit();
+        }
+    });
+    radio::link::on_irq();
+}
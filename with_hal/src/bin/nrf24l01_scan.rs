@@ -0,0 +1,95 @@
+#![no_std]
+#![no_main]
+
+use stm32f4_playground as _; // Global logger + panicking-behavior
+use hal::prelude::*;
+use hal::spi::{Mode, Phase, Polarity, Spi};
+use stm32f4_playground::radio::{scan::Histogram, LinkConfig, Nrf24};
+use stm32f4_playground::uid;
+use stm32f4xx_hal as hal;
+
+/// Sweeps between histogram reports
+const SWEEPS: u16 = 100;
+/// Channel and power used by the continuous carrier test
+const CARRIER_CHANNEL: u8 = 8;
+const CARRIER_POWER: u8 = 3;
+
+/// RF diagnostics: sweeps all 126 channels and reports how often each one was
+/// busy, hold the on-board button (PA0) during reset to output a continuous
+/// carrier instead
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    defmt::info!("Board {:?}", uid::hex().as_str());
+    if let (Some(dp), Some(cp)) = (
+        hal::stm32::Peripherals::take(),
+        cortex_m::peripheral::Peripherals::take(),
+    ) {
+        let rcc = dp.RCC.constrain();
+        let clocks = rcc.cfgr.sysclk(48.mhz()).freeze();
+
+        let gpioa = dp.GPIOA.split();
+
+        // On-board button
+        let button = gpioa.pa0.into_pull_up_input();
+        // Delay
+        let mut delay = hal::delay::Delay::new(cp.SYST, clocks);
+        // SPI Setup
+        let sck = gpioa.pa5.into_alternate_af5();
+        let miso = gpioa.pa6.into_alternate_af5();
+        let mosi = gpioa.pa7.into_alternate_af5();
+        let spi = Spi::spi1(
+            dp.SPI1,
+            (sck, miso, mosi),
+            Mode {
+                polarity: Polarity::IdleLow,
+                phase: Phase::CaptureOnFirstTransition,
+            },
+            hal::time::KiloHertz(8000).into(),
+            clocks,
+        );
+        // CE and CSN pins for nrf24l01
+        let ce = gpioa.pa4.into_push_pull_output();
+        let csn = gpioa.pa3.into_push_pull_output();
+        // nrf24l01 setup
+        let mut radio = Nrf24::new(spi, ce, csn);
+
+        if button.is_low().unwrap() {
+            defmt::info!(
+                "Continuous carrier on channel {:?} ({:?} MHz)",
+                CARRIER_CHANNEL,
+                2400 + u16::from(CARRIER_CHANNEL)
+            );
+            radio.start_carrier(CARRIER_CHANNEL, CARRIER_POWER).unwrap();
+            loop {
+                cortex_m::asm::wfi();
+            }
+        }
+
+        radio.configure(&LinkConfig::default()).unwrap();
+        let mut histogram = Histogram::default();
+        loop {
+            for _ in 0..SWEEPS {
+                histogram.sweep(&mut radio, &mut delay).unwrap();
+            }
+            defmt::info!("Channel occupancy over {:?} sweeps:", histogram.sweeps);
+            for (channel, hits) in histogram.hits.iter().enumerate() {
+                if *hits > 0 {
+                    defmt::info!(
+                        "{:?} ({:?} MHz): {:?}%",
+                        channel,
+                        2400 + channel,
+                        u32::from(*hits) * 100 / u32::from(histogram.sweeps)
+                    );
+                }
+            }
+            let quietest = histogram.quietest();
+            defmt::info!(
+                "Quietest channel: {:?} ({:?} MHz)",
+                quietest,
+                2400 + u16::from(quietest)
+            );
+            histogram.clear();
+        }
+    }
+    loop {}
+}
@@ -2,32 +2,53 @@
 #![no_main]
 
 use stm32f4_playground as _; // Global logger + panicking-behavior
-use embedded_nrf24l01::{Configuration, CrcMode, DataRate, NRF24L01};
+use core::{cell::RefCell, ops::DerefMut};
+use cortex_m::interrupt::Mutex;
+use embedded_nrf24l01::NRF24L01;
+use hal::gpio::{gpiob::PB0, Edge, ExtiPin, Input, PullUp};
 use hal::prelude::*;
 use hal::spi::{Mode, Phase, Polarity, Spi};
+use hal::stm32::{interrupt, Interrupt};
+use chacha20poly1305::Key;
+use core::convert::TryFrom;
+use stm32f4_playground::flash::{Flash, Region};
+use stm32f4_playground::nonce::NonceSequence;
+use stm32f4_playground::radio::{self, Link, LinkConfig, SecureLink, SendOnly, Transport};
+use stm32f4_playground::time::DwtClock;
+use stm32f4_playground::uid;
 use stm32f4xx_hal as hal;
 
-macro_rules! success_blink {
-    ($led: expr, $delay: expr) => {
-        for _ in 0..10 {
-            $led.set_high().unwrap();
-            $delay.delay_ms(200_u32);
-            $led.set_low().unwrap();
-            $delay.delay_ms(200_u32);
-        }
-    };
-}
+/// DEMO ONLY, NOT A SECRET: this key is compiled into every build and published
+/// with the source, anyone can read and forge frames under it. Replace it with
+/// a key of your own before sending anything that matters.
+const DEMO_KEY: &[u8; 32] = b"an example very very secret key.";
+
+static IRQ_PIN: Mutex<RefCell<Option<PB0<Input<PullUp>>>>> = Mutex::new(RefCell::new(None));
 
 #[cortex_m_rt::entry]
 fn main() -> ! {
-    if let (Some(dp), Some(cp)) = (
+    defmt::info!("Board {:?}", uid::hex().as_str());
+    if let (Some(mut dp), Some(mut cp)) = (
         hal::stm32::Peripherals::take(),
         cortex_m::peripheral::Peripherals::take(),
     ) {
+        // Every boot claims the next counter range, so neither a nonce nor a
+        // counter the receiver has seen is ever reused after a reset
+        let mut flash = Flash::new(dp.FLASH);
+        let boot = match NonceSequence::start(&mut flash, &Region::storage()) {
+            Ok(Some(nonces)) => u16::try_from(nonces.boot()).unwrap(),
+            Ok(None) => defmt::panic!("Boot counter exhausted, erase storage and change the key"),
+            Err(e) => defmt::panic!("Recording the boot failed: {:?}", e),
+        };
+        defmt::info!("Boot {:?}", boot);
+
+        // Enable the SYSCFG clock so the IRQ pin can be routed to EXTI
+        dp.RCC.apb2enr.modify(|_, w| w.syscfgen().enabled());
         let rcc = dp.RCC.constrain();
         let clocks = rcc.cfgr.sysclk(48.mhz()).freeze();
 
         let gpioc = dp.GPIOC.split();
+        let gpiob = dp.GPIOB.split();
         let gpioa = dp.GPIOA.split();
 
         // On-board LED
@@ -51,36 +72,47 @@ fn main() -> ! {
         // CE and CSN pins for nrf24l01
         let ce = gpioa.pa4.into_push_pull_output();
         let csn = gpioa.pa3.into_push_pull_output();
+        // IRQ pin for nrf24l01, active low
+        let mut irq = gpiob.pb0.into_pull_up_input();
+        irq.make_interrupt_source(&mut dp.SYSCFG);
+        irq.trigger_on_edge(&mut dp.EXTI, Edge::FALLING);
+        irq.enable_interrupt(&mut dp.EXTI);
+        cortex_m::interrupt::free(|cs| IRQ_PIN.borrow(cs).replace(Some(irq)));
+        unsafe {
+            cortex_m::peripheral::NVIC::unmask(Interrupt::EXTI0);
+        }
         // nrf24l01 setup
-        let mut radio = NRF24L01::new(ce, csn, spi).unwrap();
-        radio.set_frequency(8).unwrap();
-        radio.set_auto_retransmit(0, 0).unwrap();
-        radio.set_rf(&DataRate::R2Mbps, 3).unwrap();
-        radio
-            .set_pipes_rx_enable(&[true, false, false, false, false, false])
-            .unwrap();
-        radio.set_auto_ack(&[false; 6]).unwrap();
-        radio.set_crc(CrcMode::Disabled).unwrap();
-        radio.set_tx_addr(b"stm32").unwrap();
-        radio.set_rx_addr(0, b"stm32").unwrap();
-        let mut tx = radio.tx().unwrap();
-        let data = b"hello";
+        let radio = NRF24L01::new(ce, csn, spi).unwrap();
+        let link = Link::new(radio, &LinkConfig::default()).unwrap();
+        let clock = DwtClock::new(&mut cp.DCB, &mut cp.DWT, clocks.sysclk());
+        // Every frame is encrypted and authenticated with key id 0
+        let mut link = SecureLink::per_boot(link, SendOnly, 1, boot);
+        defmt::warn!("Using the built-in demo key, frames are neither secret nor authentic");
+        link.add_key(0, Key::from_slice(DEMO_KEY)).unwrap();
+        let mut transport = Transport::new(link, clock);
+        // Longer than a single 32 byte packet
+        let data = b"hello from the other side of a fragmented nRF24L01 link!";
 
         loop {
-            led.set_high().unwrap(); // OFF
-            delay.delay_ms(500_u32);
-            if let Ok(_) = tx.wait_empty() {
-                // Sending message
-                led.set_low().unwrap(); // ON
-                if let Ok(true) = tx.can_send() {
-                    if tx.send(data).is_ok() {
-                        success_blink!(led, delay);
-                    }
-                }
+            led.set_low().unwrap(); // ON
+            if transport.send(data).is_err() {
+                defmt::warn!("Message not delivered");
             }
+            defmt::info!("{:?}", transport.link().link().stats());
             led.set_high().unwrap(); // OFF
-            delay.delay_ms(500_u32);
+            delay.delay_ms(1000_u32);
         }
     }
     loop {}
 }
+
+/// Falling edge on the nrf24l01 IRQ pin
+#[interrupt]
+fn EXTI0() {
+    cortex_m::interrupt::free(|cs| {
+        if let Some(irq) = IRQ_PIN.borrow(cs).borrow_mut().deref_mut() {
+            irq.clear_interrupt_pending_bit();
+        }
+    });
+    radio::link::on_irq();
+}
@@ -4,10 +4,12 @@
 
 use cortex_m::asm::delay;
 use stm32f4_playground as _; // Global logger + panicking-behavior
+use stm32f4_playground::uid;
 use stm32f4xx_hal::{prelude::*, pwm, stm32 as device};
 
 #[cortex_m_rt::entry]
 fn main() -> ! {
+    defmt::info!("Board {:?}", uid::hex().as_str());
     defmt::info!("Plug your RGB LED into PA8-10! Don't forget current limiting resistors!");
     // Take ownership of the device peripherals singleton
     if let Some(dp) = device::Peripherals::take() {
@@ -2,24 +2,55 @@
 #![no_main]
 
 use stm32f4_playground as _; // Global logger + panicking-behavior
-use chacha20poly1305::aead::heapless::{consts::U128, Vec};
-use chacha20poly1305::aead::{AeadInPlace, NewAead};
-use chacha20poly1305::{ChaCha8Poly1305, Key, Nonce};
-use stm32f4xx_hal::otg_fs::{UsbBus, USB};
+use core::convert::TryFrom;
+use cortex_m::peripheral::DWT;
+use crypto_protocol::{self as protocol, Algorithm, Opcode, Request, Response, Status, TAG_LEN};
+use stm32f4_playground::cipher::Cipher;
+use stm32f4_playground::flash::{Flash, Region};
+use stm32f4_playground::keystore::{self, KeyStore, KEY_LEN};
+use stm32f4_playground::nonce::{NonceSequence, NONCE_LEN};
+use stm32f4_playground::{uid, usb};
+use stm32f4xx_hal::otg_fs::USB;
+use stm32f4xx_hal::stm32::interrupt;
 use stm32f4xx_hal::{prelude::*, stm32};
-use usb_device::prelude::*;
-use usbd_serial;
+use usb_device::prelude::UsbVidPid;
 
 static mut EP_MEMORY: [u32; 1024] = [0; 1024];
 
-/// Accepts a string via USB serial, encrypts the given string, then outputs the
-/// encrypted string back out via the USB connection
-/// Accepted strings should be terminated with ~ character
-/// Ex. "Hello world~"
+/// Encryption service speaking the framed protocol of the `crypto-protocol` crate over USB
+/// serial, the first provisioned key is loaded at boot
 #[cortex_m_rt::entry]
 fn main() -> ! {
+    // `dfu-util -e` ends up here, nothing may be initialized before
+    usb::dfu::enter_bootloader_if_requested();
     defmt::info!("Unplug your debugger and send messages to be encrypted over USB!");
+    defmt::info!("Board {:?}", uid::serial_number());
     let dp = stm32::Peripherals::take().unwrap();
+    let mut cp = cortex_m::Peripherals::take().unwrap();
+
+    // Cycle counter for Benchmark
+    cp.DCB.enable_trace();
+    cp.DWT.enable_cycle_counter();
+
+    // Every boot gets a fresh nonce prefix, before any USB traffic
+    let mut flash = Flash::new(dp.FLASH);
+    let nonces = NonceSequence::start(&mut flash, &Region::storage()).unwrap();
+    if nonces.is_none() {
+        defmt::warn!("Boot counter exhausted, erase storage and change the key");
+    }
+    let mut service = Service {
+        key: None,
+        algorithm: Algorithm::default(),
+        cipher: None,
+        slot: None,
+        nonces,
+        keys: KeyStore::new(Region::keys()),
+        flash,
+    };
+    match (0..keystore::SLOTS).find(|slot| service.load(*slot).is_ok()) {
+        Some(slot) => defmt::info!("Using the key in slot {:?}", slot),
+        None => defmt::warn!("No key provisioned"),
+    }
 
     let rcc = dp.RCC.constrain();
     let clocks = rcc.cfgr
@@ -38,45 +69,293 @@ fn main() -> ! {
         hclk: clocks.hclk(),
     };
 
-    let usb_bus = UsbBus::new(usb, unsafe { &mut EP_MEMORY });
+    // Serviced from OTG_FS from here on, the main loop sleeps while idle
+    let ep_memory = unsafe { &mut EP_MEMORY };
+    // The host CLI finds the device by its IDs and this product string
+    let strings = usb::Strings {
+        product: "AEAD over USB",
+        ..usb::Strings::default()
+    };
+    let mut cdc = usb::init(usb, ep_memory, UsbVidPid(0x16c0, 0x27dd), &strings);
+    usb::set_report(service.report());
 
-    let mut serial = usbd_serial::SerialPort::new(&usb_bus);
+    let mut reader = protocol::FrameReader::default();
 
-    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x16c0, 0x27dd))
-        .manufacturer("Yusef")
-        .product("Crypto")
-        .serial_number("1234")
-        .device_class(usbd_serial::USB_CLASS_CDC)
-        .build();
+    loop {
+        let mut tmp = [0u8; 64];
+        let count = cdc.recv(&mut tmp);
 
-    let key = Key::from_slice(b"an example very very secret key.");
-    let cipher = ChaCha8Poly1305::new(key);
-    let mut buffer: Vec<u8, U128> = Vec::new();
+        for byte in &tmp[..count] {
+            let frame = match reader.push(*byte) {
+                Some(frame) => frame,
+                None => continue,
+            };
+            let mut payload = [0; protocol::MAX_PAYLOAD + 2];
+            let mut data = [0; protocol::MAX_PAYLOAD];
+            let decoded = frame.and_then(|frame| protocol::decode_frame(frame, &mut payload));
+            let response = match decoded {
+                Ok(len) => match Request::decode(&payload[..len]) {
+                    Ok(request) => match service.handle(&request, &mut data) {
+                        Ok(len) => Response {
+                            status: Status::Ok,
+                            seq: request.seq,
+                            data: &data[..len],
+                        },
+                        Err(status) => Response {
+                            status,
+                            seq: request.seq,
+                            data: &[],
+                        },
+                    },
+                    Err(status) => Response {
+                        status,
+                        seq: payload.get(1).copied().unwrap_or(0),
+                        data: &[],
+                    },
+                },
+                // The sequence number of a broken frame can not be trusted
+                Err(status) => Response {
+                    status,
+                    seq: 0,
+                    data: &[],
+                },
+            };
 
-    loop {
-        if !usb_dev.poll(&mut [&mut serial]) {
-            continue;
+            let mut encoded = [0; protocol::MAX_PAYLOAD];
+            let mut out = [0; protocol::MAX_FRAME];
+            if let Some(len) = response
+                .encode(&mut encoded)
+                .and_then(|len| protocol::encode_frame(&encoded[..len], &mut out))
+            {
+                cdc.write_all(&out[..len]);
+            }
+            // Requests and responses may carry keys or plaintext
+            keystore::wipe(&mut payload);
+            keystore::wipe(&mut data);
+            usb::set_report(service.report());
+        }
+    }
+}
+
+struct Service {
+    key: Option<[u8; KEY_LEN]>,
+    /// Algorithm of the session, RAM only
+    algorithm: Algorithm,
+    /// `key` set up for `algorithm`
+    cipher: Option<Cipher>,
+    /// Key slot the key was loaded from, `None` for a SetKey key
+    slot: Option<u8>,
+    nonces: Option<NonceSequence>,
+    keys: KeyStore,
+    flash: Flash,
+}
+
+impl Service {
+    /// HID status: bit 0 a key is active, bit 1 the boot counter ran out
+    fn report(&self) -> usb::Report {
+        usb::Report {
+            buttons: 0,
+            status: self.cipher.is_some() as u8 | (self.nonces.is_none() as u8) << 1,
         }
+    }
 
-        let mut tmp = [0u8; 64];
+    /// Makes the key in `slot` the active one
+    fn load(&mut self, slot: usize) -> Result<(), keystore::Error> {
+        let mut key = [0; KEY_LEN];
+        self.keys.load(slot, &mut key)?;
+        self.set_key(Some(&key), Some(slot as u8));
+        keystore::wipe(&mut key);
+        Ok(())
+    }
+
+    /// Replaces (or with `None` forgets) the active key
+    fn set_key(&mut self, key: Option<&[u8; KEY_LEN]>, slot: Option<u8>) {
+        if let Some(old) = self.key.as_mut() {
+            keystore::wipe(old);
+        }
+        self.key = key.copied();
+        self.slot = slot;
+        self.cipher = key.map(|key| Cipher::new(self.algorithm, key));
+    }
+
+    fn set_algorithm(&mut self, algorithm: Algorithm) {
+        self.algorithm = algorithm;
+        self.cipher = self.key.as_ref().map(|key| Cipher::new(algorithm, key));
+    }
 
-        match serial.read(&mut tmp) {
-            Ok(count) if count > 0 => {
-                if let Ok(_) = buffer.extend_from_slice(&tmp[0..count]) {
-                    if buffer.ends_with(&[b'~']) {
-                        let nonce = Nonce::from_slice(b"unique nonce");
-                        if let Ok(_) = cipher.encrypt_in_place(nonce, b"", &mut buffer) {
-                            serial.write(&buffer).unwrap();
-                        } else {
-                            serial.write(&"ENCRYPTION FAILED".as_bytes()).unwrap();
+    /// Nonce for the next Encrypt, XChaCha20 nonces start with the UID so they
+    /// stay unique even across devices sharing a key
+    fn next_nonce(&mut self, nonce: &mut [u8; protocol::MAX_NONCE]) -> Result<usize, Status> {
+        let sequence = self.nonces.as_mut().ok_or(Status::NonceExhausted)?.next();
+        match self.algorithm.nonce_len() {
+            24 => {
+                nonce[..12].copy_from_slice(&uid::read());
+                nonce[12..24].copy_from_slice(&sequence);
+            }
+            _ => nonce[..NONCE_LEN].copy_from_slice(&sequence),
+        }
+        Ok(self.algorithm.nonce_len())
+    }
+
+    /// Runs a request, the response data is written to `out`
+    fn handle(&mut self, request: &Request, out: &mut [u8]) -> Result<usize, Status> {
+        match request.opcode {
+            Opcode::Encrypt => {
+                if self.cipher.is_none() {
+                    return Err(Status::NoKey);
+                }
+                let len = request.data.len();
+                if len > protocol::MAX_DATA {
+                    return Err(Status::BadLength);
+                }
+                let mut nonce = [0; protocol::MAX_NONCE];
+                let nonce_len = self.next_nonce(&mut nonce)?;
+                let nonce = &nonce[..nonce_len];
+                let (head, rest) = out.split_at_mut(nonce.len());
+                let (body, tail) = rest.split_at_mut(len);
+                head.copy_from_slice(nonce);
+                body.copy_from_slice(request.data);
+                let cipher = self.cipher.as_ref().ok_or(Status::NoKey)?;
+                let tag = cipher
+                    .encrypt(nonce, request.aad, body)
+                    .map_err(|_| Status::BadLength)?;
+                tail[..TAG_LEN].copy_from_slice(&tag);
+                Ok(nonce.len() + len + TAG_LEN)
+            }
+            Opcode::Decrypt => {
+                let cipher = self.cipher.as_ref().ok_or(Status::NoKey)?;
+                let data = request.data;
+                let nonce_len = self.algorithm.nonce_len();
+                if data.len() < nonce_len + TAG_LEN {
+                    return Err(Status::BadLength);
+                }
+                let (nonce, rest) = data.split_at(nonce_len);
+                let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+                let body = &mut out[..ciphertext.len()];
+                body.copy_from_slice(ciphertext);
+                cipher
+                    .decrypt(nonce, request.aad, body, tag)
+                    .map_err(|_| Status::AuthFailed)?;
+                Ok(ciphertext.len())
+            }
+            Opcode::SetKey => {
+                let mut key = [0; KEY_LEN];
+                if request.data.len() != KEY_LEN {
+                    return Err(Status::BadLength);
+                }
+                key.copy_from_slice(request.data);
+                self.set_key(Some(&key), None);
+                keystore::wipe(&mut key);
+                Ok(0)
+            }
+            Opcode::SetAlgorithm => match request.data {
+                [algorithm] => {
+                    self.set_algorithm(Algorithm::try_from(*algorithm)?);
+                    Ok(0)
+                }
+                _ => Err(Status::BadLength),
+            },
+            Opcode::Benchmark => {
+                if request.data.len() != 3 {
+                    return Err(Status::BadLength);
+                }
+                let algorithm = Algorithm::try_from(request.data[0])?;
+                let len = usize::from(u16::from_le_bytes([request.data[1], request.data[2]]));
+                if len > usize::from(protocol::BENCH_MAX) {
+                    return Err(Status::BadLength);
+                }
+                // Throwaway key and nonce, the output is discarded
+                let cipher = Cipher::new(algorithm, &[0; KEY_LEN]);
+                let nonce = [0; protocol::MAX_NONCE];
+                let mut buf = [0; protocol::BENCH_MAX as usize];
+                let start = DWT::cycle_count();
+                cipher
+                    .encrypt(&nonce[..algorithm.nonce_len()], &[], &mut buf[..len])
+                    .map_err(|_| Status::BadLength)?;
+                let cycles = DWT::cycle_count().wrapping_sub(start);
+                defmt::info!("{:?} bytes in {:?} cycles", len, cycles);
+                out[..4].copy_from_slice(&cycles.to_le_bytes());
+                out[4..6].copy_from_slice(&(len as u16).to_le_bytes());
+                Ok(6)
+            }
+            Opcode::Provision => {
+                if request.data.len() != 5 + KEY_LEN {
+                    return Err(Status::BadLength);
+                }
+                let nonces = self.nonces.as_mut().ok_or(Status::NonceExhausted)?;
+                let slot = usize::from(request.data[0]);
+                let mut id = [0; 4];
+                id.copy_from_slice(&request.data[1..5]);
+                let mut key = [0; KEY_LEN];
+                key.copy_from_slice(&request.data[5..]);
+                let result = self.keys.store(
+                    &mut self.flash,
+                    slot,
+                    u32::from_le_bytes(id),
+                    &key,
+                    &nonces.next(),
+                );
+                keystore::wipe(&mut key);
+                result.map_err(status)?;
+                defmt::info!("Provisioned slot {:?}", slot);
+                Ok(0)
+            }
+            Opcode::LoadKey => match request.data {
+                [slot] => self.load(usize::from(*slot)).map(|_| 0).map_err(status),
+                _ => Err(Status::BadLength),
+            },
+            Opcode::Zeroize => {
+                match request.data {
+                    [] => {
+                        self.keys.erase(&mut self.flash).map_err(status)?;
+                        // The boot counter shares the sector and went with it
+                        if let Some(nonces) = &self.nonces {
+                            nonces
+                                .restore(&mut self.flash, &Region::storage())
+                                .map_err(|_| Status::StorageFailed)?;
+                        }
+                    }
+                    [slot] => {
+                        self.keys
+                            .zeroize(&mut self.flash, usize::from(*slot))
+                            .map_err(status)?;
+                        if self.slot != Some(*slot) {
+                            return Ok(0);
                         }
-                        buffer.truncate(0);
-                    };
-                } else {
-                    buffer.truncate(0);
+                    }
+                    _ => return Err(Status::BadLength),
                 }
+                // The active key was destroyed, so is its copy in RAM
+                self.set_key(None, None);
+                defmt::info!("Zeroized");
+                Ok(0)
+            }
+            Opcode::GetInfo => {
+                let boot = self.nonces.as_ref().map_or(u32::MAX, |n| n.boot());
+                out[0] = protocol::VERSION;
+                out[1] = self.cipher.is_some() as u8;
+                out[2..6].copy_from_slice(&boot.to_le_bytes());
+                out[6..8].copy_from_slice(&(protocol::MAX_DATA as u16).to_le_bytes());
+                out[8] = self.slot.unwrap_or(protocol::NO_SLOT);
+                out[9..11].copy_from_slice(&self.keys.used().to_le_bytes());
+                out[11] = self.algorithm as u8;
+                Ok(12)
             }
-            _ => {}
         }
     }
 }
+
+fn status(e: keystore::Error) -> Status {
+    match e {
+        keystore::Error::Flash(_) => Status::StorageFailed,
+        keystore::Error::InvalidSlot => Status::BadLength,
+        keystore::Error::SlotEmpty => Status::SlotEmpty,
+        keystore::Error::SlotInUse => Status::SlotInUse,
+        keystore::Error::Corrupt => Status::AuthFailed,
+    }
+}
+
+#[interrupt]
+fn OTG_FS() {
+    usb::on_interrupt();
+}
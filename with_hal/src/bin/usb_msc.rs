@@ -0,0 +1,111 @@
+#![no_std]
+#![no_main]
+
+use cortex_m::peripheral::NVIC;
+use hal::otg_fs::{UsbBus, USB};
+use hal::prelude::*;
+use hal::spi::{Mode, Phase, Polarity, Spi};
+use hal::stm32::{interrupt, Interrupt};
+use stm32f4_playground as _; // Global logger + panicking-behavior
+use stm32f4_playground::block::{BlockDevice, FlashDisk, SpiNor};
+use stm32f4_playground::flash::Region;
+use stm32f4_playground::usb::{self, msc::MassStorage};
+use stm32f4_playground::uid;
+use stm32f4xx_hal as hal;
+use usb_device::prelude::*;
+
+static mut EP_MEMORY: [u32; 1024] = [0; 1024];
+
+/// What the drive shows
+#[allow(dead_code)]
+enum Disk {
+    /// The DISK region of the internal flash, read-only
+    Internal,
+    /// W25Qxx on SPI1 (SCK: PA5, MISO: PA6, MOSI: PA7, CS: PA4)
+    SpiNor,
+}
+
+const DISK: Disk = Disk::SpiNor;
+
+/// USB drive backed by internal or SPI NOR flash, e.g. to get logs off a unit
+/// without special software. A fresh SPI NOR chip has to be formatted by the
+/// host first. The internal drive is its own region of sector 5, the keys and
+/// the nonce boot counter next to it are never exported.
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    defmt::info!("Board {:?}", uid::serial_number());
+    let dp = hal::stm32::Peripherals::take().unwrap();
+
+    let rcc = dp.RCC.constrain();
+    let clocks = rcc.cfgr
+        .use_hse(25.mhz())
+        .sysclk(48.mhz())
+        .require_pll48clk()
+        .freeze();
+
+    let gpioa = dp.GPIOA.split();
+    let usb = USB {
+        usb_global: dp.OTG_FS_GLOBAL,
+        usb_device: dp.OTG_FS_DEVICE,
+        usb_pwrclk: dp.OTG_FS_PWRCLK,
+        pin_dm: gpioa.pa11.into_alternate_af10(),
+        pin_dp: gpioa.pa12.into_alternate_af10(),
+        hclk: clocks.hclk(),
+    };
+
+    match DISK {
+        Disk::Internal => serve(usb, FlashDisk::new(Region::disk())),
+        Disk::SpiNor => {
+            let sck = gpioa.pa5.into_alternate_af5();
+            let miso = gpioa.pa6.into_alternate_af5();
+            let mosi = gpioa.pa7.into_alternate_af5();
+            let spi = Spi::spi1(
+                dp.SPI1,
+                (sck, miso, mosi),
+                Mode {
+                    polarity: Polarity::IdleLow,
+                    phase: Phase::CaptureOnFirstTransition,
+                },
+                hal::time::KiloHertz(12_000).into(),
+                clocks,
+            );
+            let cs = gpioa.pa4.into_push_pull_output();
+            match SpiNor::new(spi, cs) {
+                Ok(nor) => serve(usb, nor),
+                Err(e) => defmt::panic!("No SPI NOR flash found: {:?}", e),
+            }
+        }
+    }
+}
+
+fn serve<D: BlockDevice>(usb: USB, disk: D) -> ! {
+    defmt::info!("Serving {:?} blocks", disk.block_count());
+    let usb_bus = UsbBus::new(usb, unsafe { &mut EP_MEMORY });
+    let mut msc = MassStorage::new(&usb_bus, disk, "stm32f4", "Playground disk");
+
+    let strings = usb::Strings::default();
+    // pid.codes test ID, only for private use
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1209, 0x0001))
+        .manufacturer(strings.manufacturer)
+        .product("STM32F4 drive")
+        .serial_number(strings.serial_number)
+        .build();
+
+    loop {
+        usb_dev.poll(&mut [&mut msc]);
+        // Block device accesses may take a while (a SPI NOR sector erase is
+        // tens of milliseconds), so they run here and not in the interrupt,
+        // which only wakes the core. A pending interrupt ends the WFI even
+        // with interrupts disabled, so none is missed.
+        cortex_m::interrupt::free(|_| {
+            unsafe { NVIC::unmask(Interrupt::OTG_FS) };
+            cortex_m::asm::wfi();
+        });
+    }
+}
+
+#[interrupt]
+fn OTG_FS() {
+    // Unmasked again once the main loop has polled
+    NVIC::mask(Interrupt::OTG_FS);
+}
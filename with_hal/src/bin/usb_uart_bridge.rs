@@ -0,0 +1,154 @@
+#![no_std]
+#![no_main]
+
+use stm32f4_playground as _; // Global logger + panicking-behavior
+use stm32f4_playground::uart::{self, Uart};
+use stm32f4_playground::{uid, usb};
+use stm32f4xx_hal::otg_fs::USB;
+use stm32f4xx_hal::stm32::interrupt;
+use stm32f4xx_hal::{prelude::*, stm32};
+use usb_device::prelude::UsbVidPid;
+
+static mut EP_MEMORY: [u32; 1024] = [0; 1024];
+
+/// Which USART the bridge drives
+#[allow(dead_code)]
+enum Port {
+    /// TX on PB6, RX on PB7, as in `without_hal/src/bin/uart.rs`
+    Usart1,
+    /// TX on PA2, RX on PA3
+    Usart2,
+}
+
+const PORT: Port = Port::Usart1;
+
+/// USB to serial adapter: the CDC-ACM port is bridged to a USART, which follows
+/// the baud rate, parity, stop bits and data bits the host sets. DTR and RTS
+/// drive PB8 and PB9, active low like on common adapters, so they can reset a
+/// target or put it into its bootloader.
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    usb::dfu::enter_bootloader_if_requested();
+    defmt::info!("Board {:?}", uid::serial_number());
+    let dp = stm32::Peripherals::take().unwrap();
+
+    let rcc = dp.RCC.constrain();
+    let clocks = rcc.cfgr
+        .use_hse(25.mhz())
+        .sysclk(48.mhz())
+        .require_pll48clk()
+        .freeze();
+
+    let gpioa = dp.GPIOA.split();
+    let gpiob = dp.GPIOB.split();
+
+    let mut uart = match PORT {
+        Port::Usart1 => {
+            gpiob.pb6.into_alternate_af7();
+            gpiob.pb7.into_alternate_af7();
+            Uart::usart1(dp.USART1, &clocks)
+        }
+        Port::Usart2 => {
+            gpioa.pa2.into_alternate_af7();
+            gpioa.pa3.into_alternate_af7();
+            Uart::usart2(dp.USART2, &clocks)
+        }
+    };
+    let mut dtr_pin = gpiob.pb8.into_push_pull_output();
+    let mut rts_pin = gpiob.pb9.into_push_pull_output();
+    dtr_pin.set_high().ok();
+    rts_pin.set_high().ok();
+
+    let usb = USB {
+        usb_global: dp.OTG_FS_GLOBAL,
+        usb_device: dp.OTG_FS_DEVICE,
+        usb_pwrclk: dp.OTG_FS_PWRCLK,
+        pin_dm: gpioa.pa11.into_alternate_af10(),
+        pin_dp: gpioa.pa12.into_alternate_af10(),
+        hclk: clocks.hclk(),
+    };
+    let ep_memory = unsafe { &mut EP_MEMORY };
+    let strings = usb::Strings {
+        product: "USB serial bridge",
+        ..usb::Strings::default()
+    };
+    // Not the ID of serial_rust_crypto, whose host CLI looks for that one
+    let mut cdc = usb::init(usb, ep_memory, UsbVidPid(0x16c0, 0x05e1), &strings);
+
+    let mut coding = cdc.line_coding();
+    let mut control = (false, false);
+    // Bytes from the target the host had no room for, plus those the USART
+    // dropped itself
+    let mut dropped = 0;
+    let mut reported = 0;
+
+    loop {
+        // Host to target
+        let mut buf = [0; 64];
+        let count = cdc.read(&mut buf);
+        for byte in &buf[..count] {
+            uart.write(*byte);
+        }
+
+        // Target to host, dropped when the host does not keep up like on any
+        // other adapter
+        let count = uart.read(&mut buf);
+        dropped += (count - cdc.write(&buf[..count])) as u32;
+        let lost = uart.lost() + dropped;
+        if lost != reported {
+            defmt::warn!("{:?} bytes from the target lost so far", lost);
+            reported = lost;
+        }
+
+        let requested = cdc.line_coding();
+        if requested != coding {
+            match uart.configure(&requested) {
+                Ok(()) => defmt::info!("Line coding {:?}", requested),
+                Err(e) => defmt::warn!("Unsupported line coding {:?}: {:?}", requested, e),
+            }
+            coding = requested;
+        }
+
+        let requested = (cdc.dtr(), cdc.rts());
+        if requested != control {
+            control = requested;
+            if control.0 {
+                dtr_pin.set_low().ok();
+            } else {
+                dtr_pin.set_high().ok();
+            }
+            if control.1 {
+                rts_pin.set_low().ok();
+            } else {
+                rts_pin.set_high().ok();
+            }
+        }
+
+        // Sleep until either side has something new, checked with interrupts
+        // masked so nothing arrives unnoticed before the WFI
+        cortex_m::interrupt::free(|_| {
+            let idle = cdc.available() == 0
+                && uart.available() == 0
+                && cdc.line_coding() == coding
+                && (cdc.dtr(), cdc.rts()) == control;
+            if idle {
+                cortex_m::asm::wfi();
+            }
+        });
+    }
+}
+
+#[interrupt]
+fn OTG_FS() {
+    usb::on_interrupt();
+}
+
+#[interrupt]
+fn USART1() {
+    uart::on_interrupt();
+}
+
+#[interrupt]
+fn USART2() {
+    uart::on_interrupt();
+}
@@ -0,0 +1,47 @@
+//! Read-only view of a reserved internal flash region, normally
+//! [`Region::disk`]
+//!
+//! Internal flash is erased in sectors of up to 128K, more than the RAM it
+//! would take to rewrite one of the blocks in them, so the host can not write.
+//! The contents are put there by the firmware itself or by a debug probe,
+//! e.g. a FAT image written with `probe-rs download --format bin`. The probe
+//! erases the whole of sector 5 for that, keys and boot counter included, so
+//! new keys have to be provisioned afterwards.
+use super::{BlockDevice, Error, BLOCK_SIZE};
+use crate::flash::Region;
+
+pub struct FlashDisk {
+    region: Region,
+}
+
+impl FlashDisk {
+    pub fn new(region: Region) -> Self {
+        FlashDisk { region }
+    }
+}
+
+impl BlockDevice for FlashDisk {
+    fn block_count(&self) -> u32 {
+        (self.region.len() / BLOCK_SIZE) as u32
+    }
+
+    fn is_read_only(&self) -> bool {
+        true
+    }
+
+    fn read(&mut self, block: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<(), Error> {
+        if block >= self.block_count() {
+            return Err(Error::OutOfRange);
+        }
+        let start = block as usize * BLOCK_SIZE / 4;
+        let words = &self.region.words()[start..start + BLOCK_SIZE / 4];
+        for (chunk, word) in buf.chunks_exact_mut(4).zip(words) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, _block: u32, _buf: &[u8; BLOCK_SIZE]) -> Result<(), Error> {
+        Err(Error::WriteProtected)
+    }
+}
@@ -0,0 +1,36 @@
+//! Storage addressed in fixed-size blocks, as exported over USB by
+//! [`crate::usb::msc`]
+pub mod flash;
+pub mod nor;
+
+pub use flash::FlashDisk;
+pub use nor::SpiNor;
+
+/// Size of a block in bytes, the sector size of nearly every host file system
+pub const BLOCK_SIZE: usize = 512;
+
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum Error {
+    /// Block number is not below [`BlockDevice::block_count`]
+    OutOfRange,
+    WriteProtected,
+    /// The underlying memory failed or did not answer
+    Device,
+}
+
+pub trait BlockDevice {
+    fn block_count(&self) -> u32;
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    fn read(&mut self, block: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<(), Error>;
+
+    fn write(&mut self, block: u32, buf: &[u8; BLOCK_SIZE]) -> Result<(), Error>;
+
+    /// Makes every earlier write persistent, for devices that buffer them
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
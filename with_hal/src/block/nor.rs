@@ -0,0 +1,190 @@
+//! SPI NOR flash such as the Winbond W25Q series
+//!
+//! Only the commands every 25-series part understands are used: JEDEC ID,
+//! read, page program and 4K sector erase, all with 24-bit addresses, so at
+//! most 16M are usable. Writes go to a one sector cache, which is erased and
+//! programmed when a write moves on to another sector or on [`flush`].
+//!
+//! [`flush`]: BlockDevice::flush
+use super::{BlockDevice, Error, BLOCK_SIZE};
+use embedded_hal::blocking::spi::{Transfer, Write};
+use embedded_hal::digital::v2::OutputPin;
+
+// Commands
+const WRITE_ENABLE: u8 = 0x06;
+const READ_STATUS: u8 = 0x05;
+const READ_DATA: u8 = 0x03;
+const PAGE_PROGRAM: u8 = 0x02;
+const SECTOR_ERASE: u8 = 0x20;
+const JEDEC_ID: u8 = 0x9F;
+
+// Status register bits
+const BUSY: u8 = 1 << 0;
+
+const SECTOR_SIZE: usize = 4096;
+const PAGE_SIZE: usize = 256;
+const BLOCKS_PER_SECTOR: u32 = (SECTOR_SIZE / BLOCK_SIZE) as u32;
+/// Largest capacity reachable with 24-bit addresses
+const MAX_CAPACITY: u32 = 16 * 1024 * 1024;
+
+pub struct SpiNor<SPI, CS> {
+    spi: SPI,
+    cs: CS,
+    blocks: u32,
+    cache: [u8; SECTOR_SIZE],
+    /// Sector held in `cache`
+    cached: Option<u32>,
+    /// `cache` differs from the flash
+    dirty: bool,
+}
+
+impl<SPI, CS, E> SpiNor<SPI, CS>
+where
+    SPI: Transfer<u8, Error = E> + Write<u8, Error = E>,
+    CS: OutputPin,
+{
+    /// Identifies the chip, its capacity comes from the JEDEC ID
+    pub fn new(spi: SPI, mut cs: CS) -> Result<Self, Error> {
+        cs.set_high().ok();
+        let mut nor = SpiNor {
+            spi,
+            cs,
+            blocks: 0,
+            cache: [0; SECTOR_SIZE],
+            cached: None,
+            dirty: false,
+        };
+        let mut id = [JEDEC_ID, 0, 0, 0];
+        nor.transfer(&mut id)?;
+        // Manufacturer, memory type, capacity as a power of two
+        defmt::info!("SPI NOR {:?}", &id[1..]);
+        let capacity = match id[3] {
+            16..=31 => (1u32 << id[3]).min(MAX_CAPACITY),
+            _ => return Err(Error::Device),
+        };
+        nor.blocks = capacity / BLOCK_SIZE as u32;
+        Ok(nor)
+    }
+
+    pub fn release(self) -> (SPI, CS) {
+        (self.spi, self.cs)
+    }
+
+    fn transfer(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        self.cs.set_low().ok();
+        let result = self.spi.transfer(buf).map(|_| ());
+        self.cs.set_high().ok();
+        result.map_err(|_| Error::Device)
+    }
+
+    /// Sends `command` with a 24-bit address followed by the data phase `io`
+    fn command(&mut self, command: u8, address: u32, io: Io) -> Result<(), Error> {
+        let a = address.to_be_bytes();
+        self.cs.set_low().ok();
+        let mut result = self.spi.write(&[command, a[1], a[2], a[3]]);
+        if result.is_ok() {
+            result = match io {
+                Io::Read(buf) => self.spi.transfer(buf).map(|_| ()),
+                Io::Write(buf) => self.spi.write(buf),
+            };
+        }
+        self.cs.set_high().ok();
+        result.map_err(|_| Error::Device)
+    }
+
+    /// Sets the write enable latch, then waits for the operation started
+    /// by `command` to finish
+    fn modify(&mut self, command: u8, address: u32, data: &[u8]) -> Result<(), Error> {
+        self.transfer(&mut [WRITE_ENABLE])?;
+        self.command(command, address, Io::Write(data))?;
+        loop {
+            let mut status = [READ_STATUS, 0];
+            self.transfer(&mut status)?;
+            if status[1] & BUSY == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    fn load(&mut self, sector: u32) -> Result<(), Error> {
+        if self.cached == Some(sector) {
+            return Ok(());
+        }
+        self.flush()?;
+        // Page by page, the cache can not be borrowed while `self` is
+        self.cached = None;
+        let address = sector * SECTOR_SIZE as u32;
+        for i in 0..SECTOR_SIZE / PAGE_SIZE {
+            let mut page = [0; PAGE_SIZE];
+            self.command(
+                READ_DATA,
+                address + (i * PAGE_SIZE) as u32,
+                Io::Read(&mut page),
+            )?;
+            self.cache[i * PAGE_SIZE..(i + 1) * PAGE_SIZE].copy_from_slice(&page);
+        }
+        self.cached = Some(sector);
+        Ok(())
+    }
+}
+
+/// Data phase of a command
+enum Io<'a> {
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+}
+
+impl<SPI, CS, E> BlockDevice for SpiNor<SPI, CS>
+where
+    SPI: Transfer<u8, Error = E> + Write<u8, Error = E>,
+    CS: OutputPin,
+{
+    fn block_count(&self) -> u32 {
+        self.blocks
+    }
+
+    fn read(&mut self, block: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<(), Error> {
+        if block >= self.blocks {
+            return Err(Error::OutOfRange);
+        }
+        let sector = block / BLOCKS_PER_SECTOR;
+        if self.cached == Some(sector) {
+            let start = (block % BLOCKS_PER_SECTOR) as usize * BLOCK_SIZE;
+            buf.copy_from_slice(&self.cache[start..start + BLOCK_SIZE]);
+            return Ok(());
+        }
+        self.command(READ_DATA, block * BLOCK_SIZE as u32, Io::Read(buf))
+    }
+
+    fn write(&mut self, block: u32, buf: &[u8; BLOCK_SIZE]) -> Result<(), Error> {
+        if block >= self.blocks {
+            return Err(Error::OutOfRange);
+        }
+        self.load(block / BLOCKS_PER_SECTOR)?;
+        let start = (block % BLOCKS_PER_SECTOR) as usize * BLOCK_SIZE;
+        if self.cache[start..start + BLOCK_SIZE] != buf[..] {
+            self.cache[start..start + BLOCK_SIZE].copy_from_slice(buf);
+            self.dirty = true;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        let sector = match self.cached {
+            Some(sector) if self.dirty => sector,
+            _ => return Ok(()),
+        };
+        let address = sector * SECTOR_SIZE as u32;
+        self.modify(SECTOR_ERASE, address, &[])?;
+        for i in 0..SECTOR_SIZE / PAGE_SIZE {
+            let mut page = [0; PAGE_SIZE];
+            page.copy_from_slice(&self.cache[i * PAGE_SIZE..(i + 1) * PAGE_SIZE]);
+            // Erased pages need no programming
+            if page.iter().any(|byte| *byte != 0xFF) {
+                self.modify(PAGE_PROGRAM, address + (i * PAGE_SIZE) as u32, &page)?;
+            }
+        }
+        self.dirty = false;
+        Ok(())
+    }
+}
@@ -0,0 +1,109 @@
+//! The AEADs of the crypto service behind one type
+//!
+//! Every [`Algorithm`] takes a 32 byte key and produces a 16 byte tag, only the
+//! nonce length differs (see [`Algorithm::nonce_len`]).
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::aead::generic_array::GenericArray;
+use chacha20poly1305::aead::{AeadInPlace, NewAead};
+use chacha20poly1305::{ChaCha12Poly1305, ChaCha20Poly1305, ChaCha8Poly1305, XChaCha20Poly1305};
+use crate::keystore::KEY_LEN;
+use crypto_protocol::{Algorithm, TAG_LEN};
+
+/// Wrong nonce length or failed authentication
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub struct Error;
+
+pub enum Cipher {
+    ChaCha8(ChaCha8Poly1305),
+    ChaCha12(ChaCha12Poly1305),
+    ChaCha20(ChaCha20Poly1305),
+    XChaCha20(XChaCha20Poly1305),
+    Aes256Gcm(Aes256Gcm),
+}
+
+impl Cipher {
+    pub fn new(algorithm: Algorithm, key: &[u8; KEY_LEN]) -> Self {
+        let key = GenericArray::from_slice(key);
+        match algorithm {
+            Algorithm::ChaCha8Poly1305 => Cipher::ChaCha8(ChaCha8Poly1305::new(key)),
+            Algorithm::ChaCha12Poly1305 => Cipher::ChaCha12(ChaCha12Poly1305::new(key)),
+            Algorithm::ChaCha20Poly1305 => Cipher::ChaCha20(ChaCha20Poly1305::new(key)),
+            Algorithm::XChaCha20Poly1305 => Cipher::XChaCha20(XChaCha20Poly1305::new(key)),
+            Algorithm::Aes256Gcm => Cipher::Aes256Gcm(Aes256Gcm::new(key)),
+        }
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        match self {
+            Cipher::ChaCha8(_) => Algorithm::ChaCha8Poly1305,
+            Cipher::ChaCha12(_) => Algorithm::ChaCha12Poly1305,
+            Cipher::ChaCha20(_) => Algorithm::ChaCha20Poly1305,
+            Cipher::XChaCha20(_) => Algorithm::XChaCha20Poly1305,
+            Cipher::Aes256Gcm(_) => Algorithm::Aes256Gcm,
+        }
+    }
+
+    /// Encrypts `buf` in place and returns the tag
+    pub fn encrypt(
+        &self,
+        nonce: &[u8],
+        aad: &[u8],
+        buf: &mut [u8],
+    ) -> Result<[u8; TAG_LEN], Error> {
+        if nonce.len() != self.algorithm().nonce_len() {
+            return Err(Error);
+        }
+        // XChaCha20 nonces have a different type, so each arm converts its own
+        let tag = match self {
+            Cipher::ChaCha8(aead) => {
+                aead.encrypt_in_place_detached(GenericArray::from_slice(nonce), aad, buf)
+            }
+            Cipher::ChaCha12(aead) => {
+                aead.encrypt_in_place_detached(GenericArray::from_slice(nonce), aad, buf)
+            }
+            Cipher::ChaCha20(aead) => {
+                aead.encrypt_in_place_detached(GenericArray::from_slice(nonce), aad, buf)
+            }
+            Cipher::XChaCha20(aead) => {
+                aead.encrypt_in_place_detached(GenericArray::from_slice(nonce), aad, buf)
+            }
+            Cipher::Aes256Gcm(aead) => {
+                aead.encrypt_in_place_detached(GenericArray::from_slice(nonce), aad, buf)
+            }
+        }
+        .map_err(|_| Error)?;
+        Ok(tag.into())
+    }
+
+    /// Decrypts `buf` in place if `tag` matches
+    pub fn decrypt(
+        &self,
+        nonce: &[u8],
+        aad: &[u8],
+        buf: &mut [u8],
+        tag: &[u8],
+    ) -> Result<(), Error> {
+        if nonce.len() != self.algorithm().nonce_len() || tag.len() != TAG_LEN {
+            return Err(Error);
+        }
+        let tag = GenericArray::from_slice(tag);
+        match self {
+            Cipher::ChaCha8(aead) => {
+                aead.decrypt_in_place_detached(GenericArray::from_slice(nonce), aad, buf, tag)
+            }
+            Cipher::ChaCha12(aead) => {
+                aead.decrypt_in_place_detached(GenericArray::from_slice(nonce), aad, buf, tag)
+            }
+            Cipher::ChaCha20(aead) => {
+                aead.decrypt_in_place_detached(GenericArray::from_slice(nonce), aad, buf, tag)
+            }
+            Cipher::XChaCha20(aead) => {
+                aead.decrypt_in_place_detached(GenericArray::from_slice(nonce), aad, buf, tag)
+            }
+            Cipher::Aes256Gcm(aead) => {
+                aead.decrypt_in_place_detached(GenericArray::from_slice(nonce), aad, buf, tag)
+            }
+        }
+        .map_err(|_| Error)
+    }
+}
@@ -0,0 +1,285 @@
+//! Minimal internal flash programming for persistent data
+//!
+//! `memory.x` keeps the application in sectors 0-4 and splits sector 5 into
+//! regions for data, their bounds are exported by the linker script and
+//! exposed here as [`Region`]s. A sector is the smallest unit that can be
+//! erased, so erasing any region erases all of them: keys, boot counter and
+//! everything else stored in sector 5.
+//! See Section 3 of RM0368 for the erase and program sequences.
+//!
+//! This follows the driver of `without_hal/src/flash.rs`: the same errors,
+//! programming only clears bits and everything is read back afterwards. It
+//! can not be shared, both crates are packages named `stm32f4-playground`
+//! that bring their own global logger and panic handler, and only this one
+//! owns the peripherals through the HAL. A fix to one likely applies to both.
+use core::ptr;
+use stm32f4xx_hal::stm32::FLASH;
+
+/// Sector boundaries of the STM32F401xC (256K), see Table 5 of RM0368
+const SECTORS: [(usize, usize); 6] = [
+    (0x0800_0000, 16 * 1024),
+    (0x0800_4000, 16 * 1024),
+    (0x0800_8000, 16 * 1024),
+    (0x0800_C000, 16 * 1024),
+    (0x0801_0000, 64 * 1024),
+    (0x0802_0000, 128 * 1024),
+];
+
+/// Value of an erased flash word
+pub const ERASED: u32 = 0xFFFF_FFFF;
+
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum Error {
+    /// Address is not word aligned or outside a reserved region
+    Address,
+    /// Programming sequence error (PGSERR)
+    Sequence,
+    /// Parallelism error (PGPERR), access size does not match PSIZE
+    Parallelism,
+    /// Alignment error (PGAERR)
+    Alignment,
+    /// Target is write protected (WRPERR)
+    WriteProtected,
+    /// A bit that is 0 would have to go back to 1, which takes an erase
+    NotErased,
+    /// Read back differs from what was programmed
+    Verify,
+}
+
+extern "C" {
+    static _keys_start: u32;
+    static _keys_end: u32;
+    static _storage_start: u32;
+    static _storage_end: u32;
+    static _floors_start: u32;
+    static _floors_end: u32;
+    static _disk_start: u32;
+    static _disk_end: u32;
+}
+
+/// Range of flash reserved for data, part of sector 5
+#[derive(Clone, Copy)]
+pub struct Region {
+    start: usize,
+    end: usize,
+}
+
+impl Region {
+    /// Reserved for provisioned keys by `memory.x`
+    pub fn keys() -> Self {
+        unsafe {
+            Region {
+                start: &_keys_start as *const u32 as usize,
+                end: &_keys_end as *const u32 as usize,
+            }
+        }
+    }
+
+    /// Reserved by `memory.x` for the boot counter of
+    /// [`crate::nonce::NonceSequence`], anything else written there would
+    /// bring old nonces back
+    pub fn storage() -> Self {
+        unsafe {
+            Region {
+                start: &_storage_start as *const u32 as usize,
+                end: &_storage_end as *const u32 as usize,
+            }
+        }
+    }
+
+    /// Reserved by `memory.x` for the replay floors of
+    /// [`crate::radio::floors::FlashFloors`]
+    pub fn floors() -> Self {
+        unsafe {
+            Region {
+                start: &_floors_start as *const u32 as usize,
+                end: &_floors_end as *const u32 as usize,
+            }
+        }
+    }
+
+    /// Reserved by `memory.x` for the read-only drive of
+    /// [`crate::block::FlashDisk`], apart from the keys and the boot counter
+    pub fn disk() -> Self {
+        unsafe {
+            Region {
+                start: &_disk_start as *const u32 as usize,
+                end: &_disk_end as *const u32 as usize,
+            }
+        }
+    }
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// Contents of the region, flash is memory mapped
+    pub fn words(&self) -> &'static [u32] {
+        unsafe { core::slice::from_raw_parts(self.start as *const u32, self.len() / 4) }
+    }
+
+    fn contains(&self, address: usize, len: usize) -> bool {
+        address % 4 == 0 && address >= self.start && address + len <= self.end
+    }
+}
+
+pub struct Flash {
+    flash: FLASH,
+}
+
+impl Flash {
+    pub fn new(flash: FLASH) -> Self {
+        Flash { flash }
+    }
+
+    /// Erases every sector overlapping `region`, all words read back as
+    /// [`ERASED`]. This includes the other regions sharing those sectors.
+    pub fn erase(&mut self, region: &Region) -> Result<(), Error> {
+        for (sector, (start, len)) in SECTORS.iter().enumerate() {
+            if *start < region.end && start + len > region.start {
+                self.erase_sector(sector as u8)?;
+                let words = unsafe { core::slice::from_raw_parts(*start as *const u32, len / 4) };
+                if words.iter().any(|word| *word != ERASED) {
+                    return Err(Error::Verify);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn erase_sector(&mut self, sector: u8) -> Result<(), Error> {
+        self.unlock();
+        let mut result = self.wait();
+        if result.is_ok() {
+            // 32-bit parallelism, requires a 2.7 V - 3.6 V supply
+            self.flash
+                .cr
+                .modify(|_, w| unsafe { w.psize().bits(0b10).ser().set_bit().snb().bits(sector) });
+            self.flash.cr.modify(|_, w| w.strt().set_bit());
+            result = self.wait();
+        }
+        self.flash.cr.modify(|_, w| w.ser().clear_bit());
+        self.lock();
+        self.reset_caches();
+        result
+    }
+
+    /// Programs `words` starting at `address`, which must lie in `region`,
+    /// then reads them back. Programming can only clear bits.
+    pub fn program(&mut self, region: &Region, address: usize, words: &[u32]) -> Result<(), Error> {
+        if !region.contains(address, words.len() * 4) {
+            return Err(Error::Address);
+        }
+        let current = unsafe { core::slice::from_raw_parts(address as *const u32, words.len()) };
+        if current
+            .iter()
+            .zip(words)
+            .any(|(old, new)| old & new != *new)
+        {
+            return Err(Error::NotErased);
+        }
+
+        self.unlock();
+        let mut result = self.wait();
+        if result.is_ok() {
+            self.flash
+                .cr
+                .modify(|_, w| unsafe { w.psize().bits(0b10).pg().set_bit() });
+            for (i, word) in words.iter().enumerate() {
+                unsafe { ptr::write_volatile((address + i * 4) as *mut u32, *word) };
+                result = self.wait();
+                if result.is_err() {
+                    break;
+                }
+            }
+        }
+        self.flash.cr.modify(|_, w| w.pg().clear_bit());
+        self.lock();
+        result?;
+        let written = unsafe { core::slice::from_raw_parts(address as *const u32, words.len()) };
+        if written == words {
+            Ok(())
+        } else {
+            Err(Error::Verify)
+        }
+    }
+
+    /// The ART caches may still hold erased contents, see Section 3.5.2 of
+    /// RM0368. They can only be reset while disabled.
+    fn reset_caches(&mut self) {
+        let (icen, dcen) = {
+            let acr = self.flash.acr.read();
+            (acr.icen().bit(), acr.dcen().bit())
+        };
+        self.flash
+            .acr
+            .modify(|_, w| w.icen().clear_bit().dcen().clear_bit());
+        self.flash
+            .acr
+            .modify(|_, w| w.icrst().set_bit().dcrst().set_bit());
+        self.flash.acr.modify(|_, w| {
+            w.icrst()
+                .clear_bit()
+                .dcrst()
+                .clear_bit()
+                .icen()
+                .bit(icen)
+                .dcen()
+                .bit(dcen)
+        });
+    }
+
+    fn unlock(&mut self) {
+        if self.flash.cr.read().lock().bit_is_set() {
+            self.flash
+                .keyr
+                .write(|w| unsafe { w.key().bits(0x4567_0123) });
+            self.flash
+                .keyr
+                .write(|w| unsafe { w.key().bits(0xCDEF_89AB) });
+        }
+    }
+
+    fn lock(&mut self) {
+        self.flash.cr.modify(|_, w| w.lock().set_bit());
+    }
+
+    /// Waits for the current operation and reports (then clears) its errors
+    fn wait(&mut self) -> Result<(), Error> {
+        while self.flash.sr.read().bsy().bit_is_set() {}
+        let sr = self.flash.sr.read();
+        let result = if sr.wrperr().bit_is_set() {
+            Err(Error::WriteProtected)
+        } else if sr.pgserr().bit_is_set() {
+            Err(Error::Sequence)
+        } else if sr.pgperr().bit_is_set() {
+            Err(Error::Parallelism)
+        } else if sr.pgaerr().bit_is_set() {
+            Err(Error::Alignment)
+        } else {
+            Ok(())
+        };
+        // Error and EOP flags are cleared by writing 1
+        self.flash.sr.write(|w| {
+            w.eop()
+                .set_bit()
+                .wrperr()
+                .set_bit()
+                .pgaerr()
+                .set_bit()
+                .pgperr()
+                .set_bit()
+                .pgserr()
+                .set_bit()
+        });
+        result
+    }
+}
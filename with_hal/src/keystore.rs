@@ -0,0 +1,191 @@
+//! Provisioned keys, wrapped with a device-unique key and kept in flash
+//!
+//! [`Region::keys`] holds a table of [`SLOTS`] fixed-size records:
+//! | magic | key id | nonce (3 words) | wrapped key (8 words) | tag (4 words) |
+//! An erased slot is free, a slot with the magic holds a key and anything else
+//! (a zeroized slot or an interrupted write) is unusable. Slots are written
+//! once, making them free again takes an erase of the whole table.
+//!
+//! The wrapping key is the UID followed by a fixed label, so a flash dump is
+//! useless on another chip. The UID is readable by anyone with debug access
+//! though, only read protection (RDP level 1 or 2) keeps keys inside a device.
+use crate::flash::{self, Flash, Region, ERASED};
+use crate::nonce::NONCE_LEN;
+use crate::uid::{self, UID_LEN};
+use chacha20poly1305::aead::{AeadInPlace, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, Tag};
+use core::sync::atomic::{self, Ordering};
+
+/// Number of key slots in the table
+pub const SLOTS: usize = 16;
+pub const KEY_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+/// "KEY1"
+const MAGIC: u32 = 0x4B45_5931;
+const WRAP_LABEL: &[u8; KEY_LEN - UID_LEN] = b"stm32f4 key wrap v1\0";
+/// Words of a record, see the module documentation
+const RECORD_WORDS: usize = 2 + (NONCE_LEN + KEY_LEN + TAG_LEN) / 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum Error {
+    Flash(flash::Error),
+    /// Slot index is not below [`SLOTS`]
+    InvalidSlot,
+    /// Slot holds no key
+    SlotEmpty,
+    /// Slot has been written since the last erase
+    SlotInUse,
+    /// Key does not unwrap, the record is damaged or from another device
+    Corrupt,
+}
+
+impl From<flash::Error> for Error {
+    fn from(e: flash::Error) -> Self {
+        Error::Flash(e)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum Slot {
+    Free,
+    /// Holds the key with the given id
+    Used {
+        id: u32,
+    },
+    /// Zeroized or damaged, unusable until the table is erased
+    Zeroized,
+}
+
+/// Overwrites `buf` with zeroes in a way the compiler can not optimize away
+pub fn wipe(buf: &mut [u8]) {
+    for byte in buf.iter_mut() {
+        unsafe { core::ptr::write_volatile(byte, 0) };
+    }
+    atomic::compiler_fence(Ordering::SeqCst);
+}
+
+pub struct KeyStore {
+    region: Region,
+    wrap: ChaCha20Poly1305,
+}
+
+impl KeyStore {
+    /// Key table in `region`, normally [`Region::keys`]
+    pub fn new(region: Region) -> Self {
+        let mut kek = [0; KEY_LEN];
+        kek[..UID_LEN].copy_from_slice(&uid::read());
+        kek[UID_LEN..].copy_from_slice(WRAP_LABEL);
+        let wrap = ChaCha20Poly1305::new(Key::from_slice(&kek));
+        wipe(&mut kek);
+        KeyStore { region, wrap }
+    }
+
+    fn record(&self, slot: usize) -> Result<&'static [u32], Error> {
+        if slot >= SLOTS {
+            return Err(Error::InvalidSlot);
+        }
+        let start = slot * RECORD_WORDS;
+        Ok(&self.region.words()[start..start + RECORD_WORDS])
+    }
+
+    fn address(&self, slot: usize) -> usize {
+        self.region.start() + slot * RECORD_WORDS * 4
+    }
+
+    pub fn slot(&self, slot: usize) -> Result<Slot, Error> {
+        let record = self.record(slot)?;
+        Ok(if record[0] == MAGIC {
+            Slot::Used { id: record[1] }
+        } else if record.iter().all(|word| *word == ERASED) {
+            Slot::Free
+        } else {
+            Slot::Zeroized
+        })
+    }
+
+    /// Bitmap of the slots holding a key
+    pub fn used(&self) -> u16 {
+        (0..SLOTS)
+            .filter(|slot| matches!(self.slot(*slot), Ok(Slot::Used { .. })))
+            .fold(0, |bits, slot| bits | 1 << slot)
+    }
+
+    /// Wraps `key` and writes it to a free slot, `nonce` must never have been
+    /// used for wrapping on this device before
+    pub fn store(
+        &self,
+        flash: &mut Flash,
+        slot: usize,
+        id: u32,
+        key: &[u8; KEY_LEN],
+        nonce: &[u8; NONCE_LEN],
+    ) -> Result<(), Error> {
+        if self.slot(slot)? != Slot::Free {
+            return Err(Error::SlotInUse);
+        }
+        let mut wrapped = *key;
+        let tag = self
+            .wrap
+            .encrypt_in_place_detached(Nonce::from_slice(nonce), &aad(slot, id), &mut wrapped)
+            .map_err(|_| Error::Corrupt)?;
+
+        let mut record = [0; RECORD_WORDS];
+        record[0] = MAGIC;
+        record[1] = id;
+        let bytes = nonce.iter().chain(wrapped.iter()).chain(tag.iter());
+        for (i, byte) in bytes.enumerate() {
+            record[2 + i / 4] |= u32::from(*byte) << (8 * (i % 4));
+        }
+        // Magic last, a record only counts once it is complete
+        let address = self.address(slot);
+        flash.program(&self.region, address + 4, &record[1..])?;
+        flash.program(&self.region, address, &record[..1])?;
+        Ok(())
+    }
+
+    /// Unwraps the key in `slot`, returns its id
+    pub fn load(&self, slot: usize, key: &mut [u8; KEY_LEN]) -> Result<u32, Error> {
+        let id = match self.slot(slot)? {
+            Slot::Used { id } => id,
+            _ => return Err(Error::SlotEmpty),
+        };
+        let mut bytes = [0; NONCE_LEN + KEY_LEN + TAG_LEN];
+        for (chunk, word) in bytes.chunks_exact_mut(4).zip(&self.record(slot)?[2..]) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        let (nonce, rest) = bytes.split_at(NONCE_LEN);
+        let (wrapped, tag) = rest.split_at(KEY_LEN);
+        key.copy_from_slice(wrapped);
+        self.wrap
+            .decrypt_in_place_detached(
+                Nonce::from_slice(nonce),
+                &aad(slot, id),
+                key,
+                Tag::from_slice(tag),
+            )
+            .map_err(|_| {
+                wipe(key);
+                Error::Corrupt
+            })?;
+        Ok(id)
+    }
+
+    /// Programs every word of `slot` to zero, the key is gone for good
+    pub fn zeroize(&self, flash: &mut Flash, slot: usize) -> Result<(), Error> {
+        self.record(slot)?;
+        flash.program(&self.region, self.address(slot), &[0; RECORD_WORDS])?;
+        Ok(())
+    }
+
+    /// Erases the whole table, every slot becomes free. The table shares its
+    /// sector with other data, see [`crate::flash`], which is erased as well.
+    pub fn erase(&self, flash: &mut Flash) -> Result<(), Error> {
+        Ok(flash.erase(&self.region)?)
+    }
+}
+
+/// Binds a wrapped key to its slot and id
+fn aad(slot: usize, id: u32) -> [u8; 5] {
+    let id = id.to_le_bytes();
+    [slot as u8, id[0], id[1], id[2], id[3]]
+}
@@ -3,6 +3,17 @@
 use defmt_rtt as _; // Global logger
 use panic_probe as _;
 
+pub mod block;
+pub mod cipher;
+pub mod flash;
+pub mod keystore;
+pub mod nonce;
+pub mod radio;
+pub mod time;
+pub mod uart;
+pub mod uid;
+pub mod usb;
+
 // Same panicking *behavior* as `panic-probe` but doesn't print a panic message
 // this prevents the panic message being printed *twice* when `defmt::panic` is invoked
 #[defmt::panic_handler]
@@ -0,0 +1,66 @@
+//! Unique 96-bit nonces without a hardware RNG
+//!
+//! The STM32F401 has no RNG, so nonces are built from the device, a boot
+//! counter kept in flash and a message counter kept in RAM:
+//! | [`uid::short_id`] (u32 BE) | boot counter (u16 BE) | message counter (u48 BE) |
+//! The ID keeps boards sharing a key from producing the same nonces, as long
+//! as their IDs differ, which is likely but not certain for a 32-bit hash.
+//! Every boot programs one more word of the region, the number of programmed
+//! words is the boot counter. Erasing the region would bring old boot counter
+//! values back, so once it is full no more nonces are handed out and the key
+//! has to be replaced together with an erase. Erasing another region of the
+//! same sector takes the counter along, [`NonceSequence::restore`] puts it
+//! back.
+use crate::flash::{self, Flash, Region, ERASED};
+use crate::uid;
+
+pub const NONCE_LEN: usize = 12;
+
+pub struct NonceSequence {
+    id: u32,
+    boot: u32,
+    counter: u64,
+}
+
+impl NonceSequence {
+    /// Records a new boot in `region`, returns `None` once it is full
+    pub fn start(flash: &mut Flash, region: &Region) -> Result<Option<Self>, flash::Error> {
+        let words = region.words();
+        // Programmed words always form a prefix of the region
+        let boot = words.partition_point(|word| *word != ERASED);
+        if boot == words.len() {
+            return Ok(None);
+        }
+        flash.program(region, region.start() + boot * 4, &[0])?;
+        Ok(Some(NonceSequence {
+            id: uid::short_id(),
+            boot: boot as u32,
+            counter: 0,
+        }))
+    }
+
+    /// Programs the boot counter into `region` again after it was erased,
+    /// this boot included
+    pub fn restore(&self, flash: &mut Flash, region: &Region) -> Result<(), flash::Error> {
+        for boot in 0..=self.boot as usize {
+            flash.program(region, region.start() + boot * 4, &[0])?;
+        }
+        Ok(())
+    }
+
+    pub fn boot(&self) -> u32 {
+        self.boot
+    }
+
+    /// Next unused nonce
+    pub fn next(&mut self) -> [u8; NONCE_LEN] {
+        let mut nonce = [0; NONCE_LEN];
+        nonce[..4].copy_from_slice(&self.id.to_be_bytes());
+        // The region holds far fewer than 2^16 boots
+        nonce[4..6].copy_from_slice(&(self.boot as u16).to_be_bytes());
+        nonce[6..].copy_from_slice(&self.counter.to_be_bytes()[2..]);
+        // 2^48 messages will not happen in a single boot
+        self.counter += 1;
+        nonce
+    }
+}
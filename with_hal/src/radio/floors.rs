@@ -0,0 +1,77 @@
+//! [`FloorStore`] in internal flash
+//!
+//! Every raised floor appends a record of two words to [`Region::floors`]:
+//! | "FL" | key id | sender id | floor (u32) |
+//! The last record of a sender holds its floor. The region can not be erased
+//! on its own, it shares sector 5 with the keys and the boot counter (see
+//! [`crate::flash`]), so once it is full no floor can be raised and every
+//! frame of a new or advancing sender is dropped. Erasing the sector starts
+//! over, the key has to be changed along with it or old frames pass again.
+use super::secure::FloorStore;
+use crate::flash::{Flash, Region, ERASED};
+
+/// Words of a record
+const RECORD_WORDS: usize = 2;
+/// "FL" in the upper half of the first word, never [`ERASED`]
+const MAGIC: u32 = 0x464C_0000;
+
+pub struct FlashFloors {
+    flash: Flash,
+    region: Region,
+    /// Index of the first free record
+    next: usize,
+}
+
+impl FlashFloors {
+    /// Floors in `region`, normally [`Region::floors`]
+    pub fn new(flash: Flash, region: Region) -> Self {
+        // Records are only appended, a failed one may leave an erased gap
+        let next = region
+            .words()
+            .chunks_exact(RECORD_WORDS)
+            .rposition(|record| record.iter().any(|word| *word != ERASED))
+            .map_or(0, |last| last + 1);
+        FlashFloors {
+            flash,
+            region,
+            next,
+        }
+    }
+
+    /// Records left before the region is full
+    pub fn free(&self) -> usize {
+        self.region.len() / 4 / RECORD_WORDS - self.next
+    }
+}
+
+fn tag(key_id: u8, sender: u8) -> u32 {
+    MAGIC | u32::from(key_id) << 8 | u32::from(sender)
+}
+
+impl FloorStore for FlashFloors {
+    fn floor(&mut self, key_id: u8, sender: u8) -> Option<u32> {
+        let tag = tag(key_id, sender);
+        self.region.words()[..self.next * RECORD_WORDS]
+            .chunks_exact(RECORD_WORDS)
+            .rev()
+            .find(|record| record[0] == tag)
+            .map(|record| record[1])
+    }
+
+    fn raise(&mut self, key_id: u8, sender: u8, floor: u32) -> bool {
+        if self.free() == 0 {
+            return false;
+        }
+        let address = self.region.start() + self.next * RECORD_WORDS * 4;
+        // Taken even if programming fails, a damaged record is never reused
+        self.next += 1;
+        // Tag last, a record only counts once it is complete
+        self.flash
+            .program(&self.region, address + 4, &[floor])
+            .is_ok()
+            && self
+                .flash
+                .program(&self.region, address, &[tag(key_id, sender)])
+                .is_ok()
+    }
+}
@@ -0,0 +1,26 @@
+//! Fragmentation and reassembly of messages larger than a single radio frame
+//!
+//! The framing lives in the host-buildable `radio_transport` crate, where it
+//! is tested against a simulated lossy channel. This only plugs the nRF24L01
+//! [`Link`] into it.
+use super::link::{self, Delivery, Link, MAX_PAYLOAD};
+use embedded_nrf24l01::Device;
+
+pub use radio_transport::{Error, FrameLink, Transport, HEADER_LEN, MAX_FRAGMENTS, MAX_MESSAGE};
+
+impl<D: Device> FrameLink for Link<D> {
+    type Error = link::Error<D::Error>;
+    const MTU: usize = MAX_PAYLOAD;
+
+    fn send_frame(&mut self, frame: &[u8]) -> Result<bool, Self::Error> {
+        Ok(self.send(frame)? != Delivery::Lost)
+    }
+
+    /// Sleeps until a frame arrives
+    fn recv_frame(&mut self, buf: &mut [u8]) -> Result<Option<usize>, Self::Error> {
+        let (_, payload) = self.recv()?;
+        let len = payload.len().min(buf.len());
+        buf[..len].copy_from_slice(&payload.as_ref()[..len]);
+        Ok(Some(len))
+    }
+}
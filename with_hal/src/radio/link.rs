@@ -0,0 +1,242 @@
+//! Reliable nRF24L01 link layer using Enhanced ShockBurst
+//!
+//! Every packet is sent with a 2-byte CRC and must be acknowledged by the
+//! receiver, the radio retransmits on its own up to `LinkConfig::retries` times.
+//! Completion is signalled by the IRQ pin (active low), which should be routed
+//! through EXTI to an interrupt handler that calls [`on_irq`].
+use core::sync::atomic::{AtomicBool, Ordering};
+use embedded_nrf24l01::{
+    Configuration, CrcMode, DataRate, Device, Payload, RxMode, StandbyMode, TxMode,
+};
+
+/// Largest payload a single nRF24L01 packet can carry
+pub const MAX_PAYLOAD: usize = 32;
+
+/// Set by the EXTI handler wired to the IRQ pin, cleared by the link
+static IRQ: AtomicBool = AtomicBool::new(false);
+
+/// Must be called from the EXTI interrupt handler the IRQ pin is routed to
+pub fn on_irq() {
+    IRQ.store(true, Ordering::Release);
+}
+
+/// Sleeps until the IRQ pin has fired since the last call
+pub fn wait_irq() {
+    // Check and sleep with interrupts masked, a pending interrupt still wakes
+    // up WFI so an edge arriving between the check and the WFI is not missed
+    while !IRQ.swap(false, Ordering::AcqRel) {
+        cortex_m::interrupt::free(|_| {
+            if !IRQ.load(Ordering::Acquire) {
+                cortex_m::asm::wfi();
+            }
+        });
+    }
+}
+
+/// Radio settings, both ends of a link must agree on all of them
+pub struct LinkConfig<'a> {
+    /// RF channel, 2400 + `channel` MHz (0-125)
+    pub channel: u8,
+    pub data_rate: DataRate,
+    /// Output power, 0 (-18 dBm) to 3 (0 dBm)
+    pub power: u8,
+    /// Automatic retransmissions before giving up (0-15)
+    pub retries: u8,
+    /// Delay between retransmissions in steps of 250 µs, (n + 1) * 250 µs (0-15)
+    pub retry_delay: u8,
+    /// TX address, also used on RX pipe 0 to receive the auto-acks
+    pub address: &'a [u8],
+}
+
+impl Default for LinkConfig<'_> {
+    fn default() -> Self {
+        LinkConfig {
+            channel: 8,
+            data_rate: DataRate::R2Mbps,
+            power: 3,
+            retries: 15,
+            retry_delay: 5,
+            address: b"stm32",
+        }
+    }
+}
+
+/// Outcome of a single [`Link::send`]
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum Delivery {
+    /// Acknowledged by the receiver after `retries` retransmissions
+    Delivered { retries: u8 },
+    /// Not acknowledged, even after all retransmissions
+    Lost,
+}
+
+/// Running counters, the retransmission count comes from OBSERVE_TX.ARC_CNT
+#[derive(Clone, Copy, Default, defmt::Format)]
+pub struct LinkStats {
+    pub sent: u32,
+    pub delivered: u32,
+    pub lost: u32,
+    pub retransmits: u32,
+    pub received: u32,
+}
+
+#[derive(Debug, defmt::Format)]
+pub enum Error<E> {
+    /// SPI or pin error reported by the driver
+    Radio(E),
+    /// Payload is longer than [`MAX_PAYLOAD`]
+    PayloadTooLarge,
+    /// A previous mode change failed and the driver was lost
+    Unavailable,
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(e: E) -> Self {
+        Error::Radio(e)
+    }
+}
+
+enum Mode<D: Device> {
+    Standby(StandbyMode<D>),
+    Tx(TxMode<D>),
+    Rx(RxMode<D>),
+}
+
+pub struct Link<D: Device> {
+    mode: Option<Mode<D>>,
+    stats: LinkStats,
+}
+
+impl<D: Device> Link<D> {
+    /// Configures the radio for Enhanced ShockBurst and leaves it in standby
+    pub fn new(mut radio: StandbyMode<D>, config: &LinkConfig) -> Result<Self, Error<D::Error>> {
+        radio.set_frequency(config.channel)?;
+        radio.set_rf(&config.data_rate, config.power)?;
+        radio.set_crc(CrcMode::TwoBytes)?;
+        radio.set_auto_retransmit(config.retry_delay, config.retries)?;
+        radio.set_auto_ack(&[true; 6])?;
+        // Dynamic payload lengths on every pipe
+        radio.set_pipes_rx_lengths(&[None; 6])?;
+        radio.set_pipes_rx_enable(&[true, false, false, false, false, false])?;
+        radio.set_tx_addr(config.address)?;
+        radio.set_rx_addr(0, config.address)?;
+        radio.flush_tx()?;
+        radio.flush_rx()?;
+        radio.clear_interrupts()?;
+
+        Ok(Link {
+            mode: Some(Mode::Standby(radio)),
+            stats: LinkStats::default(),
+        })
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        self.stats
+    }
+
+    /// Sends `payload` and sleeps until it is acknowledged or dropped
+    pub fn send(&mut self, payload: &[u8]) -> Result<Delivery, Error<D::Error>> {
+        if payload.len() > MAX_PAYLOAD {
+            return Err(Error::PayloadTooLarge);
+        }
+
+        let tx = self.tx()?;
+        tx.send(payload)?;
+        let delivered = loop {
+            match tx.poll_send() {
+                Ok(delivered) => break delivered,
+                Err(nb::Error::WouldBlock) => wait_irq(),
+                Err(nb::Error::Other(e)) => return Err(Error::Radio(e)),
+            }
+        };
+        // ARC_CNT holds the retransmissions of the last packet
+        let retries = tx.observe()?.arc_cnt();
+
+        self.stats.sent += 1;
+        self.stats.retransmits += u32::from(retries);
+        if delivered {
+            self.stats.delivered += 1;
+            Ok(Delivery::Delivered { retries })
+        } else {
+            self.stats.lost += 1;
+            Ok(Delivery::Lost)
+        }
+    }
+
+    /// Returns a received packet and the pipe it arrived on, if there is one
+    pub fn try_recv(&mut self) -> Result<Option<(u8, Payload)>, Error<D::Error>> {
+        let rx = self.rx()?;
+        match rx.can_read()? {
+            Some(pipe) => {
+                let payload = rx.read()?;
+                // Release the IRQ line so the next packet produces a new edge
+                rx.clear_interrupts()?;
+                self.stats.received += 1;
+                Ok(Some((pipe, payload)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Sleeps until a packet arrives
+    pub fn recv(&mut self) -> Result<(u8, Payload), Error<D::Error>> {
+        loop {
+            if let Some(packet) = self.try_recv()? {
+                return Ok(packet);
+            }
+            wait_irq();
+        }
+    }
+
+    /// Puts the radio in standby, flushing anything left in the TX FIFO
+    pub fn standby(&mut self) -> Result<&mut StandbyMode<D>, Error<D::Error>> {
+        let standby = match self.mode.take() {
+            Some(Mode::Standby(standby)) => standby,
+            Some(Mode::Rx(rx)) => rx.standby(),
+            Some(Mode::Tx(tx)) => tx.standby()?,
+            None => return Err(Error::Unavailable),
+        };
+        match self.mode.insert(Mode::Standby(standby)) {
+            Mode::Standby(standby) => Ok(standby),
+            _ => unreachable!(),
+        }
+    }
+
+    fn tx(&mut self) -> Result<&mut TxMode<D>, Error<D::Error>> {
+        if !matches!(self.mode, Some(Mode::Tx(_))) {
+            self.standby()?;
+            if let Some(Mode::Standby(standby)) = self.mode.take() {
+                match standby.tx() {
+                    Ok(tx) => self.mode = Some(Mode::Tx(tx)),
+                    Err((e, standby)) => {
+                        self.mode = Some(Mode::Standby(standby));
+                        return Err(Error::Radio(e));
+                    }
+                }
+            }
+        }
+        match &mut self.mode {
+            Some(Mode::Tx(tx)) => Ok(tx),
+            _ => Err(Error::Unavailable),
+        }
+    }
+
+    fn rx(&mut self) -> Result<&mut RxMode<D>, Error<D::Error>> {
+        if !matches!(self.mode, Some(Mode::Rx(_))) {
+            self.standby()?;
+            if let Some(Mode::Standby(standby)) = self.mode.take() {
+                match standby.rx() {
+                    Ok(rx) => self.mode = Some(Mode::Rx(rx)),
+                    Err((e, standby)) => {
+                        self.mode = Some(Mode::Standby(standby));
+                        return Err(Error::Radio(e));
+                    }
+                }
+            }
+        }
+        match &mut self.mode {
+            Some(Mode::Rx(rx)) => Ok(rx),
+            _ => Err(Error::Unavailable),
+        }
+    }
+}
@@ -0,0 +1,16 @@
+//! nRF24L01 helpers built on top of the `embedded_nrf24l01` driver
+
+pub mod floors;
+pub mod fragment;
+pub mod link;
+pub mod raw;
+pub mod scan;
+pub mod secure;
+pub mod star;
+
+pub use floors::FlashFloors;
+pub use fragment::{FrameLink, Transport};
+pub use link::{Delivery, Error, Link, LinkConfig, LinkStats};
+pub use raw::Nrf24;
+pub use secure::{FloorStore, SecureLink, SendOnly};
+pub use star::{Hub, Node};
@@ -0,0 +1,323 @@
+//! Register-level access to the nRF24L01
+//!
+//! `embedded_nrf24l01` does not expose ACK payloads, the RPD register or the
+//! test modes, so the star network and the channel scanner talk to the radio
+//! through this instead. See the nRF24L01+ product specification v1.0.
+use super::link::{LinkConfig, MAX_PAYLOAD};
+use embedded_hal::blocking::spi::Transfer;
+use embedded_hal::digital::v2::OutputPin;
+use embedded_nrf24l01::DataRate;
+
+// Commands
+const R_REGISTER: u8 = 0x00;
+const W_REGISTER: u8 = 0x20;
+const R_RX_PAYLOAD: u8 = 0x61;
+const W_TX_PAYLOAD: u8 = 0xA0;
+const FLUSH_TX: u8 = 0xE1;
+const FLUSH_RX: u8 = 0xE2;
+const R_RX_PL_WID: u8 = 0x60;
+const W_ACK_PAYLOAD: u8 = 0xA8;
+const NOP: u8 = 0xFF;
+
+// Registers
+pub const CONFIG: u8 = 0x00;
+pub const EN_AA: u8 = 0x01;
+pub const EN_RXADDR: u8 = 0x02;
+pub const SETUP_AW: u8 = 0x03;
+pub const SETUP_RETR: u8 = 0x04;
+pub const RF_CH: u8 = 0x05;
+pub const RF_SETUP: u8 = 0x06;
+pub const STATUS: u8 = 0x07;
+pub const OBSERVE_TX: u8 = 0x08;
+pub const RPD: u8 = 0x09;
+pub const RX_ADDR_P0: u8 = 0x0A;
+pub const TX_ADDR: u8 = 0x10;
+pub const FIFO_STATUS: u8 = 0x17;
+pub const DYNPD: u8 = 0x1C;
+pub const FEATURE: u8 = 0x1D;
+
+// CONFIG bits
+const EN_CRC: u8 = 1 << 3;
+const CRCO: u8 = 1 << 2;
+const PWR_UP: u8 = 1 << 1;
+const PRIM_RX: u8 = 1 << 0;
+// STATUS bits
+pub const RX_DR: u8 = 1 << 6;
+pub const TX_DS: u8 = 1 << 5;
+pub const MAX_RT: u8 = 1 << 4;
+// FIFO_STATUS bits
+const RX_EMPTY: u8 = 1 << 0;
+const TX_FULL: u8 = 1 << 5;
+// FEATURE bits
+const EN_DPL: u8 = 1 << 2;
+const EN_ACK_PAY: u8 = 1 << 1;
+// RF_SETUP bits
+pub const CONT_WAVE: u8 = 1 << 7;
+const RF_DR_LOW: u8 = 1 << 5;
+pub const PLL_LOCK: u8 = 1 << 4;
+const RF_DR_HIGH: u8 = 1 << 3;
+
+/// Outcome of a transmission in TX mode
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum TxResult {
+    /// Acknowledged, carrying an ACK payload of the given length if there was one
+    Delivered {
+        ack_len: Option<usize>,
+    },
+    Lost,
+}
+
+/// STATUS reads before [`Nrf24::transmit`] gives up. Even at the slowest
+/// auto retransmit setting (15 retries, 4 ms apart) the radio is done within
+/// 65 ms, while this many reads take well over 100 ms at any SPI clock.
+const TX_POLLS: u32 = 100_000;
+
+#[derive(Debug, defmt::Format)]
+pub enum TxError<E> {
+    Spi(E),
+    /// Neither TX_DS nor MAX_RT showed up, the radio is not responding
+    Timeout,
+}
+
+impl<E> From<E> for TxError<E> {
+    fn from(e: E) -> Self {
+        TxError::Spi(e)
+    }
+}
+
+pub struct Nrf24<SPI, CE, CSN> {
+    spi: SPI,
+    ce: CE,
+    csn: CSN,
+}
+
+impl<SPI, CE, CSN, E> Nrf24<SPI, CE, CSN>
+where
+    SPI: Transfer<u8, Error = E>,
+    CE: OutputPin,
+    CSN: OutputPin,
+{
+    pub fn new(spi: SPI, mut ce: CE, mut csn: CSN) -> Self {
+        ce.set_low().ok();
+        csn.set_high().ok();
+        Nrf24 { spi, ce, csn }
+    }
+
+    pub fn release(self) -> (SPI, CE, CSN) {
+        (self.spi, self.ce, self.csn)
+    }
+
+    /// Clocks out `buf` (command byte followed by data), the response is
+    /// written back into `buf` and the STATUS byte is returned
+    fn transfer(&mut self, buf: &mut [u8]) -> Result<u8, E> {
+        self.csn.set_low().ok();
+        let result = self.spi.transfer(buf).map(|response| response[0]);
+        self.csn.set_high().ok();
+        result
+    }
+
+    pub fn read_register(&mut self, register: u8) -> Result<u8, E> {
+        let mut buf = [R_REGISTER | register, NOP];
+        self.transfer(&mut buf)?;
+        Ok(buf[1])
+    }
+
+    pub fn write_register(&mut self, register: u8, value: u8) -> Result<(), E> {
+        self.transfer(&mut [W_REGISTER | register, value])?;
+        Ok(())
+    }
+
+    /// Writes up to 5 bytes, used for the address registers
+    pub fn write_address(&mut self, register: u8, address: &[u8]) -> Result<(), E> {
+        let mut buf = [0; 6];
+        buf[0] = W_REGISTER | register;
+        buf[1..=address.len()].copy_from_slice(address);
+        self.transfer(&mut buf[..=address.len()])?;
+        Ok(())
+    }
+
+    fn command(&mut self, command: u8) -> Result<u8, E> {
+        self.transfer(&mut [command])
+    }
+
+    pub fn status(&mut self) -> Result<u8, E> {
+        self.command(NOP)
+    }
+
+    pub fn clear_interrupts(&mut self) -> Result<(), E> {
+        self.write_register(STATUS, RX_DR | TX_DS | MAX_RT)
+    }
+
+    pub fn flush_tx(&mut self) -> Result<(), E> {
+        self.command(FLUSH_TX).map(|_| ())
+    }
+
+    pub fn flush_rx(&mut self) -> Result<(), E> {
+        self.command(FLUSH_RX).map(|_| ())
+    }
+
+    pub fn set_ce(&mut self, enable: bool) {
+        if enable {
+            self.ce.set_high().ok();
+        } else {
+            self.ce.set_low().ok();
+        }
+    }
+
+    pub fn set_channel(&mut self, channel: u8) -> Result<(), E> {
+        self.write_register(RF_CH, channel & 0x7F)
+    }
+
+    /// Applies the channel, data rate, power and retransmission settings with
+    /// Enhanced ShockBurst, 2-byte CRC, dynamic payloads and ACK payloads
+    /// enabled on all six pipes, leaves the radio powered up in standby
+    pub fn configure(&mut self, config: &LinkConfig) -> Result<(), E> {
+        self.set_ce(false);
+        self.write_register(CONFIG, EN_CRC | CRCO)?;
+        self.write_register(SETUP_AW, 0b11)?; // 5 byte addresses
+        self.write_register(
+            SETUP_RETR,
+            ((config.retry_delay & 0xF) << 4) | (config.retries & 0xF),
+        )?;
+        self.set_channel(config.channel)?;
+        let rate = match config.data_rate {
+            DataRate::R250Kbps => RF_DR_LOW,
+            DataRate::R1Mbps => 0,
+            DataRate::R2Mbps => RF_DR_HIGH,
+        };
+        self.write_register(RF_SETUP, rate | ((config.power & 0b11) << 1))?;
+        self.write_register(EN_AA, 0x3F)?;
+        self.write_register(FEATURE, EN_DPL | EN_ACK_PAY)?;
+        self.write_register(DYNPD, 0x3F)?;
+        self.flush_tx()?;
+        self.flush_rx()?;
+        self.clear_interrupts()?;
+        self.write_register(CONFIG, EN_CRC | CRCO | PWR_UP)?;
+        // Tpd2stby, 1.5 ms from power down to standby
+        cortex_m::asm::delay(150_000);
+        Ok(())
+    }
+
+    /// Enters RX mode (PRIM_RX = 1, CE = 1)
+    pub fn listen(&mut self) -> Result<(), E> {
+        let config = self.read_register(CONFIG)?;
+        self.write_register(CONFIG, config | PRIM_RX)?;
+        self.set_ce(true);
+        Ok(())
+    }
+
+    /// Back to standby with PRIM_RX = 0, ready to transmit
+    pub fn standby(&mut self) -> Result<(), E> {
+        self.set_ce(false);
+        let config = self.read_register(CONFIG)?;
+        self.write_register(CONFIG, config & !PRIM_RX)
+    }
+
+    /// Pipe number of the payload at the head of the RX FIFO, if any
+    pub fn rx_pipe(&mut self) -> Result<Option<u8>, E> {
+        if self.read_register(FIFO_STATUS)? & RX_EMPTY != 0 {
+            return Ok(None);
+        }
+        let pipe = (self.status()? >> 1) & 0b111;
+        Ok(if pipe < 6 { Some(pipe) } else { None })
+    }
+
+    /// Reads the payload at the head of the RX FIFO into `buf`
+    pub fn read_payload(&mut self, buf: &mut [u8]) -> Result<usize, E> {
+        let mut width = [R_RX_PL_WID, NOP];
+        self.transfer(&mut width)?;
+        let len = usize::from(width[1]);
+        if len > MAX_PAYLOAD {
+            // Corrupt width, the datasheet says to flush the RX FIFO
+            self.flush_rx()?;
+            return Ok(0);
+        }
+        let mut frame = [0; MAX_PAYLOAD + 1];
+        frame[0] = R_RX_PAYLOAD;
+        self.transfer(&mut frame[..=len])?;
+        let len = len.min(buf.len());
+        buf[..len].copy_from_slice(&frame[1..=len]);
+        self.write_register(STATUS, RX_DR)?;
+        Ok(len)
+    }
+
+    /// Queues a payload to be sent with the next ACK on `pipe` (RX mode only),
+    /// returns `false` without queueing it if the TX FIFO already holds three
+    pub fn write_ack_payload(&mut self, pipe: u8, payload: &[u8]) -> Result<bool, E> {
+        if self.read_register(FIFO_STATUS)? & TX_FULL != 0 {
+            return Ok(false);
+        }
+        self.write_payload(W_ACK_PAYLOAD | (pipe & 0b111), payload)?;
+        Ok(true)
+    }
+
+    fn write_payload(&mut self, command: u8, payload: &[u8]) -> Result<(), E> {
+        let len = payload.len().min(MAX_PAYLOAD);
+        let mut frame = [0; MAX_PAYLOAD + 1];
+        frame[0] = command;
+        frame[1..=len].copy_from_slice(&payload[..len]);
+        self.transfer(&mut frame[..=len])?;
+        Ok(())
+    }
+
+    /// Sends a payload from standby and polls until it is acknowledged or the
+    /// retransmissions run out, an ACK payload is left in the RX FIFO
+    pub fn transmit(&mut self, payload: &[u8]) -> Result<TxResult, TxError<E>> {
+        self.write_payload(W_TX_PAYLOAD, payload)?;
+        self.set_ce(true);
+        let mut status = 0;
+        for _ in 0..TX_POLLS {
+            status = self.status()?;
+            if status & (TX_DS | MAX_RT) != 0 {
+                break;
+            }
+        }
+        self.set_ce(false);
+        if status & (TX_DS | MAX_RT) == 0 {
+            // Nothing is left behind for the next transmission
+            self.flush_tx()?;
+            return Err(TxError::Timeout);
+        }
+        self.clear_interrupts()?;
+
+        if status & MAX_RT != 0 {
+            // The payload stays in the TX FIFO after MAX_RT
+            self.flush_tx()?;
+            return Ok(TxResult::Lost);
+        }
+        let ack_len = if status & RX_DR != 0 {
+            let mut width = [R_RX_PL_WID, NOP];
+            self.transfer(&mut width)?;
+            Some(usize::from(width[1]))
+        } else {
+            None
+        };
+        Ok(TxResult::Delivered { ack_len })
+    }
+
+    /// Received Power Detector, set if a signal above -64 dBm was present on
+    /// the channel for at least 40 µs, only valid 170 µs after entering RX mode
+    pub fn received_power(&mut self) -> Result<bool, E> {
+        Ok(self.read_register(RPD)? & 1 != 0)
+    }
+
+    /// Starts transmitting an unmodulated carrier on `channel` (nRF24L01+ only),
+    /// meant for RF testing with a spectrum analyzer
+    pub fn start_carrier(&mut self, channel: u8, power: u8) -> Result<(), E> {
+        self.set_ce(false);
+        self.write_register(CONFIG, PWR_UP)?;
+        // Tpd2stby, 1.5 ms from power down to standby
+        cortex_m::asm::delay(150_000);
+        self.write_register(RF_SETUP, CONT_WAVE | PLL_LOCK | ((power & 0b11) << 1))?;
+        self.set_channel(channel)?;
+        self.set_ce(true);
+        Ok(())
+    }
+
+    /// Stops the carrier, the radio needs [`Nrf24::configure`] again afterwards
+    pub fn stop_carrier(&mut self) -> Result<(), E> {
+        self.set_ce(false);
+        self.write_register(RF_SETUP, 0)?;
+        self.write_register(CONFIG, 0)
+    }
+}
@@ -0,0 +1,70 @@
+//! Channel occupancy scanner based on the RPD (Received Power Detector)
+use super::raw::Nrf24;
+use embedded_hal::blocking::delay::DelayUs;
+use embedded_hal::blocking::spi::Transfer;
+use embedded_hal::digital::v2::OutputPin;
+
+/// RF_CH accepts 0-125 (2400-2525 MHz)
+pub const CHANNELS: usize = 126;
+
+/// Per-channel count of sweeps in which RPD reported a signal above -64 dBm
+pub struct Histogram {
+    pub hits: [u16; CHANNELS],
+    pub sweeps: u16,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            hits: [0; CHANNELS],
+            sweeps: 0,
+        }
+    }
+}
+
+impl Histogram {
+    /// Listens on every channel once, the radio must be configured and in standby
+    pub fn sweep<SPI, CE, CSN, E, D>(
+        &mut self,
+        radio: &mut Nrf24<SPI, CE, CSN>,
+        delay: &mut D,
+    ) -> Result<(), E>
+    where
+        SPI: Transfer<u8, Error = E>,
+        CE: OutputPin,
+        CSN: OutputPin,
+        D: DelayUs<u16>,
+    {
+        for (channel, hits) in self.hits.iter_mut().enumerate() {
+            radio.set_channel(channel as u8)?;
+            radio.listen()?;
+            // RPD is only valid 170 µs after entering RX mode, dwell a bit longer
+            // to catch more of the bursty traffic (WiFi, Bluetooth)
+            delay.delay_us(500);
+            if radio.received_power()? {
+                *hits = hits.saturating_add(1);
+            }
+            radio.standby()?;
+        }
+        self.sweeps = self.sweeps.saturating_add(1);
+        Ok(())
+    }
+
+    /// Channel with the least activity, hits two channels either side count
+    /// as well since a link occupies 1 MHz (2 MHz at 2 Mbps)
+    pub fn quietest(&self) -> u8 {
+        let score = |channel: usize| -> u32 {
+            let lower = channel.saturating_sub(2);
+            let upper = (channel + 2).min(CHANNELS - 1);
+            let neighbours: u32 = self.hits[lower..=upper].iter().map(|&h| u32::from(h)).sum();
+            u32::from(self.hits[channel]) * 8 + neighbours
+        };
+        (0..CHANNELS)
+            .min_by_key(|&channel| score(channel))
+            .unwrap_or(0) as u8
+    }
+
+    pub fn clear(&mut self) {
+        *self = Histogram::default();
+    }
+}
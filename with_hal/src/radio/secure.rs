@@ -0,0 +1,366 @@
+//! Encrypted and authenticated radio frames using ChaCha8Poly1305
+//!
+//! Every frame is sent as:
+//! | key id | sender id | counter (u32 LE) | ciphertext ... | tag (16 bytes) |
+//! The 6 byte header is authenticated as associated data and the nonce is
+//! built from it, so a nonce never repeats as long as each sender uses a
+//! unique sender id and never reuses a counter value with the same key.
+//! Receivers keep a sliding window per sender to reject replayed frames, and
+//! a floor per sender in a [`FloorStore`] so that resets and evicted windows
+//! do not let old frames back in.
+use super::fragment::FrameLink;
+use chacha20poly1305::aead::{AeadInPlace, NewAead};
+use chacha20poly1305::{ChaCha8Poly1305, Key, Nonce, Tag};
+
+pub const HEADER_LEN: usize = 6;
+pub const TAG_LEN: usize = 16;
+/// Bytes added to every frame
+pub const OVERHEAD: usize = HEADER_LEN + TAG_LEN;
+/// Keys that can be installed at the same time, enough to rotate without downtime
+pub const MAX_KEYS: usize = 4;
+/// Senders tracked for replay protection
+pub const MAX_PEERS: usize = 6;
+/// Frames a [`SecureLink::per_boot`] link can send before it needs a reset
+pub const FRAMES_PER_BOOT: u32 = 0xFFFF;
+/// How far a floor is raised past the accepted counter, so the store is
+/// written once every this many frames of a sender. A reset receiver drops up
+/// to this many frames of a sender that keeps counting.
+pub const FLOOR_STEP: u32 = 1024;
+
+#[derive(Debug, defmt::Format)]
+pub enum Error<E> {
+    Link(E),
+    /// Frame is larger than the MTU of the secure link
+    TooLarge,
+    /// No key is installed under the requested or selected id
+    NoKey,
+    /// Every counter value has been used with the current key, rotate it
+    CounterExhausted,
+}
+
+/// Frames dropped by the receiver
+#[derive(Clone, Copy, Default, defmt::Format)]
+pub struct SecureStats {
+    pub malformed: u32,
+    pub unknown_key: u32,
+    pub replayed: u32,
+    pub forged: u32,
+    /// Authentic, but the floor could not be raised to cover it
+    pub unrecorded: u32,
+}
+
+/// Persists the replay floor of every sender, see [`crate::radio::floors`]
+pub trait FloorStore {
+    /// Counter values of `sender` with `key_id` at or below the floor are
+    /// rejected, `None` for a sender that was never accepted
+    fn floor(&mut self, key_id: u8, sender: u8) -> Option<u32>;
+
+    /// Stores a higher floor, returns `false` if that failed
+    fn raise(&mut self, key_id: u8, sender: u8, floor: u32) -> bool;
+}
+
+/// [`FloorStore`] of a link that only sends, every received frame is dropped
+pub struct SendOnly;
+
+impl FloorStore for SendOnly {
+    fn floor(&mut self, _key_id: u8, _sender: u8) -> Option<u32> {
+        Some(u32::MAX)
+    }
+
+    fn raise(&mut self, _key_id: u8, _sender: u8, _floor: u32) -> bool {
+        false
+    }
+}
+
+/// Anti-replay window over the last 32 counter values (RFC 4303 style)
+#[derive(Clone, Copy)]
+struct Window {
+    highest: u32,
+    /// Bit n set means `highest - n` was received
+    seen: u32,
+}
+
+impl Window {
+    fn new(counter: u32) -> Self {
+        Window {
+            highest: counter,
+            seen: 1,
+        }
+    }
+
+    fn is_replay(&self, counter: u32) -> bool {
+        if counter > self.highest {
+            return false;
+        }
+        let age = self.highest - counter;
+        age >= 32 || self.seen & (1 << age) != 0
+    }
+
+    fn accept(&mut self, counter: u32) {
+        if counter > self.highest {
+            let shift = counter - self.highest;
+            self.seen = if shift >= 32 { 0 } else { self.seen << shift };
+            self.seen |= 1;
+            self.highest = counter;
+        } else {
+            self.seen |= 1 << (self.highest - counter);
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Peer {
+    key_id: u8,
+    sender: u8,
+    window: Window,
+    /// Last value given to [`FloorStore::raise`]
+    floor: Option<u32>,
+}
+
+pub struct SecureLink<L, F> {
+    link: L,
+    floors: F,
+    keys: [Option<(u8, ChaCha8Poly1305)>; MAX_KEYS],
+    tx_key: u8,
+    sender: u8,
+    counter: u32,
+    /// First counter value that may not be used
+    end: u32,
+    peers: [Option<Peer>; MAX_PEERS],
+    next_peer: usize,
+    stats: SecureStats,
+}
+
+impl<L: FrameLink, F: FloorStore> SecureLink<L, F> {
+    /// `sender` must be unique among every device sharing a key and `counter`
+    /// must be larger than any value this sender used before with the key,
+    /// either persist it across resets or rotate the key on every boot.
+    /// Floors are kept per key id, a key id reused for a new key must not
+    /// restart the counters of its senders.
+    pub fn new(link: L, floors: F, sender: u8, counter: u32) -> Self {
+        SecureLink {
+            link,
+            floors,
+            keys: Default::default(),
+            tx_key: 0,
+            sender,
+            counter,
+            end: u32::MAX,
+            peers: [None; MAX_PEERS],
+            next_peer: 0,
+            stats: SecureStats::default(),
+        }
+    }
+
+    /// Gives every boot its own counter range, starting at `boot << 16`, so a
+    /// boot counter kept in flash (see [`crate::nonce::NonceSequence`]) is all
+    /// that has to be persisted. Sending fails with
+    /// [`Error::CounterExhausted`] after [`FRAMES_PER_BOOT`] frames.
+    pub fn per_boot(link: L, floors: F, sender: u8, boot: u16) -> Self {
+        let counter = u32::from(boot) << 16;
+        let mut link = Self::new(link, floors, sender, counter);
+        link.end = counter + FRAMES_PER_BOOT;
+        link
+    }
+
+    /// Installs (or replaces) the key used for frames tagged with `id`
+    pub fn add_key(&mut self, id: u8, key: &Key) -> Result<(), Error<L::Error>> {
+        self.remove_key(id);
+        let slot = self
+            .keys
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(Error::NoKey)?;
+        *slot = Some((id, ChaCha8Poly1305::new(key)));
+        Ok(())
+    }
+
+    /// Forgets a key along with the replay state of every sender that used it
+    pub fn remove_key(&mut self, id: u8) {
+        for slot in self.keys.iter_mut() {
+            if matches!(slot, Some((key_id, _)) if *key_id == id) {
+                *slot = None;
+            }
+        }
+        for peer in self.peers.iter_mut() {
+            if matches!(peer, Some(p) if p.key_id == id) {
+                *peer = None;
+            }
+        }
+    }
+
+    /// Selects the key for outgoing frames, resets the counter to `counter`,
+    /// which must stay below the end of a [`SecureLink::per_boot`] range
+    pub fn use_key(&mut self, id: u8, counter: u32) -> Result<(), Error<L::Error>> {
+        self.cipher(id).ok_or(Error::NoKey)?;
+        if counter >= self.end {
+            return Err(Error::CounterExhausted);
+        }
+        self.tx_key = id;
+        self.counter = counter;
+        Ok(())
+    }
+
+    /// Next counter value, persist it to avoid reusing nonces after a reset
+    pub fn counter(&self) -> u32 {
+        self.counter
+    }
+
+    pub fn stats(&self) -> SecureStats {
+        self.stats
+    }
+
+    pub fn link(&mut self) -> &mut L {
+        &mut self.link
+    }
+
+    fn cipher(&self, id: u8) -> Option<&ChaCha8Poly1305> {
+        self.keys.iter().find_map(|slot| match slot {
+            Some((key_id, cipher)) if *key_id == id => Some(cipher),
+            _ => None,
+        })
+    }
+
+    fn peer(&mut self, key_id: u8, sender: u8) -> Option<&mut Peer> {
+        self.peers
+            .iter_mut()
+            .flatten()
+            .find(|p| p.key_id == key_id && p.sender == sender)
+    }
+}
+
+/// key id | sender id | 0 0 | counter (u32 LE) | 0 0 0 0
+fn nonce(header: &[u8]) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    nonce[0] = header[0];
+    nonce[1] = header[1];
+    nonce[4..8].copy_from_slice(&header[2..6]);
+    nonce
+}
+
+impl<L: FrameLink, F: FloorStore> FrameLink for SecureLink<L, F> {
+    type Error = Error<L::Error>;
+    const MTU: usize = L::MTU - OVERHEAD;
+
+    fn send_frame(&mut self, frame: &[u8]) -> Result<bool, Self::Error> {
+        if frame.len() > Self::MTU {
+            return Err(Error::TooLarge);
+        }
+        let counter = self.counter;
+        if counter >= self.end {
+            return Err(Error::CounterExhausted);
+        }
+        self.counter = counter + 1;
+
+        let mut buf = [0; super::link::MAX_PAYLOAD];
+        let (header, rest) = buf.split_at_mut(HEADER_LEN);
+        header[0] = self.tx_key;
+        header[1] = self.sender;
+        header[2..].copy_from_slice(&counter.to_le_bytes());
+        let (body, rest) = rest.split_at_mut(frame.len());
+        body.copy_from_slice(frame);
+
+        let nonce = nonce(header);
+        let tag = self
+            .cipher(self.tx_key)
+            .ok_or(Error::NoKey)?
+            .encrypt_in_place_detached(Nonce::from_slice(&nonce), header, body)
+            .map_err(|_| Error::TooLarge)?;
+        rest[..TAG_LEN].copy_from_slice(&tag);
+
+        self.link
+            .send_frame(&buf[..frame.len() + OVERHEAD])
+            .map_err(Error::Link)
+    }
+
+    /// Returns `None` for frames that were dropped, they are counted in [`SecureStats`]
+    fn recv_frame(&mut self, buf: &mut [u8]) -> Result<Option<usize>, Self::Error> {
+        let mut frame = [0; super::link::MAX_PAYLOAD];
+        let len = match self.link.recv_frame(&mut frame).map_err(Error::Link)? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        if len < OVERHEAD || len - OVERHEAD > buf.len() {
+            self.stats.malformed += 1;
+            return Ok(None);
+        }
+
+        let (header, rest) = frame[..len].split_at_mut(HEADER_LEN);
+        let (body, tag) = rest.split_at_mut(len - OVERHEAD);
+        let (key_id, sender) = (header[0], header[1]);
+        let mut counter = [0; 4];
+        counter.copy_from_slice(&header[2..]);
+        let counter = u32::from_le_bytes(counter);
+
+        if self.cipher(key_id).is_none() {
+            self.stats.unknown_key += 1;
+            return Ok(None);
+        }
+        if self
+            .peer(key_id, sender)
+            .map_or(false, |p| p.window.is_replay(counter))
+        {
+            self.stats.replayed += 1;
+            return Ok(None);
+        }
+
+        let nonce = nonce(header);
+        let cipher = self.cipher(key_id).ok_or(Error::NoKey)?;
+        if cipher
+            .decrypt_in_place_detached(
+                Nonce::from_slice(&nonce),
+                header,
+                body,
+                Tag::from_slice(tag),
+            )
+            .is_err()
+        {
+            self.stats.forged += 1;
+            return Ok(None);
+        }
+
+        // Only authentic frames may move the window or claim a peer slot. A
+        // new window starts above the stored floor, which covers everything
+        // accepted before a reset or an eviction.
+        let floor = match self.peer(key_id, sender) {
+            Some(peer) => peer.floor,
+            None => {
+                let floor = self.floors.floor(key_id, sender);
+                if floor.map_or(false, |floor| counter <= floor) {
+                    self.stats.replayed += 1;
+                    return Ok(None);
+                }
+                floor
+            }
+        };
+        // The floor has to be stored ahead of the frame being handed out
+        let floor = if floor.map_or(true, |floor| counter > floor) {
+            let raised = counter.saturating_add(FLOOR_STEP);
+            if !self.floors.raise(key_id, sender, raised) {
+                self.stats.unrecorded += 1;
+                return Ok(None);
+            }
+            Some(raised)
+        } else {
+            floor
+        };
+        match self.peer(key_id, sender) {
+            Some(peer) => {
+                peer.window.accept(counter);
+                peer.floor = floor;
+            }
+            None => {
+                self.peers[self.next_peer] = Some(Peer {
+                    key_id,
+                    sender,
+                    window: Window::new(counter),
+                    floor,
+                });
+                self.next_peer = (self.next_peer + 1) % MAX_PEERS;
+            }
+        }
+
+        buf[..body.len()].copy_from_slice(body);
+        Ok(Some(body.len()))
+    }
+}
@@ -0,0 +1,306 @@
+//! Star network of one hub and up to five sensor nodes
+//!
+//! The hub listens on all six RX pipes. Pipe 0 is the join pipe that every
+//! node starts on, pipes 1-5 are the node slots handed out by the hub.
+//! Addresses share the last four bytes of `LinkConfig::address`, the first
+//! byte selects the pipe. Address registers are written LSByte first, and
+//! pipes 2-5 only have an LSByte of their own, the upper four bytes come
+//! from pipe 1.
+//!
+//! Downlink data travels in ACK payloads: the hub queues it for a pipe and the
+//! radio attaches it to the ACK of that node's next uplink packet. Nodes that
+//! expect downlink data therefore have to keep sending, an empty packet works.
+//! The TX FIFO holds three ACK payloads, the hub keeps one of them free for
+//! join assignments and queues at most one downlink per node.
+//!
+//! Joining:
+//! 1. The node sends `[JOIN, uid]` on the join pipe
+//! 2. The hub picks a slot for `uid` and queues `[ASSIGN, uid, slot]` as the
+//!    ACK payload on pipe 0
+//! 3. The node repeats the request, the ACK of a later attempt carries the
+//!    assignment, after which it switches to the slot's address. The hub
+//!    does not answer the request that picked up the assignment again.
+use super::link::{LinkConfig, MAX_PAYLOAD};
+use super::raw::{self, Nrf24, TxError, TxResult};
+use embedded_hal::blocking::spi::Transfer;
+use embedded_hal::digital::v2::OutputPin;
+
+/// Data pipes available to nodes, pipe 0 is reserved for joining
+pub const MAX_NODES: usize = 5;
+/// ACK payloads queued for nodes at the same time, the TX FIFO holds one more
+pub const MAX_DOWNLINKS: usize = 2;
+/// First (least significant) address byte per pipe
+const PIPE_LSB: [u8; 6] = [0x00, 0xC1, 0xC2, 0xC3, 0xC4, 0xC5];
+const JOIN: u8 = 0x4A;
+const ASSIGN: u8 = 0x41;
+
+#[derive(Debug, defmt::Format)]
+pub enum Error<E> {
+    Radio(E),
+    /// All node slots are taken
+    NetworkFull,
+    /// The hub did not answer the join request
+    JoinFailed,
+    /// Node has not joined a network yet
+    NotJoined,
+    /// Slot is not in 1-5
+    InvalidSlot,
+    /// No room for another ACK payload, or one is already queued for the node
+    FifoFull,
+    /// The radio never finished a transmission
+    Timeout,
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(e: E) -> Self {
+        Error::Radio(e)
+    }
+}
+
+/// Address of `pipe` for the network described by `config`
+pub fn pipe_address(config: &LinkConfig, pipe: u8) -> [u8; 5] {
+    let mut address = [0; 5];
+    address[0] = PIPE_LSB[usize::from(pipe)];
+    address[1..].copy_from_slice(&config.address[1..5]);
+    address
+}
+
+/// Something the hub received
+pub enum Event {
+    /// A node (re)joined and was given `slot`
+    Joined { slot: u8, uid: u32 },
+    /// Uplink data from the node in `slot`, stored at the start of the buffer
+    Data { slot: u8, len: usize },
+}
+
+pub struct Hub<SPI, CE, CSN> {
+    radio: Nrf24<SPI, CE, CSN>,
+    /// Unique id of the node owning each slot (pipes 1-5)
+    nodes: [Option<u32>; MAX_NODES],
+    /// Pipes with an ACK payload in the TX FIFO, as far as the hub can tell.
+    /// The next packet on the pipe takes it along.
+    queued: [bool; 6],
+    /// Node the queued pipe 0 payload is assigning a slot to
+    assigning: Option<u32>,
+}
+
+impl<SPI, CE, CSN, E> Hub<SPI, CE, CSN>
+where
+    SPI: Transfer<u8, Error = E>,
+    CE: OutputPin,
+    CSN: OutputPin,
+{
+    /// Configures all six pipes and starts listening
+    pub fn new(mut radio: Nrf24<SPI, CE, CSN>, config: &LinkConfig) -> Result<Self, E> {
+        radio.configure(config)?;
+        // Pipes 0 and 1 take full addresses, 2-5 only their LSByte
+        radio.write_address(raw::RX_ADDR_P0, &pipe_address(config, 0))?;
+        radio.write_address(raw::RX_ADDR_P0 + 1, &pipe_address(config, 1))?;
+        for pipe in 2..6 {
+            radio.write_register(raw::RX_ADDR_P0 + pipe, PIPE_LSB[usize::from(pipe)])?;
+        }
+        radio.write_register(raw::EN_RXADDR, 0x3F)?;
+        radio.listen()?;
+        Ok(Hub {
+            radio,
+            nodes: [None; MAX_NODES],
+            queued: [false; 6],
+            assigning: None,
+        })
+    }
+
+    /// Unique id of the node in `slot`
+    pub fn node(&self, slot: u8) -> Option<u32> {
+        slot.checked_sub(1)
+            .and_then(|i| self.nodes.get(usize::from(i)).copied().flatten())
+    }
+
+    /// Drops a node, its slot can be handed out again
+    pub fn remove(&mut self, slot: u8) {
+        if let Some(node) = slot
+            .checked_sub(1)
+            .and_then(|i| self.nodes.get_mut(usize::from(i)))
+        {
+            *node = None;
+        }
+    }
+
+    /// Handles the next packet in the RX FIFO, uplink data is copied to `buf`
+    pub fn poll(&mut self, buf: &mut [u8; MAX_PAYLOAD]) -> Result<Option<Event>, Error<E>> {
+        let pipe = match self.radio.rx_pipe()? {
+            Some(pipe) => pipe,
+            None => {
+                self.radio.clear_interrupts()?;
+                return Ok(None);
+            }
+        };
+        let len = self.radio.read_payload(buf)?;
+        // Whatever was queued for the pipe went out with the ACK
+        self.queued[usize::from(pipe)] = false;
+        if pipe != 0 {
+            return Ok(Some(Event::Data { slot: pipe, len }));
+        }
+
+        let assigned = self.assigning.take();
+        if len != 5 || buf[0] != JOIN {
+            return Ok(None);
+        }
+        let mut uid = [0; 4];
+        uid.copy_from_slice(&buf[1..5]);
+        let uid = u32::from_le_bytes(uid);
+        if assigned == Some(uid) {
+            // The repeated request that just picked up its assignment, another
+            // one would linger in the FIFO for the next node that joins
+            return Ok(None);
+        }
+        // A node that rejoins (e.g. after a reset) gets its old slot back
+        let index = match self.nodes.iter().position(|n| *n == Some(uid)) {
+            Some(index) => index,
+            None => self
+                .nodes
+                .iter()
+                .position(Option::is_none)
+                .ok_or(Error::NetworkFull)?,
+        };
+        self.nodes[index] = Some(uid);
+        let slot = index as u8 + 1;
+
+        let mut assign = [0; 6];
+        assign[0] = ASSIGN;
+        assign[1..5].copy_from_slice(&uid.to_le_bytes());
+        assign[5] = slot;
+        self.queue(0, &assign)?;
+        self.assigning = Some(uid);
+        Ok(Some(Event::Joined { slot, uid }))
+    }
+
+    /// Queues `data` for the node in `slot`, it is delivered with the ACK of
+    /// the node's next uplink packet. At most one downlink per node and
+    /// [`MAX_DOWNLINKS`] in total can wait for delivery.
+    pub fn send_to(&mut self, slot: u8, data: &[u8]) -> Result<(), Error<E>> {
+        if slot == 0 || usize::from(slot) > MAX_NODES {
+            return Err(Error::InvalidSlot);
+        }
+        let downlinks = self.queued[1..].iter().filter(|queued| **queued).count();
+        if self.queued[usize::from(slot)] || downlinks >= MAX_DOWNLINKS {
+            return Err(Error::FifoFull);
+        }
+        self.queue(slot, data)
+    }
+
+    fn queue(&mut self, pipe: u8, data: &[u8]) -> Result<(), Error<E>> {
+        if !self.radio.write_ack_payload(pipe, data)? {
+            return Err(Error::FifoFull);
+        }
+        self.queued[usize::from(pipe)] = true;
+        Ok(())
+    }
+}
+
+pub struct Node<'a, SPI, CE, CSN> {
+    radio: Nrf24<SPI, CE, CSN>,
+    config: LinkConfig<'a>,
+    uid: u32,
+    slot: Option<u8>,
+}
+
+impl<'a, SPI, CE, CSN, E> Node<'a, SPI, CE, CSN>
+where
+    SPI: Transfer<u8, Error = E>,
+    CE: OutputPin,
+    CSN: OutputPin,
+{
+    /// `uid` must be unique in the network, the hub uses it to tell nodes apart.
+    /// [`crate::uid::short_id`] gives every board its own.
+    pub fn new(
+        mut radio: Nrf24<SPI, CE, CSN>,
+        config: LinkConfig<'a>,
+        uid: u32,
+    ) -> Result<Self, E> {
+        radio.configure(&config)?;
+        // Only pipe 0 is needed, to receive ACKs for whatever we transmit
+        radio.write_register(raw::EN_RXADDR, 0x01)?;
+        Ok(Node {
+            radio,
+            config,
+            uid,
+            slot: None,
+        })
+    }
+
+    /// Skips the join procedure for a node with a fixed slot
+    pub fn with_slot(&mut self, slot: u8) -> Result<(), Error<E>> {
+        if slot == 0 || usize::from(slot) > MAX_NODES {
+            return Err(Error::InvalidSlot);
+        }
+        self.set_address(slot)?;
+        self.slot = Some(slot);
+        Ok(())
+    }
+
+    pub fn slot(&self) -> Option<u8> {
+        self.slot
+    }
+
+    /// Asks the hub for a slot, sending up to `attempts` join requests
+    pub fn join(&mut self, attempts: u32) -> Result<u8, Error<E>> {
+        self.set_address(0)?;
+        let mut request = [0; 5];
+        request[0] = JOIN;
+        request[1..].copy_from_slice(&self.uid.to_le_bytes());
+        let mut ack = [0; MAX_PAYLOAD];
+
+        for _ in 0..attempts {
+            if let TxResult::Delivered { ack_len: Some(_) } = self.transmit(&request)? {
+                let len = self.radio.read_payload(&mut ack)?;
+                let slot = ack[5];
+                if len == 6
+                    && ack[0] == ASSIGN
+                    && ack[1..5] == self.uid.to_le_bytes()
+                    && slot >= 1
+                    && usize::from(slot) <= MAX_NODES
+                {
+                    self.set_address(slot)?;
+                    self.slot = Some(slot);
+                    return Ok(slot);
+                }
+            }
+            // Leave the hub some time to queue our assignment
+            cortex_m::asm::delay(500_000);
+        }
+        Err(Error::JoinFailed)
+    }
+
+    /// Sends `data` to the hub, returns the length of any downlink data that
+    /// came back in the ACK (copied to `downlink`) or `None` if it was lost
+    pub fn send(
+        &mut self,
+        data: &[u8],
+        downlink: &mut [u8; MAX_PAYLOAD],
+    ) -> Result<Option<usize>, Error<E>> {
+        if self.slot.is_none() {
+            return Err(Error::NotJoined);
+        }
+        match self.transmit(data)? {
+            TxResult::Delivered { ack_len: Some(_) } => {
+                Ok(Some(self.radio.read_payload(downlink)?))
+            }
+            TxResult::Delivered { ack_len: None } => Ok(Some(0)),
+            TxResult::Lost => Ok(None),
+        }
+    }
+
+    fn transmit(&mut self, data: &[u8]) -> Result<TxResult, Error<E>> {
+        self.radio.transmit(data).map_err(|e| match e {
+            TxError::Spi(e) => Error::Radio(e),
+            TxError::Timeout => Error::Timeout,
+        })
+    }
+
+    /// TX address and pipe 0 (for the auto-ACK) both point at the hub's `pipe`
+    fn set_address(&mut self, pipe: u8) -> Result<(), E> {
+        let address = pipe_address(&self.config, pipe);
+        self.radio.write_address(raw::TX_ADDR, &address)?;
+        self.radio.write_address(raw::RX_ADDR_P0, &address)
+    }
+}
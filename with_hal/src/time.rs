@@ -0,0 +1,39 @@
+//! Millisecond time source for protocol timeouts
+use cortex_m::peripheral::{DCB, DWT};
+use stm32f4xx_hal::time::Hertz;
+
+pub use radio_transport::Clock;
+
+/// Clock driven by the DWT cycle counter
+/// The cycle counter wraps every 2^32 cycles (~89 s at 48 MHz) so `now_ms`
+/// must be called at least that often to keep track of time
+pub struct DwtClock {
+    cycles_per_ms: u32,
+    last: u32,
+    remainder: u32,
+    ms: u32,
+}
+
+impl DwtClock {
+    pub fn new(dcb: &mut DCB, dwt: &mut DWT, sysclk: Hertz) -> Self {
+        dcb.enable_trace();
+        dwt.enable_cycle_counter();
+        DwtClock {
+            cycles_per_ms: sysclk.0 / 1000,
+            last: DWT::cycle_count(),
+            remainder: 0,
+            ms: 0,
+        }
+    }
+}
+
+impl Clock for DwtClock {
+    fn now_ms(&mut self) -> u32 {
+        let now = DWT::cycle_count();
+        let elapsed = now.wrapping_sub(self.last).saturating_add(self.remainder);
+        self.last = now;
+        self.ms = self.ms.wrapping_add(elapsed / self.cycles_per_ms);
+        self.remainder = elapsed % self.cycles_per_ms;
+        self.ms
+    }
+}
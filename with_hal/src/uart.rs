@@ -0,0 +1,202 @@
+//! Interrupt-driven USART whose line coding can change at runtime
+//!
+//! Received bytes are queued by the USART interrupt, whose handler must call
+//! [`on_interrupt`]. Transmission waits for TXE byte by byte. Only one [`Uart`]
+//! can be in use at a time.
+use crate::usb::{LineCoding, Parity, StopBits};
+use core::cell::RefCell;
+use cortex_m::interrupt::{self, Mutex};
+use heapless::consts::U256;
+use heapless::spsc::Queue;
+use stm32f4xx_hal::rcc::Clocks;
+use stm32f4xx_hal::stm32::{self, usart1::RegisterBlock, Interrupt};
+
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum Error {
+    /// Baud rate out of reach of the peripheral clock
+    Baud,
+    /// Data bits, parity or stop bits the USART can not produce
+    Format,
+}
+
+struct Rx {
+    regs: &'static RegisterBlock,
+    queue: Queue<u8, U256>,
+    /// Data bits of a received word, the rest is parity
+    mask: u8,
+    /// Bytes lost to overruns, a full queue or framing and parity errors
+    lost: u32,
+}
+
+static RX: Mutex<RefCell<Option<Rx>>> = Mutex::new(RefCell::new(None));
+
+/// Must be called from the interrupt handler of the USART in use
+pub fn on_interrupt() {
+    interrupt::free(|cs| {
+        if let Some(rx) = RX.borrow(cs).borrow_mut().as_mut() {
+            let sr = rx.regs.sr.read();
+            if sr.rxne().bit_is_clear() && sr.ore().bit_is_clear() {
+                return;
+            }
+            // Reading DR after SR also clears the error flags
+            let byte = rx.regs.dr.read().dr().bits() as u8 & rx.mask;
+            if sr.ore().bit_is_set() {
+                rx.lost += 1;
+            }
+            if sr.fe().bit_is_set() || sr.pe().bit_is_set() || rx.queue.enqueue(byte).is_err() {
+                rx.lost += 1;
+            }
+        }
+    });
+}
+
+pub struct Uart {
+    regs: &'static RegisterBlock,
+    /// Clock of the APB bus the USART sits on
+    pclk: u32,
+}
+
+impl Uart {
+    /// USART1, its pins (e.g. PB6 TX and PB7 RX) must be in alternate
+    /// function 7 already
+    pub fn usart1(_usart: stm32::USART1, clocks: &Clocks) -> Self {
+        let rcc = unsafe { &*stm32::RCC::ptr() };
+        rcc.apb2enr.modify(|_, w| w.usart1en().enabled());
+        Uart::new(
+            unsafe { &*stm32::USART1::ptr() },
+            clocks.pclk2().0,
+            Interrupt::USART1,
+        )
+    }
+
+    /// USART2, its pins (e.g. PA2 TX and PA3 RX) must be in alternate
+    /// function 7 already
+    pub fn usart2(_usart: stm32::USART2, clocks: &Clocks) -> Self {
+        let rcc = unsafe { &*stm32::RCC::ptr() };
+        rcc.apb1enr.modify(|_, w| w.usart2en().enabled());
+        Uart::new(
+            unsafe { &*stm32::USART2::ptr() },
+            clocks.pclk1().0,
+            Interrupt::USART2,
+        )
+    }
+
+    /// Starts out as 8N1 at 9600 baud, the CDC-ACM default
+    fn new(regs: &'static RegisterBlock, pclk: u32, irq: Interrupt) -> Self {
+        interrupt::free(|cs| {
+            RX.borrow(cs).replace(Some(Rx {
+                regs,
+                queue: Queue::new(),
+                mask: 0xFF,
+                lost: 0,
+            }))
+        });
+        let mut uart = Uart { regs, pclk };
+        uart.configure(&LineCoding {
+            baud: 9600,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+        })
+        .unwrap();
+        unsafe { cortex_m::peripheral::NVIC::unmask(irq) };
+        uart
+    }
+
+    /// Switches to `coding`, waiting for the last byte to leave first. The
+    /// old settings stay if `coding` is not supported.
+    pub fn configure(&mut self, coding: &LineCoding) -> Result<(), Error> {
+        // Oversampling by 16, BRR is the clock divider with 4 fraction bits
+        let brr = self
+            .pclk
+            .checked_add(coding.baud / 2)
+            .and_then(|pclk| pclk.checked_div(coding.baud))
+            .ok_or(Error::Baud)?;
+        if !(16..=0xFFFF).contains(&brr) {
+            return Err(Error::Baud);
+        }
+        let parity = coding.parity != Parity::None;
+        // The word length M includes the parity bit
+        let nine_bits = match (coding.data_bits, parity) {
+            (8, false) | (7, true) => false,
+            (8, true) => true,
+            _ => return Err(Error::Format),
+        };
+        let odd = match coding.parity {
+            Parity::None | Parity::Even => false,
+            Parity::Odd => true,
+            Parity::Mark | Parity::Space => return Err(Error::Format),
+        };
+
+        while self.regs.sr.read().tc().bit_is_clear() {}
+        self.regs.cr1.modify(|_, w| w.ue().disabled());
+        self.regs.brr.write(|w| {
+            w.div_mantissa()
+                .bits((brr >> 4) as u16)
+                .div_fraction()
+                .bits((brr & 0xF) as u8)
+        });
+        self.regs.cr2.modify(|_, w| match coding.stop_bits {
+            StopBits::One => w.stop().stop1(),
+            StopBits::OnePointFive => w.stop().stop1p5(),
+            StopBits::Two => w.stop().stop2(),
+        });
+        interrupt::free(|cs| {
+            if let Some(rx) = RX.borrow(cs).borrow_mut().as_mut() {
+                rx.mask = ((1u16 << coding.data_bits) - 1) as u8;
+            }
+        });
+        self.regs.cr1.write(|w| {
+            w.over8()
+                .oversample16()
+                .m()
+                .bit(nine_bits)
+                .pce()
+                .bit(parity)
+                .ps()
+                .bit(odd)
+                .rxneie()
+                .enabled()
+                .te()
+                .enabled()
+                .re()
+                .enabled()
+                .ue()
+                .enabled()
+        });
+        Ok(())
+    }
+
+    /// Sends `byte`, waiting until the transmit register is free
+    pub fn write(&mut self, byte: u8) {
+        while self.regs.sr.read().txe().bit_is_clear() {}
+        self.regs.dr.write(|w| w.dr().bits(u16::from(byte)));
+    }
+
+    /// Copies received bytes into `buf` without blocking, returns the count
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        interrupt::free(|cs| {
+            let mut rx = RX.borrow(cs).borrow_mut();
+            let queue = &mut rx.as_mut().unwrap().queue;
+            let mut count = 0;
+            for slot in buf.iter_mut() {
+                match queue.dequeue() {
+                    Some(byte) => *slot = byte,
+                    None => break,
+                }
+                count += 1;
+            }
+            count
+        })
+    }
+
+    /// Number of received bytes waiting to be read
+    pub fn available(&self) -> usize {
+        interrupt::free(|cs| RX.borrow(cs).borrow().as_ref().unwrap().queue.len())
+    }
+
+    /// Received bytes dropped so far
+    pub fn lost(&self) -> u32 {
+        interrupt::free(|cs| RX.borrow(cs).borrow().as_ref().unwrap().lost)
+    }
+}
@@ -0,0 +1,90 @@
+//! 96-bit unique device ID, see Section 24.2 of RM0368
+//!
+//! Gives every board a stable identity: the USB serial number, IDs and
+//! addresses for the radio and a name for the logs.
+use core::ptr;
+
+/// Address of the first of the three UID words
+const UID_BASE: usize = 0x1FFF_7A10;
+pub const UID_LEN: usize = 12;
+
+/// Reads the UID, little endian word by word as stored
+pub fn read() -> [u8; UID_LEN] {
+    let mut uid = [0; UID_LEN];
+    for (i, chunk) in uid.chunks_exact_mut(4).enumerate() {
+        let word = unsafe { ptr::read_volatile((UID_BASE + i * 4) as *const u32) };
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    uid
+}
+
+/// Characters of [`Hex`]
+pub const HEX_LEN: usize = UID_LEN * 2;
+
+/// The UID as upper case hex digits, in the byte order of [`read`]
+#[derive(Clone, Copy, PartialEq)]
+pub struct Hex([u8; HEX_LEN]);
+
+impl Hex {
+    pub fn as_str(&self) -> &str {
+        // Only ever holds ASCII hex digits
+        core::str::from_utf8(&self.0).unwrap()
+    }
+}
+
+impl core::fmt::Display for Hex {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+pub fn hex() -> Hex {
+    const DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+    let mut hex = [0; HEX_LEN];
+    for (pair, byte) in hex.chunks_exact_mut(2).zip(read().iter()) {
+        pair[0] = DIGITS[usize::from(byte >> 4)];
+        pair[1] = DIGITS[usize::from(byte & 0xF)];
+    }
+    Hex(hex)
+}
+
+/// [`hex`] with a static lifetime, as needed for USB string descriptors
+pub fn serial_number() -> &'static str {
+    static mut SERIAL: Option<Hex> = None;
+    // Written once and never again, so handing out references is fine
+    cortex_m::interrupt::free(|_| unsafe {
+        if SERIAL.is_none() {
+            SERIAL = Some(hex());
+        }
+        SERIAL.as_ref().unwrap().as_str()
+    })
+}
+
+/// 32-bit FNV-1a hash of the UID, for protocols with shorter IDs such as the
+/// node IDs of `radio::star`. Collisions are unlikely but possible.
+pub fn short_id() -> u32 {
+    fnv1a(0x811C_9DC5)
+}
+
+/// FNV-1a over the UID starting from `basis`
+fn fnv1a(basis: u32) -> u32 {
+    read().iter().fold(basis, |hash, byte| {
+        (hash ^ u32::from(*byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// 5 byte nRF24L01 address unique to this board. Bytes that look like the
+/// preamble or a constant level (0x55, 0xAA, 0x00 and 0xFF) are avoided, the
+/// radio picks up false packets from noise with those.
+pub fn radio_address() -> [u8; 5] {
+    let mut address = [0; 5];
+    address[..4].copy_from_slice(&short_id().to_le_bytes());
+    // Another basis for the fifth byte
+    address[4] = fnv1a(0x1234_5678) as u8;
+    for byte in address.iter_mut() {
+        if matches!(*byte, 0x00 | 0xFF | 0x55 | 0xAA) {
+            *byte ^= 0x3C;
+        }
+    }
+    address
+}
@@ -0,0 +1,170 @@
+//! DFU runtime interface, lets `dfu-util` switch the board to the bootloader
+//!
+//! After a DFU_DETACH request the device resets itself and, before anything
+//! else is initialized, jumps to the ST system bootloader, which enumerates as
+//! a DFU device (0483:df11) able to write the flash. Firmware using this
+//! interface must call [`enter_bootloader_if_requested`] first thing in `main`.
+use core::mem::MaybeUninit;
+use core::ptr;
+use stm32f4xx_hal::stm32;
+use usb_device::class_prelude::*;
+use usb_device::control::{Recipient, RequestType};
+use usb_device::Result;
+
+const USB_CLASS_APPLICATION_SPECIFIC: u8 = 0xFE;
+const DFU_SUBCLASS: u8 = 0x01;
+const DFU_PROTOCOL_RUNTIME: u8 = 0x01;
+const DESCRIPTOR_DFU_FUNCTIONAL: u8 = 0x21;
+
+const DFU_DETACH: u8 = 0;
+const DFU_GETSTATUS: u8 = 3;
+const DFU_GETSTATE: u8 = 5;
+
+/// The device detaches on its own, the system bootloader can download and
+/// upload
+const ATTRIBUTES: u8 = 0x08 | 0x02 | 0x01;
+/// Milliseconds the host waits for the detach
+const DETACH_TIMEOUT: u16 = 255;
+/// Block size of the system bootloader
+const TRANSFER_SIZE: u16 = 2048;
+
+const STATE_APP_IDLE: u8 = 0;
+const STATE_APP_DETACH: u8 = 1;
+
+/// Start of the system memory, holding the vector table of the bootloader
+const SYSTEM_MEMORY: usize = 0x1FFF_0000;
+/// "DFU!", survives a system reset in RAM that is not initialized at startup
+const MAGIC: u32 = 0x4446_5521;
+
+#[link_section = ".uninit.DFU_REQUEST"]
+static mut REQUEST: MaybeUninit<u32> = MaybeUninit::uninit();
+
+#[derive(Clone, Copy, PartialEq)]
+enum Detach {
+    Idle,
+    /// DFU_DETACH was accepted, its status stage is still in flight
+    Requested,
+    /// Status stage done with the next poll
+    Pending,
+}
+
+pub struct DfuRuntime {
+    interface: InterfaceNumber,
+    detach: Detach,
+}
+
+impl DfuRuntime {
+    pub fn new<B: UsbBus>(alloc: &UsbBusAllocator<B>) -> Self {
+        DfuRuntime {
+            interface: alloc.interface(),
+            detach: Detach::Idle,
+        }
+    }
+
+    fn state(&self) -> u8 {
+        match self.detach {
+            Detach::Idle => STATE_APP_IDLE,
+            _ => STATE_APP_DETACH,
+        }
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for DfuRuntime {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(
+            self.interface,
+            USB_CLASS_APPLICATION_SPECIFIC,
+            DFU_SUBCLASS,
+            DFU_PROTOCOL_RUNTIME,
+        )?;
+        let timeout = DETACH_TIMEOUT.to_le_bytes();
+        let transfer = TRANSFER_SIZE.to_le_bytes();
+        writer.write(
+            DESCRIPTOR_DFU_FUNCTIONAL,
+            // DFU 1.1a
+            &[
+                ATTRIBUTES,
+                timeout[0],
+                timeout[1],
+                transfer[0],
+                transfer[1],
+                0x1A,
+                0x01,
+            ],
+        )
+    }
+
+    fn poll(&mut self) {
+        match self.detach {
+            Detach::Idle => {}
+            Detach::Requested => self.detach = Detach::Pending,
+            Detach::Pending => reboot_into_bootloader(),
+        }
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+        if req.request_type != RequestType::Class
+            || req.recipient != Recipient::Interface
+            || req.index != u8::from(self.interface) as u16
+        {
+            return;
+        }
+        match req.request {
+            // OK, no poll timeout, no status string
+            DFU_GETSTATUS => xfer.accept_with(&[0, 0, 0, 0, self.state(), 0]).ok(),
+            DFU_GETSTATE => xfer.accept_with(&[self.state()]).ok(),
+            _ => xfer.reject().ok(),
+        };
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+        if req.request_type != RequestType::Class
+            || req.recipient != Recipient::Interface
+            || req.index != u8::from(self.interface) as u16
+        {
+            return;
+        }
+        match req.request {
+            DFU_DETACH => {
+                // Resetting right away would cut off the status stage
+                self.detach = Detach::Requested;
+                xfer.accept().ok();
+            }
+            _ => {
+                xfer.reject().ok();
+            }
+        };
+    }
+}
+
+/// Resets the device into the system bootloader
+pub fn reboot_into_bootloader() -> ! {
+    unsafe { ptr::write_volatile(REQUEST.as_mut_ptr(), MAGIC) };
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+/// Jumps to the system bootloader if the last reset came from
+/// [`reboot_into_bootloader`], must run while the peripherals are still in
+/// their reset state
+pub fn enter_bootloader_if_requested() {
+    unsafe {
+        if ptr::read_volatile(REQUEST.as_ptr()) != MAGIC {
+            return;
+        }
+        ptr::write_volatile(REQUEST.as_mut_ptr(), 0);
+
+        // Map the system memory at 0, the bootloader expects to run from there
+        let rcc = &*stm32::RCC::ptr();
+        rcc.apb2enr.modify(|_, w| w.syscfgen().set_bit());
+        let syscfg = &*stm32::SYSCFG::ptr();
+        syscfg.memrm.modify(|_, w| w.mem_mode().bits(0b01));
+
+        let vectors = SYSTEM_MEMORY as *const u32;
+        let stack = ptr::read_volatile(vectors);
+        let reset: extern "C" fn() -> ! = core::mem::transmute(ptr::read_volatile(vectors.add(1)));
+        cortex_m::register::msp::write(stack);
+        reset()
+    }
+}
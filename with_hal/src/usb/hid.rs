@@ -0,0 +1,168 @@
+//! Minimal HID interface reporting buttons and a status byte
+//!
+//! The single input report is two bytes: eight buttons as a bitmap followed by
+//! an application defined status byte, both under a vendor usage page so no
+//! host driver turns them into key presses. Any hidraw or HID API client can
+//! read them.
+use usb_device::class_prelude::*;
+use usb_device::control::{self, Recipient, RequestType};
+use usb_device::Result;
+
+const USB_CLASS_HID: u8 = 0x03;
+const DESCRIPTOR_HID: u8 = 0x21;
+const DESCRIPTOR_REPORT: u8 = 0x22;
+
+const GET_REPORT: u8 = 0x01;
+const GET_IDLE: u8 = 0x02;
+const SET_IDLE: u8 = 0x0A;
+
+/// Polling interval of the interrupt endpoint in milliseconds
+const INTERVAL: u8 = 10;
+
+#[rustfmt::skip]
+const REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x00, 0xFF, // Usage Page (Vendor 0xFF00)
+    0x09, 0x01,       // Usage (1)
+    0xA1, 0x01,       // Collection (Application)
+    0x05, 0x09,       //   Usage Page (Button)
+    0x19, 0x01,       //   Usage Minimum (1)
+    0x29, 0x08,       //   Usage Maximum (8)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x25, 0x01,       //   Logical Maximum (1)
+    0x75, 0x01,       //   Report Size (1)
+    0x95, 0x08,       //   Report Count (8)
+    0x81, 0x02,       //   Input (Data, Variable, Absolute)
+    0x06, 0x00, 0xFF, //   Usage Page (Vendor 0xFF00)
+    0x09, 0x02,       //   Usage (2)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x75, 0x08,       //   Report Size (8)
+    0x95, 0x01,       //   Report Count (1)
+    0x81, 0x02,       //   Input (Data, Variable, Absolute)
+    0xC0,             // End Collection
+];
+
+#[derive(Clone, Copy, Default, PartialEq, defmt::Format)]
+pub struct Report {
+    /// Bit n set while button n + 1 is pressed
+    pub buttons: u8,
+    pub status: u8,
+}
+
+impl Report {
+    fn to_bytes(self) -> [u8; 2] {
+        [self.buttons, self.status]
+    }
+}
+
+pub struct Hid<'a, B: UsbBus> {
+    interface: InterfaceNumber,
+    endpoint: EndpointIn<'a, B>,
+    report: Report,
+    /// `report` changed and has not been handed to the endpoint yet
+    pending: bool,
+    idle: u8,
+}
+
+impl<'a, B: UsbBus> Hid<'a, B> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        Hid {
+            interface: alloc.interface(),
+            endpoint: alloc.interrupt(8, INTERVAL),
+            report: Report::default(),
+            pending: false,
+            idle: 0,
+        }
+    }
+
+    /// Queues `report` if it differs from the last one, it goes out with the
+    /// next poll of the host
+    pub fn set(&mut self, report: Report) {
+        if report != self.report {
+            self.report = report;
+            self.pending = true;
+            self.send();
+        }
+    }
+
+    pub fn report(&self) -> Report {
+        self.report
+    }
+
+    fn send(&mut self) {
+        // Busy until the host fetched the previous report, retried on poll
+        if self.endpoint.write(&self.report.to_bytes()).is_ok() {
+            self.pending = false;
+        }
+    }
+
+    fn hid_descriptor() -> [u8; 7] {
+        let len = (REPORT_DESCRIPTOR.len() as u16).to_le_bytes();
+        // HID 1.11, no country code, one report descriptor
+        [0x11, 0x01, 0x00, 0x01, DESCRIPTOR_REPORT, len[0], len[1]]
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for Hid<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(self.interface, USB_CLASS_HID, 0, 0)?;
+        writer.write(DESCRIPTOR_HID, &Self::hid_descriptor())?;
+        writer.endpoint(&self.endpoint)
+    }
+
+    fn reset(&mut self) {
+        self.pending = true;
+        self.idle = 0;
+    }
+
+    fn poll(&mut self) {
+        if self.pending {
+            self.send();
+        }
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+        if req.recipient != Recipient::Interface || req.index != u8::from(self.interface) as u16 {
+            return;
+        }
+        let descriptor = (req.value >> 8) as u8;
+        match req.request_type {
+            RequestType::Standard if req.request == control::Request::GET_DESCRIPTOR => {
+                match descriptor {
+                    DESCRIPTOR_REPORT => xfer.accept_with_static(REPORT_DESCRIPTOR).ok(),
+                    DESCRIPTOR_HID => xfer.accept_with(&Self::hid_descriptor()).ok(),
+                    _ => xfer.reject().ok(),
+                };
+            }
+            RequestType::Class => {
+                match req.request {
+                    GET_REPORT => xfer.accept_with(&self.report.to_bytes()).ok(),
+                    GET_IDLE => xfer.accept_with(&[self.idle]).ok(),
+                    _ => xfer.reject().ok(),
+                };
+            }
+            _ => {}
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+        if req.request_type != RequestType::Class
+            || req.recipient != Recipient::Interface
+            || req.index != u8::from(self.interface) as u16
+        {
+            return;
+        }
+        match req.request {
+            SET_IDLE => {
+                // Reports are only sent on change, the rate is just remembered
+                self.idle = (req.value >> 8) as u8;
+                xfer.accept().ok();
+            }
+            _ => {
+                xfer.reject().ok();
+            }
+        }
+    }
+}
@@ -0,0 +1,370 @@
+//! Interrupt-driven composite USB device
+//!
+//! Three functions share the device: a CDC-ACM serial port for data, a
+//! [`hid`] interface for buttons and status and a [`dfu`] runtime interface to
+//! reboot into the system bootloader. The CDC function spans two interfaces
+//! and is grouped by the interface association descriptor `usbd_serial` writes,
+//! so the device announces itself as composite with IADs.
+//!
+//! Everything lives in interrupt-safe shared state and is serviced from the
+//! OTG_FS interrupt, whose handler must call [`on_interrupt`]. Application code
+//! exchanges data through RX and TX queues with the [`Cdc`] handle returned by
+//! [`init`] and can sleep in between. When the RX queue is full the host is
+//! NAKed until the application catches up.
+//!
+//! The mass storage class in [`msc`] is used on its own, the composite device
+//! already takes every IN endpoint the OTG_FS peripheral has.
+pub mod dfu;
+pub mod hid;
+pub mod msc;
+
+use crate::uid;
+use core::cell::RefCell;
+use core::convert::Infallible;
+use cortex_m::interrupt::{self, Mutex};
+use embedded_hal::serial;
+use heapless::consts::U256;
+use heapless::spsc::Queue;
+use stm32f4xx_hal::otg_fs::{UsbBus, USB};
+use stm32f4xx_hal::stm32::Interrupt;
+use usb_device::bus::UsbBusAllocator;
+use usb_device::class::UsbClass;
+use usb_device::prelude::*;
+use usbd_serial::{ParityType, SerialPort};
+
+pub use dfu::DfuRuntime;
+pub use hid::{Hid, Report};
+
+pub type Bus = UsbBus<USB>;
+
+/// Largest chunk moved between the queues and the endpoints at once
+const PACKET: usize = 64;
+
+/// Miscellaneous device class, common class subclass and IAD protocol
+const USB_CLASS_MISC: u8 = 0xEF;
+const MISC_SUBCLASS_COMMON: u8 = 0x02;
+const MISC_PROTOCOL_IAD: u8 = 0x01;
+
+/// Device strings, the product shows up in `lsusb` and as the name of the
+/// serial port. The default serial number is the chip's UID, so boards on one
+/// host can be told apart, e.g. by `/dev/serial/by-id`.
+#[derive(Clone, Copy)]
+pub struct Strings {
+    pub manufacturer: &'static str,
+    pub product: &'static str,
+    pub serial_number: &'static str,
+}
+
+impl Default for Strings {
+    fn default() -> Self {
+        Strings {
+            manufacturer: "stm32f4-playground",
+            product: "STM32F4 composite device",
+            serial_number: uid::serial_number(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    Mark,
+    Space,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum StopBits {
+    One,
+    OnePointFive,
+    Two,
+}
+
+/// Serial settings requested by the host with SET_LINE_CODING, CDC-ACM itself
+/// ignores them
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub struct LineCoding {
+    pub baud: u32,
+    /// 5, 6, 7, 8 or 16
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+struct State {
+    device: UsbDevice<'static, Bus>,
+    serial: SerialPort<'static, Bus>,
+    hid: Hid<'static, Bus>,
+    dfu: DfuRuntime,
+    rx: Queue<u8, U256>,
+    tx: Queue<u8, U256>,
+}
+
+impl State {
+    /// Moves received bytes into the RX queue while there is room
+    fn fill_rx(&mut self) {
+        let mut buf = [0; PACKET];
+        loop {
+            let room = (self.rx.capacity() - self.rx.len()).min(PACKET);
+            if room == 0 {
+                return;
+            }
+            match self.serial.read(&mut buf[..room]) {
+                Ok(count) if count > 0 => {
+                    for byte in &buf[..count] {
+                        self.rx.enqueue(*byte).ok();
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    /// Hands queued bytes to the IN endpoint while it accepts them
+    fn drain_tx(&mut self) {
+        let mut buf = [0; PACKET];
+        while !self.tx.is_empty() {
+            let mut len = 0;
+            for (slot, byte) in buf.iter_mut().zip(self.tx.iter()) {
+                *slot = *byte;
+                len += 1;
+            }
+            match self.serial.write(&buf[..len]) {
+                Ok(count) => {
+                    for _ in 0..count {
+                        self.tx.dequeue();
+                    }
+                }
+                // Not configured yet or the endpoint is busy, retried on the
+                // next interrupt
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+static STATE: Mutex<RefCell<Option<State>>> = Mutex::new(RefCell::new(None));
+
+/// Must be called from the OTG_FS interrupt handler
+pub fn on_interrupt() {
+    interrupt::free(|cs| {
+        if let Some(state) = STATE.borrow(cs).borrow_mut().as_mut() {
+            let classes: &mut [&mut dyn UsbClass<Bus>] =
+                &mut [&mut state.serial, &mut state.hid, &mut state.dfu];
+            if state.device.poll(classes) {
+                state.fill_rx();
+            }
+            state.drain_tx();
+        }
+    });
+}
+
+/// Sets up the composite device with `vid_pid` and `strings`. Can only be
+/// called once.
+pub fn init(usb: USB, ep_memory: &'static mut [u32], vid_pid: UsbVidPid, strings: &Strings) -> Cdc {
+    let bus: &'static UsbBusAllocator<Bus> =
+        cortex_m::singleton!(: UsbBusAllocator<Bus> = UsbBus::new(usb, ep_memory))
+            .expect("USB already initialized");
+    // Endpoints are allocated in this order, the OTG_FS peripheral has just
+    // enough IN endpoints for the CDC and HID ones
+    let serial = SerialPort::new(bus);
+    let hid = Hid::new(bus);
+    let dfu = DfuRuntime::new(bus);
+    let device = UsbDeviceBuilder::new(bus, vid_pid)
+        .manufacturer(strings.manufacturer)
+        .product(strings.product)
+        .serial_number(strings.serial_number)
+        .device_class(USB_CLASS_MISC)
+        .device_sub_class(MISC_SUBCLASS_COMMON)
+        .device_protocol(MISC_PROTOCOL_IAD)
+        .build();
+    interrupt::free(|cs| {
+        STATE.borrow(cs).replace(Some(State {
+            device,
+            serial,
+            hid,
+            dfu,
+            rx: Queue::new(),
+            tx: Queue::new(),
+        }))
+    });
+    unsafe {
+        cortex_m::peripheral::NVIC::unmask(Interrupt::OTG_FS);
+    }
+    Cdc { _private: () }
+}
+
+/// Replaces the report of the HID interface, it is sent when it changed.
+/// Only valid after [`init`].
+pub fn set_report(report: Report) {
+    with_state(|state| state.hid.set(report));
+}
+
+/// Runs `f` on the shared state, `init` has been called if a `Cdc` exists
+fn with_state<R>(f: impl FnOnce(&mut State) -> R) -> R {
+    interrupt::free(|cs| f(STATE.borrow(cs).borrow_mut().as_mut().unwrap()))
+}
+
+/// Sleeps until `ready` holds, `ready` runs with interrupts disabled
+fn wait_for(mut ready: impl FnMut(&mut State) -> bool) {
+    loop {
+        // A pending interrupt still wakes up WFI with interrupts masked, so
+        // an event between the check and the WFI is not missed
+        let done = interrupt::free(|cs| {
+            let done = ready(STATE.borrow(cs).borrow_mut().as_mut().unwrap());
+            if !done {
+                cortex_m::asm::wfi();
+            }
+            done
+        });
+        if done {
+            return;
+        }
+    }
+}
+
+/// Handle to the buffered serial port
+pub struct Cdc {
+    _private: (),
+}
+
+impl Cdc {
+    /// True once the host has configured the device
+    pub fn is_configured(&self) -> bool {
+        with_state(|state| state.device.state() == UsbDeviceState::Configured)
+    }
+
+    /// Line coding last set by the host, 8N1 at 9600 baud until then
+    pub fn line_coding(&self) -> LineCoding {
+        with_state(|state| {
+            let coding = state.serial.line_coding();
+            LineCoding {
+                baud: coding.data_rate(),
+                data_bits: coding.data_bits(),
+                parity: match coding.parity_type() {
+                    ParityType::None => Parity::None,
+                    ParityType::Odd => Parity::Odd,
+                    // Sic, spelled like this in usbd-serial 0.1
+                    ParityType::Event => Parity::Even,
+                    ParityType::Mark => Parity::Mark,
+                    ParityType::Space => Parity::Space,
+                },
+                stop_bits: match coding.stop_bits() {
+                    usbd_serial::StopBits::One => StopBits::One,
+                    usbd_serial::StopBits::OnePointFive => StopBits::OnePointFive,
+                    usbd_serial::StopBits::Two => StopBits::Two,
+                },
+            }
+        })
+    }
+
+    /// Data Terminal Ready as set by the host, usually while the port is open
+    pub fn dtr(&self) -> bool {
+        with_state(|state| state.serial.dtr())
+    }
+
+    /// Request To Send as set by the host
+    pub fn rts(&self) -> bool {
+        with_state(|state| state.serial.rts())
+    }
+
+    /// Number of received bytes waiting to be read
+    pub fn available(&self) -> usize {
+        with_state(|state| state.rx.len())
+    }
+
+    /// Copies received bytes into `buf` without blocking, returns the count
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        with_state(|state| {
+            let mut count = 0;
+            for slot in buf.iter_mut() {
+                match state.rx.dequeue() {
+                    Some(byte) => *slot = byte,
+                    None => break,
+                }
+                count += 1;
+            }
+            // Space was freed, pick up what the endpoint had to hold back
+            state.fill_rx();
+            count
+        })
+    }
+
+    /// Sleeps until at least one byte arrived, then reads like [`Cdc::read`]
+    pub fn recv(&mut self, buf: &mut [u8]) -> usize {
+        wait_for(|state| {
+            state.fill_rx();
+            !state.rx.is_empty()
+        });
+        self.read(buf)
+    }
+
+    /// Queues as much of `data` as fits without blocking, returns the count
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        with_state(|state| {
+            let mut count = 0;
+            for byte in data {
+                if state.tx.enqueue(*byte).is_err() {
+                    break;
+                }
+                count += 1;
+            }
+            state.drain_tx();
+            count
+        })
+    }
+
+    /// Queues all of `data`, sleeping while the TX queue is full
+    pub fn write_all(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let count = self.write(data);
+            data = &data[count..];
+            if !data.is_empty() {
+                wait_for(|state| state.tx.len() < state.tx.capacity());
+            }
+        }
+    }
+
+    /// Sleeps until every queued byte has been handed to the endpoint
+    pub fn flush(&mut self) {
+        wait_for(|state| {
+            state.drain_tx();
+            state.tx.is_empty()
+        });
+    }
+}
+
+impl serial::Read<u8> for Cdc {
+    type Error = Infallible;
+
+    fn read(&mut self) -> nb::Result<u8, Infallible> {
+        let mut byte = [0];
+        match Cdc::read(self, &mut byte) {
+            1 => Ok(byte[0]),
+            _ => Err(nb::Error::WouldBlock),
+        }
+    }
+}
+
+impl serial::Write<u8> for Cdc {
+    type Error = Infallible;
+
+    fn write(&mut self, byte: u8) -> nb::Result<(), Infallible> {
+        match Cdc::write(self, &[byte]) {
+            1 => Ok(()),
+            _ => Err(nb::Error::WouldBlock),
+        }
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Infallible> {
+        if with_state(|state| {
+            state.drain_tx();
+            state.tx.is_empty()
+        }) {
+            Ok(())
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+}
@@ -0,0 +1,471 @@
+//! USB mass storage, bulk-only transport with the SCSI transparent command set
+//!
+//! Exposes one [`BlockDevice`] as a removable drive that every OS mounts
+//! without extra drivers. Each command arrives in a command block wrapper
+//! (CBW) on the bulk OUT endpoint, is followed by its data and answered with a
+//! command status wrapper (CSW) on the bulk IN endpoint. Only the commands
+//! Linux, macOS and Windows actually send are implemented, anything else fails
+//! with ILLEGAL REQUEST in the sense data. A failed command that owes the host
+//! data stalls the bulk IN endpoint (section 6.7.2 of the bulk-only transport)
+//! and sends its CSW once the host cleared the halt. See the USB Mass Storage
+//! Class Bulk-Only Transport 1.0 and SPC-2/SBC-2 specifications.
+//!
+//! Block device accesses run inside `UsbDevice::poll`, which is why
+//! `src/bin/usb_msc.rs` polls from the main loop instead of the USB interrupt.
+use crate::block::{BlockDevice, Error, BLOCK_SIZE};
+use usb_device::class_prelude::*;
+use usb_device::control::{Recipient, Request, RequestType};
+use usb_device::Result;
+
+const USB_CLASS_MSC: u8 = 0x08;
+const MSC_SUBCLASS_SCSI: u8 = 0x06;
+const MSC_PROTOCOL_BOT: u8 = 0x50;
+
+// Class requests
+const GET_MAX_LUN: u8 = 0xFE;
+const BULK_ONLY_RESET: u8 = 0xFF;
+
+const PACKET: usize = 64;
+/// "USBC"
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CBW_LEN: usize = 31;
+/// "USBS"
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+
+// CSW status
+const PASSED: u8 = 0;
+const FAILED: u8 = 1;
+
+// SCSI commands
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const INQUIRY: u8 = 0x12;
+const MODE_SENSE_6: u8 = 0x1A;
+const START_STOP_UNIT: u8 = 0x1B;
+const PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1E;
+const READ_FORMAT_CAPACITIES: u8 = 0x23;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2A;
+const VERIFY_10: u8 = 0x2F;
+const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+const MODE_SENSE_10: u8 = 0x5A;
+
+/// Sense key, additional sense code and qualifier of the last failure
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+struct Sense(u8, u8, u8);
+
+impl Sense {
+    const NONE: Sense = Sense(0x00, 0x00, 0x00);
+    const INVALID_COMMAND: Sense = Sense(0x05, 0x20, 0x00);
+    const INVALID_FIELD: Sense = Sense(0x05, 0x24, 0x00);
+    const OUT_OF_RANGE: Sense = Sense(0x05, 0x21, 0x00);
+    const WRITE_PROTECTED: Sense = Sense(0x07, 0x27, 0x00);
+    const READ_ERROR: Sense = Sense(0x03, 0x11, 0x00);
+    const WRITE_ERROR: Sense = Sense(0x03, 0x0C, 0x00);
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Stage {
+    /// Waiting for a CBW
+    Command,
+    /// Sending `buf`, refilled from the disk while `blocks` remain
+    DataIn,
+    /// Receiving into `buf`, written to the disk unless `discard` is set
+    DataOut,
+    /// The CSW did not fit into the endpoint yet
+    Status,
+    /// Bulk IN is halted after a failed data stage, waiting for the host to
+    /// clear it
+    Stalled,
+}
+
+pub struct MassStorage<'a, B: UsbBus, D: BlockDevice> {
+    interface: InterfaceNumber,
+    bulk_in: EndpointIn<'a, B>,
+    bulk_out: EndpointOut<'a, B>,
+    disk: D,
+    /// Vendor (8 bytes) and product (16 bytes) for INQUIRY, padded with spaces
+    identification: [u8; 24],
+    stage: Stage,
+    tag: u32,
+    /// Data length announced by the CBW
+    expected: u32,
+    transferred: u32,
+    status: u8,
+    sense: Sense,
+    buf: [u8; BLOCK_SIZE],
+    len: usize,
+    position: usize,
+    /// Next block to read or write and the number left after it
+    block: u32,
+    blocks: u32,
+    discard: bool,
+    zero_length_sent: bool,
+}
+
+impl<'a, B: UsbBus, D: BlockDevice> MassStorage<'a, B, D> {
+    /// `vendor` and `product` show up as the drive model, longer strings are
+    /// cut to 8 and 16 characters
+    pub fn new(alloc: &'a UsbBusAllocator<B>, disk: D, vendor: &str, product: &str) -> Self {
+        let mut identification = [b' '; 24];
+        for (slot, byte) in identification[..8].iter_mut().zip(vendor.bytes()) {
+            *slot = byte;
+        }
+        for (slot, byte) in identification[8..].iter_mut().zip(product.bytes()) {
+            *slot = byte;
+        }
+        MassStorage {
+            interface: alloc.interface(),
+            bulk_in: alloc.bulk(PACKET as u16),
+            bulk_out: alloc.bulk(PACKET as u16),
+            disk,
+            identification,
+            stage: Stage::Command,
+            tag: 0,
+            expected: 0,
+            transferred: 0,
+            status: PASSED,
+            sense: Sense::NONE,
+            buf: [0; BLOCK_SIZE],
+            len: 0,
+            position: 0,
+            block: 0,
+            blocks: 0,
+            discard: false,
+            zero_length_sent: false,
+        }
+    }
+
+    pub fn disk(&mut self) -> &mut D {
+        &mut self.disk
+    }
+
+    fn fail(&mut self, sense: Sense) {
+        self.status = FAILED;
+        self.sense = sense;
+    }
+
+    /// Sends the first `len` bytes of `buf`, cut to what the host asked for
+    fn respond(&mut self, len: usize) {
+        self.len = len.min(self.expected as usize);
+        self.position = 0;
+        self.stage = Stage::DataIn;
+        self.send();
+    }
+
+    /// Receives the data announced by the CBW, dropping it if `discard` is set
+    fn receive_data(&mut self, discard: bool) {
+        self.discard = discard;
+        self.position = 0;
+        self.stage = Stage::DataOut;
+        if self.expected == 0 {
+            self.finish();
+        }
+    }
+
+    fn execute(&mut self, cb: &[u8; 16]) {
+        self.status = PASSED;
+        self.transferred = 0;
+        self.zero_length_sent = false;
+        self.blocks = 0;
+        let blocks = self.disk.block_count();
+        let read_only = self.disk.is_read_only();
+        // LBA and length of READ(10), WRITE(10) and VERIFY(10)
+        let lba = u32::from_be_bytes([cb[2], cb[3], cb[4], cb[5]]);
+        let count = u32::from(u16::from_be_bytes([cb[7], cb[8]]));
+        let in_range = lba.checked_add(count).map_or(false, |end| end <= blocks);
+
+        if cb[0] != REQUEST_SENSE {
+            self.sense = Sense::NONE;
+        }
+        match cb[0] {
+            TEST_UNIT_READY | PREVENT_ALLOW_MEDIUM_REMOVAL => self.respond(0),
+            REQUEST_SENSE => {
+                let Sense(key, asc, ascq) = self.sense;
+                self.sense = Sense::NONE;
+                self.buf[..18].copy_from_slice(&[
+                    0x70, 0, key, 0, 0, 0, 0, 10, 0, 0, 0, 0, asc, ascq, 0, 0, 0, 0,
+                ]);
+                self.respond(18);
+            }
+            INQUIRY if cb[1] & 0x01 != 0 => {
+                // No vital product data pages
+                self.fail(Sense::INVALID_FIELD);
+                self.respond(0);
+            }
+            INQUIRY => {
+                // Direct access, removable, SPC-2, 31 more bytes
+                self.buf[..8].copy_from_slice(&[0x00, 0x80, 0x04, 0x02, 31, 0, 0, 0]);
+                self.buf[8..32].copy_from_slice(&self.identification);
+                self.buf[32..36].copy_from_slice(b"1.0 ");
+                self.respond(36);
+            }
+            READ_CAPACITY_10 => {
+                self.buf[..4].copy_from_slice(&blocks.saturating_sub(1).to_be_bytes());
+                self.buf[4..8].copy_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+                self.respond(8);
+            }
+            READ_FORMAT_CAPACITIES => {
+                let size = (BLOCK_SIZE as u32).to_be_bytes();
+                // One descriptor of formatted media
+                self.buf[..4].copy_from_slice(&[0, 0, 0, 8]);
+                self.buf[4..8].copy_from_slice(&blocks.to_be_bytes());
+                self.buf[8..12].copy_from_slice(&[0x02, size[1], size[2], size[3]]);
+                self.respond(12);
+            }
+            MODE_SENSE_6 => {
+                let protect = if read_only { 0x80 } else { 0 };
+                self.buf[..4].copy_from_slice(&[3, 0, protect, 0]);
+                self.respond(4);
+            }
+            MODE_SENSE_10 => {
+                let protect = if read_only { 0x80 } else { 0 };
+                self.buf[..8].copy_from_slice(&[0, 6, 0, protect, 0, 0, 0, 0]);
+                self.respond(8);
+            }
+            START_STOP_UNIT | SYNCHRONIZE_CACHE_10 => {
+                // Sent on eject and unmount
+                if self.disk.flush().is_err() {
+                    self.fail(Sense::WRITE_ERROR);
+                }
+                self.respond(0);
+            }
+            VERIFY_10 => {
+                if !in_range {
+                    self.fail(Sense::OUT_OF_RANGE);
+                }
+                self.respond(0);
+            }
+            READ_10 if !in_range => {
+                self.fail(Sense::OUT_OF_RANGE);
+                self.respond(0);
+            }
+            READ_10 => {
+                self.block = lba;
+                self.blocks = count;
+                self.len = 0;
+                self.position = 0;
+                self.stage = Stage::DataIn;
+                self.send();
+            }
+            WRITE_10 if read_only || !in_range => {
+                self.fail(if read_only {
+                    Sense::WRITE_PROTECTED
+                } else {
+                    Sense::OUT_OF_RANGE
+                });
+                self.receive_data(true);
+            }
+            WRITE_10 => {
+                self.block = lba;
+                self.blocks = count;
+                self.receive_data(false);
+            }
+            _ => {
+                defmt::debug!("Unsupported SCSI command {:?}", cb[0]);
+                self.fail(Sense::INVALID_COMMAND);
+                // Data the host wants to send is taken and dropped, data it
+                // wants to receive never comes and the endpoint stalls
+                if self.expected > 0 && self.stage == Stage::DataOut {
+                    self.receive_data(true);
+                } else {
+                    self.respond(0);
+                }
+            }
+        }
+    }
+
+    /// Hands the next packet of the data stage to the IN endpoint
+    fn send(&mut self) {
+        if self.position == self.len && self.blocks > 0 && self.transferred < self.expected {
+            if self.disk.read(self.block, &mut self.buf).is_ok() {
+                self.block += 1;
+                self.blocks -= 1;
+                self.len = BLOCK_SIZE.min((self.expected - self.transferred) as usize);
+                self.position = 0;
+            } else {
+                self.fail(Sense::READ_ERROR);
+                self.blocks = 0;
+            }
+        }
+        if self.position < self.len {
+            let end = (self.position + PACKET).min(self.len);
+            if let Ok(count) = self.bulk_in.write(&self.buf[self.position..end]) {
+                self.position += count;
+                self.transferred += count as u32;
+            }
+            return;
+        }
+        let short = self.transferred < self.expected;
+        if short && self.status == FAILED {
+            self.bulk_in.stall();
+            self.stage = Stage::Stalled;
+            return;
+        }
+        // Stopping short of what the host expects right after a full packet
+        // takes a zero length packet to end the transfer
+        if short && self.transferred % PACKET as u32 == 0 && !self.zero_length_sent {
+            self.zero_length_sent = self.bulk_in.write(&[]).is_ok();
+            return;
+        }
+        self.finish();
+    }
+
+    /// Takes the next packet of the data stage from the OUT endpoint
+    fn receive(&mut self) {
+        let mut packet = [0; PACKET];
+        let count = match self.bulk_out.read(&mut packet) {
+            Ok(count) => count,
+            Err(_) => return,
+        };
+        self.transferred += count as u32;
+        if !self.discard {
+            let end = (self.position + count).min(BLOCK_SIZE);
+            self.buf[self.position..end].copy_from_slice(&packet[..end - self.position]);
+            self.position = end;
+            if self.position == BLOCK_SIZE && self.blocks > 0 {
+                if let Err(e) = self.disk.write(self.block, &self.buf) {
+                    self.fail(match e {
+                        Error::OutOfRange => Sense::OUT_OF_RANGE,
+                        Error::WriteProtected => Sense::WRITE_PROTECTED,
+                        Error::Device => Sense::WRITE_ERROR,
+                    });
+                    self.discard = true;
+                }
+                self.block += 1;
+                self.blocks -= 1;
+                self.position = 0;
+            }
+        }
+        if self.transferred >= self.expected {
+            // Written through at the end of every command, so nothing is
+            // lost when the drive is pulled without ejecting it
+            if !self.discard && self.disk.flush().is_err() {
+                self.fail(Sense::WRITE_ERROR);
+            }
+            self.finish();
+        }
+    }
+
+    /// Sends the CSW, the residue is what the host expected but did not get
+    fn finish(&mut self) {
+        let mut csw = [0; 13];
+        csw[..4].copy_from_slice(&CSW_SIGNATURE.to_le_bytes());
+        csw[4..8].copy_from_slice(&self.tag.to_le_bytes());
+        let residue = self.expected.saturating_sub(self.transferred);
+        csw[8..12].copy_from_slice(&residue.to_le_bytes());
+        csw[12] = self.status;
+        self.stage = match self.bulk_in.write(&csw) {
+            Ok(_) => Stage::Command,
+            Err(_) => Stage::Status,
+        };
+    }
+
+    fn command(&mut self) {
+        let mut cbw = [0; PACKET];
+        let count = match self.bulk_out.read(&mut cbw) {
+            Ok(count) => count,
+            Err(_) => return,
+        };
+        if count != CBW_LEN || u32::from_le_bytes([cbw[0], cbw[1], cbw[2], cbw[3]]) != CBW_SIGNATURE
+        {
+            // Not meaningful, wait for the next one
+            return;
+        }
+        self.tag = u32::from_le_bytes([cbw[4], cbw[5], cbw[6], cbw[7]]);
+        self.expected = u32::from_le_bytes([cbw[8], cbw[9], cbw[10], cbw[11]]);
+        // Direction of the data stage, only needed to tell unknown commands
+        // with data for the device apart
+        self.stage = if cbw[12] & 0x80 == 0 {
+            Stage::DataOut
+        } else {
+            Stage::DataIn
+        };
+        let mut cb = [0; 16];
+        cb.copy_from_slice(&cbw[15..31]);
+        self.execute(&cb);
+    }
+}
+
+impl<B: UsbBus, D: BlockDevice> UsbClass<B> for MassStorage<'_, B, D> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(
+            self.interface,
+            USB_CLASS_MSC,
+            MSC_SUBCLASS_SCSI,
+            MSC_PROTOCOL_BOT,
+        )?;
+        writer.endpoint(&self.bulk_in)?;
+        writer.endpoint(&self.bulk_out)
+    }
+
+    fn reset(&mut self) {
+        self.stage = Stage::Command;
+    }
+
+    fn poll(&mut self) {
+        if self.stage == Stage::Status {
+            self.finish();
+        }
+    }
+
+    fn endpoint_out(&mut self, addr: EndpointAddress) {
+        if addr != self.bulk_out.address() {
+            return;
+        }
+        match self.stage {
+            Stage::Command => self.command(),
+            Stage::DataOut => self.receive(),
+            // The host waits for the data or CSW first
+            Stage::DataIn | Stage::Status | Stage::Stalled => {}
+        }
+    }
+
+    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        if addr != self.bulk_in.address() {
+            return;
+        }
+        match self.stage {
+            Stage::DataIn => self.send(),
+            Stage::Status => self.finish(),
+            Stage::Command | Stage::DataOut | Stage::Stalled => {}
+        }
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+        if req.request_type == RequestType::Class
+            && req.recipient == Recipient::Interface
+            && req.index == u8::from(self.interface) as u16
+            && req.request == GET_MAX_LUN
+        {
+            // A single logical unit
+            xfer.accept_with(&[0]).ok();
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+        // Left to `UsbDevice` to accept, which unstalls the endpoint, the
+        // CSW goes out on the next poll
+        if self.stage == Stage::Stalled
+            && req.request_type == RequestType::Standard
+            && req.recipient == Recipient::Endpoint
+            && req.request == Request::CLEAR_FEATURE
+            && req.value == Request::FEATURE_ENDPOINT_HALT
+            && req.index == u16::from(u8::from(self.bulk_in.address()))
+        {
+            self.stage = Stage::Status;
+            return;
+        }
+        if req.request_type == RequestType::Class
+            && req.recipient == Recipient::Interface
+            && req.index == u8::from(self.interface) as u16
+            && req.request == BULK_ONLY_RESET
+        {
+            self.stage = Stage::Command;
+            xfer.accept().ok();
+        }
+    }
+}
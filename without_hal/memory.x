@@ -1,6 +1,15 @@
 /* Linker script for the STM32F401CCU6 */
 MEMORY
 {
-  FLASH : ORIGIN = 0x08000000, LENGTH = 256K
+  /* Sectors 0-3 */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 64K
+  /* Sectors 4 and 5, reserved for the key-value store (see src/kv.rs) */
+  KV_A : ORIGIN = 0x08010000, LENGTH = 64K
+  KV_B : ORIGIN = 0x08020000, LENGTH = 128K
   RAM : ORIGIN = 0x20000000, LENGTH = 64K
 }
+
+_kv_a_start = ORIGIN(KV_A);
+_kv_a_end = ORIGIN(KV_A) + LENGTH(KV_A);
+_kv_b_start = ORIGIN(KV_B);
+_kv_b_end = ORIGIN(KV_B) + LENGTH(KV_B);
@@ -0,0 +1,62 @@
+//! Die temperature and backup battery voltage from the internal channels
+//!
+//! Both share channel 18. The temperature sensor is converted against its
+//! two factory calibration points, sampled at 30 °C and 110 °C with VDDA =
+//! 3.3 V, after scaling the sample to that supply. VBAT is measured through
+//! an internal divider by 4, so even a fully charged coin cell stays below
+//! VDDA.
+//!
+//! For accurate readings, [`Adc::measure_vdda`] should be called first and
+//! repeated now and then if the supply drifts.
+use super::{Adc, Error, Resolution, SampleTime, TEMPERATURE, VBAT, VDDA_CAL_MV};
+
+/// Temperature sensor sample taken at 30 °C with VDDA = 3.3 V, see the
+/// temperature sensor characteristics in DS9716
+const TS_CAL1: *const u16 = 0x1FFF_7A2C as *const u16;
+/// Temperature sensor sample taken at 110 °C with VDDA = 3.3 V
+const TS_CAL2: *const u16 = 0x1FFF_7A2E as *const u16;
+const TS_CAL1_C: f32 = 30.0;
+const TS_CAL2_C: f32 = 110.0;
+/// The sensor needs at least 10 µs to start up and to be sampled
+const TS_START_US: u32 = 10;
+
+/// Ratio of the VBAT divider
+const VBAT_DIVIDER: u32 = 4;
+
+impl Adc {
+    /// Die temperature in °C. Leaves the internal channels connected and
+    /// channel 18 at the longest sample time.
+    pub fn temperature(&mut self) -> Result<f32, Error> {
+        // VBAT takes precedence over the sensor on channel 18
+        self.enable_vbat(false);
+        self.enable_internal(true);
+        self.delay_us(TS_START_US);
+        // 480 cycles are 13 µs at the fastest ADC clock
+        self.set_sample_time(TEMPERATURE, SampleTime::Cycles480)?;
+        let sample = self.read_12bit(TEMPERATURE)?;
+
+        // SAFETY: Factory calibration values in system memory
+        let (cal1, cal2) = unsafe {
+            (
+                core::ptr::read_volatile(TS_CAL1),
+                core::ptr::read_volatile(TS_CAL2),
+            )
+        };
+        // What the sensor would have read at the calibration supply
+        let sample = sample as f32 * self.vdda_mv as f32 / VDDA_CAL_MV as f32;
+        let slope = (TS_CAL2_C - TS_CAL1_C) / (f32::from(cal2) - f32::from(cal1));
+        Ok(TS_CAL1_C + slope * (sample - f32::from(cal1)))
+    }
+
+    /// Battery voltage at the VBAT pin in volts. The divider is only
+    /// connected for the conversion, it would drain the battery otherwise.
+    pub fn vbat(&mut self) -> Result<f32, Error> {
+        self.enable_vbat(true);
+        self.set_sample_time(VBAT, SampleTime::Cycles480)?;
+        let sample = self.read_12bit(VBAT);
+        self.enable_vbat(false);
+        let mv = u32::from(sample?) * self.vdda_mv * VBAT_DIVIDER
+            / u32::from(Resolution::Twelve.full_scale());
+        Ok(mv as f32 / 1000.0)
+    }
+}
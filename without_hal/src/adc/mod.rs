@@ -0,0 +1,667 @@
+//! ADC1, see Section 11 of RM0368
+//!
+//! Channels 0-15 are the pins (PA0-PA7 are 0-7, PB0-PB1 8-9, PC0-PC5
+//! 10-15), which must be in analog mode already. Channel 17 is the internal
+//! reference and 18 the temperature sensor or VBAT.
+//!
+//! Besides single and continuous conversions of one channel, a regular
+//! sequence of up to 16 channels can be scanned into a buffer by DMA2
+//! (stream 0 or 4, channel 0), either back to back or once per period of a
+//! [`SampleClock`]. Up to 4 injected channels can be converted in between,
+//! even while a scan is running. Samples become millivolts against the supply
+//! measured with the factory calibrated internal reference.
+//!
+//! The die temperature and backup battery voltage are read with
+//! [`Adc::temperature`] and [`Adc::vbat`], see [`internal`].
+use crate::clocks::Clocks;
+use crate::dma::{self, Channel, PeripheralTarget, Stream};
+use core::sync::atomic::{AtomicBool, Ordering};
+use cortex_m::peripheral::NVIC;
+use embedded_dma::WriteBuffer;
+use stm32f4::stm32f401::{self as device, adc1::RegisterBlock, Interrupt};
+
+pub mod internal;
+
+/// Internal reference voltage
+pub const VREFINT: u8 = 17;
+/// Internal temperature sensor, shares its input with VBAT
+pub const TEMPERATURE: u8 = 18;
+/// VBAT / 4, takes precedence over the temperature sensor when enabled
+pub const VBAT: u8 = 18;
+
+/// VREFINT sample taken at 30 °C with VDDA = 3.3 V, see Table 69 of DS9716
+const VREFINT_CAL: *const u16 = 0x1FFF_7A2A as *const u16;
+/// VDDA during calibration
+const VDDA_CAL_MV: u32 = 3300;
+/// Largest clock the ADC may run at with VDDA above 2.4 V
+const MAX_ADCCLK: u32 = 36_000_000;
+
+// SR bits
+const AWD: u32 = 1 << 0;
+const EOC: u32 = 1 << 1;
+const JEOC: u32 = 1 << 2;
+const OVR: u32 = 1 << 5;
+
+// CR1 bits
+const AWDCH: u32 = 0;
+const AWDIE: u32 = 1 << 6;
+const SCAN: u32 = 1 << 8;
+const AWDSGL: u32 = 1 << 9;
+const JAWDEN: u32 = 1 << 22;
+const AWDEN: u32 = 1 << 23;
+const RES: u32 = 24;
+
+// CR2 bits
+const ADON: u32 = 1 << 0;
+const CONT: u32 = 1 << 1;
+const DMA: u32 = 1 << 8;
+const DDS: u32 = 1 << 9;
+const JSWSTART: u32 = 1 << 22;
+const EXTSEL: u32 = 24;
+const EXTEN_RISING: u32 = 0b01 << 28;
+const SWSTART: u32 = 1 << 30;
+/// EXTSEL of TIM2 TRGO
+const TIM2_TRGO: u32 = 0b0110;
+
+// CCR bits
+const ADCPRE: u32 = 16;
+const VBATE: u32 = 1 << 22;
+const TSVREFE: u32 = 1 << 23;
+
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum Error {
+    /// Channel above 18
+    Channel,
+    /// Regular sequence empty or longer than 16, injected longer than 4
+    Sequence,
+    /// A conversion finished before the previous result was read
+    Overrun,
+    /// The sample clock can not run at the requested rate
+    Rate,
+    /// DMA stream that does not serve ADC1
+    Stream,
+    /// The DMA stream failed
+    Dma(dma::Error),
+}
+
+/// Cycles a channel is sampled for, longer suits higher source impedances
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum SampleTime {
+    Cycles3,
+    Cycles15,
+    Cycles28,
+    Cycles56,
+    Cycles84,
+    Cycles112,
+    Cycles144,
+    Cycles480,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum Resolution {
+    Twelve,
+    Ten,
+    Eight,
+    Six,
+}
+
+impl Resolution {
+    /// Largest sample
+    pub fn full_scale(self) -> u16 {
+        match self {
+            Resolution::Twelve => 4095,
+            Resolution::Ten => 1023,
+            Resolution::Eight => 255,
+            Resolution::Six => 63,
+        }
+    }
+}
+
+/// What the analog watchdog guards
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum Guard {
+    /// One channel of both groups
+    Channel(u8),
+    /// Every regular and injected channel
+    All,
+}
+
+/// Set by [`on_interrupt`] when the analog watchdog trips
+static TRIPPED: AtomicBool = AtomicBool::new(false);
+
+/// Must be called from the `ADC` interrupt handler if the analog watchdog
+/// interrupt is enabled
+pub fn on_interrupt() {
+    let regs = registers();
+    if regs.sr.read().bits() & AWD != 0 {
+        // The flags are cleared by writing 0, writing 1 leaves them alone
+        regs.sr.write(|w| unsafe { w.bits(!AWD) });
+        TRIPPED.store(true, Ordering::Release);
+    }
+}
+
+fn registers() -> &'static RegisterBlock {
+    // SAFETY: Only `Adc` or a `Scan` owning it configures the ADC, the
+    // interrupt handler only clears a flag
+    unsafe { &*device::ADC1::ptr() }
+}
+
+fn common() -> &'static device::adc_common::RegisterBlock {
+    // SAFETY: Only ADC1 exists on the STM32F401
+    unsafe { &*device::ADC_COMMON::ptr() }
+}
+
+pub struct Adc {
+    adc: device::ADC1,
+    resolution: Resolution,
+    /// Supply voltage the samples are relative to
+    vdda_mv: u32,
+    /// For start up delays
+    sysclk: u32,
+}
+
+impl Adc {
+    /// Powers up ADC1 at 12 bits with the shortest sample time on every
+    /// channel, assuming VDDA = 3.3 V until [`Adc::measure_vdda`] says
+    /// otherwise
+    pub fn adc1(adc: device::ADC1, rcc: &device::RCC, clocks: &Clocks) -> Self {
+        rcc.apb2enr.modify(|_, w| w.adc1en().enabled());
+        rcc.apb2rstr.modify(|_, w| w.adcrst().set_bit());
+        rcc.apb2rstr.modify(|_, w| w.adcrst().clear_bit());
+        // ADCCLK = PCLK2 / 2, 4, 6 or 8
+        let adcpre = (0..4)
+            .find(|pre| clocks.pclk2 / (2 * (pre + 1)) <= MAX_ADCCLK)
+            .unwrap_or(3);
+        common().ccr.modify(|r, w| {
+            let ccr = r.bits() & !(0b11 << ADCPRE);
+            unsafe { w.bits(ccr | adcpre << ADCPRE) }
+        });
+
+        let regs = registers();
+        regs.cr2.write(|w| unsafe { w.bits(ADON) });
+        let adc = Adc {
+            adc,
+            resolution: Resolution::Twelve,
+            vdda_mv: VDDA_CAL_MV,
+            sysclk: clocks.sysclk,
+        };
+        // t_STAB, the ADC needs up to 3 µs to power up
+        adc.delay_us(3);
+        adc
+    }
+
+    pub fn set_resolution(&mut self, resolution: Resolution) {
+        registers().cr1.modify(|r, w| unsafe {
+            w.bits(r.bits() & !(0b11 << RES) | (resolution as u32) << RES)
+        });
+        self.resolution = resolution;
+    }
+
+    pub fn resolution(&self) -> Resolution {
+        self.resolution
+    }
+
+    pub fn set_sample_time(&mut self, channel: u8, time: SampleTime) -> Result<(), Error> {
+        let regs = registers();
+        let time = time as u32;
+        match channel {
+            0..=9 => {
+                let shift = 3 * u32::from(channel);
+                regs.smpr2.modify(|r, w| {
+                    let smpr = r.bits() & !(0b111 << shift);
+                    unsafe { w.bits(smpr | time << shift) }
+                });
+            }
+            10..=18 => {
+                let shift = 3 * u32::from(channel - 10);
+                regs.smpr1.modify(|r, w| {
+                    let smpr = r.bits() & !(0b111 << shift);
+                    unsafe { w.bits(smpr | time << shift) }
+                });
+            }
+            _ => return Err(Error::Channel),
+        }
+        Ok(())
+    }
+
+    /// Connects the temperature sensor and VREFINT to channels 18 and 17,
+    /// the sensor needs 10 µs to start up
+    pub fn enable_internal(&mut self, enable: bool) {
+        common().ccr.modify(|r, w| unsafe {
+            w.bits(if enable {
+                r.bits() | TSVREFE
+            } else {
+                r.bits() & !TSVREFE
+            })
+        });
+    }
+
+    /// Connects VBAT / 4 to channel 18 instead of the temperature sensor.
+    /// Best left off between readings, the divider drains the battery.
+    pub fn enable_vbat(&mut self, enable: bool) {
+        common().ccr.modify(|r, w| unsafe {
+            w.bits(if enable {
+                r.bits() | VBATE
+            } else {
+                r.bits() & !VBATE
+            })
+        });
+    }
+
+    /// Converts `channel` once
+    pub fn read(&mut self, channel: u8) -> Result<u16, Error> {
+        self.set_sequence(&[channel])?;
+        let regs = registers();
+        regs.cr1.modify(|r, w| unsafe { w.bits(r.bits() & !SCAN) });
+        regs.cr2.write(|w| unsafe { w.bits(ADON) });
+        self.clear(EOC | OVR);
+        regs.cr2
+            .modify(|r, w| unsafe { w.bits(r.bits() | SWSTART) });
+        while regs.sr.read().bits() & EOC == 0 {}
+        Ok(regs.dr.read().bits() as u16)
+    }
+
+    /// Converts `channel` over and over, see [`Adc::latest`]
+    pub fn start_continuous(&mut self, channel: u8) -> Result<(), Error> {
+        self.set_sequence(&[channel])?;
+        let regs = registers();
+        regs.cr1.modify(|r, w| unsafe { w.bits(r.bits() & !SCAN) });
+        regs.cr2.write(|w| unsafe { w.bits(ADON | CONT) });
+        self.clear(EOC | OVR);
+        regs.cr2
+            .modify(|r, w| unsafe { w.bits(r.bits() | SWSTART) });
+        Ok(())
+    }
+
+    /// The newest continuous conversion, if there was one since the last
+    /// call. Samples not picked up in time are overwritten.
+    pub fn latest(&mut self) -> Option<u16> {
+        let regs = registers();
+        if regs.sr.read().bits() & EOC == 0 {
+            return None;
+        }
+        let sample = regs.dr.read().bits() as u16;
+        self.clear(OVR);
+        Some(sample)
+    }
+
+    /// Stops after the conversion in progress
+    pub fn stop_continuous(&mut self) {
+        registers()
+            .cr2
+            .modify(|r, w| unsafe { w.bits(r.bits() & !CONT) });
+    }
+
+    /// Sets the injected group, converted in order by
+    /// [`Adc::read_injected`]
+    pub fn set_injected(&mut self, channels: &[u8]) -> Result<(), Error> {
+        set_injected(channels)
+    }
+
+    /// Converts the injected group, interrupting regular conversions
+    pub fn read_injected(&mut self) -> [u16; 4] {
+        read_injected()
+    }
+
+    /// Sets the analog watchdog to flag samples outside of `low..=high`,
+    /// raising the `ADC` interrupt too if `interrupt` is set
+    pub fn set_watchdog(
+        &mut self,
+        guard: Guard,
+        low: u16,
+        high: u16,
+        interrupt: bool,
+    ) -> Result<(), Error> {
+        let regs = registers();
+        let mut cr1 = AWDEN | JAWDEN;
+        match guard {
+            Guard::Channel(channel) if channel <= 18 => {
+                cr1 |= AWDSGL | u32::from(channel) << AWDCH;
+            }
+            Guard::Channel(_) => return Err(Error::Channel),
+            Guard::All => {}
+        }
+        if interrupt {
+            cr1 |= AWDIE;
+        }
+        // The thresholds are compared against 12-bit samples
+        regs.ltr
+            .write(|w| unsafe { w.bits(u32::from(low.min(4095))) });
+        regs.htr
+            .write(|w| unsafe { w.bits(u32::from(high.min(4095))) });
+        regs.cr1.modify(|r, w| unsafe {
+            w.bits(r.bits() & !(AWDEN | JAWDEN | AWDSGL | AWDIE | 0b1_1111) | cr1)
+        });
+        self.clear(AWD);
+        TRIPPED.store(false, Ordering::Relaxed);
+        if interrupt {
+            unsafe { NVIC::unmask(Interrupt::ADC) };
+        }
+        Ok(())
+    }
+
+    pub fn disable_watchdog(&mut self) {
+        registers()
+            .cr1
+            .modify(|r, w| unsafe { w.bits(r.bits() & !(AWDEN | JAWDEN | AWDIE)) });
+    }
+
+    /// Whether a sample went outside of the watchdog thresholds since the
+    /// last call
+    pub fn watchdog_tripped(&mut self) -> bool {
+        watchdog_tripped()
+    }
+
+    /// Measures VDDA against the internal reference, see Section 11.9 of
+    /// RM0368. [`Adc::millivolts`] uses the result from then on.
+    pub fn measure_vdda(&mut self) -> Result<u32, Error> {
+        self.enable_internal(true);
+        self.delay_us(10);
+        // VREFINT wants at least 10 µs of sampling, 480 cycles are 13 µs
+        // at the fastest ADC clock
+        self.set_sample_time(VREFINT, SampleTime::Cycles480)?;
+        let sample = u32::from(self.read_12bit(VREFINT)?).max(1);
+        // SAFETY: Factory calibration value in system memory
+        let cal = u32::from(unsafe { core::ptr::read_volatile(VREFINT_CAL) });
+        self.vdda_mv = VDDA_CAL_MV * cal / sample;
+        Ok(self.vdda_mv)
+    }
+
+    pub fn vdda_mv(&self) -> u32 {
+        self.vdda_mv
+    }
+
+    /// Voltage of a sample taken at the current resolution
+    pub fn millivolts(&self, sample: u16) -> u32 {
+        u32::from(sample) * self.vdda_mv / u32::from(self.resolution.full_scale())
+    }
+
+    /// Converts `sequence` into `buf` by DMA, sample after sample in
+    /// sequence order, back to back or once per `clock` period. With a
+    /// circular `config`, `buf` should hold a whole number of sequences.
+    pub fn scan<B>(
+        self,
+        sequence: &[u8],
+        clock: Option<SampleClock>,
+        stream: Stream<device::DMA2>,
+        buf: B,
+        config: &dma::Config,
+    ) -> Result<Scan<B>, ScanRejected<B>>
+    where
+        B: WriteBuffer<Word = u16>,
+    {
+        if !(stream.number() == 0 || stream.number() == 4) {
+            return Err((Error::Stream, self, clock, stream, buf));
+        }
+        if let Err(e) = self.set_sequence(sequence) {
+            return Err((e, self, clock, stream, buf));
+        }
+        let config = dma::Config {
+            channel: Channel::C0,
+            ..*config
+        };
+        let transfer = match stream.peripheral_to_memory(Dr, buf, &config) {
+            Ok(transfer) => transfer,
+            Err((e, stream, _, buf)) => return Err((Error::Dma(e), self, clock, stream, buf)),
+        };
+
+        let regs = registers();
+        regs.cr1.modify(|r, w| unsafe { w.bits(r.bits() | SCAN) });
+        // Keep requesting DMA transfers after the first round when circular
+        let dds = if config.circular { DDS } else { 0 };
+        let cr2 = match &clock {
+            Some(_) => ADON | DMA | dds | EXTEN_RISING | TIM2_TRGO << EXTSEL,
+            None => ADON | DMA | dds | CONT,
+        };
+        regs.cr2.write(|w| unsafe { w.bits(cr2) });
+        self.clear(EOC | OVR);
+        match &clock {
+            Some(clock) => clock.start(),
+            None => regs
+                .cr2
+                .modify(|r, w| unsafe { w.bits(r.bits() | SWSTART) }),
+        }
+        Ok(Scan {
+            adc: self,
+            clock,
+            transfer,
+            circular: config.circular,
+        })
+    }
+
+    pub fn free(self) -> device::ADC1 {
+        registers().cr2.write(|w| unsafe { w.bits(0) });
+        self.adc
+    }
+
+    /// Writes the regular sequence, one conversion per channel
+    fn set_sequence(&self, channels: &[u8]) -> Result<(), Error> {
+        if channels.is_empty() || channels.len() > 16 {
+            return Err(Error::Sequence);
+        }
+        if channels.iter().any(|channel| *channel > 18) {
+            return Err(Error::Channel);
+        }
+        // SQ1-SQ6 in SQR3, SQ7-SQ12 in SQR2, SQ13-SQ16 and the length L in
+        // SQR1, 5 bits each
+        let mut sqr = [0u32; 3];
+        for (i, channel) in channels.iter().enumerate() {
+            sqr[i / 6] |= u32::from(*channel) << (5 * (i % 6));
+        }
+        sqr[2] |= (channels.len() as u32 - 1) << 20;
+        let regs = registers();
+        regs.sqr3.write(|w| unsafe { w.bits(sqr[0]) });
+        regs.sqr2.write(|w| unsafe { w.bits(sqr[1]) });
+        regs.sqr1.write(|w| unsafe { w.bits(sqr[2]) });
+        Ok(())
+    }
+
+    /// Converts `channel` once at 12 bits, the resolution of the factory
+    /// calibration values
+    fn read_12bit(&mut self, channel: u8) -> Result<u16, Error> {
+        let resolution = self.resolution;
+        self.set_resolution(Resolution::Twelve);
+        let sample = self.read(channel);
+        self.set_resolution(resolution);
+        sample
+    }
+
+    fn delay_us(&self, us: u32) {
+        cortex_m::asm::delay(self.sysclk / 1_000_000 * us + 1);
+    }
+
+    /// Clears status flags
+    fn clear(&self, flags: u32) {
+        registers().sr.write(|w| unsafe { w.bits(!flags) });
+    }
+}
+
+fn set_injected(channels: &[u8]) -> Result<(), Error> {
+    if channels.is_empty() || channels.len() > 4 {
+        return Err(Error::Sequence);
+    }
+    if channels.iter().any(|channel| *channel > 18) {
+        return Err(Error::Channel);
+    }
+    // A sequence shorter than 4 ends at JSQ4, so it starts at JSQ(4 - JL)
+    let start = 4 - channels.len();
+    let mut jsqr = (channels.len() as u32 - 1) << 20;
+    for (i, channel) in channels.iter().enumerate() {
+        jsqr |= u32::from(*channel) << (5 * (start + i));
+    }
+    registers().jsqr.write(|w| unsafe { w.bits(jsqr) });
+    Ok(())
+}
+
+/// Results land in JDR1 onwards in conversion order
+fn read_injected() -> [u16; 4] {
+    let regs = registers();
+    regs.sr.write(|w| unsafe { w.bits(!JEOC) });
+    regs.cr2
+        .modify(|r, w| unsafe { w.bits(r.bits() | JSWSTART) });
+    while regs.sr.read().bits() & JEOC == 0 {}
+    regs.sr.write(|w| unsafe { w.bits(!JEOC) });
+    [
+        regs.jdr1.read().bits() as u16,
+        regs.jdr2.read().bits() as u16,
+        regs.jdr3.read().bits() as u16,
+        regs.jdr4.read().bits() as u16,
+    ]
+}
+
+fn watchdog_tripped() -> bool {
+    let regs = registers();
+    // Either still flagged or already taken care of by the interrupt
+    let flagged = regs.sr.read().bits() & AWD != 0;
+    if flagged {
+        regs.sr.write(|w| unsafe { w.bits(!AWD) });
+    }
+    TRIPPED.swap(false, Ordering::Acquire) || flagged
+}
+
+/// The regular data register, for the DMA
+struct Dr;
+
+unsafe impl PeripheralTarget for Dr {
+    type Word = u16;
+
+    fn address(&self) -> u32 {
+        &registers().dr as *const _ as u32
+    }
+}
+
+/// TIM2 as a sample rate generator, its update event (TRGO) starts each
+/// round of a [`Scan`]
+pub struct SampleClock {
+    tim: device::TIM2,
+}
+
+impl SampleClock {
+    pub fn tim2(
+        tim: device::TIM2,
+        rcc: &device::RCC,
+        clocks: &Clocks,
+        hz: u32,
+    ) -> Result<Self, Error> {
+        // TIM2 is 32 bits wide, so no prescaler is needed
+        let arr = clocks
+            .timclk1()
+            .checked_div(hz)
+            .filter(|ticks| *ticks >= 2)
+            .ok_or(Error::Rate)?;
+        rcc.apb1enr.modify(|_, w| w.tim2en().enabled());
+        tim.cr1.write(|w| w.cen().disabled());
+        tim.psc.write(|w| w.psc().bits(0));
+        tim.arr.write(|w| unsafe { w.bits(arr - 1) });
+        // Master mode: TRGO on update
+        tim.cr2.write(|w| w.mms().update());
+        Ok(SampleClock { tim })
+    }
+
+    fn start(&self) {
+        self.tim.cnt.write(|w| unsafe { w.bits(0) });
+        self.tim.cr1.write(|w| w.cen().enabled());
+    }
+
+    fn stop(&self) {
+        self.tim.cr1.write(|w| w.cen().disabled());
+    }
+
+    pub fn free(self) -> device::TIM2 {
+        self.stop();
+        self.tim
+    }
+}
+
+/// A scan that could not start, with everything it was given
+pub type ScanRejected<B> = (Error, Adc, Option<SampleClock>, Stream<device::DMA2>, B);
+
+/// A regular sequence being converted into a buffer
+pub struct Scan<B> {
+    adc: Adc,
+    clock: Option<SampleClock>,
+    transfer: dma::Transfer<device::DMA2, Dr, B>,
+    circular: bool,
+}
+
+impl<B> Scan<B> {
+    /// See [`dma::Transfer::poll`], a stalled ADC is reported as overrun
+    pub fn poll(&mut self) -> nb::Result<(), Error> {
+        // A one-shot scan keeps converting once its buffer is full, which
+        // sets OVR, so completion goes first
+        match self.transfer.poll() {
+            Ok(()) => {
+                if !self.circular {
+                    self.halt();
+                }
+                Ok(())
+            }
+            Err(nb::Error::Other(e)) => Err(nb::Error::Other(Error::Dma(e))),
+            Err(nb::Error::WouldBlock) if registers().sr.read().bits() & OVR != 0 => {
+                Err(nb::Error::Other(Error::Overrun))
+            }
+            Err(nb::Error::WouldBlock) => Err(nb::Error::WouldBlock),
+        }
+    }
+
+    pub fn wait(&mut self) -> Result<(), Error> {
+        nb::block!(self.poll())
+    }
+
+    /// See [`dma::Transfer::wait_half`]
+    pub fn wait_half(&mut self) -> Result<dma::Half, Error> {
+        // Only a circular scan keeps the DMA requests going, a one-shot one
+        // overruns once the second half is done, see `poll`
+        if self.circular && registers().sr.read().bits() & OVR != 0 {
+            return Err(Error::Overrun);
+        }
+        let half = self.transfer.wait_half().map_err(Error::Dma)?;
+        if !self.circular && matches!(half, dma::Half::Second) {
+            self.halt();
+        }
+        Ok(half)
+    }
+
+    /// See [`dma::Transfer::peek`]
+    pub fn peek(&self) -> &B {
+        self.transfer.peek()
+    }
+
+    pub fn adc(&self) -> &Adc {
+        &self.adc
+    }
+
+    /// Converts the injected group in between regular conversions
+    pub fn read_injected(&mut self) -> [u16; 4] {
+        read_injected()
+    }
+
+    pub fn set_injected(&mut self, channels: &[u8]) -> Result<(), Error> {
+        set_injected(channels)
+    }
+
+    pub fn watchdog_tripped(&mut self) -> bool {
+        watchdog_tripped()
+    }
+
+    /// Stops a one-shot scan after its last conversion, before it overruns
+    fn halt(&self) {
+        if let Some(clock) = &self.clock {
+            clock.stop();
+        }
+        registers().cr2.write(|w| unsafe { w.bits(ADON) });
+        self.adc.clear(EOC | OVR);
+    }
+
+    /// Stops converting and hands everything back
+    pub fn stop(self) -> (Adc, Option<SampleClock>, Stream<device::DMA2>, B) {
+        if let Some(clock) = &self.clock {
+            clock.stop();
+        }
+        let regs = registers();
+        regs.cr2.write(|w| unsafe { w.bits(ADON) });
+        let (stream, _, buf) = self.transfer.free();
+        self.adc.clear(EOC | OVR);
+        (self.adc, self.clock, stream, buf)
+    }
+}
@@ -0,0 +1,112 @@
+#![deny(unsafe_code)]
+#![no_std]
+#![no_main]
+
+use stm32f4_playground as _; // Global logger + panicking-behavior
+use stm32f4::stm32f401 as device;
+use stm32f4::stm32f401::interrupt;
+use stm32f4_playground::adc::{self, Adc, Guard, SampleClock, SampleTime};
+use stm32f4_playground::clocks::Clocks;
+use stm32f4_playground::dma::{self, Half};
+
+/// Channels scanned per round: PA0, PA1 and the internal reference
+const SEQUENCE: [u8; 3] = [0, 1, adc::VREFINT];
+/// Rounds per half of the DMA buffer
+const ROUNDS: usize = 100;
+/// Rounds per second
+const RATE_HZ: u32 = 1000;
+
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    defmt::info!("Hook up potentiometers to PA0 and PA1!");
+
+    // Take ownership of the device peripherals singleton
+    if let Some(dp) = device::Peripherals::take() {
+        // Take and own RCC RegisterBlock out of dp
+        let rcc = dp.RCC;
+        // Take and own GPIOA, ADC1, TIM2 & DMA2 out of dp
+        let (gpioa, adc1, tim2, dma2) = (dp.GPIOA, dp.ADC1, dp.TIM2, dp.DMA2);
+
+        // Enable GPIOA clock
+        rcc.ahb1enr.write(|w| w.gpioaen().enabled());
+        // Set PA0-1 as analog inputs, which disconnects the digital input
+        gpioa.moder.write(|w| w.moder0().analog().moder1().analog());
+
+        let clocks = Clocks::read(&rcc);
+        let mut adc = Adc::adc1(adc1, &rcc, &clocks);
+        let vdda = adc.measure_vdda().unwrap();
+        defmt::info!("VDDA: {:?} mV", vdda);
+        for channel in SEQUENCE.iter() {
+            adc.set_sample_time(*channel, SampleTime::Cycles480)
+                .unwrap();
+        }
+
+        // Single conversion
+        let sample = adc.read(0).unwrap();
+        defmt::info!("PA0: {:?} mV", adc.millivolts(sample));
+
+        // PA1 as an injected channel, converted on demand even mid-scan
+        adc.set_injected(&[1]).unwrap();
+        // Complain when PA0 leaves the middle half of its range
+        adc.set_watchdog(Guard::Channel(0), 1024, 3072, true)
+            .unwrap();
+
+        // Three channels every millisecond into a circular buffer, one
+        // half is averaged while the DMA fills the other
+        #[allow(unsafe_code)]
+        let buf: &'static mut [u16] =
+            cortex_m::singleton!(: [u16; 2 * ROUNDS * 3] = [0; 2 * ROUNDS * 3]).unwrap();
+        let clock = SampleClock::tim2(tim2, &rcc, &clocks, RATE_HZ).unwrap();
+        let streams = dma::split(dma2, &rcc);
+        let config = dma::Config {
+            circular: true,
+            ..dma::Config::default()
+        };
+        let mut scan = match adc.scan(&SEQUENCE, Some(clock), streams.s0, buf, &config) {
+            Ok(scan) => scan,
+            Err((e, ..)) => defmt::panic!("Scan rejected: {:?}", e),
+        };
+
+        loop {
+            let half = match scan.wait_half() {
+                Ok(half) => half,
+                Err(e) => defmt::panic!("Scan failed: {:?}", e),
+            };
+            let samples = match half {
+                Half::First => &scan.peek()[..ROUNDS * 3],
+                Half::Second => &scan.peek()[ROUNDS * 3..],
+            };
+            let mut sums = [0u32; 3];
+            for round in samples.chunks(3) {
+                for (sum, sample) in sums.iter_mut().zip(round) {
+                    *sum += u32::from(*sample);
+                }
+            }
+            let mv = |sum: u32| scan.adc().millivolts((sum / ROUNDS as u32) as u16);
+            defmt::info!(
+                "PA0: {:?} mV, PA1: {:?} mV, VREFINT: {:?} mV",
+                mv(sums[0]),
+                mv(sums[1]),
+                mv(sums[2])
+            );
+
+            let injected = scan.read_injected();
+            defmt::info!(
+                "PA1 (injected): {:?} mV",
+                scan.adc().millivolts(injected[0])
+            );
+            if scan.watchdog_tripped() {
+                defmt::warn!("PA0 left 1024..=3072");
+            }
+        }
+    };
+
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
+
+#[interrupt]
+fn ADC() {
+    adc::on_interrupt();
+}
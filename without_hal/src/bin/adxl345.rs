@@ -11,8 +11,12 @@
 use cortex_m::asm::delay;
 use stm32f4::stm32f401 as device;
 use stm32f4_playground as _; // Global logger + panicking-behavior
+use stm32f4_playground::adc::Adc;
+use stm32f4_playground::clocks::Clocks;
 
 const ADXL345_ADDRESS: u8 = 0x53;
+/// Below this the RTC battery should be replaced, VBAT has to stay above 1.65 V
+const VBAT_LOW: f32 = 2.0;
 #[allow(non_camel_case_types)]
 #[allow(dead_code)]
 enum ADXL345_Reg {
@@ -31,8 +35,8 @@ fn main() -> ! {
     if let Some(dp) = device::Peripherals::take() {
         // Take and own RCC RegisterBlock out of dp
         let rcc = dp.RCC;
-        // Take and own GPIOB & I2C1 out of dp
-        let (gpiob, i2c1) = (dp.GPIOB, dp.I2C1);
+        // Take and own GPIOB, I2C1 & ADC1 out of dp
+        let (gpiob, i2c1, adc1) = (dp.GPIOB, dp.I2C1, dp.ADC1);
 
         /* GPIO configuration: PB6 = SCL1, PB7 = SDA1 */
         // Enable clock for GPIOB
@@ -56,6 +60,14 @@ fn main() -> ! {
         i2c1.enable_clock(&rcc);
         i2c1.init();
         defmt::info!("I2C Initialization Complete");
+
+        /* ADC1 setup, for the die temperature and the RTC battery */
+        let mut adc = Adc::adc1(adc1, &rcc, &Clocks::read(&rcc));
+        match adc.measure_vdda() {
+            Ok(vdda) => defmt::info!("VDDA: {:?} mV", vdda),
+            Err(e) => defmt::error!("Could not measure VDDA: {:?}", e),
+        }
+
         let mut data = [0; 6];
         // i2c1.write(ADXL345_ADDRESS, &[ADXL345_Reg::DEVID as u8]);
         // i2c1.read(ADXL345_ADDRESS, &mut data[..1]);
@@ -71,6 +83,15 @@ fn main() -> ! {
             defmt::info!("X: {:?}", format(&data[0..2]));
             defmt::info!("Y: {:?}", format(&data[2..4]));
             defmt::info!("Z: {:?}", format(&data[4..6]));
+            match (adc.temperature(), adc.vbat()) {
+                (Ok(temperature), Ok(vbat)) => {
+                    defmt::info!("Die: {:?} °C, VBAT: {:?} V", temperature, vbat);
+                    if vbat < VBAT_LOW {
+                        defmt::warn!("RTC battery low");
+                    }
+                }
+                (Err(e), _) | (_, Err(e)) => defmt::error!("ADC: {:?}", e),
+            }
             delay(5_000_000); // Delay for at least n instruction cycles
         }
     };
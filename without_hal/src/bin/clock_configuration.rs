@@ -6,6 +6,7 @@ use stm32f4_playground as _; // Global logger + panicking-behavior
 use core::{cell::RefCell, ops::Deref};
 use cortex_m::{asm::wfi, interrupt::Mutex, peripheral::syst::SystClkSource};
 use stm32f4::stm32f401 as device;
+use stm32f4_playground::clocks::Clocks;
 
 static GPIOC: Mutex<RefCell<Option<device::GPIOC>>> = Mutex::new(RefCell::new(None));
 
@@ -27,7 +28,7 @@ fn main() -> ! {
             let gpioc = dp.GPIOC;
 
             // Initialize clock to use PLL and HSI for 84 MHz frequency
-            system_clock_init(&flash, &rcc);
+            Clocks::hsi_pll_84mhz(&flash, &rcc);
 
             // Use internal clock provided by the core for SysTick
             systick.set_clock_source(SystClkSource::Core);
@@ -59,53 +60,6 @@ fn main() -> ! {
     }
 }
 
-/// SystemCoreClock = ((INPUT_CLK / PLL_M) * PLL_N) / PLL_P
-/// See Section 6,  Figure 12 of RM0368
-fn system_clock_init(flash: &device::FLASH, rcc: &device::RCC) {
-    // TODO: Replace this with safe code after PAC update
-    // https://github.com/stm32-rs/stm32-rs/pull/374
-    // To read data from FLASH memory, the correct number of wait states must
-    // be set, two wait states, if 60 < HCLK ≤ 84 and 2.7V - 3.6V
-    #[allow(unsafe_code)]
-    unsafe {
-        flash.acr.write(|w| w.bits(2));
-    }
-
-    // Enable the Internal High Speed oscillator (HSI)
-    rcc.cr.modify(|_, w| w.hsion().on());
-    while rcc.cr.read().hsirdy().is_not_ready() {}
-
-    // Select HSI as clock source for PLL
-    rcc.pllcfgr.modify(|_, w| w.pllsrc().hsi());
-    // Configure PLL to output 84 MHz, where HSI = 16 MHz
-    // ((16 / 16) * 336) / 4 = 84
-    #[allow(unsafe_code)]
-    unsafe {
-        rcc.pllcfgr.modify(|_, w| {
-            w.pllm()
-                .bits(16)
-                .plln()
-                .bits(336)
-                .pllp()
-                .div4()
-                .pllq()
-                .bits(7)
-        });
-    }
-
-    // Enable Phase Lock Loop (PLL)
-    rcc.cr.modify(|_, w| w.pllon().on());
-    while rcc.cr.read().pllrdy().is_not_ready() {}
-
-    // AHB will run at 84 MHz, APB1 at 42 MHz, APB2 at 84 MHz
-    rcc.cfgr
-        .modify(|_, w| w.hpre().div1().ppre1().div2().ppre2().div1());
-
-    // Select PLL as system clock input
-    rcc.cfgr.modify(|_, w| w.sw().pll());
-    while !rcc.cfgr.read().sws().is_pll() {}
-}
-
 // This is the exception handler that gets called when the the SysTick
 // triggers an exception after its countdown
 #[cortex_m_rt::exception]
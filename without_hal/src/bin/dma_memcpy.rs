@@ -0,0 +1,85 @@
+#![deny(unsafe_code)]
+#![no_std]
+#![no_main]
+
+use stm32f4_playground as _; // Global logger + panicking-behavior
+use core::sync::atomic::{AtomicBool, Ordering};
+use stm32f4::stm32f401 as device;
+use stm32f4::stm32f401::interrupt;
+use stm32f4_playground::dma::{self, Config, Flags, Priority};
+
+/// Words copied per transfer
+const LEN: usize = 1024;
+
+/// Set from the DMA2 stream 0 interrupt
+static DONE: AtomicBool = AtomicBool::new(false);
+
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    defmt::info!("Copying memory with DMA2!");
+
+    // Take ownership of the device peripherals singleton
+    if let Some(dp) = device::Peripherals::take() {
+        // Take and own RCC RegisterBlock out of dp
+        let rcc = dp.RCC;
+        // Only DMA2 can copy from memory to memory
+        let streams = dma::split(dp.DMA2, &rcc);
+
+        // Buffers handed to the DMA must live forever
+        #[allow(unsafe_code)]
+        let src = cortex_m::singleton!(: [u32; LEN] = [0; LEN]).unwrap();
+        #[allow(unsafe_code)]
+        let dst = cortex_m::singleton!(: [u32; LEN] = [0; LEN]).unwrap();
+        for (i, word) in src.iter_mut().enumerate() {
+            *word = i as u32 * 0x0101_0101;
+        }
+
+        let config = Config {
+            priority: Priority::High,
+            complete_interrupt: true,
+            error_interrupt: true,
+            callback: Some(on_done),
+            ..Config::default()
+        };
+        let mut transfer = match streams.s0.memory_to_memory(src, dst, &config) {
+            Ok(transfer) => transfer,
+            Err((e, ..)) => defmt::panic!("Transfer rejected: {:?}", e),
+        };
+        // The CPU is free to do something else meanwhile, here it sleeps.
+        // DONE is checked with interrupts masked so the wakeup can not slip
+        // in between, the pending interrupt still ends the WFI.
+        while !DONE.load(Ordering::Acquire) {
+            cortex_m::interrupt::free(|_| {
+                if !DONE.load(Ordering::Acquire) {
+                    cortex_m::asm::wfi();
+                }
+            });
+        }
+        if let Err(e) = transfer.wait() {
+            defmt::panic!("Transfer failed: {:?}", e);
+        }
+
+        let (_stream, src, dst) = transfer.free();
+        if src == dst {
+            defmt::info!("Copied {:?} words", LEN);
+        } else {
+            defmt::error!("Copy differs from the original");
+        }
+    };
+
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
+
+/// Runs in the interrupt with the flags that fired
+fn on_done(flags: Flags) {
+    if flags.complete() || flags.transfer_error() {
+        DONE.store(true, Ordering::Release);
+    }
+}
+
+#[interrupt]
+fn DMA2_STREAM0() {
+    dma::on_interrupt::<device::DMA2>(0);
+}
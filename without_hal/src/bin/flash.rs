@@ -0,0 +1,52 @@
+#![deny(unsafe_code)]
+#![no_std]
+#![no_main]
+
+use stm32f4_playground as _; // Global logger + panicking-behavior
+use stm32f4::stm32f401 as device;
+use stm32f4_playground::flash::{self, Error, Flash, Parallelism};
+
+/// Last 128K sector, far away from this program. It also holds half of the
+/// key-value store, run `kv` afterwards to start that over.
+const SECTOR: u8 = 5;
+
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    defmt::info!("Erasing and programming flash sector {:?}!", SECTOR);
+
+    // Take ownership of the device peripherals singleton
+    if let Some(dp) = device::Peripherals::take() {
+        // Powered from USB, 3.3 V allows 32 bits at a time
+        let mut flash = Flash::new(dp.FLASH, Parallelism::X32);
+        defmt::info!("Option bytes: {:?}", flash.option_bytes());
+
+        let (start, _) = flash::SECTORS[usize::from(SECTOR)];
+        flash.erase_sector(SECTOR).unwrap();
+        defmt::info!("Erased, first word: {:?}", flash.read(start, 4));
+
+        // Something like a calibration record
+        let record = *b"calibration: 1.0234, offset: -17";
+        flash.program(start, &record).unwrap();
+        defmt::info!("Programmed and verified {:?} bytes", record.len());
+
+        // Clearing bits works without an erase, setting them does not
+        match flash.program(start, &[0xFF; 4]) {
+            Err(Error::NotErased) => defmt::info!("Setting bits again needs an erase"),
+            Err(e) => defmt::error!("Unexpected error: {:?}", e),
+            Ok(()) => defmt::error!("Bits went back to 1 without an erase?"),
+        }
+        flash.program(start, &[0; 4]).unwrap();
+        defmt::info!("Zeroed the first word: {:?}", flash.read(start, 4));
+
+        // This program itself can not be erased
+        match flash.erase_sector(0) {
+            Err(Error::Address) => defmt::info!("Sector 0 holds this program, refused"),
+            Err(e) => defmt::error!("Unexpected error: {:?}", e),
+            Ok(()) => defmt::error!("Erased the running program?"),
+        }
+    };
+
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
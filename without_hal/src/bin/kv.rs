@@ -0,0 +1,70 @@
+#![deny(unsafe_code)]
+#![no_std]
+#![no_main]
+
+use stm32f4_playground as _; // Global logger + panicking-behavior
+use stm32f4::stm32f401 as device;
+use stm32f4_playground::flash::{Flash, Parallelism};
+use stm32f4_playground::kv::{Error, Store};
+
+// Keys
+const BOOTS: u16 = 0;
+const CHANNEL: u16 = 1;
+const BAUD_RATE: u16 = 2;
+const OFFSET: u16 = 3;
+const LAST_ERROR: u16 = 4;
+
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    defmt::info!("Reset me, the settings stay!");
+
+    // Take ownership of the device peripherals singleton
+    if let Some(dp) = device::Peripherals::take() {
+        // Powered from USB, 3.3 V allows 32 bits at a time
+        let flash = Flash::new(dp.FLASH, Parallelism::X32);
+        let mut store = match Store::open(flash) {
+            Ok(store) => store,
+            Err((e, _)) => defmt::panic!("Opening the store failed: {:?}", e),
+        };
+
+        let boots = store.get::<u32>(BOOTS).unwrap_or(0) + 1;
+        store.set(BOOTS, &boots).unwrap();
+        defmt::info!("Boot number {:?}", boots);
+
+        // Defaults on the first boot, unchanged values are not written again
+        let channel = store.get::<u8>(CHANNEL).unwrap_or(76);
+        let baud_rate = store.get::<u32>(BAUD_RATE).unwrap_or(115_200);
+        // Something like a calibration that drifts a bit on every boot
+        let offset = store.get::<f32>(OFFSET).unwrap_or(0.0) + 0.01;
+        store.set(CHANNEL, &channel).unwrap();
+        store.set(BAUD_RATE, &baud_rate).unwrap();
+        store.set(OFFSET, &offset).unwrap();
+        defmt::info!(
+            "Channel {:?}, {:?} baud, offset {:?}",
+            channel,
+            baud_rate,
+            offset
+        );
+
+        // Only set on odd boots, removed on even ones
+        match store.get::<bool>(LAST_ERROR) {
+            Some(_) => defmt::info!("Error flag set by the last boot"),
+            None => defmt::info!("No error flag"),
+        }
+        if boots % 2 == 1 {
+            store.set(LAST_ERROR, &true).unwrap();
+        } else {
+            store.remove(LAST_ERROR).unwrap();
+        }
+
+        match store.set(0xFFFF, &0u8) {
+            Err(Error::Key) => defmt::info!("Key 0xFFFF is reserved"),
+            Err(e) => defmt::error!("Unexpected error: {:?}", e),
+            Ok(()) => defmt::error!("Stored under the reserved key?"),
+        }
+    };
+
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
@@ -0,0 +1,151 @@
+#![deny(unsafe_code)]
+#![no_std]
+#![no_main]
+
+use stm32f4_playground as _; // Global logger + panicking-behavior
+use core::convert::Infallible;
+use cortex_m::asm::delay;
+use embedded_hal::digital::v2::OutputPin;
+use embedded_nrf24l01::{Configuration, CrcMode, DataRate, NRF24L01};
+use stm32f4::stm32f401 as device;
+use stm32f4::stm32f401::interrupt;
+use stm32f4_playground::clocks::Clocks;
+use stm32f4_playground::spi::{self, Spi};
+
+/// nRF24L01 read register command, ORed with the register address
+const R_REGISTER: u8 = 0x00;
+/// nRF24L01 CONFIG register, 0x08 after power on
+const CONFIG: u8 = 0x00;
+
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    defmt::info!("nRF24L01 on a register-level SPI1!");
+
+    // Take ownership of the device peripherals singleton
+    if let Some(dp) = device::Peripherals::take() {
+        // Take and own RCC RegisterBlock out of dp
+        let rcc = dp.RCC;
+        // Take and own GPIOA & SPI1 out of dp
+        let (gpioa, spi1) = (dp.GPIOA, dp.SPI1);
+
+        /* GPIO configuration: PA5 = SCK1, PA6 = MISO1, PA7 = MOSI1,
+         * PA4 = CE, PA3 = CSN, same wiring as the with_hal radio examples */
+        // Enable clock for GPIOA
+        rcc.ahb1enr.write(|w| w.gpioaen().enabled());
+        // Set PA5-7 as alternate function, PA3-4 as outputs
+        gpioa.moder.write(|w| {
+            w.moder5()
+                .alternate()
+                .moder6()
+                .alternate()
+                .moder7()
+                .alternate()
+                .moder3()
+                .output()
+                .moder4()
+                .output()
+        });
+        // Alternate function mapping 5 for SPI1 (see DS9716 datasheet)
+        gpioa
+            .afrl
+            .write(|w| w.afrl5().af5().afrl6().af5().afrl7().af5());
+        // Set GPIO speed for PA5-7 as high speed
+        gpioa.ospeedr.write(|w| {
+            w.ospeedr5()
+                .high_speed()
+                .ospeedr6()
+                .high_speed()
+                .ospeedr7()
+                .high_speed()
+        });
+        // CSN idles high, CE low
+        let mut csn = PinA(3);
+        let ce = PinA(4);
+        csn.set_high().ok();
+
+        /* SPI1 setup, the nRF24L01 takes up to 10 MHz in mode 0 */
+        // Nothing configured the clocks, so this is the HSI: SCK = 16 / 2 MHz
+        let clocks = Clocks::read(&rcc);
+        let config = spi::Config {
+            frequency: 8_000_000,
+            ..spi::Config::default()
+        };
+        let mut spi = Spi::new(spi1, &rcc, &clocks, &config).unwrap();
+        defmt::info!("SPI1 SCK at {:?} Hz", spi.frequency());
+
+        // Peek at CONFIG with an interrupt transfer, the first byte received
+        // is STATUS and the second CONFIG
+        #[allow(unsafe_code)]
+        let buf = cortex_m::singleton!(: [u8; 2] = [R_REGISTER | CONFIG, 0]).unwrap();
+        csn.set_low().ok();
+        spi.start_transfer(buf).map_err(|(e, _)| e).unwrap();
+        let (buf, result) = spi.wait_transfer().unwrap();
+        csn.set_high().ok();
+        result.unwrap();
+        defmt::info!("STATUS: {:?}, CONFIG: {:?}", buf[0], buf[1]);
+
+        // From here on the driver does blocking transfers through the
+        // embedded_hal traits
+        let mut radio = NRF24L01::new(ce, csn, spi).unwrap();
+        radio.set_frequency(8).unwrap();
+        radio.set_rf(&DataRate::R2Mbps, 3).unwrap();
+        radio.set_crc(CrcMode::TwoBytes).unwrap();
+        radio.set_auto_retransmit(5, 15).unwrap();
+        radio.set_auto_ack(&[true; 6]).unwrap();
+        radio.set_tx_addr(b"stm32").unwrap();
+        // Acknowledgements arrive on pipe 0
+        radio.set_rx_addr(0, b"stm32").unwrap();
+        let mut tx = radio.tx().map_err(|(e, _)| e).unwrap();
+
+        loop {
+            tx.send(b"Look Ma, No HAL!").unwrap();
+            let delivered = loop {
+                match tx.poll_send() {
+                    Ok(delivered) => break delivered,
+                    Err(nb::Error::WouldBlock) => {}
+                    Err(nb::Error::Other(_)) => defmt::panic!("Lost the radio"),
+                }
+            };
+            if delivered {
+                defmt::info!("Delivered");
+            } else {
+                defmt::warn!("No acknowledgement");
+            }
+            delay(16_000_000); // About a second at 16 MHz
+        }
+    };
+
+    defmt::panic!("Uh oh, reached unreachable code!");
+}
+
+/// Push-pull output on GPIOA
+struct PinA(u8);
+
+impl OutputPin for PinA {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        // Bits 16-31 of BSRR reset the pin atomically
+        #[allow(unsafe_code)]
+        unsafe {
+            (*device::GPIOA::ptr())
+                .bsrr
+                .write(|w| w.bits(1 << (self.0 + 16)));
+        }
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        // Bits 0-15 of BSRR set the pin atomically
+        #[allow(unsafe_code)]
+        unsafe {
+            (*device::GPIOA::ptr()).bsrr.write(|w| w.bits(1 << self.0));
+        }
+        Ok(())
+    }
+}
+
+#[interrupt]
+fn SPI1() {
+    spi::on_interrupt();
+}
@@ -0,0 +1,66 @@
+#![deny(unsafe_code)]
+#![no_std]
+#![no_main]
+
+use stm32f4_playground as _; // Global logger + panicking-behavior
+use stm32f4::stm32f401 as device;
+use stm32f4::stm32f401::interrupt;
+use stm32f4_playground::clocks::Clocks;
+use stm32f4_playground::power::{Power, Regulator};
+use stm32f4_playground::rtc::{self, Rtc, Source};
+
+/// Stop mode naps before going into Standby
+const NAPS: u32 = 3;
+
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    defmt::info!("Low-power modes, press PA0 to leave Standby early!");
+
+    // Take ownership of the core & device peripheral singletons
+    if let (Some(cp), Some(dp)) = (
+        cortex_m::Peripherals::take(),
+        device::Peripherals::take(),
+    ) {
+        // Take and own SCB out of cp
+        let mut scb = cp.SCB;
+        // Take and own FLASH, RCC & PWR RegisterBlocks out of dp
+        let (flash, rcc, pwr) = (dp.FLASH, dp.RCC, dp.PWR);
+
+        let mut power = Power::new(pwr, &rcc);
+        defmt::info!("Reset reason: {:?}", power.reset_reason());
+
+        // Stop mode falls back to the HSI, this is what has to come back
+        Clocks::hsi_pll_84mhz(&flash, &rcc);
+
+        // The LSI keeps running in Stop and Standby on any board
+        let mut rtc = match Rtc::new(dp.RTC, &rcc, Source::Lsi) {
+            Ok(rtc) => rtc,
+            Err((e, _)) => defmt::panic!("RTC failed: {:?}", e),
+        };
+        rtc.enable_wakeup(2000).unwrap();
+
+        for nap in 1..=NAPS {
+            // Low-power regulator and flash off, woken by the RTC
+            power.stop(&mut scb, &rcc, Regulator::LowPower, true);
+            defmt::info!(
+                "Nap {:?}: woken by the RTC: {:?}, SYSCLK back at {:?} Hz",
+                nap,
+                rtc.wakeup_fired(),
+                Clocks::read(&rcc).sysclk
+            );
+        }
+
+        defmt::info!("Standby for 10 s");
+        rtc.enable_wakeup(10_000).unwrap();
+        power.standby(&mut scb, true);
+    };
+
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
+
+#[interrupt]
+fn RTC_WKUP() {
+    rtc::on_wakeup_interrupt();
+}
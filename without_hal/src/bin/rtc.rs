@@ -0,0 +1,94 @@
+#![deny(unsafe_code)]
+#![no_std]
+#![no_main]
+
+use stm32f4_playground as _; // Global logger + panicking-behavior
+use stm32f4::stm32f401 as device;
+use stm32f4::stm32f401::interrupt;
+use stm32f4_playground::rtc::{self, Alarm, AlarmTime, DateTime, Rtc, Source};
+
+/// Backup register counting the resets since the backup domain lost power
+const BOOTS: usize = 0;
+/// Where the calendar starts if it was never set
+const EPOCH: DateTime = DateTime {
+    year: 2021,
+    month: 1,
+    day: 1,
+    weekday: 5,
+    hours: 0,
+    minutes: 0,
+    seconds: 0,
+};
+
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    defmt::info!("Reset me, the timestamps keep counting!");
+
+    // Take ownership of the device peripherals singleton
+    if let Some(dp) = device::Peripherals::take() {
+        // Take and own RCC RegisterBlock out of dp
+        let rcc = dp.RCC;
+
+        // Prefer the 32.768 kHz crystal, which keeps time while VDD is off.
+        // A board that had to fall back to the LSI keeps it, so its calendar
+        // is not reset on every boot.
+        let source = match rtc::running_source(&rcc) {
+            Some(Source::Lsi) => Source::Lsi,
+            _ => Source::Lse,
+        };
+        let mut rtc = match Rtc::new(dp.RTC, &rcc, source) {
+            Ok(rtc) => rtc,
+            Err((e, rtc)) if source == Source::Lse => {
+                defmt::warn!("LSE failed: {:?}, falling back to the LSI", e);
+                match Rtc::new(rtc, &rcc, Source::Lsi) {
+                    Ok(rtc) => rtc,
+                    Err((e, _)) => defmt::panic!("LSI failed: {:?}", e),
+                }
+            }
+            Err((e, _)) => defmt::panic!("LSI failed: {:?}", e),
+        };
+        if !rtc.is_set() {
+            defmt::info!("Calendar was reset, starting over");
+            rtc.set_date_time(&EPOCH).unwrap();
+        }
+
+        let boots = rtc.backup(BOOTS) + 1;
+        rtc.set_backup(BOOTS, boots);
+        defmt::info!("{:?} boot #{:?}", rtc.date_time(), boots);
+
+        // Alarm A at the start of every minute
+        let minute = AlarmTime {
+            seconds: Some(0),
+            ..AlarmTime::default()
+        };
+        rtc.set_alarm(Alarm::A, &minute).unwrap();
+        // Wakeup timer every 5 s
+        rtc.enable_wakeup(5000).unwrap();
+
+        loop {
+            // Both interrupts end the WFI, at worst a flag set just before it
+            // is noticed one wakeup period late
+            cortex_m::asm::wfi();
+            if rtc.wakeup_fired() {
+                defmt::info!("{:?} tick", rtc.date_time());
+            }
+            if rtc.alarm_fired(Alarm::A) {
+                defmt::info!("{:?} another minute", rtc.date_time());
+            }
+        }
+    };
+
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
+
+#[interrupt]
+fn RTC_ALARM() {
+    rtc::on_alarm_interrupt();
+}
+
+#[interrupt]
+fn RTC_WKUP() {
+    rtc::on_wakeup_interrupt();
+}
@@ -0,0 +1,159 @@
+#![deny(unsafe_code)]
+#![no_std]
+#![no_main]
+
+use stm32f4_playground as _; // Global logger + panicking-behavior
+use core::convert::Infallible;
+use core::sync::atomic::{AtomicU32, Ordering};
+use embedded_hal::digital::v2::OutputPin;
+use stm32f4::stm32f401 as device;
+use stm32f4::stm32f401::interrupt;
+use stm32f4_playground::clocks::Clocks;
+use stm32f4_playground::dma::{self, Flags};
+use stm32f4_playground::spi::{self, dma::SpiDma, Spi};
+
+/// Bytes per transaction, e.g. a few lines of a display
+const LEN: usize = 4096;
+
+/// Transactions the stream interrupts reported as done
+static COMPLETED: AtomicU32 = AtomicU32::new(0);
+
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    defmt::info!("SPI1 loopback via DMA2, connect PA6 (MISO) to PA7 (MOSI)!");
+
+    // Take ownership of the device peripherals singleton
+    if let Some(dp) = device::Peripherals::take() {
+        // Take and own RCC RegisterBlock out of dp
+        let rcc = dp.RCC;
+        // Take and own GPIOA, SPI1 & DMA2 out of dp
+        let (gpioa, spi1, dma2) = (dp.GPIOA, dp.SPI1, dp.DMA2);
+
+        /* GPIO configuration: PA5 = SCK1, PA6 = MISO1, PA7 = MOSI1, PA4 = CS */
+        // Enable clock for GPIOA
+        rcc.ahb1enr.write(|w| w.gpioaen().enabled());
+        // Set PA5-7 as alternate function, PA4 as an output
+        gpioa.moder.write(|w| {
+            w.moder5()
+                .alternate()
+                .moder6()
+                .alternate()
+                .moder7()
+                .alternate()
+                .moder4()
+                .output()
+        });
+        // Alternate function mapping 5 for SPI1 (see DS9716 datasheet)
+        gpioa
+            .afrl
+            .write(|w| w.afrl5().af5().afrl6().af5().afrl7().af5());
+        // Set GPIO speed for PA5-7 as high speed
+        gpioa.ospeedr.write(|w| {
+            w.ospeedr5()
+                .high_speed()
+                .ospeedr6()
+                .high_speed()
+                .ospeedr7()
+                .high_speed()
+        });
+
+        /* SPI1 at the full 8 MHz the HSI allows, RX on DMA2 stream 0 and
+         * TX on DMA2 stream 3 */
+        let clocks = Clocks::read(&rcc);
+        let config = spi::Config {
+            frequency: 8_000_000,
+            ..spi::Config::default()
+        };
+        let spi = Spi::new(spi1, &rcc, &clocks, &config).unwrap();
+        let streams = dma::split(dma2, &rcc);
+        let mut spi = SpiDma::new(spi, streams.s3, streams.s0, PinA(4));
+        spi.set_callback(Some(on_complete));
+
+        // Buffers handed to the DMA must live forever
+        #[allow(unsafe_code)]
+        let tx = cortex_m::singleton!(: [u8; LEN] = [0; LEN]).unwrap();
+        #[allow(unsafe_code)]
+        let rx = cortex_m::singleton!(: [u8; LEN] = [0; LEN]).unwrap();
+        for (i, byte) in tx.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+
+        // Full duplex, everything sent should come right back
+        let mut transaction = match spi.transfer(tx, rx) {
+            Ok(transaction) => transaction,
+            Err((e, ..)) => defmt::panic!("Transfer rejected: {:?}", e),
+        };
+        // The CPU is free to do something else meanwhile
+        let result = transaction.wait();
+        let (spi, tx, rx) = transaction.free();
+        match result {
+            Ok(()) if tx == rx => defmt::info!("{:?} bytes looped back", LEN),
+            Ok(()) => defmt::error!("Received something else, is PA6 wired to PA7?"),
+            Err(e) => defmt::error!("Transfer failed: {:?}", e),
+        }
+
+        // Write-only, like pushing a framebuffer to a display
+        let mut transaction = match spi.write(tx) {
+            Ok(transaction) => transaction,
+            Err((e, ..)) => defmt::panic!("Write rejected: {:?}", e),
+        };
+        if let Err(e) = transaction.wait() {
+            defmt::error!("Write failed: {:?}", e);
+        }
+        let _ = transaction.free();
+        defmt::info!(
+            "{:?} transactions completed",
+            COMPLETED.load(Ordering::Relaxed)
+        );
+    };
+
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
+
+/// Runs in the interrupt of the stream that finished
+fn on_complete(flags: Flags) {
+    if flags.complete() {
+        COMPLETED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Push-pull output on GPIOA
+struct PinA(u8);
+
+impl OutputPin for PinA {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        // Bits 16-31 of BSRR reset the pin atomically
+        #[allow(unsafe_code)]
+        unsafe {
+            (*device::GPIOA::ptr())
+                .bsrr
+                .write(|w| w.bits(1 << (self.0 + 16)));
+        }
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        // Bits 0-15 of BSRR set the pin atomically
+        #[allow(unsafe_code)]
+        unsafe {
+            (*device::GPIOA::ptr()).bsrr.write(|w| w.bits(1 << self.0));
+        }
+        Ok(())
+    }
+}
+
+// RX stream, finishes full duplex transfers
+#[interrupt]
+fn DMA2_STREAM0() {
+    dma::on_interrupt::<device::DMA2>(0);
+}
+
+// TX stream, finishes writes
+#[interrupt]
+fn DMA2_STREAM3() {
+    dma::on_interrupt::<device::DMA2>(3);
+}
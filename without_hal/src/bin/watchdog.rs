@@ -0,0 +1,70 @@
+#![deny(unsafe_code)]
+#![no_std]
+#![no_main]
+
+use stm32f4_playground as _; // Global logger + panicking-behavior
+use stm32f4::stm32f401 as device;
+use stm32f4::stm32f401::interrupt;
+use stm32f4_playground::clocks::Clocks;
+use stm32f4_playground::power::{Power, ResetReason};
+use stm32f4_playground::watchdog::{self, IndependentWatchdog, WindowWatchdog};
+
+/// Feeds before pretending to hang
+const FEEDS: u32 = 100;
+
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    defmt::info!("Watchdogs, each boot hangs on purpose and the next one tells why!");
+
+    // Take ownership of the device peripherals singleton
+    if let Some(dp) = device::Peripherals::take() {
+        // Take and own RCC RegisterBlock out of dp
+        let rcc = dp.RCC;
+        let clocks = Clocks::read(&rcc);
+
+        // Boot-time report, also kept around for the application
+        let power = Power::new(dp.PWR, &rcc);
+        defmt::info!("Reset reason: {:?}", power.reset_reason());
+        defmt::info!("Reset flags: {:?}", power.reset_flags());
+
+        // Neither watchdog can be stopped, take turns between boots
+        let mut wwdg = if power.reset_reason() == ResetReason::WindowWatchdog {
+            None
+        } else {
+            // Fed every 20 ms, so between 10 and 50 ms is fine
+            let mut wwdg = WindowWatchdog::start(dp.WWDG, &rcc, &clocks, 50, 10).unwrap();
+            wwdg.enable_early_wakeup(Some(last_words));
+            Some(wwdg)
+        };
+        let mut iwdg = IndependentWatchdog::start(dp.IWDG, 1000).unwrap();
+        defmt::info!("IWDG timeout: {:?} ms", iwdg.timeout_ms());
+
+        for _ in 0..FEEDS {
+            cortex_m::asm::delay(clocks.sysclk / 50);
+            iwdg.feed();
+            if let Some(wwdg) = &mut wwdg {
+                wwdg.feed();
+            }
+        }
+
+        // Like waiting for a flag of a peripheral that never answers
+        defmt::warn!("Stuck!");
+        loop {
+            cortex_m::asm::nop();
+        }
+    };
+
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
+
+/// Runs from the early wakeup interrupt, one WWDG tick before the reset
+fn last_words() {
+    defmt::error!("WWDG about to reset");
+}
+
+#[interrupt]
+fn WWDG() {
+    watchdog::on_interrupt();
+}
@@ -0,0 +1,140 @@
+//! Bus clock frequencies, decoded from whatever the RCC is currently set to
+//!
+//! Peripherals that divide a bus clock (baud rates, SPI prescalers) need the
+//! frequency of that bus. Rather than trusting the caller, [`Clocks::read`]
+//! works it out from RCC_CFGR and RCC_PLLCFGR, see Section 6.2 of RM0368.
+use stm32f4::stm32f401 as device;
+
+/// Internal high speed oscillator
+pub const HSI_HZ: u32 = 16_000_000;
+/// External crystal of the WeAct "Black Pill" boards
+pub const HSE_HZ: u32 = 25_000_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub struct Clocks {
+    /// System clock (SYSCLK)
+    pub sysclk: u32,
+    /// AHB clock (HCLK), feeds the core and the GPIO ports
+    pub hclk: u32,
+    /// APB1 clock, at most 42 MHz (SPI2, SPI3, USART2, I2C, TIM2-5)
+    pub pclk1: u32,
+    /// APB2 clock, at most 84 MHz (SPI1, USART1, USART6, ADC1, TIM1)
+    pub pclk2: u32,
+}
+
+impl Clocks {
+    /// Reset state, everything runs from the HSI
+    pub const fn hsi() -> Self {
+        Clocks {
+            sysclk: HSI_HZ,
+            hclk: HSI_HZ,
+            pclk1: HSI_HZ,
+            pclk2: HSI_HZ,
+        }
+    }
+
+    /// Runs everything from the HSI through the PLL at the 84 MHz maximum,
+    /// except APB1 at 42 MHz
+    ///
+    /// SystemCoreClock = ((INPUT_CLK / PLL_M) * PLL_N) / PLL_P
+    /// See Section 6,  Figure 12 of RM0368
+    pub fn hsi_pll_84mhz(flash: &device::FLASH, rcc: &device::RCC) -> Self {
+        // TODO: Replace this with safe code after PAC update
+        // https://github.com/stm32-rs/stm32-rs/pull/374
+        // To read data from FLASH memory, the correct number of wait states must
+        // be set, two wait states, if 60 < HCLK ≤ 84 and 2.7V - 3.6V
+        unsafe {
+            flash.acr.write(|w| w.bits(2));
+        }
+
+        // Enable the Internal High Speed oscillator (HSI)
+        rcc.cr.modify(|_, w| w.hsion().on());
+        while rcc.cr.read().hsirdy().is_not_ready() {}
+
+        // Select HSI as clock source for PLL
+        rcc.pllcfgr.modify(|_, w| w.pllsrc().hsi());
+        // Configure PLL to output 84 MHz, where HSI = 16 MHz
+        // ((16 / 16) * 336) / 4 = 84
+        unsafe {
+            rcc.pllcfgr.modify(|_, w| {
+                w.pllm()
+                    .bits(16)
+                    .plln()
+                    .bits(336)
+                    .pllp()
+                    .div4()
+                    .pllq()
+                    .bits(7)
+            });
+        }
+
+        // Enable Phase Lock Loop (PLL)
+        rcc.cr.modify(|_, w| w.pllon().on());
+        while rcc.cr.read().pllrdy().is_not_ready() {}
+
+        // AHB will run at 84 MHz, APB1 at 42 MHz, APB2 at 84 MHz
+        rcc.cfgr
+            .modify(|_, w| w.hpre().div1().ppre1().div2().ppre2().div1());
+
+        // Select PLL as system clock input
+        rcc.cfgr.modify(|_, w| w.sw().pll());
+        while !rcc.cfgr.read().sws().is_pll() {}
+
+        Clocks {
+            sysclk: 84_000_000,
+            hclk: 84_000_000,
+            pclk1: 42_000_000,
+            pclk2: 84_000_000,
+        }
+    }
+
+    /// Decodes the current clock tree, assuming an HSE of [`HSE_HZ`]
+    pub fn read(rcc: &device::RCC) -> Self {
+        let cfgr = rcc.cfgr.read();
+        let sysclk = match cfgr.sws().bits() {
+            0b01 => HSE_HZ,
+            0b10 => {
+                let pllcfgr = rcc.pllcfgr.read();
+                let input = if pllcfgr.pllsrc().bit_is_set() {
+                    HSE_HZ
+                } else {
+                    HSI_HZ
+                };
+                // f_VCO = f_in * (PLLN / PLLM), f_PLL = f_VCO / PLLP
+                let vco =
+                    input / u32::from(pllcfgr.pllm().bits()) * u32::from(pllcfgr.plln().bits());
+                vco / (2 * (u32::from(pllcfgr.pllp().bits()) + 1))
+            }
+            _ => HSI_HZ,
+        };
+        // HPRE 0xxx: not divided, 1000: /2 ... 1111: /512 (there is no /32)
+        let hclk = match cfgr.hpre().bits() {
+            hpre @ 0b1000..=0b1011 => sysclk >> (hpre - 0b0111),
+            hpre @ 0b1100..=0b1111 => sysclk >> (hpre - 0b0110),
+            _ => sysclk,
+        };
+        Clocks {
+            sysclk,
+            hclk,
+            pclk1: apb(hclk, cfgr.ppre1().bits()),
+            pclk2: apb(hclk, cfgr.ppre2().bits()),
+        }
+    }
+
+    /// Clock of the APB1 timers (TIM2-5), twice PCLK1 if APB1 is divided
+    pub fn timclk1(&self) -> u32 {
+        if self.pclk1 == self.hclk {
+            self.pclk1
+        } else {
+            self.pclk1 * 2
+        }
+    }
+}
+
+/// PPRE 0xx: not divided, 100: /2 ... 111: /16
+fn apb(hclk: u32, ppre: u8) -> u32 {
+    match ppre {
+        0b100..=0b111 => hclk >> (ppre - 0b011),
+        _ => hclk,
+    }
+}
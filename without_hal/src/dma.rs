@@ -0,0 +1,716 @@
+//! DMA1/DMA2 streams, see Section 9 of RM0368
+//!
+//! [`split`] hands out the eight streams of a controller, each of which can
+//! run one transfer at a time. A transfer owns its stream, the peripheral it
+//! serves and its buffers until it is freed, so neither the CPU nor another
+//! stream can touch memory the DMA is using. Buffers must be `'static` (see
+//! `embedded_dma`), a stack buffer could go away while still being written.
+//!
+//! Drivers make a data register available by implementing
+//! [`PeripheralTarget`]. Which stream and channel serve which peripheral
+//! request is fixed in hardware, see Tables 27 and 28 of RM0368.
+//!
+//! With interrupts enabled in the [`Config`], the handler of the stream must
+//! call [`on_interrupt`], which clears the flags, keeps them for the transfer
+//! and runs the [`Config::callback`].
+use core::cell::RefCell;
+use core::marker::PhantomData;
+use core::sync::atomic::{compiler_fence, Ordering};
+use cortex_m::interrupt::{self, Mutex};
+use cortex_m::peripheral::NVIC;
+use embedded_dma::{ReadBuffer, WriteBuffer};
+use stm32f4::stm32f401::{self as device, dma2::RegisterBlock, Interrupt};
+
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum Error {
+    /// Bus error, e.g. an address outside of RAM or a peripheral
+    Transfer,
+    /// The peripheral asked for the next word before the last one was moved
+    DirectMode,
+    /// FIFO overrun or underrun
+    Fifo,
+    /// The CPU did not keep up with a circular or double-buffer transfer
+    Overrun,
+    /// Buffer empty or longer than 65535 words
+    Length,
+    /// Circular mode is not allowed for memory-to-memory transfers
+    Config,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum Channel {
+    C0,
+    C1,
+    C2,
+    C3,
+    C4,
+    C5,
+    C6,
+    C7,
+}
+
+/// Arbitration between streams of the same controller
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum Priority {
+    Low,
+    Medium,
+    High,
+    VeryHigh,
+}
+
+/// FIFO fill level that triggers a burst to memory
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum FifoThreshold {
+    Quarter,
+    Half,
+    ThreeQuarters,
+    Full,
+}
+
+/// Half of a circular buffer
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum Half {
+    First,
+    Second,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Config {
+    pub channel: Channel,
+    pub priority: Priority,
+    /// Restart at the beginning of the buffer after the last word
+    pub circular: bool,
+    /// `None` for direct mode, where each request moves one word straight
+    /// through. Memory-to-memory transfers always use the FIFO.
+    pub fifo: Option<FifoThreshold>,
+    pub half_transfer_interrupt: bool,
+    pub complete_interrupt: bool,
+    /// Transfer, direct mode and FIFO errors
+    pub error_interrupt: bool,
+    /// Called by [`on_interrupt`] with the flags that fired
+    pub callback: Option<fn(Flags)>,
+}
+
+impl Default for Config {
+    /// Channel 0, low priority, direct mode, one shot and no interrupts
+    fn default() -> Self {
+        Config {
+            channel: Channel::C0,
+            priority: Priority::Low,
+            circular: false,
+            fifo: None,
+            half_transfer_interrupt: false,
+            complete_interrupt: false,
+            error_interrupt: false,
+            callback: None,
+        }
+    }
+}
+
+/// Status flags of a stream, laid out as in LISR/HISR
+#[derive(Clone, Copy, Debug, Default, PartialEq, defmt::Format)]
+pub struct Flags(u8);
+
+impl Flags {
+    const FIFO_ERROR: u8 = 1 << 0;
+    const DIRECT_MODE_ERROR: u8 = 1 << 2;
+    const TRANSFER_ERROR: u8 = 1 << 3;
+    const HALF_TRANSFER: u8 = 1 << 4;
+    const COMPLETE: u8 = 1 << 5;
+    const ALL: u8 = 0b11_1101;
+
+    pub fn half_transfer(self) -> bool {
+        self.0 & Flags::HALF_TRANSFER != 0
+    }
+
+    pub fn complete(self) -> bool {
+        self.0 & Flags::COMPLETE != 0
+    }
+
+    pub fn transfer_error(self) -> bool {
+        self.0 & Flags::TRANSFER_ERROR != 0
+    }
+
+    pub fn direct_mode_error(self) -> bool {
+        self.0 & Flags::DIRECT_MODE_ERROR != 0
+    }
+
+    pub fn fifo_error(self) -> bool {
+        self.0 & Flags::FIFO_ERROR != 0
+    }
+
+    /// The first error, FIFO errors only count when the FIFO is in use
+    fn error(self, fifo: bool) -> Option<Error> {
+        if self.transfer_error() {
+            Some(Error::Transfer)
+        } else if self.direct_mode_error() {
+            Some(Error::DirectMode)
+        } else if fifo && self.fifo_error() {
+            Some(Error::Fifo)
+        } else {
+            None
+        }
+    }
+}
+
+/// Size of a word moved per request
+///
+/// # Safety
+///
+/// `SIZE` must be the PSIZE/MSIZE encoding of the type's size
+pub unsafe trait Word {
+    const SIZE: u32;
+}
+
+unsafe impl Word for u8 {
+    const SIZE: u32 = 0b00;
+}
+
+unsafe impl Word for u16 {
+    const SIZE: u32 = 0b01;
+}
+
+unsafe impl Word for u32 {
+    const SIZE: u32 = 0b10;
+}
+
+/// A peripheral data register a stream can read from or write to
+///
+/// # Safety
+///
+/// `address` must be a register that accepts accesses of `Word` size for as
+/// long as the implementor exists, and nothing else may use the register while
+/// a transfer owns the implementor
+pub unsafe trait PeripheralTarget {
+    type Word: Word;
+
+    fn address(&self) -> u32;
+}
+
+/// A DMA controller
+pub trait Instance {
+    /// 0 for DMA1, 1 for DMA2
+    const INDEX: usize;
+    const INTERRUPTS: [Interrupt; 8];
+
+    fn registers() -> &'static RegisterBlock;
+
+    fn enable(rcc: &device::RCC);
+}
+
+impl Instance for device::DMA1 {
+    const INDEX: usize = 0;
+    const INTERRUPTS: [Interrupt; 8] = [
+        Interrupt::DMA1_STREAM0,
+        Interrupt::DMA1_STREAM1,
+        Interrupt::DMA1_STREAM2,
+        Interrupt::DMA1_STREAM3,
+        Interrupt::DMA1_STREAM4,
+        Interrupt::DMA1_STREAM5,
+        Interrupt::DMA1_STREAM6,
+        Interrupt::DMA1_STREAM7,
+    ];
+
+    fn registers() -> &'static RegisterBlock {
+        // SAFETY: Each stream only touches its own registers and flags
+        unsafe { &*device::DMA1::ptr() }
+    }
+
+    fn enable(rcc: &device::RCC) {
+        rcc.ahb1enr.modify(|_, w| w.dma1en().enabled());
+    }
+}
+
+impl Instance for device::DMA2 {
+    const INDEX: usize = 1;
+    const INTERRUPTS: [Interrupt; 8] = [
+        Interrupt::DMA2_STREAM0,
+        Interrupt::DMA2_STREAM1,
+        Interrupt::DMA2_STREAM2,
+        Interrupt::DMA2_STREAM3,
+        Interrupt::DMA2_STREAM4,
+        Interrupt::DMA2_STREAM5,
+        Interrupt::DMA2_STREAM6,
+        Interrupt::DMA2_STREAM7,
+    ];
+
+    fn registers() -> &'static RegisterBlock {
+        // SAFETY: Each stream only touches its own registers and flags
+        unsafe { &*device::DMA2::ptr() }
+    }
+
+    fn enable(rcc: &device::RCC) {
+        rcc.ahb1enr.modify(|_, w| w.dma2en().enabled());
+    }
+}
+
+pub struct Streams<DMA> {
+    pub s0: Stream<DMA>,
+    pub s1: Stream<DMA>,
+    pub s2: Stream<DMA>,
+    pub s3: Stream<DMA>,
+    pub s4: Stream<DMA>,
+    pub s5: Stream<DMA>,
+    pub s6: Stream<DMA>,
+    pub s7: Stream<DMA>,
+}
+
+/// Enables the controller and hands out its streams
+pub fn split<DMA: Instance>(_dma: DMA, rcc: &device::RCC) -> Streams<DMA> {
+    DMA::enable(rcc);
+    Streams {
+        s0: Stream::new(0),
+        s1: Stream::new(1),
+        s2: Stream::new(2),
+        s3: Stream::new(3),
+        s4: Stream::new(4),
+        s5: Stream::new(5),
+        s6: Stream::new(6),
+        s7: Stream::new(7),
+    }
+}
+
+/// Flags latched by [`on_interrupt`] and callbacks, per DMA1 stream then
+/// per DMA2 stream
+struct Handlers {
+    flags: [u8; 16],
+    callbacks: [Option<fn(Flags)>; 16],
+}
+
+static HANDLERS: Mutex<RefCell<Handlers>> = Mutex::new(RefCell::new(Handlers {
+    flags: [0; 16],
+    callbacks: [None; 16],
+}));
+
+/// Must be called from the interrupt handler of every stream a transfer
+/// with interrupts runs on, e.g. `on_interrupt::<DMA2>(0)` from
+/// `DMA2_STREAM0`
+pub fn on_interrupt<DMA: Instance>(stream: usize) {
+    let stream = Stream::<DMA>::new(stream);
+    let flags = stream.clear_flags();
+    let callback = interrupt::free(|cs| {
+        let mut handlers = HANDLERS.borrow(cs).borrow_mut();
+        handlers.flags[stream.slot()] |= flags.0;
+        handlers.callbacks[stream.slot()]
+    });
+    if let Some(callback) = callback {
+        callback(flags);
+    }
+}
+
+// CR bits
+const EN: u32 = 1 << 0;
+const DMEIE: u32 = 1 << 1;
+const TEIE: u32 = 1 << 2;
+const HTIE: u32 = 1 << 3;
+const TCIE: u32 = 1 << 4;
+const DIR_P2M: u32 = 0b00 << 6;
+const DIR_M2P: u32 = 0b01 << 6;
+const DIR_M2M: u32 = 0b10 << 6;
+const CIRC: u32 = 1 << 8;
+const PINC: u32 = 1 << 9;
+const MINC: u32 = 1 << 10;
+const PSIZE: u32 = 11;
+const MSIZE: u32 = 13;
+const PL: u32 = 16;
+const DBM: u32 = 1 << 18;
+const CT: u32 = 1 << 19;
+const CHSEL: u32 = 25;
+
+// FCR bits
+const DMDIS: u32 = 1 << 2;
+const FEIE: u32 = 1 << 7;
+
+pub struct Stream<DMA> {
+    number: usize,
+    _dma: PhantomData<DMA>,
+}
+
+impl<DMA: Instance> Stream<DMA> {
+    fn new(number: usize) -> Self {
+        Stream {
+            number,
+            _dma: PhantomData,
+        }
+    }
+
+    pub fn number(&self) -> usize {
+        self.number
+    }
+
+    /// Interrupt whose handler must call [`on_interrupt`]
+    pub fn interrupt(&self) -> Interrupt {
+        DMA::INTERRUPTS[self.number]
+    }
+
+    /// Moves words from `peripheral` into `buf` as the peripheral requests
+    /// them
+    pub fn peripheral_to_memory<P, B>(
+        self,
+        peripheral: P,
+        mut buf: B,
+        config: &Config,
+    ) -> Result<Transfer<DMA, P, B>, Rejected<DMA, P, B>>
+    where
+        P: PeripheralTarget,
+        B: WriteBuffer<Word = P::Word>,
+    {
+        let (ptr, len) = unsafe { buf.write_buffer() };
+        let size = <P::Word as Word>::SIZE;
+        let cr = DIR_P2M | MINC | size << PSIZE | size << MSIZE;
+        let setup = Setup {
+            par: peripheral.address(),
+            m0ar: ptr as u32,
+            m1ar: None,
+            len,
+            cr,
+        };
+        match self.start(&setup, config) {
+            Ok(fifo) => Ok(Transfer::new(self, peripheral, buf, fifo)),
+            Err(e) => Err((e, self, peripheral, buf)),
+        }
+    }
+
+    /// Moves words from `buf` to `peripheral` as the peripheral requests
+    /// them
+    pub fn memory_to_peripheral<P, B>(
+        self,
+        peripheral: P,
+        buf: B,
+        config: &Config,
+    ) -> Result<Transfer<DMA, P, B>, Rejected<DMA, P, B>>
+    where
+        P: PeripheralTarget,
+        B: ReadBuffer<Word = P::Word>,
+    {
+        let (ptr, len) = unsafe { buf.read_buffer() };
+        let size = <P::Word as Word>::SIZE;
+        let cr = DIR_M2P | MINC | size << PSIZE | size << MSIZE;
+        let setup = Setup {
+            par: peripheral.address(),
+            m0ar: ptr as u32,
+            m1ar: None,
+            len,
+            cr,
+        };
+        match self.start(&setup, config) {
+            Ok(fifo) => Ok(Transfer::new(self, peripheral, buf, fifo)),
+            Err(e) => Err((e, self, peripheral, buf)),
+        }
+    }
+
+    /// Alternates between two buffers, the CPU works on one while the
+    /// stream fills or drains the other. Always circular.
+    pub fn double_buffer<P, B>(
+        self,
+        peripheral: P,
+        mut buffers: (B, B),
+        direction: Direction,
+        config: &Config,
+    ) -> Result<DoubleBuffer<DMA, P, B>, Rejected<DMA, P, (B, B)>>
+    where
+        P: PeripheralTarget,
+        B: WriteBuffer<Word = P::Word>,
+    {
+        let (m0, len0) = unsafe { buffers.0.write_buffer() };
+        let (m1, len1) = unsafe { buffers.1.write_buffer() };
+        let dir = match direction {
+            Direction::PeripheralToMemory => DIR_P2M,
+            Direction::MemoryToPeripheral => DIR_M2P,
+        };
+        let size = <P::Word as Word>::SIZE;
+        let cr = dir | MINC | CIRC | DBM | size << PSIZE | size << MSIZE;
+        let setup = Setup {
+            par: peripheral.address(),
+            m0ar: m0 as u32,
+            m1ar: Some(m1 as u32),
+            // Both halves have to be the same size
+            len: if len0 == len1 { len0 } else { 0 },
+            cr,
+        };
+        match self.start(&setup, config) {
+            Ok(fifo) => Ok(DoubleBuffer {
+                transfer: Transfer::new(self, peripheral, buffers, fifo),
+            }),
+            Err(e) => Err((e, self, peripheral, buffers)),
+        }
+    }
+
+    /// Clears the stream's flags in LIFCR/HIFCR, returns those that were set
+    fn clear_flags(&self) -> Flags {
+        let regs = DMA::registers();
+        // Streams 0-3 are in the low registers at bits 0, 6, 16 and 22, 4-7
+        // in the high ones at the same positions
+        let shift = [0, 6, 16, 22][self.number % 4];
+        if self.number < 4 {
+            let flags = (regs.lisr.read().bits() >> shift) as u8 & Flags::ALL;
+            regs.lifcr
+                .write(|w| unsafe { w.bits(u32::from(flags) << shift) });
+            Flags(flags)
+        } else {
+            let flags = (regs.hisr.read().bits() >> shift) as u8 & Flags::ALL;
+            regs.hifcr
+                .write(|w| unsafe { w.bits(u32::from(flags) << shift) });
+            Flags(flags)
+        }
+    }
+
+    /// Flags set since the last call, including those [`on_interrupt`] took
+    fn take_flags(&self) -> Flags {
+        let flags = self.clear_flags();
+        interrupt::free(|cs| {
+            let mut handlers = HANDLERS.borrow(cs).borrow_mut();
+            let latched = core::mem::take(&mut handlers.flags[self.slot()]);
+            Flags(flags.0 | latched)
+        })
+    }
+
+    fn slot(&self) -> usize {
+        DMA::INDEX * 8 + self.number
+    }
+
+    /// Configures and enables the stream, returns whether it uses the FIFO
+    fn start(&self, setup: &Setup, config: &Config) -> Result<bool, Error> {
+        if setup.len == 0 || setup.len > 0xFFFF {
+            return Err(Error::Length);
+        }
+        let memory_to_memory = setup.cr & DIR_M2M != 0;
+        if memory_to_memory && config.circular {
+            return Err(Error::Config);
+        }
+
+        let st = &DMA::registers().st[self.number];
+        // A stream can only be set up while disabled
+        self.disable();
+        self.clear_flags();
+        interrupt::free(|cs| {
+            let mut handlers = HANDLERS.borrow(cs).borrow_mut();
+            handlers.flags[self.slot()] = 0;
+            handlers.callbacks[self.slot()] = config.callback;
+        });
+
+        let mut cr = setup.cr | (config.channel as u32) << CHSEL | (config.priority as u32) << PL;
+        if config.circular {
+            cr |= CIRC;
+        }
+        if config.half_transfer_interrupt {
+            cr |= HTIE;
+        }
+        if config.complete_interrupt {
+            cr |= TCIE;
+        }
+        let fifo = match config.fifo {
+            Some(threshold) => Some(threshold),
+            // Direct mode does not exist between memories
+            None if memory_to_memory => Some(FifoThreshold::Full),
+            None => None,
+        };
+        let mut fcr = match fifo {
+            Some(threshold) => DMDIS | threshold as u32,
+            None => 0,
+        };
+        if config.error_interrupt {
+            cr |= TEIE | DMEIE;
+            if fifo.is_some() {
+                fcr |= FEIE;
+            }
+        }
+
+        st.par.write(|w| unsafe { w.bits(setup.par) });
+        st.m0ar.write(|w| unsafe { w.bits(setup.m0ar) });
+        if let Some(m1ar) = setup.m1ar {
+            st.m1ar.write(|w| unsafe { w.bits(m1ar) });
+        }
+        st.ndtr.write(|w| unsafe { w.bits(setup.len as u32) });
+        st.fcr.write(|w| unsafe { w.bits(fcr) });
+        // Everything written to the buffers so far must be in memory before
+        // the stream starts reading them
+        compiler_fence(Ordering::Release);
+        st.cr.write(|w| unsafe { w.bits(cr | EN) });
+
+        if cr & (HTIE | TCIE | TEIE) != 0 {
+            unsafe { NVIC::unmask(self.interrupt()) };
+        }
+        Ok(fifo.is_some())
+    }
+
+    /// Stops the stream, it finishes the word in flight first
+    fn disable(&self) {
+        let st = &DMA::registers().st[self.number];
+        st.cr.modify(|r, w| unsafe { w.bits(r.bits() & !EN) });
+        while st.cr.read().bits() & EN != 0 {}
+        // Nothing the stream wrote may be read before it stopped
+        compiler_fence(Ordering::Acquire);
+    }
+}
+
+impl Stream<device::DMA2> {
+    /// Copies `src` into `dst` as fast as the bus allows, DMA2 only. `dst`
+    /// must be at least as long as `src`.
+    pub fn memory_to_memory<S, B>(
+        self,
+        src: S,
+        mut dst: B,
+        config: &Config,
+    ) -> Result<Transfer<device::DMA2, S, B>, Rejected<device::DMA2, S, B>>
+    where
+        S: ReadBuffer,
+        S::Word: Word,
+        B: WriteBuffer<Word = S::Word>,
+    {
+        let (src_ptr, src_len) = unsafe { src.read_buffer() };
+        let (dst_ptr, dst_len) = unsafe { dst.write_buffer() };
+        // The peripheral port reads from the source
+        let size = <S::Word as Word>::SIZE;
+        let cr = DIR_M2M | PINC | MINC | size << PSIZE | size << MSIZE;
+        let setup = Setup {
+            par: src_ptr as u32,
+            m0ar: dst_ptr as u32,
+            m1ar: None,
+            len: if src_len <= dst_len { src_len } else { 0 },
+            cr,
+        };
+        match self.start(&setup, config) {
+            Ok(fifo) => Ok(Transfer::new(self, src, dst, fifo)),
+            Err(e) => Err((e, self, src, dst)),
+        }
+    }
+}
+
+/// A transfer that could not start, with everything it was given
+pub type Rejected<DMA, P, B> = (Error, Stream<DMA>, P, B);
+
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum Direction {
+    PeripheralToMemory,
+    MemoryToPeripheral,
+}
+
+/// Registers of a stream, before the [`Config`] is applied
+struct Setup {
+    par: u32,
+    m0ar: u32,
+    m1ar: Option<u32>,
+    len: usize,
+    cr: u32,
+}
+
+pub struct Transfer<DMA, P, B> {
+    stream: Stream<DMA>,
+    peripheral: P,
+    buf: B,
+    fifo: bool,
+    complete: bool,
+}
+
+impl<DMA: Instance, P, B> Transfer<DMA, P, B> {
+    fn new(stream: Stream<DMA>, peripheral: P, buf: B, fifo: bool) -> Self {
+        Transfer {
+            stream,
+            peripheral,
+            buf,
+            fifo,
+            complete: false,
+        }
+    }
+
+    /// `Ok` once every word has been moved, circular transfers never end
+    pub fn poll(&mut self) -> nb::Result<(), Error> {
+        if self.complete {
+            return Ok(());
+        }
+        let flags = self.stream.take_flags();
+        if let Some(e) = flags.error(self.fifo) {
+            return Err(nb::Error::Other(e));
+        }
+        if flags.complete() && !self.is_circular() {
+            self.complete = true;
+            return Ok(());
+        }
+        Err(nb::Error::WouldBlock)
+    }
+
+    /// Busy-waits for [`poll`](Transfer::poll)
+    pub fn wait(&mut self) -> Result<(), Error> {
+        nb::block!(self.poll())
+    }
+
+    /// For circular transfers: waits for the next half of the buffer to be
+    /// done with, which the CPU can then use until the stream comes back
+    /// to it
+    pub fn wait_half(&mut self) -> Result<Half, Error> {
+        loop {
+            let flags = self.stream.take_flags();
+            if let Some(e) = flags.error(self.fifo) {
+                return Err(e);
+            }
+            match (flags.half_transfer(), flags.complete()) {
+                (true, true) => return Err(Error::Overrun),
+                (true, false) => return Ok(Half::First),
+                (false, true) => return Ok(Half::Second),
+                (false, false) => {}
+            }
+        }
+    }
+
+    /// Words the stream has yet to move in this round
+    pub fn remaining(&self) -> usize {
+        DMA::registers().st[self.stream.number].ndtr.read().bits() as usize
+    }
+
+    /// The buffer, which the stream may still be using. Meant for circular
+    /// transfers, together with [`wait_half`](Transfer::wait_half).
+    pub fn peek(&self) -> &B {
+        compiler_fence(Ordering::Acquire);
+        &self.buf
+    }
+
+    /// Stops the stream if it is still running and hands everything back
+    pub fn free(self) -> (Stream<DMA>, P, B) {
+        self.stream.disable();
+        self.stream.take_flags();
+        (self.stream, self.peripheral, self.buf)
+    }
+
+    fn is_circular(&self) -> bool {
+        DMA::registers().st[self.stream.number].cr.read().bits() & CIRC != 0
+    }
+}
+
+pub struct DoubleBuffer<DMA, P, B> {
+    transfer: Transfer<DMA, P, (B, B)>,
+}
+
+impl<DMA: Instance, P, B> DoubleBuffer<DMA, P, B> {
+    /// Waits for the stream to switch buffers and returns the one it left,
+    /// which has to be dealt with before the stream is done with the other
+    pub fn next_buffer(&mut self) -> Result<&mut B, Error> {
+        loop {
+            let flags = self.transfer.stream.take_flags();
+            if let Some(e) = flags.error(self.transfer.fifo) {
+                return Err(e);
+            }
+            if flags.complete() {
+                break;
+            }
+        }
+        compiler_fence(Ordering::Acquire);
+        // CT names the buffer the stream is on now
+        let st = &DMA::registers().st[self.transfer.stream.number];
+        let buffers = &mut self.transfer.buf;
+        if st.cr.read().bits() & CT != 0 {
+            Ok(&mut buffers.0)
+        } else {
+            Ok(&mut buffers.1)
+        }
+    }
+
+    /// Words the stream has yet to move into or out of its current buffer
+    pub fn remaining(&self) -> usize {
+        self.transfer.remaining()
+    }
+
+    pub fn free(self) -> (Stream<DMA>, P, (B, B)) {
+        self.transfer.free()
+    }
+}
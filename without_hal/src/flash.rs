@@ -0,0 +1,318 @@
+//! Internal flash, see Section 3 of RM0368
+//!
+//! The 256K of the STM32F401xC are split into sectors of 16, 64 and 128K,
+//! the smallest unit that can be erased. Erasing sets every bit, programming
+//! can only clear bits again, so a programmed location can be overwritten
+//! as long as no bit has to go back to 1.
+//!
+//! Programming goes 8, 16 or 32 bits at a time, the wider the faster, but a
+//! wider [`Parallelism`] needs a higher supply voltage. Every operation
+//! unlocks the flash interface, waits for it and locks it again, and
+//! programmed data is read back to make sure it stuck.
+//!
+//! The sectors the running program occupies can not be erased, `memory.x`
+//! should keep the program away from sectors used for data.
+use core::ptr;
+use stm32f4::stm32f401 as device;
+
+/// Start and length of every sector, see Table 5 of RM0368
+pub const SECTORS: [(usize, usize); 6] = [
+    (0x0800_0000, 16 * 1024),
+    (0x0800_4000, 16 * 1024),
+    (0x0800_8000, 16 * 1024),
+    (0x0800_C000, 16 * 1024),
+    (0x0801_0000, 64 * 1024),
+    (0x0802_0000, 128 * 1024),
+];
+
+/// Value of an erased flash word
+pub const ERASED: u32 = 0xFFFF_FFFF;
+
+// FLASH_KEYR keys
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xCDEF_89AB;
+
+// FLASH_SR bits
+const EOP: u32 = 1 << 0;
+const OPERR: u32 = 1 << 1;
+const WRPERR: u32 = 1 << 4;
+const PGAERR: u32 = 1 << 5;
+const PGPERR: u32 = 1 << 6;
+const PGSERR: u32 = 1 << 7;
+const BSY: u32 = 1 << 16;
+
+// FLASH_CR bits
+const PG: u32 = 1 << 0;
+const SER: u32 = 1 << 1;
+const SNB: u32 = 3;
+const PSIZE: u32 = 8;
+const STRT: u32 = 1 << 16;
+const LOCK: u32 = 1 << 31;
+
+// FLASH_ACR bits
+const ICEN: u32 = 1 << 9;
+const DCEN: u32 = 1 << 10;
+const ICRST: u32 = 1 << 11;
+const DCRST: u32 = 1 << 12;
+
+// FLASH_OPTCR bits
+const BOR_LEV: u32 = 2;
+const WDG_SW: u32 = 1 << 5;
+const NRST_STOP: u32 = 1 << 6;
+const NRST_STDBY: u32 = 1 << 7;
+const RDP: u32 = 8;
+const NWRP: u32 = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum Error {
+    /// Outside of the flash, overlapping the running program or not aligned
+    /// to the parallelism
+    Address,
+    /// Programming sequence error (PGSERR)
+    Sequence,
+    /// Parallelism error (PGPERR), access size does not match PSIZE
+    Parallelism,
+    /// Alignment error (PGAERR)
+    Alignment,
+    /// Target sector is write protected (WRPERR)
+    WriteProtected,
+    /// A bit that is 0 would have to go back to 1, which takes an erase
+    NotErased,
+    /// Read back differs from what was programmed
+    Verify,
+}
+
+/// Bits programmed at a time, see Table 6 of RM0368
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum Parallelism {
+    /// 1.8 V - 3.6 V
+    X8,
+    /// 2.1 V - 3.6 V
+    X16,
+    /// 2.7 V - 3.6 V
+    X32,
+}
+
+impl Parallelism {
+    fn bytes(self) -> usize {
+        1 << self as usize
+    }
+}
+
+/// Option bytes as loaded at reset, see Section 3.6 of RM0368
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub struct OptionBytes {
+    /// 0xAA: level 0, 0xCC: level 2 (permanent), anything else: level 1
+    pub read_protection: u8,
+    /// Bit n set if sector n is write protected
+    pub write_protection: u8,
+    /// 0b11: off, 0b10: 2.2 V, 0b01: 2.5 V, 0b00: 2.8 V
+    pub bor_level: u8,
+    /// The IWDG starts on its own at reset
+    pub hardware_watchdog: bool,
+    /// Entering Stop mode resets instead
+    pub reset_on_stop: bool,
+    /// Entering Standby mode resets instead
+    pub reset_on_standby: bool,
+}
+
+/// Sector holding `address`
+pub fn sector(address: usize) -> Option<u8> {
+    SECTORS
+        .iter()
+        .position(|(start, len)| (*start..start + len).contains(&address))
+        .map(|sector| sector as u8)
+}
+
+/// End of the running program, the initial values of `.data` come last
+fn image_end() -> usize {
+    extern "C" {
+        static __sidata: u32;
+        static __sdata: u32;
+        static __edata: u32;
+    }
+    // SAFETY: Only the addresses of the linker symbols are taken
+    unsafe {
+        let data = &__edata as *const u32 as usize - &__sdata as *const u32 as usize;
+        &__sidata as *const u32 as usize + data
+    }
+}
+
+pub struct Flash {
+    flash: device::FLASH,
+    parallelism: Parallelism,
+}
+
+impl Flash {
+    /// Programs with `parallelism`, which must suit the supply voltage
+    pub fn new(flash: device::FLASH, parallelism: Parallelism) -> Self {
+        Flash { flash, parallelism }
+    }
+
+    pub fn parallelism(&self) -> Parallelism {
+        self.parallelism
+    }
+
+    /// Erases `sector`, every word reads back as [`ERASED`] afterwards
+    pub fn erase_sector(&mut self, sector: u8) -> Result<(), Error> {
+        let (start, len) = *SECTORS.get(usize::from(sector)).ok_or(Error::Address)?;
+        if start < image_end() {
+            return Err(Error::Address);
+        }
+
+        self.unlock();
+        let cr = u32::from(sector) << SNB | (self.parallelism as u32) << PSIZE | SER;
+        let result = self.wait().and_then(|_| {
+            self.flash.cr.write(|w| unsafe { w.bits(cr) });
+            self.flash.cr.write(|w| unsafe { w.bits(cr | STRT) });
+            self.wait()
+        });
+        self.flash.cr.write(|w| unsafe { w.bits(0) });
+        self.lock();
+        self.reset_caches();
+        result?;
+
+        if self.read(start, len).iter().all(|byte| *byte == 0xFF) {
+            Ok(())
+        } else {
+            Err(Error::Verify)
+        }
+    }
+
+    /// Programs `data` at `address`, both aligned to the parallelism, then
+    /// reads it back
+    pub fn program(&mut self, address: usize, data: &[u8]) -> Result<(), Error> {
+        let size = self.parallelism.bytes();
+        self.check(address, data.len())?;
+        if address % size != 0 || data.len() % size != 0 {
+            return Err(Error::Address);
+        }
+        let current = self.read(address, data.len());
+        if current.iter().zip(data).any(|(old, new)| old & new != *new) {
+            return Err(Error::NotErased);
+        }
+
+        self.unlock();
+        let cr = (self.parallelism as u32) << PSIZE | PG;
+        let mut result = self.wait();
+        if result.is_ok() {
+            self.flash.cr.write(|w| unsafe { w.bits(cr) });
+            for (i, chunk) in data.chunks(size).enumerate() {
+                let target = address + i * size;
+                // SAFETY: Inside the flash, aligned to the access size
+                unsafe {
+                    match self.parallelism {
+                        Parallelism::X8 => ptr::write_volatile(target as *mut u8, chunk[0]),
+                        Parallelism::X16 => ptr::write_volatile(
+                            target as *mut u16,
+                            u16::from_le_bytes([chunk[0], chunk[1]]),
+                        ),
+                        Parallelism::X32 => ptr::write_volatile(
+                            target as *mut u32,
+                            u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]),
+                        ),
+                    }
+                }
+                result = self.wait();
+                if result.is_err() {
+                    break;
+                }
+            }
+        }
+        self.flash.cr.write(|w| unsafe { w.bits(0) });
+        self.lock();
+        result?;
+        self.verify(address, data)
+    }
+
+    /// Compares the flash at `address` with `data`
+    pub fn verify(&self, address: usize, data: &[u8]) -> Result<(), Error> {
+        self.check(address, data.len())?;
+        if self.read(address, data.len()) == data {
+            Ok(())
+        } else {
+            Err(Error::Verify)
+        }
+    }
+
+    /// The flash is memory mapped, this is just a view of it. Panics outside
+    /// of the flash.
+    pub fn read(&self, address: usize, len: usize) -> &'static [u8] {
+        assert!(self.check(address, len).is_ok());
+        // SAFETY: Inside the flash, which is always readable
+        unsafe { core::slice::from_raw_parts(address as *const u8, len) }
+    }
+
+    pub fn option_bytes(&self) -> OptionBytes {
+        let optcr = self.flash.optcr.read().bits();
+        OptionBytes {
+            read_protection: (optcr >> RDP) as u8,
+            // nWRP bits are cleared to protect a sector
+            write_protection: !(optcr >> NWRP) as u8 & 0b11_1111,
+            bor_level: (optcr >> BOR_LEV) as u8 & 0b11,
+            hardware_watchdog: optcr & WDG_SW == 0,
+            reset_on_stop: optcr & NRST_STOP == 0,
+            reset_on_standby: optcr & NRST_STDBY == 0,
+        }
+    }
+
+    pub fn free(self) -> device::FLASH {
+        self.flash
+    }
+
+    fn check(&self, address: usize, len: usize) -> Result<(), Error> {
+        let (start, _) = SECTORS[0];
+        let (last, last_len) = SECTORS[SECTORS.len() - 1];
+        if address >= start && address.checked_add(len).ok_or(Error::Address)? <= last + last_len {
+            Ok(())
+        } else {
+            Err(Error::Address)
+        }
+    }
+
+    /// The ART caches may still hold erased contents, see Section 3.5.2 of
+    /// RM0368. They can only be reset while disabled.
+    fn reset_caches(&mut self) {
+        let acr = self.flash.acr.read().bits();
+        self.flash
+            .acr
+            .write(|w| unsafe { w.bits(acr & !(ICEN | DCEN)) });
+        self.flash
+            .acr
+            .write(|w| unsafe { w.bits(acr & !(ICEN | DCEN) | ICRST | DCRST) });
+        self.flash.acr.write(|w| unsafe { w.bits(acr) });
+    }
+
+    fn unlock(&mut self) {
+        if self.flash.cr.read().bits() & LOCK != 0 {
+            self.flash.keyr.write(|w| unsafe { w.bits(KEY1) });
+            self.flash.keyr.write(|w| unsafe { w.bits(KEY2) });
+        }
+    }
+
+    fn lock(&mut self) {
+        self.flash.cr.write(|w| unsafe { w.bits(LOCK) });
+    }
+
+    /// Waits for the current operation and reports (then clears) its errors
+    fn wait(&mut self) -> Result<(), Error> {
+        while self.flash.sr.read().bits() & BSY != 0 {}
+        let sr = self.flash.sr.read().bits();
+        let result = if sr & WRPERR != 0 {
+            Err(Error::WriteProtected)
+        } else if sr & PGSERR != 0 {
+            Err(Error::Sequence)
+        } else if sr & PGPERR != 0 {
+            Err(Error::Parallelism)
+        } else if sr & PGAERR != 0 {
+            Err(Error::Alignment)
+        } else {
+            Ok(())
+        };
+        // Error and EOP flags are cleared by writing 1
+        self.flash
+            .sr
+            .write(|w| unsafe { w.bits(EOP | OPERR | WRPERR | PGAERR | PGPERR | PGSERR) });
+        result
+    }
+}
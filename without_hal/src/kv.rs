@@ -0,0 +1,513 @@
+//! Wear-leveled key-value store in the two flash sectors `memory.x` reserves
+//!
+//! Values are never overwritten in place. Every [`Store::set`] appends a
+//! record to the active sector and the last valid record of a key wins.
+//! Once the active sector is full, the latest record of every key is copied
+//! to the other sector, which then takes over, and the old one is erased.
+//! Both sectors take turns, so each is erased once per round trip instead of
+//! on every write. The latest values of all keys therefore have to fit into
+//! the smaller of the two, the 64K of KV_A. A swap that does not fit is not
+//! tried again until another record was written, so a full store does not
+//! erase a sector on every write. The full sector still takes small records:
+//! removing keys or shrinking values makes room for the next swap. A swap
+//! cut short by a flash error is simply tried again.
+//!
+//! Every record carries a sequence number and a CRC-32. A record torn by a
+//! power failure fails its CRC and is skipped, leaving the previous value in
+//! place. A sector only becomes active once its header is programmed, after
+//! everything was copied into it. Until the old sector is erased both have a
+//! valid header, then the one with the higher sequence number wins.
+//!
+//! Sector layout, little endian words:
+//!
+//! ```text
+//! MAGIC | sector sequence | record | record | ... | erased
+//! ```
+//!
+//! Record layout, padded to whole words with 0xFF:
+//!
+//! ```text
+//! key (16 bits) length (16 bits) | sequence | value ... | CRC-32
+//! ```
+use crate::flash::{self, Flash, ERASED, SECTORS};
+
+/// Marks a formatted sector
+const MAGIC: u32 = 0x4B56_5331;
+/// Sector header length
+const HEADER: usize = 8;
+/// Key, length and sequence
+const RECORD_HEADER: usize = 8;
+const CRC: usize = 4;
+
+/// Longest value
+pub const MAX_VALUE: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum Error {
+    /// Programming or erasing failed
+    Flash(flash::Error),
+    /// `memory.x` does not reserve two whole sectors
+    Layout,
+    /// Key 0xFFFF is reserved
+    Key,
+    /// Value empty or longer than [`MAX_VALUE`]
+    Size,
+    /// The latest values of all keys do not fit into the smaller sector, or
+    /// they did not at the last swap and nothing was written since
+    Full,
+}
+
+impl From<flash::Error> for Error {
+    fn from(e: flash::Error) -> Self {
+        Error::Flash(e)
+    }
+}
+
+/// Something that can be stored under a key
+pub trait Value: Sized {
+    /// Bytes taken, at most [`MAX_VALUE`]
+    const SIZE: usize;
+
+    fn to_bytes(&self, bytes: &mut [u8]);
+
+    /// `None` if the stored bytes do not make a valid value
+    fn from_bytes(bytes: &[u8]) -> Option<Self>;
+}
+
+macro_rules! value {
+    ($($type:ty),*) => {
+        $(
+            impl Value for $type {
+                const SIZE: usize = core::mem::size_of::<$type>();
+
+                fn to_bytes(&self, bytes: &mut [u8]) {
+                    bytes.copy_from_slice(&self.to_le_bytes());
+                }
+
+                fn from_bytes(bytes: &[u8]) -> Option<Self> {
+                    let mut le = [0; core::mem::size_of::<$type>()];
+                    le.copy_from_slice(bytes);
+                    Some(<$type>::from_le_bytes(le))
+                }
+            }
+        )*
+    };
+}
+
+value!(u8, u16, u32, u64, i8, i16, i32, i64, f32);
+
+impl Value for bool {
+    const SIZE: usize = 1;
+
+    fn to_bytes(&self, bytes: &mut [u8]) {
+        bytes[0] = u8::from(*self);
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        match bytes[0] {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+}
+
+extern "C" {
+    static _kv_a_start: u32;
+    static _kv_a_end: u32;
+    static _kv_b_start: u32;
+    static _kv_b_end: u32;
+}
+
+/// One of the two sectors
+#[derive(Clone, Copy)]
+struct Page {
+    sector: u8,
+    start: usize,
+    len: usize,
+}
+
+impl Page {
+    /// Checks that `start..end` is exactly one sector
+    fn new(start: usize, end: usize) -> Result<Self, Error> {
+        let sector = flash::sector(start).ok_or(Error::Layout)?;
+        match SECTORS[usize::from(sector)] {
+            (first, len) if first == start && start + len == end => Ok(Page { sector, start, len }),
+            _ => Err(Error::Layout),
+        }
+    }
+
+    fn word(&self, flash: &Flash, offset: usize) -> u32 {
+        let bytes = flash.read(self.start + offset, 4);
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    /// Sector sequence number if the header is valid
+    fn sequence(&self, flash: &Flash) -> Option<u32> {
+        let sequence = self.word(flash, 4);
+        if self.word(flash, 0) == MAGIC && sequence != ERASED {
+            Some(sequence)
+        } else {
+            None
+        }
+    }
+
+    fn is_erased(&self, flash: &Flash) -> bool {
+        flash
+            .read(self.start, self.len)
+            .iter()
+            .all(|byte| *byte == 0xFF)
+    }
+
+    fn records<'a>(&self, flash: &'a Flash) -> Records<'a> {
+        Records {
+            flash,
+            page: *self,
+            offset: HEADER,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Record {
+    key: u16,
+    sequence: u32,
+    value: &'static [u8],
+    /// The CRC matches
+    valid: bool,
+}
+
+impl Record {
+    /// Removed keys leave an empty record behind
+    fn is_removed(&self) -> bool {
+        self.value.is_empty()
+    }
+}
+
+/// Bytes a record with a value of `len` bytes takes
+fn record_len(len: usize) -> usize {
+    RECORD_HEADER + (len + 3) / 4 * 4 + CRC
+}
+
+/// Reads the record at `offset` of `page` and where the next one starts.
+/// `None` at the end of the records.
+fn read_record(flash: &Flash, page: Page, offset: usize) -> Option<(Record, usize)> {
+    if offset + RECORD_HEADER > page.len {
+        return None;
+    }
+    let header = page.word(flash, offset);
+    let (key, len) = (header as u16, (header >> 16) as usize);
+    let size = record_len(len);
+    // An erased header ends the records, a torn one as well since nothing
+    // after it can be trusted
+    if key == 0xFFFF || len > MAX_VALUE || offset + size > page.len {
+        return None;
+    }
+
+    let bytes = flash.read(page.start + offset, size);
+    let record = Record {
+        key,
+        sequence: page.word(flash, offset + 4),
+        value: &bytes[RECORD_HEADER..RECORD_HEADER + len],
+        valid: crc32(&bytes[..size - CRC]) == page.word(flash, offset + size - CRC),
+    };
+    Some((record, offset + size))
+}
+
+/// Walks the records of a page in the order they were written
+struct Records<'a> {
+    flash: &'a Flash,
+    page: Page,
+    /// Where the next record starts
+    offset: usize,
+}
+
+impl Iterator for Records<'_> {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        let (record, next) = read_record(self.flash, self.page, self.offset)?;
+        self.offset = next;
+        Some(record)
+    }
+}
+
+/// CRC-32 (IEEE 802.3), bit by bit, records are short
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+pub struct Store {
+    flash: Flash,
+    pages: [Page; 2],
+    /// Index of the page written to
+    active: usize,
+    /// Where the next record goes in the active page
+    end: usize,
+    /// For the next record
+    sequence: u32,
+    /// The last swap did not fit, see [`Error::Full`]
+    stuck: bool,
+}
+
+impl Store {
+    /// Opens the store, formatting it if neither sector holds one and
+    /// finishing a sector swap a power failure interrupted
+    pub fn open(flash: Flash) -> Result<Self, (Error, Flash)> {
+        // SAFETY: Only the addresses of the linker symbols are taken
+        let pages = unsafe {
+            [
+                Page::new(
+                    &_kv_a_start as *const u32 as usize,
+                    &_kv_a_end as *const u32 as usize,
+                ),
+                Page::new(
+                    &_kv_b_start as *const u32 as usize,
+                    &_kv_b_end as *const u32 as usize,
+                ),
+            ]
+        };
+        let pages = match pages {
+            [Ok(a), Ok(b)] => [a, b],
+            _ => return Err((Error::Layout, flash)),
+        };
+        let mut store = Store {
+            flash,
+            pages,
+            active: 0,
+            end: HEADER,
+            sequence: 0,
+            stuck: false,
+        };
+        match store.mount() {
+            Ok(()) => Ok(store),
+            Err(e) => Err((e, store.flash)),
+        }
+    }
+
+    /// Reads the latest value of `key`, `None` if it was never set, was
+    /// removed or does not make a `V`
+    pub fn get<V: Value>(&self, key: u16) -> Option<V> {
+        let mut bytes = [0; MAX_VALUE];
+        match self.get_bytes(key, &mut bytes) {
+            Some(len) if len == V::SIZE => V::from_bytes(&bytes[..len]),
+            _ => None,
+        }
+    }
+
+    pub fn set<V: Value>(&mut self, key: u16, value: &V) -> Result<(), Error> {
+        let mut bytes = [0; MAX_VALUE];
+        let bytes = bytes.get_mut(..V::SIZE).ok_or(Error::Size)?;
+        value.to_bytes(bytes);
+        self.set_bytes(key, bytes)
+    }
+
+    /// Copies the latest value of `key` into `buf` and returns its length.
+    /// Panics if `buf` is too short.
+    pub fn get_bytes(&self, key: u16, buf: &mut [u8]) -> Option<usize> {
+        let record = self.latest(self.pages[self.active], key)?;
+        if record.is_removed() {
+            return None;
+        }
+        buf[..record.value.len()].copy_from_slice(record.value);
+        Some(record.value.len())
+    }
+
+    /// Stores `value` under `key`, unless it is stored already
+    pub fn set_bytes(&mut self, key: u16, value: &[u8]) -> Result<(), Error> {
+        if value.is_empty() || value.len() > MAX_VALUE {
+            return Err(Error::Size);
+        }
+        match self.latest(self.pages[self.active], key) {
+            Some(record) if record.value == value => Ok(()),
+            _ => self.append(key, value),
+        }
+    }
+
+    /// Forgets `key`, which also makes room in a store that ran
+    /// [`Error::Full`]
+    pub fn remove(&mut self, key: u16) -> Result<(), Error> {
+        match self.latest(self.pages[self.active], key) {
+            Some(record) if !record.is_removed() => self.append(key, &[]),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn free(self) -> Flash {
+        self.flash
+    }
+
+    /// Picks the active page and finds the end of its records
+    fn mount(&mut self) -> Result<(), Error> {
+        let sequences = [
+            self.pages[0].sequence(&self.flash),
+            self.pages[1].sequence(&self.flash),
+        ];
+        match sequences {
+            [None, None] => {
+                // Never used, or the very first swap did not finish
+                for page in self.pages.iter() {
+                    if !page.is_erased(&self.flash) {
+                        self.flash.erase_sector(page.sector)?;
+                    }
+                }
+                self.format(0, 0)?;
+                self.active = 0;
+            }
+            [Some(a), Some(b)] => {
+                // A swap was cut short after the new page took over
+                self.active = usize::from(b > a);
+                let stale = self.pages[1 - self.active].sector;
+                self.flash.erase_sector(stale)?;
+            }
+            [Some(_), None] => self.active = 0,
+            [None, Some(_)] => self.active = 1,
+        }
+
+        let page = self.pages[self.active];
+        let mut records = page.records(&self.flash);
+        let mut sequence = 0;
+        for record in &mut records {
+            if record.valid {
+                sequence = sequence.max(record.sequence.wrapping_add(1));
+            }
+        }
+        // Past a torn header the page counts as full
+        let end = records.offset;
+        self.end = if end + 4 <= page.len && page.word(&self.flash, end) == ERASED {
+            end
+        } else {
+            page.len
+        };
+        self.sequence = sequence;
+        Ok(())
+    }
+
+    /// Programs the header that makes page `index` a valid one
+    fn format(&mut self, index: usize, sequence: u32) -> Result<(), Error> {
+        let mut header = [0; HEADER];
+        header[..4].copy_from_slice(&MAGIC.to_le_bytes());
+        header[4..].copy_from_slice(&sequence.to_le_bytes());
+        self.flash.program(self.pages[index].start, &header)?;
+        Ok(())
+    }
+
+    /// The last valid record of `key` in `page`
+    fn latest(&self, page: Page, key: u16) -> Option<Record> {
+        page.records(&self.flash)
+            .filter(|record| record.valid && record.key == key)
+            .last()
+    }
+
+    fn append(&mut self, key: u16, value: &[u8]) -> Result<(), Error> {
+        if key == 0xFFFF {
+            return Err(Error::Key);
+        }
+        let size = record_len(value.len());
+        if self.end + size > self.pages[self.active].len {
+            if value.is_empty() {
+                // The swap leaves a removed key behind, nothing more to write
+                return self.swap(Some(key));
+            }
+            self.swap(None)?;
+            if self.end + size > self.pages[self.active].len {
+                // Swapping again would not make more room
+                self.stuck = true;
+                return Err(Error::Full);
+            }
+        }
+        self.write(key, self.sequence, value)?;
+        self.sequence = self.sequence.wrapping_add(1);
+        // The live set changed, the next swap may fit
+        self.stuck = false;
+        Ok(())
+    }
+
+    /// Programs a record at the end of the active page
+    fn write(&mut self, key: u16, sequence: u32, value: &[u8]) -> Result<(), Error> {
+        let size = record_len(value.len());
+        let mut bytes = [0xFF; RECORD_HEADER + MAX_VALUE + CRC];
+        let header = u32::from(key) | (value.len() as u32) << 16;
+        bytes[..4].copy_from_slice(&header.to_le_bytes());
+        bytes[4..8].copy_from_slice(&sequence.to_le_bytes());
+        bytes[RECORD_HEADER..RECORD_HEADER + value.len()].copy_from_slice(value);
+        let crc = crc32(&bytes[..size - CRC]);
+        bytes[size - CRC..size].copy_from_slice(&crc.to_le_bytes());
+
+        let address = self.pages[self.active].start + self.end;
+        // Whatever happens, a half written record must not be written over
+        self.end += size;
+        self.flash.program(address, &bytes[..size])?;
+        Ok(())
+    }
+
+    /// Copies the latest record of every key but `removed` to the other page,
+    /// which takes over, then erases the old one. Once the records did not
+    /// fit, only a removal tries again before the next write, anything else
+    /// would erase the other page for nothing.
+    fn swap(&mut self, removed: Option<u16>) -> Result<(), Error> {
+        if self.stuck && removed.is_none() {
+            return Err(Error::Full);
+        }
+        let (old, end) = (self.active, self.end);
+        if let Err(e) = self.copy_live(old, removed) {
+            // The old page stays in charge with whatever room it has left
+            self.stuck = e == Error::Full;
+            self.active = old;
+            self.end = end;
+            return Err(e);
+        }
+        self.stuck = false;
+        // The new page is in charge, should this fail the next mount or swap
+        // erases the old one
+        self.flash.erase_sector(self.pages[old].sector)?;
+        Ok(())
+    }
+
+    /// Copies the records and programs the header of the other page, which
+    /// takes over
+    fn copy_live(&mut self, old: usize, removed: Option<u16>) -> Result<(), Error> {
+        let (source, new) = (self.pages[old], 1 - old);
+        let target = self.pages[new];
+        let sequence = source.sequence(&self.flash).unwrap_or(0).wrapping_add(1);
+        if !target.is_erased(&self.flash) {
+            self.flash.erase_sector(target.sector)?;
+        }
+
+        // Records go in first and the header last, so the old page stays in
+        // charge until everything has been copied
+        self.active = new;
+        self.end = HEADER;
+        let mut offset = HEADER;
+        while let Some((record, next)) = read_record(&self.flash, source, offset) {
+            offset = next;
+            if !record.valid || record.is_removed() || Some(record.key) == removed {
+                continue;
+            }
+            let mut later = Records {
+                flash: &self.flash,
+                page: source,
+                offset,
+            };
+            if later.any(|other| other.valid && other.key == record.key) {
+                continue;
+            }
+            if self.end + record_len(record.value.len()) > target.len {
+                return Err(Error::Full);
+            }
+            self.write(record.key, record.sequence, record.value)?;
+        }
+        self.format(new, sequence)
+    }
+}
@@ -3,6 +3,16 @@
 use defmt_rtt as _; // Global logger
 use panic_probe as _;
 
+pub mod adc;
+pub mod clocks;
+pub mod dma;
+pub mod flash;
+pub mod kv;
+pub mod power;
+pub mod rtc;
+pub mod spi;
+pub mod watchdog;
+
 // Same panicking *behavior* as `panic-probe` but doesn't print a panic message
 // this prevents the panic message being printed *twice* when `defmt::panic` is invoked
 #[defmt::panic_handler]
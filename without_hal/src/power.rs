@@ -0,0 +1,285 @@
+//! Low-power modes and reset reasons, see Section 5.3 of RM0368
+//!
+//! * Sleep stops the core until an interrupt, with sleep-on-exit it goes
+//!   straight back to sleep after each handler so `main` never runs again.
+//! * Stop halts every clock but the LSE/LSI, keeping RAM and registers. Any
+//!   EXTI line wakes it up, including the RTC alarms (17) and wakeup timer
+//!   (22). The core resumes on the HSI, [`Power::stop`] brings back the HSE
+//!   and PLL it ran from before.
+//! * Standby powers down everything outside the backup domain, leaving only
+//!   a reset: the WKUP pin (PA0) rising, an RTC alarm or the wakeup timer.
+//!   [`Power::reset_reason`] tells it apart from other resets.
+//!
+//! Stop and Standby are skipped if a wakeup event is already pending, so the
+//! RTC flags and EXTI pending bits have to be cleared first, which the
+//! handlers in [`crate::rtc`] do.
+use cortex_m::peripheral::SCB;
+use stm32f4::stm32f401 as device;
+
+// PWR_CR bits
+const LPDS: u32 = 1 << 0;
+const PDDS: u32 = 1 << 1;
+const CWUF: u32 = 1 << 2;
+const CSBF: u32 = 1 << 3;
+const FPDS: u32 = 1 << 9;
+
+// PWR_CSR bits
+const WUF: u32 = 1 << 0;
+const SBF: u32 = 1 << 1;
+const EWUP: u32 = 1 << 8;
+
+// RCC_CR bits
+const HSEON: u32 = 1 << 16;
+const HSERDY: u32 = 1 << 17;
+const PLLON: u32 = 1 << 24;
+const PLLRDY: u32 = 1 << 25;
+const PLLI2SON: u32 = 1 << 26;
+
+// RCC_CFGR bits
+const SW: u32 = 0b11;
+const SWS: u32 = 2;
+
+// RCC_CSR bits
+const RMVF: u32 = 1 << 24;
+const BORRSTF: u32 = 1 << 25;
+const PINRSTF: u32 = 1 << 26;
+const PORRSTF: u32 = 1 << 27;
+const SFTRSTF: u32 = 1 << 28;
+const IWDGRSTF: u32 = 1 << 29;
+const WWDGRSTF: u32 = 1 << 30;
+const LPWRRSTF: u32 = 1 << 31;
+
+// RTC_ISR bits
+const RTC_ALRAF: u32 = 1 << 8;
+const RTC_ALRBF: u32 = 1 << 9;
+const RTC_WUTF: u32 = 1 << 10;
+
+/// Why the device last came out of reset
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum ResetReason {
+    /// Woke up from Standby
+    Standby(Wakeup),
+    /// Supply came up
+    PowerOn,
+    /// Supply dipped below the brownout threshold
+    Brownout,
+    /// The independent watchdog was not fed in time
+    IndependentWatchdog,
+    /// The window watchdog was fed outside its window or not at all
+    WindowWatchdog,
+    /// Stop or Standby entered while the option bytes forbid it
+    LowPower,
+    /// `SCB::sys_reset`
+    Software,
+    /// NRST pulled low
+    Pin,
+    /// No flag was set, e.g. after a debugger cleared them
+    Unknown,
+}
+
+/// RCC_CSR reset flags, several can be set by one reset
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub struct ResetFlags {
+    pub power_on: bool,
+    pub brownout: bool,
+    pub pin: bool,
+    pub software: bool,
+    pub independent_watchdog: bool,
+    pub window_watchdog: bool,
+    pub low_power: bool,
+}
+
+impl ResetFlags {
+    fn from_csr(csr: u32) -> Self {
+        ResetFlags {
+            power_on: csr & PORRSTF != 0,
+            brownout: csr & BORRSTF != 0,
+            pin: csr & PINRSTF != 0,
+            software: csr & SFTRSTF != 0,
+            independent_watchdog: csr & IWDGRSTF != 0,
+            window_watchdog: csr & WWDGRSTF != 0,
+            low_power: csr & LPWRRSTF != 0,
+        }
+    }
+}
+
+/// What ended Standby
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum Wakeup {
+    /// Rising edge on PA0
+    Pin,
+    /// RTC alarm or wakeup timer
+    Rtc,
+}
+
+/// Regulator in Stop mode
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum Regulator {
+    /// Main regulator, quicker to wake up
+    Main,
+    /// Low-power regulator, draws less but adds to the wakeup time
+    LowPower,
+}
+
+pub struct Power {
+    pwr: device::PWR,
+    reset_reason: ResetReason,
+    reset_flags: ResetFlags,
+}
+
+impl Power {
+    /// Takes note of the reset reason and clears the flags, so the next
+    /// reset reports its own
+    pub fn new(pwr: device::PWR, rcc: &device::RCC) -> Self {
+        rcc.apb1enr.modify(|_, w| w.pwren().enabled());
+        let csr = rcc.csr.read().bits();
+        let reset_reason = if pwr.csr.read().bits() & SBF != 0 {
+            // SAFETY: Only reads the status flags
+            let isr = unsafe { (*device::RTC::ptr()).isr.read().bits() };
+            if isr & (RTC_ALRAF | RTC_ALRBF | RTC_WUTF) != 0 {
+                ResetReason::Standby(Wakeup::Rtc)
+            } else {
+                ResetReason::Standby(Wakeup::Pin)
+            }
+        } else {
+            reset_reason(csr)
+        };
+        pwr.cr
+            .modify(|r, w| unsafe { w.bits(r.bits() | CSBF | CWUF) });
+        rcc.csr.modify(|r, w| unsafe { w.bits(r.bits() | RMVF) });
+        Power {
+            pwr,
+            reset_reason,
+            reset_flags: ResetFlags::from_csr(csr),
+        }
+    }
+
+    pub fn reset_reason(&self) -> ResetReason {
+        self.reset_reason
+    }
+
+    /// Every flag behind [`Power::reset_reason`]
+    pub fn reset_flags(&self) -> ResetFlags {
+        self.reset_flags
+    }
+
+    /// Sleeps until an interrupt
+    pub fn sleep(&mut self, scb: &mut SCB) {
+        scb.clear_sleepdeep();
+        cortex_m::asm::wfi();
+    }
+
+    /// With `enable`, returning from the last pending interrupt handler puts
+    /// the core back to sleep instead of returning to thread mode
+    pub fn sleep_on_exit(&mut self, scb: &mut SCB, enable: bool) {
+        if enable {
+            scb.set_sleeponexit();
+        } else {
+            scb.clear_sleeponexit();
+        }
+    }
+
+    /// Enters Stop mode until an EXTI line fires, then restores the clocks.
+    /// With `flash_power_down`, the flash is switched off too, which saves
+    /// more but takes longer to wake up.
+    pub fn stop(
+        &mut self,
+        scb: &mut SCB,
+        rcc: &device::RCC,
+        regulator: Regulator,
+        flash_power_down: bool,
+    ) {
+        let mut cr = self.pwr.cr.read().bits() & !(PDDS | LPDS | FPDS);
+        if regulator == Regulator::LowPower {
+            cr |= LPDS;
+        }
+        if flash_power_down {
+            cr |= FPDS;
+        }
+        self.pwr.cr.write(|w| unsafe { w.bits(cr | CWUF) });
+
+        // Stop clears HSEON, PLLON and PLLI2SON and switches to the HSI
+        let clocks = rcc.cr.read().bits();
+        let sw = rcc.cfgr.read().bits() & SW;
+        scb.set_sleepdeep();
+        cortex_m::asm::dsb();
+        cortex_m::asm::wfi();
+        scb.clear_sleepdeep();
+        restore_clocks(rcc, clocks, sw);
+    }
+
+    /// Enters Standby mode, which only ends in a reset. With `wakeup_pin`,
+    /// a rising edge on PA0 wakes the device, armed RTC alarms and the RTC
+    /// wakeup timer do so regardless.
+    pub fn standby(&mut self, scb: &mut SCB, wakeup_pin: bool) -> ! {
+        self.pwr.csr.modify(|r, w| unsafe {
+            w.bits(if wakeup_pin {
+                r.bits() | EWUP
+            } else {
+                r.bits() & !EWUP
+            })
+        });
+        // Enabling the pin while PA0 is high counts as a wakeup event, which
+        // is cleared here along with any older one
+        self.pwr
+            .cr
+            .modify(|r, w| unsafe { w.bits(r.bits() | PDDS | CWUF) });
+        scb.set_sleepdeep();
+        cortex_m::asm::dsb();
+        loop {
+            cortex_m::asm::wfi();
+        }
+    }
+
+    /// Whether a wakeup event (WKUP pin or RTC) happened since Stop or
+    /// Standby was last entered
+    pub fn woken_up(&self) -> bool {
+        self.pwr.csr.read().bits() & WUF != 0
+    }
+
+    pub fn free(self) -> device::PWR {
+        self.pwr
+    }
+}
+
+/// Decodes RCC_CSR. A power-on reset sets the brownout and pin flags too,
+/// the other reasons drive NRST low and set the pin flag.
+fn reset_reason(csr: u32) -> ResetReason {
+    if csr & PORRSTF != 0 {
+        ResetReason::PowerOn
+    } else if csr & BORRSTF != 0 {
+        ResetReason::Brownout
+    } else if csr & IWDGRSTF != 0 {
+        ResetReason::IndependentWatchdog
+    } else if csr & WWDGRSTF != 0 {
+        ResetReason::WindowWatchdog
+    } else if csr & LPWRRSTF != 0 {
+        ResetReason::LowPower
+    } else if csr & SFTRSTF != 0 {
+        ResetReason::Software
+    } else if csr & PINRSTF != 0 {
+        ResetReason::Pin
+    } else {
+        ResetReason::Unknown
+    }
+}
+
+/// Turns the HSE and PLLs back on if `cr` had them on and switches SYSCLK
+/// back to `sw`. Their configuration survives Stop, only the enables are
+/// lost.
+fn restore_clocks(rcc: &device::RCC, cr: u32, sw: u32) {
+    if cr & HSEON != 0 {
+        rcc.cr.modify(|r, w| unsafe { w.bits(r.bits() | HSEON) });
+        while rcc.cr.read().bits() & HSERDY == 0 {}
+    }
+    if cr & PLLON != 0 {
+        rcc.cr.modify(|r, w| unsafe { w.bits(r.bits() | PLLON) });
+        while rcc.cr.read().bits() & PLLRDY == 0 {}
+    }
+    if cr & PLLI2SON != 0 {
+        rcc.cr.modify(|r, w| unsafe { w.bits(r.bits() | PLLI2SON) });
+    }
+    rcc.cfgr
+        .modify(|r, w| unsafe { w.bits(r.bits() & !SW | sw) });
+    while (rcc.cfgr.read().bits() >> SWS) & SW != sw {}
+}
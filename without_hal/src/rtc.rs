@@ -0,0 +1,550 @@
+//! Real-time clock, see Section 17 of RM0368
+//!
+//! The RTC and its 20 backup registers live in the backup domain, which runs
+//! from VBAT while VDD is off and is left alone by every reset except a
+//! backup domain reset. [`Rtc::new`] keeps a calendar that already runs from
+//! the requested clock, so timestamps carry on across resets, and only starts
+//! from scratch otherwise.
+//!
+//! Writes to the backup domain are locked twice, by DBP in PWR_CR and by the
+//! RTC_WPR key. Both locks are only lifted for as long as a write takes.
+//!
+//! Alarm A/B and the wakeup timer reach the NVIC through EXTI lines 17 and 22,
+//! whose `RTC_ALARM` and `RTC_WKUP` handlers must call [`on_alarm_interrupt`]
+//! and [`on_wakeup_interrupt`]. Both lines also wake the core from Stop mode.
+use core::sync::atomic::{AtomicBool, Ordering};
+use cortex_m::peripheral::NVIC;
+use stm32f4::stm32f401::{self as device, rtc::RegisterBlock, Interrupt};
+
+/// Low speed external crystal
+pub const LSE_HZ: u32 = 32_768;
+/// Low speed internal RC, anywhere from 17 to 47 kHz over temperature
+pub const LSI_HZ: u32 = 32_000;
+/// Number of backup registers
+pub const BACKUP_REGISTERS: usize = 20;
+
+// RCC_BDCR bits
+const LSEON: u32 = 1 << 0;
+const LSERDY: u32 = 1 << 1;
+const LSEBYP: u32 = 1 << 2;
+const RTCSEL: u32 = 8;
+const RTCEN: u32 = 1 << 15;
+const BDRST: u32 = 1 << 16;
+
+// RCC_CSR bits
+const LSION: u32 = 1 << 0;
+const LSIRDY: u32 = 1 << 1;
+
+// PWR_CR bits
+const DBP: u32 = 1 << 8;
+
+// RTC_CR bits, alarm B is one bit above alarm A
+const WUCKSEL: u32 = 0b111;
+const FMT: u32 = 1 << 6;
+const ALRAE: u32 = 1 << 8;
+const WUTE: u32 = 1 << 10;
+const ALRAIE: u32 = 1 << 12;
+const WUTIE: u32 = 1 << 14;
+
+// RTC_ISR bits, alarm B is one bit above alarm A
+const ALRAWF: u32 = 1 << 0;
+const WUTWF: u32 = 1 << 2;
+const INITS: u32 = 1 << 4;
+const RSF: u32 = 1 << 5;
+const INITF: u32 = 1 << 6;
+const INIT: u32 = 1 << 7;
+const ALRAF: u32 = 1 << 8;
+const WUTF: u32 = 1 << 10;
+
+// RTC_ALRMxR bits
+const MSK1: u32 = 1 << 7;
+const MSK2: u32 = 1 << 15;
+const MSK3: u32 = 1 << 23;
+const WDSEL: u32 = 1 << 30;
+const MSK4: u32 = 1 << 31;
+
+// EXTI lines
+const EXTI_ALARM: u32 = 1 << 17;
+const EXTI_WAKEUP: u32 = 1 << 22;
+
+/// Offset of RTC_BKP0R in words
+const BKP0R: usize = 0x50 / 4;
+
+/// Polls before giving up, the LSE alone may take 2 s to start
+const TIMEOUT: u32 = 10_000_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum Error {
+    /// The low speed oscillator did not start
+    Oscillator,
+    /// The RTC did not enter initialization mode or synchronize in time
+    Timeout,
+    /// Date, time or alarm out of range
+    DateTime,
+    /// Wakeup period of zero or above 36 hours
+    Period,
+}
+
+/// Clock of the RTC
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum Source {
+    /// 32.768 kHz crystal on PC14/PC15, keeps running from VBAT
+    Lse,
+    /// 32.768 kHz clock driven into PC14
+    LseBypass,
+    /// Internal RC, inaccurate and stopped while VDD is off
+    Lsi,
+}
+
+impl Source {
+    fn rtcsel(self) -> u32 {
+        match self {
+            Source::Lse | Source::LseBypass => 0b01,
+            Source::Lsi => 0b10,
+        }
+    }
+
+    fn hz(self) -> u32 {
+        match self {
+            Source::Lse | Source::LseBypass => LSE_HZ,
+            Source::Lsi => LSI_HZ,
+        }
+    }
+}
+
+/// Calendar date and time, 24-hour format
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub struct DateTime {
+    /// 2000-2099
+    pub year: u16,
+    /// 1-12
+    pub month: u8,
+    /// 1-31
+    pub day: u8,
+    /// 1 (Monday) to 7 (Sunday)
+    pub weekday: u8,
+    /// 0-23
+    pub hours: u8,
+    /// 0-59
+    pub minutes: u8,
+    /// 0-59
+    pub seconds: u8,
+}
+
+impl DateTime {
+    fn check(&self) -> Result<(), Error> {
+        let leap = self.year % 4 == 0;
+        let days = match self.month {
+            2 if leap => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
+        };
+        if (2000..=2099).contains(&self.year)
+            && (1..=12).contains(&self.month)
+            && (1..=days).contains(&self.day)
+            && (1..=7).contains(&self.weekday)
+            && self.hours < 24
+            && self.minutes < 60
+            && self.seconds < 60
+        {
+            Ok(())
+        } else {
+            Err(Error::DateTime)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum Alarm {
+    A,
+    B,
+}
+
+/// Day an alarm matches
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum AlarmDay {
+    /// Day of the month, 1-31
+    Date(u8),
+    /// 1 (Monday) to 7 (Sunday)
+    Weekday(u8),
+}
+
+/// When an alarm fires, fields left at `None` match any value. The default
+/// fires every second.
+#[derive(Clone, Copy, Debug, Default, PartialEq, defmt::Format)]
+pub struct AlarmTime {
+    pub day: Option<AlarmDay>,
+    pub hours: Option<u8>,
+    pub minutes: Option<u8>,
+    pub seconds: Option<u8>,
+}
+
+impl AlarmTime {
+    /// RTC_ALRMxR value
+    fn bits(&self) -> Result<u32, Error> {
+        let field = |value: Option<u8>, max: u8, shift: u32, mask: u32| match value {
+            Some(value) if value <= max => Ok(bcd(value) << shift),
+            Some(_) => Err(Error::DateTime),
+            None => Ok(mask),
+        };
+        let day = match self.day {
+            Some(AlarmDay::Date(date)) if (1..=31).contains(&date) => bcd(date) << 24,
+            Some(AlarmDay::Weekday(day)) if (1..=7).contains(&day) => WDSEL | u32::from(day) << 24,
+            Some(_) => return Err(Error::DateTime),
+            None => MSK4,
+        };
+        Ok(day
+            | field(self.hours, 23, 16, MSK3)?
+            | field(self.minutes, 59, 8, MSK2)?
+            | field(self.seconds, 59, 0, MSK1)?)
+    }
+}
+
+/// Set by [`on_alarm_interrupt`], one per alarm
+static ALARMS: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+/// Set by [`on_wakeup_interrupt`]
+static WAKEUP: AtomicBool = AtomicBool::new(false);
+
+/// Must be called from the `RTC_ALARM` interrupt handler
+pub fn on_alarm_interrupt() {
+    let isr = registers().isr.read().bits();
+    let fired = isr & (ALRAF | ALRAF << 1);
+    for (alarm, flag) in ALARMS.iter().enumerate() {
+        if fired & ALRAF << alarm != 0 {
+            flag.store(true, Ordering::Release);
+        }
+    }
+    // The RTC flags go first, or the EXTI line is raised again right away.
+    // They are cleared by writing 0, INIT has to stay 0.
+    unprotected(|regs| regs.isr.write(|w| unsafe { w.bits(!(fired | INIT)) }));
+    exti().pr.write(|w| unsafe { w.bits(EXTI_ALARM) });
+}
+
+/// Must be called from the `RTC_WKUP` interrupt handler
+pub fn on_wakeup_interrupt() {
+    if registers().isr.read().bits() & WUTF != 0 {
+        unprotected(|regs| regs.isr.write(|w| unsafe { w.bits(!(WUTF | INIT)) }));
+        WAKEUP.store(true, Ordering::Release);
+    }
+    exti().pr.write(|w| unsafe { w.bits(EXTI_WAKEUP) });
+}
+
+/// Source of a calendar that is already running, which [`Rtc::new`] keeps
+/// without a backup domain reset
+pub fn running_source(rcc: &device::RCC) -> Option<Source> {
+    let bdcr = rcc.bdcr.read().bits();
+    if bdcr & RTCEN == 0 {
+        return None;
+    }
+    match (bdcr >> RTCSEL) & 0b11 {
+        0b01 if bdcr & LSEBYP != 0 => Some(Source::LseBypass),
+        0b01 => Some(Source::Lse),
+        0b10 => Some(Source::Lsi),
+        _ => None,
+    }
+}
+
+fn registers() -> &'static RegisterBlock {
+    // SAFETY: Only `Rtc` configures the RTC, the interrupt handlers only
+    // clear flags
+    unsafe { &*device::RTC::ptr() }
+}
+
+fn exti() -> &'static device::exti::RegisterBlock {
+    // SAFETY: Only lines 17 and 22 are touched, in critical sections or
+    // through the write-1-to-clear pending register
+    unsafe { &*device::EXTI::ptr() }
+}
+
+/// Lifts both write locks of the backup domain for `f`
+fn unprotected<R>(f: impl FnOnce(&RegisterBlock) -> R) -> R {
+    // SAFETY: Only DBP is touched, in a critical section
+    let pwr = unsafe { &*device::PWR::ptr() };
+    cortex_m::interrupt::free(|_| {
+        pwr.cr.modify(|r, w| unsafe { w.bits(r.bits() | DBP) });
+        let regs = registers();
+        regs.wpr.write(|w| unsafe { w.bits(0xCA) });
+        regs.wpr.write(|w| unsafe { w.bits(0x53) });
+        let result = f(regs);
+        // Any wrong key locks the RTC again
+        regs.wpr.write(|w| unsafe { w.bits(0xFF) });
+        pwr.cr.modify(|r, w| unsafe { w.bits(r.bits() & !DBP) });
+        result
+    })
+}
+
+fn wait(error: Error, mut ready: impl FnMut() -> bool) -> Result<(), Error> {
+    for _ in 0..TIMEOUT {
+        if ready() {
+            return Ok(());
+        }
+    }
+    Err(error)
+}
+
+/// Turns the LSE on, LSEBYP may only change while it is off
+fn start_lse(rcc: &device::RCC, bypass: u32) -> Result<(), Error> {
+    unprotected(|_| {
+        rcc.bdcr
+            .modify(|r, w| unsafe { w.bits(r.bits() & !(LSEON | LSEBYP)) });
+        rcc.bdcr.modify(|r, w| unsafe { w.bits(r.bits() | bypass) });
+        rcc.bdcr
+            .modify(|r, w| unsafe { w.bits(r.bits() | LSEON | bypass) });
+    });
+    wait(Error::Oscillator, || rcc.bdcr.read().bits() & LSERDY != 0)
+}
+
+fn bcd(value: u8) -> u32 {
+    u32::from(value / 10) << 4 | u32::from(value % 10)
+}
+
+fn from_bcd(bits: u32) -> u8 {
+    ((bits >> 4) * 10 + (bits & 0xF)) as u8
+}
+
+/// Routes an RTC event to the NVIC on the rising edge of its EXTI line
+fn route(line: u32, interrupt: Interrupt) {
+    let exti = exti();
+    cortex_m::interrupt::free(|_| {
+        exti.imr.modify(|r, w| unsafe { w.bits(r.bits() | line) });
+        exti.rtsr.modify(|r, w| unsafe { w.bits(r.bits() | line) });
+    });
+    unsafe { NVIC::unmask(interrupt) };
+}
+
+pub struct Rtc {
+    rtc: device::RTC,
+    /// RTCCLK
+    hz: u32,
+}
+
+impl Rtc {
+    /// Clocks the RTC from `source`. Switching to another source resets the
+    /// backup domain, which stops the calendar and clears the backup
+    /// registers. That only happens once the new oscillator is known to run,
+    /// if it does not start the RTC is handed back untouched.
+    pub fn new(
+        rtc: device::RTC,
+        rcc: &device::RCC,
+        source: Source,
+    ) -> Result<Self, (Error, device::RTC)> {
+        rcc.apb1enr.modify(|_, w| w.pwren().enabled());
+        if source == Source::Lsi {
+            // The LSI is outside of the backup domain, every reset stops it
+            rcc.csr.modify(|r, w| unsafe { w.bits(r.bits() | LSION) });
+            if let Err(e) = wait(Error::Oscillator, || rcc.csr.read().bits() & LSIRDY != 0) {
+                return Err((e, rtc));
+            }
+        }
+
+        let bypass = if source == Source::LseBypass {
+            LSEBYP
+        } else {
+            0
+        };
+        let lse = LSEON | bypass;
+        if source != Source::Lsi && rcc.bdcr.read().bits() & (LSEON | LSEBYP) != lse {
+            // LSEON is writable whatever RTCSEL says, the calendar keeps its
+            // current clock until the LSE is known to run
+            if let Err(e) = start_lse(rcc, bypass) {
+                unprotected(|_| {
+                    rcc.bdcr
+                        .modify(|r, w| unsafe { w.bits(r.bits() & !(LSEON | LSEBYP)) })
+                });
+                return Err((e, rtc));
+            }
+        }
+
+        if running_source(rcc) != Some(source) {
+            // RTCSEL can only be changed by a backup domain reset, which
+            // also stops the LSE
+            unprotected(|_| {
+                rcc.bdcr.write(|w| unsafe { w.bits(BDRST) });
+                rcc.bdcr.write(|w| unsafe { w.bits(0) });
+            });
+            if source != Source::Lsi {
+                if let Err(e) = start_lse(rcc, bypass) {
+                    return Err((e, rtc));
+                }
+            }
+            unprotected(|_| {
+                rcc.bdcr
+                    .modify(|r, w| unsafe { w.bits(r.bits() | source.rtcsel() << RTCSEL | RTCEN) });
+            });
+        }
+
+        let rtc = Rtc {
+            rtc,
+            hz: source.hz(),
+        };
+        // The shadow registers are stale after a reset or Stop mode
+        if let Err(e) = rtc.synchronize() {
+            return Err((e, rtc.rtc));
+        }
+        Ok(rtc)
+    }
+
+    /// Whether the calendar has been set since the backup domain was reset,
+    /// which the RTC can only tell from a year other than 2000
+    pub fn is_set(&self) -> bool {
+        registers().isr.read().bits() & INITS != 0
+    }
+
+    pub fn set_date_time(&mut self, date_time: &DateTime) -> Result<(), Error> {
+        date_time.check()?;
+        let tr = bcd(date_time.hours) << 16 | bcd(date_time.minutes) << 8 | bcd(date_time.seconds);
+        let dr = bcd((date_time.year - 2000) as u8) << 16
+            | u32::from(date_time.weekday) << 13
+            | bcd(date_time.month) << 8
+            | bcd(date_time.day);
+        // ck_spre = RTCCLK / (PREDIV_A + 1) / (PREDIV_S + 1) = 1 Hz, a large
+        // asynchronous divider saves power
+        let prediv_a = 127;
+        let prediv_s = self.hz / (prediv_a + 1) - 1;
+
+        unprotected(|regs| {
+            // Sets INIT, the flags are cleared by writing 0 so they get 1
+            regs.isr.write(|w| unsafe { w.bits(!0) });
+            wait(Error::Timeout, || regs.isr.read().bits() & INITF != 0)?;
+            // The two dividers have to be written separately
+            regs.prer.write(|w| unsafe { w.bits(prediv_s) });
+            regs.prer
+                .write(|w| unsafe { w.bits(prediv_a << 16 | prediv_s) });
+            regs.tr.write(|w| unsafe { w.bits(tr) });
+            regs.dr.write(|w| unsafe { w.bits(dr) });
+            regs.cr.modify(|r, w| unsafe { w.bits(r.bits() & !FMT) });
+            // The calendar starts counting once INIT is cleared
+            regs.isr.write(|w| unsafe { w.bits(!INIT) });
+            Ok(())
+        })?;
+        self.synchronize()
+    }
+
+    pub fn date_time(&self) -> DateTime {
+        let regs = registers();
+        // Reading TR freezes DR until it is read too
+        let tr = regs.tr.read().bits();
+        let dr = regs.dr.read().bits();
+        DateTime {
+            year: 2000 + u16::from(from_bcd(dr >> 16 & 0xFF)),
+            month: from_bcd(dr >> 8 & 0x1F),
+            day: from_bcd(dr & 0x3F),
+            weekday: (dr >> 13 & 0b111) as u8,
+            hours: from_bcd(tr >> 16 & 0x3F),
+            minutes: from_bcd(tr >> 8 & 0x7F),
+            seconds: from_bcd(tr & 0x7F),
+        }
+    }
+
+    /// Arms `alarm`, which raises `RTC_ALARM` whenever the calendar matches
+    /// `time`
+    pub fn set_alarm(&mut self, alarm: Alarm, time: &AlarmTime) -> Result<(), Error> {
+        let bits = time.bits()?;
+        let shift = alarm as u32;
+        unprotected(|regs| {
+            regs.cr
+                .modify(|r, w| unsafe { w.bits(r.bits() & !(ALRAE << shift)) });
+            wait(Error::Timeout, || {
+                regs.isr.read().bits() & ALRAWF << shift != 0
+            })?;
+            match alarm {
+                Alarm::A => regs.alrmar.write(|w| unsafe { w.bits(bits) }),
+                Alarm::B => regs.alrmbr.write(|w| unsafe { w.bits(bits) }),
+            }
+            regs.isr
+                .write(|w| unsafe { w.bits(!(ALRAF << shift | INIT)) });
+            regs.cr
+                .modify(|r, w| unsafe { w.bits(r.bits() | (ALRAE | ALRAIE) << shift) });
+            Ok(())
+        })?;
+        ALARMS[alarm as usize].store(false, Ordering::Relaxed);
+        route(EXTI_ALARM, Interrupt::RTC_ALARM);
+        Ok(())
+    }
+
+    pub fn disable_alarm(&mut self, alarm: Alarm) {
+        let shift = alarm as u32;
+        unprotected(|regs| {
+            regs.cr
+                .modify(|r, w| unsafe { w.bits(r.bits() & !((ALRAE | ALRAIE) << shift)) });
+        });
+    }
+
+    /// Whether `alarm` fired since the last call
+    pub fn alarm_fired(&mut self, alarm: Alarm) -> bool {
+        ALARMS[alarm as usize].swap(false, Ordering::Acquire)
+    }
+
+    /// Raises `RTC_WKUP` every `ms` milliseconds. Periods up to 32 s are
+    /// counted in steps of 16 RTCCLK cycles (about 0.5 ms), longer ones in
+    /// whole seconds up to 36 hours.
+    pub fn enable_wakeup(&mut self, ms: u32) -> Result<(), Error> {
+        let ticks = u64::from(ms) * u64::from(self.hz) / 16 / 1000;
+        let seconds = ms / 1000;
+        // WUCKSEL 000: RTCCLK / 16, 10x: ck_spre, 11x: ck_spre and 2^16
+        // added to WUT
+        let (wucksel, wut) = match (ticks, seconds) {
+            (1..=0x1_0000, _) => (0b000, ticks as u32 - 1),
+            (_, 1..=0x1_0000) => (0b100, seconds - 1),
+            (_, 0x1_0001..=0x2_0000) => (0b110, seconds - 0x1_0001),
+            _ => return Err(Error::Period),
+        };
+        unprotected(|regs| {
+            regs.cr.modify(|r, w| unsafe { w.bits(r.bits() & !WUTE) });
+            wait(Error::Timeout, || regs.isr.read().bits() & WUTWF != 0)?;
+            regs.wutr.write(|w| unsafe { w.bits(wut) });
+            regs.isr.write(|w| unsafe { w.bits(!(WUTF | INIT)) });
+            regs.cr
+                .modify(|r, w| unsafe { w.bits(r.bits() & !WUCKSEL | wucksel | WUTE | WUTIE) });
+            Ok(())
+        })?;
+        WAKEUP.store(false, Ordering::Relaxed);
+        route(EXTI_WAKEUP, Interrupt::RTC_WKUP);
+        Ok(())
+    }
+
+    pub fn disable_wakeup(&mut self) {
+        unprotected(|regs| {
+            regs.cr
+                .modify(|r, w| unsafe { w.bits(r.bits() & !(WUTE | WUTIE)) });
+        });
+    }
+
+    /// Whether the wakeup timer fired since the last call
+    pub fn wakeup_fired(&mut self) -> bool {
+        WAKEUP.swap(false, Ordering::Acquire)
+    }
+
+    /// Backup register `index`, panics unless it is below
+    /// [`BACKUP_REGISTERS`]
+    pub fn backup(&self, index: usize) -> u32 {
+        assert!(index < BACKUP_REGISTERS);
+        // SAFETY: In bounds of the RTC register block
+        unsafe { core::ptr::read_volatile(backup_register(index)) }
+    }
+
+    /// Sets backup register `index`, panics unless it is below
+    /// [`BACKUP_REGISTERS`]
+    pub fn set_backup(&mut self, index: usize, value: u32) {
+        assert!(index < BACKUP_REGISTERS);
+        // SAFETY: In bounds of the RTC register block
+        unprotected(|_| unsafe { core::ptr::write_volatile(backup_register(index), value) });
+    }
+
+    /// Hands the RTC back, it keeps running
+    pub fn free(self) -> device::RTC {
+        self.rtc
+    }
+
+    /// Waits for the calendar shadow registers to catch up
+    fn synchronize(&self) -> Result<(), Error> {
+        unprotected(|regs| regs.isr.write(|w| unsafe { w.bits(!(RSF | INIT)) }));
+        let regs = registers();
+        wait(Error::Timeout, || regs.isr.read().bits() & RSF != 0)
+    }
+}
+
+fn backup_register(index: usize) -> *mut u32 {
+    (device::RTC::ptr() as *mut u32).wrapping_add(BKP0R + index)
+}
@@ -0,0 +1,323 @@
+//! SPI transactions that run on two DMA streams, one per direction
+//!
+//! A transaction asserts the chip select, lets the streams move every byte
+//! and releases the chip select once it is polled complete, so the CPU is
+//! only needed at the start and the end. Which streams can serve an SPI is
+//! fixed in hardware: SPI1 uses DMA2 (RX on stream 0 or 2, TX on stream 3 or
+//! 5), SPI2 and SPI3 use DMA1, see Tables 27 and 28 of RM0368.
+//!
+//! With a callback set, the streams raise their completion and error
+//! interrupts, whose handlers must call [`crate::dma::on_interrupt`].
+use super::{Error, FrameSize, Instance, Spi};
+use crate::dma::{self, Channel, Flags, PeripheralTarget, Priority, Stream};
+use core::marker::PhantomData;
+use embedded_dma::{ReadBuffer, WriteBuffer};
+use embedded_hal::digital::v2::OutputPin;
+use stm32f4::stm32f401 as device;
+
+/// An SPI with DMA requests
+pub trait DmaInstance: Instance {
+    type Dma: dma::Instance;
+    const CHANNEL: Channel;
+    /// Streams that can receive for this SPI
+    const RX_STREAMS: [usize; 2];
+    /// Streams that can transmit for this SPI
+    const TX_STREAMS: [usize; 2];
+}
+
+impl DmaInstance for device::SPI1 {
+    type Dma = device::DMA2;
+    const CHANNEL: Channel = Channel::C3;
+    const RX_STREAMS: [usize; 2] = [0, 2];
+    const TX_STREAMS: [usize; 2] = [3, 5];
+}
+
+impl DmaInstance for device::SPI2 {
+    type Dma = device::DMA1;
+    const CHANNEL: Channel = Channel::C0;
+    const RX_STREAMS: [usize; 2] = [3, 3];
+    const TX_STREAMS: [usize; 2] = [4, 4];
+}
+
+impl DmaInstance for device::SPI3 {
+    type Dma = device::DMA1;
+    const CHANNEL: Channel = Channel::C0;
+    const RX_STREAMS: [usize; 2] = [0, 2];
+    const TX_STREAMS: [usize; 2] = [5, 7];
+}
+
+/// The data register, read by the RX stream and written by the TX stream
+struct Dr<SPI>(PhantomData<SPI>);
+
+unsafe impl<SPI: Instance> PeripheralTarget for Dr<SPI> {
+    type Word = u8;
+
+    fn address(&self) -> u32 {
+        &SPI::registers().dr as *const _ as u32
+    }
+}
+
+pub struct SpiDma<SPI: DmaInstance, CS> {
+    spi: Spi<SPI>,
+    cs: CS,
+    tx: Stream<SPI::Dma>,
+    rx: Stream<SPI::Dma>,
+    callback: Option<fn(Flags)>,
+}
+
+impl<SPI: DmaInstance, CS: OutputPin> SpiDma<SPI, CS> {
+    /// Panics if `tx` or `rx` can not serve this SPI
+    pub fn new(spi: Spi<SPI>, tx: Stream<SPI::Dma>, rx: Stream<SPI::Dma>, mut cs: CS) -> Self {
+        assert!(SPI::TX_STREAMS.contains(&tx.number()));
+        assert!(SPI::RX_STREAMS.contains(&rx.number()));
+        cs.set_high().ok();
+        SpiDma {
+            spi,
+            cs,
+            tx,
+            rx,
+            callback: None,
+        }
+    }
+
+    /// Sets the function the interrupt of the stream that finishes a
+    /// transaction calls, with the flags that fired. That is the RX stream
+    /// for [`transfer`](SpiDma::transfer) and the TX stream for
+    /// [`write`](SpiDma::write).
+    pub fn set_callback(&mut self, callback: Option<fn(Flags)>) {
+        self.callback = callback;
+    }
+
+    /// The SPI for blocking transfers in between transactions, the chip
+    /// select is left alone
+    pub fn spi(&mut self) -> &mut Spi<SPI> {
+        &mut self.spi
+    }
+
+    pub fn free(self) -> (Spi<SPI>, Stream<SPI::Dma>, Stream<SPI::Dma>, CS) {
+        (self.spi, self.tx, self.rx, self.cs)
+    }
+
+    /// Sends `tx` while receiving as many bytes into `rx`, which must be
+    /// just as long
+    #[allow(clippy::type_complexity)]
+    pub fn transfer<TX, RX>(
+        self,
+        tx: TX,
+        mut rx: RX,
+    ) -> Result<Transaction<SPI, CS, TX, RX>, (Error, Self, TX, RX)>
+    where
+        TX: ReadBuffer<Word = u8>,
+        RX: WriteBuffer<Word = u8>,
+    {
+        let (_, tx_len) = unsafe { tx.read_buffer() };
+        let (_, rx_len) = unsafe { rx.write_buffer() };
+        if let Err(e) = self.check(tx_len) {
+            return Err((e, self, tx, rx));
+        }
+        if rx_len != tx_len {
+            return Err((Error::Dma(dma::Error::Length), self, tx, rx));
+        }
+
+        let SpiDma {
+            spi,
+            mut cs,
+            tx: tx_stream,
+            rx: rx_stream,
+            callback,
+        } = self;
+        let regs = SPI::registers();
+        cs.set_low().ok();
+        // The RX stream has to be ready before the first byte goes out
+        let rx = match rx_stream.peripheral_to_memory(
+            Dr(PhantomData),
+            rx,
+            &rx_config::<SPI>(callback),
+        ) {
+            Ok(transfer) => transfer,
+            Err(_) => unreachable!("length checked above"),
+        };
+        regs.cr2.modify(|_, w| w.rxdmaen().set_bit());
+        let tx = match tx_stream.memory_to_peripheral(Dr(PhantomData), tx, &tx_config::<SPI>(None))
+        {
+            Ok(transfer) => transfer,
+            Err(_) => unreachable!("length checked above"),
+        };
+        regs.cr2.modify(|_, w| w.txdmaen().set_bit());
+        Ok(Transaction {
+            spi,
+            cs,
+            callback,
+            tx,
+            rx: Rx::Busy(rx),
+            done: false,
+        })
+    }
+
+    /// Sends `tx` and ignores what comes back, e.g. for a display
+    /// framebuffer
+    #[allow(clippy::type_complexity)]
+    pub fn write<TX>(self, tx: TX) -> Result<Transaction<SPI, CS, TX, ()>, (Error, Self, TX)>
+    where
+        TX: ReadBuffer<Word = u8>,
+    {
+        let (_, tx_len) = unsafe { tx.read_buffer() };
+        if let Err(e) = self.check(tx_len) {
+            return Err((e, self, tx));
+        }
+
+        let SpiDma {
+            spi,
+            mut cs,
+            tx: tx_stream,
+            rx: rx_stream,
+            callback,
+        } = self;
+        cs.set_low().ok();
+        let tx = match tx_stream.memory_to_peripheral(
+            Dr(PhantomData),
+            tx,
+            &tx_config::<SPI>(callback),
+        ) {
+            Ok(transfer) => transfer,
+            Err(_) => unreachable!("length checked above"),
+        };
+        SPI::registers().cr2.modify(|_, w| w.txdmaen().set_bit());
+        Ok(Transaction {
+            spi,
+            cs,
+            callback,
+            tx,
+            rx: Rx::Idle(rx_stream, ()),
+            done: false,
+        })
+    }
+
+    fn check(&self, len: usize) -> Result<(), Error> {
+        if self.spi.config.frame_size != FrameSize::Eight {
+            return Err(Error::FrameSize);
+        }
+        if self.spi.is_busy() {
+            return Err(Error::Busy);
+        }
+        if len == 0 || len > 0xFFFF {
+            return Err(Error::Dma(dma::Error::Length));
+        }
+        // A byte left over from blocking use would be taken for the first
+        // one received, reading DR then SR also clears an overrun
+        let regs = SPI::registers();
+        let _ = regs.dr.read();
+        let _ = regs.sr.read();
+        Ok(())
+    }
+}
+
+/// Receiving has to win over transmitting, or the RX stream could fall
+/// behind and the SPI overrun
+fn rx_config<SPI: DmaInstance>(callback: Option<fn(Flags)>) -> dma::Config {
+    dma::Config {
+        channel: SPI::CHANNEL,
+        priority: Priority::VeryHigh,
+        complete_interrupt: callback.is_some(),
+        error_interrupt: callback.is_some(),
+        callback,
+        ..dma::Config::default()
+    }
+}
+
+fn tx_config<SPI: DmaInstance>(callback: Option<fn(Flags)>) -> dma::Config {
+    dma::Config {
+        priority: Priority::High,
+        ..rx_config::<SPI>(callback)
+    }
+}
+
+/// Receiving side of a transaction
+enum Rx<DMA, SPI, B> {
+    /// Write-only, the stream waits
+    Idle(Stream<DMA>, B),
+    Busy(dma::Transfer<DMA, Dr<SPI>, B>),
+}
+
+/// A transaction in flight
+pub struct Transaction<SPI: DmaInstance, CS, TX, RX> {
+    spi: Spi<SPI>,
+    cs: CS,
+    callback: Option<fn(Flags)>,
+    tx: dma::Transfer<SPI::Dma, Dr<SPI>, TX>,
+    rx: Rx<SPI::Dma, SPI, RX>,
+    done: bool,
+}
+
+impl<SPI: DmaInstance, CS: OutputPin, TX, RX> Transaction<SPI, CS, TX, RX> {
+    /// `Ok` once the last byte is through and the chip select is released
+    pub fn poll(&mut self) -> nb::Result<(), Error> {
+        if self.done {
+            return Ok(());
+        }
+        let tx = self.tx.poll();
+        if let Err(nb::Error::Other(e)) = tx {
+            return Err(nb::Error::Other(Error::Dma(e)));
+        }
+        // Full duplex ends with the last byte received, which can only come
+        // in after the last one was sent
+        match &mut self.rx {
+            Rx::Busy(rx) => rx.poll().map_err(|e| e.map(Error::Dma))?,
+            Rx::Idle(..) => tx.map_err(|e| e.map(Error::Dma))?,
+        }
+
+        // The TX stream is done once the last byte is in DR, it still has to
+        // be shifted out before the chip select goes up
+        let regs = SPI::registers();
+        while regs.sr.read().txe().bit_is_clear() {}
+        while regs.sr.read().bsy().bit_is_set() {}
+        if let Rx::Idle(..) = self.rx {
+            // Nobody wanted what came back, drop it with the overrun it caused
+            let _ = regs.dr.read();
+            let _ = regs.sr.read();
+        }
+        regs.cr2
+            .modify(|_, w| w.txdmaen().clear_bit().rxdmaen().clear_bit());
+        self.cs.set_high().ok();
+        self.done = true;
+        Ok(())
+    }
+
+    /// Busy-waits for [`poll`](Transaction::poll)
+    pub fn wait(&mut self) -> Result<(), Error> {
+        nb::block!(self.poll())
+    }
+
+    /// Aborts the transaction if it is still running and hands everything
+    /// back, the RX buffer only holds every byte if [`poll`] said so
+    ///
+    /// [`poll`]: Transaction::poll
+    pub fn free(self) -> (SpiDma<SPI, CS>, TX, RX) {
+        let (tx_stream, _, tx) = self.tx.free();
+        let (rx_stream, rx) = match self.rx {
+            Rx::Idle(stream, buf) => (stream, buf),
+            Rx::Busy(transfer) => {
+                let (stream, _, buf) = transfer.free();
+                (stream, buf)
+            }
+        };
+        let mut cs = self.cs;
+        if !self.done {
+            let regs = SPI::registers();
+            regs.cr2
+                .modify(|_, w| w.txdmaen().clear_bit().rxdmaen().clear_bit());
+            while regs.sr.read().bsy().bit_is_set() {}
+            let _ = regs.dr.read();
+            let _ = regs.sr.read();
+            cs.set_high().ok();
+        }
+        let spi = SpiDma {
+            spi: self.spi,
+            cs,
+            tx: tx_stream,
+            rx: rx_stream,
+            callback: self.callback,
+        };
+        (spi, tx, rx)
+    }
+}
@@ -0,0 +1,472 @@
+//! Register-level SPI master for SPI1, SPI2 and SPI3
+//!
+//! The pins must already be in their alternate function (AF5 for SPI1/SPI2,
+//! AF6 for SPI3), chip selects are plain GPIO outputs driven by the caller or
+//! the device driver. NSS is managed in software and held high internally,
+//! so the peripheral never drops out of master mode.
+//!
+//! Blocking transfers go through the `embedded_hal` traits. Interrupt
+//! transfers exchange a `'static` buffer in the background, the interrupt
+//! handler of the SPI in use must call [`on_interrupt`]. Only one interrupt
+//! transfer can be in flight at a time, across all SPIs. Longer transfers
+//! are better left to the DMA, see [`dma::SpiDma`].
+use crate::clocks::Clocks;
+use core::cell::RefCell;
+use cortex_m::interrupt::{self, Mutex};
+use cortex_m::peripheral::NVIC;
+pub use embedded_hal::spi::{Mode, Phase, Polarity, MODE_0, MODE_1, MODE_2, MODE_3};
+use stm32f4::stm32f401::{self as device, spi1::RegisterBlock, Interrupt};
+
+pub mod dma;
+
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum Error {
+    /// Requested SCK frequency is below the bus clock divided by 256
+    Frequency,
+    /// Word type does not match the configured frame size
+    FrameSize,
+    /// A word was received before the previous one was read
+    Overrun,
+    /// NSS went low, as if another master took over the bus
+    ModeFault,
+    /// An interrupt transfer is still in flight
+    Busy,
+    /// A DMA stream of the transfer failed
+    Dma(crate::dma::Error),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum BitOrder {
+    MsbFirst,
+    LsbFirst,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum FrameSize {
+    Eight,
+    Sixteen,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Config {
+    pub mode: Mode,
+    pub bit_order: BitOrder,
+    pub frame_size: FrameSize,
+    /// Upper bound for SCK, the fastest prescaler not above it is used
+    pub frequency: u32,
+}
+
+impl Default for Config {
+    /// Mode 0, MSB first, 8-bit frames at 1 MHz
+    fn default() -> Self {
+        Config {
+            mode: MODE_0,
+            bit_order: BitOrder::MsbFirst,
+            frame_size: FrameSize::Eight,
+            frequency: 1_000_000,
+        }
+    }
+}
+
+/// An SPI peripheral this driver can run
+pub trait Instance {
+    const INTERRUPT: Interrupt;
+
+    fn registers() -> &'static RegisterBlock;
+
+    /// Enables and resets the peripheral, returns the clock of its bus
+    fn enable(rcc: &device::RCC, clocks: &Clocks) -> u32;
+}
+
+impl Instance for device::SPI1 {
+    const INTERRUPT: Interrupt = Interrupt::SPI1;
+
+    fn registers() -> &'static RegisterBlock {
+        // SAFETY: The `Spi` owning the peripheral is the only user
+        unsafe { &*device::SPI1::ptr() }
+    }
+
+    fn enable(rcc: &device::RCC, clocks: &Clocks) -> u32 {
+        rcc.apb2enr.modify(|_, w| w.spi1en().enabled());
+        rcc.apb2rstr.modify(|_, w| w.spi1rst().set_bit());
+        rcc.apb2rstr.modify(|_, w| w.spi1rst().clear_bit());
+        clocks.pclk2
+    }
+}
+
+impl Instance for device::SPI2 {
+    const INTERRUPT: Interrupt = Interrupt::SPI2;
+
+    fn registers() -> &'static RegisterBlock {
+        // SAFETY: The `Spi` owning the peripheral is the only user
+        unsafe { &*device::SPI2::ptr() }
+    }
+
+    fn enable(rcc: &device::RCC, clocks: &Clocks) -> u32 {
+        rcc.apb1enr.modify(|_, w| w.spi2en().enabled());
+        rcc.apb1rstr.modify(|_, w| w.spi2rst().set_bit());
+        rcc.apb1rstr.modify(|_, w| w.spi2rst().clear_bit());
+        clocks.pclk1
+    }
+}
+
+impl Instance for device::SPI3 {
+    const INTERRUPT: Interrupt = Interrupt::SPI3;
+
+    fn registers() -> &'static RegisterBlock {
+        // SAFETY: The `Spi` owning the peripheral is the only user
+        unsafe { &*device::SPI3::ptr() }
+    }
+
+    fn enable(rcc: &device::RCC, clocks: &Clocks) -> u32 {
+        rcc.apb1enr.modify(|_, w| w.spi3en().enabled());
+        rcc.apb1rstr.modify(|_, w| w.spi3rst().set_bit());
+        rcc.apb1rstr.modify(|_, w| w.spi3rst().clear_bit());
+        clocks.pclk1
+    }
+}
+
+/// Data frame shifted out and in, 8 or 16 bits
+pub trait Word: Copy {
+    const FRAME_SIZE: FrameSize;
+
+    fn to_dr(self) -> u32;
+    fn from_dr(dr: u32) -> Self;
+}
+
+impl Word for u8 {
+    const FRAME_SIZE: FrameSize = FrameSize::Eight;
+
+    fn to_dr(self) -> u32 {
+        u32::from(self)
+    }
+
+    fn from_dr(dr: u32) -> Self {
+        dr as u8
+    }
+}
+
+impl Word for u16 {
+    const FRAME_SIZE: FrameSize = FrameSize::Sixteen;
+
+    fn to_dr(self) -> u32 {
+        u32::from(self)
+    }
+
+    fn from_dr(dr: u32) -> Self {
+        dr as u16
+    }
+}
+
+/// Interrupt transfer in flight, exchanged one byte per RXNE interrupt
+struct InFlight {
+    regs: &'static RegisterBlock,
+    buf: &'static mut [u8],
+    /// Bytes exchanged so far
+    done: usize,
+    error: Option<Error>,
+}
+
+impl InFlight {
+    fn is_finished(&self) -> bool {
+        self.done == self.buf.len() || self.error.is_some()
+    }
+}
+
+static IN_FLIGHT: Mutex<RefCell<Option<InFlight>>> = Mutex::new(RefCell::new(None));
+
+/// Must be called from the interrupt handler of the SPI doing an interrupt
+/// transfer
+pub fn on_interrupt() {
+    interrupt::free(|cs| {
+        if let Some(t) = IN_FLIGHT.borrow(cs).borrow_mut().as_mut() {
+            if t.is_finished() {
+                return;
+            }
+            let sr = t.regs.sr.read();
+            if let Err(e) = check(t.regs, &sr) {
+                t.error = Some(e);
+            } else if sr.rxne().bit_is_set() {
+                t.buf[t.done] = t.regs.dr.read().bits() as u8;
+                t.done += 1;
+                // The next byte only goes out once this one is in, so the
+                // receiver can never overrun
+                if let Some(byte) = t.buf.get(t.done) {
+                    t.regs.dr.write(|w| unsafe { w.bits(u32::from(*byte)) });
+                }
+            }
+            if t.is_finished() {
+                t.regs
+                    .cr2
+                    .modify(|_, w| w.rxneie().clear_bit().errie().clear_bit());
+            }
+        }
+    });
+}
+
+/// Turns the error flags in `sr` into an error, clearing them
+fn check(regs: &RegisterBlock, sr: &device::spi1::sr::R) -> Result<(), Error> {
+    if sr.ovr().bit_is_set() {
+        // Cleared by reading DR then SR, the word that got overwritten is lost
+        let _ = regs.dr.read();
+        let _ = regs.sr.read();
+        return Err(Error::Overrun);
+    }
+    if sr.modf().bit_is_set() {
+        // Cleared by the SR read already done plus a write to CR1, which
+        // also has to restore MSTR and SPE
+        regs.cr1.modify(|_, w| w.mstr().set_bit().spe().set_bit());
+        return Err(Error::ModeFault);
+    }
+    Ok(())
+}
+
+pub struct Spi<SPI> {
+    spi: SPI,
+    /// Clock of the APB bus the SPI sits on
+    pclk: u32,
+    config: Config,
+}
+
+impl<SPI: Instance> Spi<SPI> {
+    pub fn new(
+        spi: SPI,
+        rcc: &device::RCC,
+        clocks: &Clocks,
+        config: &Config,
+    ) -> Result<Self, Error> {
+        let pclk = SPI::enable(rcc, clocks);
+        let mut spi = Spi {
+            spi,
+            pclk,
+            config: *config,
+        };
+        spi.configure(config)?;
+        Ok(spi)
+    }
+
+    /// Switches to `config`, waiting for the bus to go idle first. The old
+    /// settings stay if `config.frequency` is out of reach.
+    pub fn configure(&mut self, config: &Config) -> Result<(), Error> {
+        // SCK = PCLK / 2^(BR + 1), see Section 20.5.1 of RM0368
+        let br = (0..8u8)
+            .find(|br| self.pclk >> (br + 1) <= config.frequency)
+            .ok_or(Error::Frequency)?;
+        if self.is_busy() {
+            return Err(Error::Busy);
+        }
+
+        let regs = SPI::registers();
+        while regs.sr.read().bsy().bit_is_set() {}
+        regs.cr1.modify(|_, w| w.spe().clear_bit());
+        regs.cr1.write(|w| {
+            w.cpha()
+                .bit(config.mode.phase == Phase::CaptureOnSecondTransition)
+                .cpol()
+                .bit(config.mode.polarity == Polarity::IdleHigh)
+                .lsbfirst()
+                .bit(config.bit_order == BitOrder::LsbFirst)
+                .dff()
+                .bit(config.frame_size == FrameSize::Sixteen)
+                .br()
+                .bits(br)
+                // NSS in software, held high so we stay the master
+                .ssm()
+                .set_bit()
+                .ssi()
+                .set_bit()
+                .mstr()
+                .set_bit()
+                .spe()
+                .set_bit()
+        });
+        self.config = *config;
+        Ok(())
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Frequency SCK actually runs at
+    pub fn frequency(&self) -> u32 {
+        let br = SPI::registers().cr1.read().br().bits();
+        self.pclk >> (br + 1)
+    }
+
+    /// Waits for the last word to leave, then disables the SPI
+    pub fn free(self) -> SPI {
+        let regs = SPI::registers();
+        while regs.sr.read().bsy().bit_is_set() {}
+        regs.cr1.modify(|_, w| w.spe().clear_bit());
+        self.spi
+    }
+
+    /// Starts exchanging `buf` in the background, 8-bit frames only. Each
+    /// byte is replaced by the one received while it was sent. On failure
+    /// `buf` is handed back untouched.
+    pub fn start_transfer(
+        &mut self,
+        buf: &'static mut [u8],
+    ) -> Result<(), (Error, &'static mut [u8])> {
+        if self.config.frame_size != FrameSize::Eight {
+            return Err((Error::FrameSize, buf));
+        }
+        let regs = SPI::registers();
+        interrupt::free(|cs| {
+            let mut slot = IN_FLIGHT.borrow(cs).borrow_mut();
+            if slot.is_some() {
+                return Err((Error::Busy, buf));
+            }
+            if let Some(byte) = buf.first() {
+                regs.dr.write(|w| unsafe { w.bits(u32::from(*byte)) });
+                regs.cr2
+                    .modify(|_, w| w.rxneie().set_bit().errie().set_bit());
+            }
+            *slot = Some(InFlight {
+                regs,
+                buf,
+                done: 0,
+                error: None,
+            });
+            Ok(())
+        })?;
+        unsafe { NVIC::unmask(SPI::INTERRUPT) };
+        Ok(())
+    }
+
+    /// Hands back the buffer of a finished interrupt transfer of this SPI
+    /// along with its outcome, `None` while it is still running or if none
+    /// was started
+    pub fn poll_transfer(&mut self) -> Option<(&'static mut [u8], Result<(), Error>)> {
+        interrupt::free(|cs| {
+            let mut slot = IN_FLIGHT.borrow(cs).borrow_mut();
+            match slot.as_ref() {
+                Some(t) if self.owns(t) && t.is_finished() => {}
+                _ => return None,
+            }
+            slot.take().map(|t| {
+                let result = t.error.map_or(Ok(()), Err);
+                (t.buf, result)
+            })
+        })
+    }
+
+    /// Sleeps until the interrupt transfer of this SPI finishes, `None` if
+    /// none was started
+    pub fn wait_transfer(&mut self) -> Option<(&'static mut [u8], Result<(), Error>)> {
+        if !self.is_busy() {
+            return None;
+        }
+        loop {
+            if let Some(done) = self.poll_transfer() {
+                return Some(done);
+            }
+            // Checked again with interrupts masked, the RXNE interrupt
+            // still ends the WFI
+            interrupt::free(|cs| {
+                let slot = IN_FLIGHT.borrow(cs).borrow();
+                if !slot.as_ref().map_or(true, InFlight::is_finished) {
+                    cortex_m::asm::wfi();
+                }
+            });
+        }
+    }
+
+    /// An interrupt transfer of this SPI has not been collected yet
+    fn is_busy(&self) -> bool {
+        interrupt::free(|cs| {
+            IN_FLIGHT
+                .borrow(cs)
+                .borrow()
+                .as_ref()
+                .map_or(false, |t| self.owns(t))
+        })
+    }
+
+    fn owns(&self, t: &InFlight) -> bool {
+        core::ptr::eq(t.regs, SPI::registers())
+    }
+
+    /// Shifts `word` out and returns the one shifted in meanwhile
+    fn exchange<W: Word>(&mut self, word: W) -> Result<W, Error> {
+        let regs = SPI::registers();
+        while regs.sr.read().txe().bit_is_clear() {}
+        regs.dr.write(|w| unsafe { w.bits(word.to_dr()) });
+        loop {
+            let sr = regs.sr.read();
+            check(regs, &sr)?;
+            if sr.rxne().bit_is_set() {
+                return Ok(W::from_dr(regs.dr.read().bits()));
+            }
+        }
+    }
+
+    fn transfer_words<W: Word>(&mut self, words: &mut [W]) -> Result<(), Error> {
+        if self.config.frame_size != W::FRAME_SIZE {
+            return Err(Error::FrameSize);
+        }
+        if self.is_busy() {
+            return Err(Error::Busy);
+        }
+        for word in words.iter_mut() {
+            *word = self.exchange(*word)?;
+        }
+        Ok(())
+    }
+
+    fn write_words<W: Word>(&mut self, words: &[W]) -> Result<(), Error> {
+        if self.config.frame_size != W::FRAME_SIZE {
+            return Err(Error::FrameSize);
+        }
+        if self.is_busy() {
+            return Err(Error::Busy);
+        }
+        let regs = SPI::registers();
+        // Back to back, without waiting for each received word
+        for word in words {
+            while regs.sr.read().txe().bit_is_clear() {}
+            regs.dr.write(|w| unsafe { w.bits(word.to_dr()) });
+        }
+        while regs.sr.read().bsy().bit_is_set() {}
+        // Nobody wants the received words, drop them along with the overrun
+        // they caused
+        let _ = regs.dr.read();
+        let _ = regs.sr.read();
+        Ok(())
+    }
+}
+
+impl<SPI: Instance> embedded_hal::blocking::spi::Transfer<u8> for Spi<SPI> {
+    type Error = Error;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Error> {
+        self.transfer_words(words)?;
+        Ok(words)
+    }
+}
+
+impl<SPI: Instance> embedded_hal::blocking::spi::Transfer<u16> for Spi<SPI> {
+    type Error = Error;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u16]) -> Result<&'w [u16], Error> {
+        self.transfer_words(words)?;
+        Ok(words)
+    }
+}
+
+impl<SPI: Instance> embedded_hal::blocking::spi::Write<u8> for Spi<SPI> {
+    type Error = Error;
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Error> {
+        self.write_words(words)
+    }
+}
+
+impl<SPI: Instance> embedded_hal::blocking::spi::Write<u16> for Spi<SPI> {
+    type Error = Error;
+
+    fn write(&mut self, words: &[u16]) -> Result<(), Error> {
+        self.write_words(words)
+    }
+}
@@ -0,0 +1,180 @@
+//! Independent and window watchdogs, see Sections 15 and 16 of RM0368
+//!
+//! Either one resets the device unless it is fed in time, which gets it out
+//! of a busy-wait on a peripheral that never answers. What caused the last
+//! reset is reported by [`crate::power::Power::reset_reason`].
+//!
+//! * The IWDG runs from the LSI, so it keeps going if the main clock fails,
+//!   and nothing but a reset stops it once started.
+//! * The WWDG runs from PCLK1 and also resets when fed too early, catching
+//!   code that runs away in a tight loop that happens to feed it. Its early
+//!   wakeup interrupt gives the application one last chance, e.g. to log,
+//!   right before the reset; the `WWDG` handler must call [`on_interrupt`].
+use crate::clocks::Clocks;
+use crate::rtc::LSI_HZ;
+use core::cell::Cell;
+use cortex_m::interrupt::Mutex;
+use cortex_m::peripheral::NVIC;
+use stm32f4::stm32f401::{self as device, Interrupt};
+
+// IWDG_KR keys
+const KEY_START: u32 = 0xCCCC;
+const KEY_FEED: u32 = 0xAAAA;
+const KEY_UNLOCK: u32 = 0x5555;
+
+// IWDG_SR bits
+const PVU: u32 = 1 << 0;
+const RVU: u32 = 1 << 1;
+
+// WWDG_CR bits
+const WDGA: u32 = 1 << 7;
+
+// WWDG_CFR bits
+const WDGTB: u32 = 7;
+const EWI: u32 = 1 << 9;
+
+// WWDG_SR bits
+const EWIF: u32 = 1 << 0;
+
+/// The WWDG resets once its 7-bit counter drops from 0x40 to 0x3F
+const WWDG_RESET: u32 = 0x3F;
+
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum Error {
+    /// Timeout of zero or beyond what the prescalers reach
+    Timeout,
+    /// Window not shorter than the timeout
+    Window,
+}
+
+pub struct IndependentWatchdog {
+    iwdg: device::IWDG,
+    timeout_ms: u32,
+}
+
+impl IndependentWatchdog {
+    /// Starts the IWDG with a timeout from 1 ms up to 32 s, rounded down to
+    /// what the prescaler and reload value can count. The LSI it runs from is
+    /// only accurate to about ±50 %, a generous timeout is a good idea.
+    pub fn start(iwdg: device::IWDG, timeout_ms: u32) -> Result<Self, Error> {
+        let ticks = u64::from(timeout_ms) * u64::from(LSI_HZ) / 1000;
+        // LSI / 4, 8 ... 256 into a 12-bit reload value
+        let pr = (0..7)
+            .find(|pr| ticks >> (pr + 2) <= 0x1000)
+            .ok_or(Error::Timeout)?;
+        let reload = (ticks >> (pr + 2)) as u32;
+        if reload == 0 {
+            return Err(Error::Timeout);
+        }
+
+        // Starting first also turns on the LSI
+        iwdg.kr.write(|w| unsafe { w.bits(KEY_START) });
+        iwdg.kr.write(|w| unsafe { w.bits(KEY_UNLOCK) });
+        iwdg.pr.write(|w| unsafe { w.bits(pr) });
+        iwdg.rlr.write(|w| unsafe { w.bits(reload - 1) });
+        // The new values have to reach the LSI domain before they count
+        while iwdg.sr.read().bits() & (PVU | RVU) != 0 {}
+        iwdg.kr.write(|w| unsafe { w.bits(KEY_FEED) });
+
+        Ok(IndependentWatchdog {
+            iwdg,
+            timeout_ms: ((u64::from(reload) << (pr + 2)) * 1000 / u64::from(LSI_HZ)) as u32,
+        })
+    }
+
+    /// Restarts the countdown
+    pub fn feed(&mut self) {
+        self.iwdg.kr.write(|w| unsafe { w.bits(KEY_FEED) });
+    }
+
+    /// Timeout after rounding, at the nominal LSI frequency
+    pub fn timeout_ms(&self) -> u32 {
+        self.timeout_ms
+    }
+}
+
+/// Called by [`on_interrupt`]
+static CALLBACK: Mutex<Cell<Option<fn()>>> = Mutex::new(Cell::new(None));
+
+/// Must be called from the `WWDG` interrupt handler if the early wakeup
+/// interrupt is enabled
+pub fn on_interrupt() {
+    // SAFETY: Only the write-0-to-clear flag is touched
+    let wwdg = unsafe { &*device::WWDG::ptr() };
+    wwdg.sr.write(|w| unsafe { w.bits(!EWIF) });
+    if let Some(callback) = cortex_m::interrupt::free(|cs| CALLBACK.borrow(cs).get()) {
+        callback();
+    }
+}
+
+pub struct WindowWatchdog {
+    wwdg: device::WWDG,
+    /// Counter value a feed reloads
+    counter: u32,
+}
+
+impl WindowWatchdog {
+    /// Starts the WWDG, which resets the device unless it is fed between
+    /// `window_ms` and `timeout_ms` after the last feed. A `window_ms` of 0
+    /// allows feeding any time. At a PCLK1 of 42 MHz the timeout can be at
+    /// most 49 ms.
+    pub fn start(
+        wwdg: device::WWDG,
+        rcc: &device::RCC,
+        clocks: &Clocks,
+        timeout_ms: u32,
+        window_ms: u32,
+    ) -> Result<Self, Error> {
+        if window_ms >= timeout_ms {
+            return Err(Error::Window);
+        }
+        // The counter ticks at PCLK1 / 4096 / 2^WDGTB and resets after 64
+        let ticks =
+            |ms: u32, wdgtb: u32| u64::from(ms) * u64::from(clocks.pclk1) / (4096 << wdgtb) / 1000;
+        let wdgtb = (0..4)
+            .find(|wdgtb| ticks(timeout_ms, *wdgtb) <= 64)
+            .ok_or(Error::Timeout)?;
+        let timeout = ticks(timeout_ms, wdgtb) as u32;
+        if timeout == 0 {
+            return Err(Error::Timeout);
+        }
+        let early = ticks(window_ms, wdgtb) as u32;
+        if early >= timeout {
+            return Err(Error::Window);
+        }
+        let counter = WWDG_RESET + timeout;
+        // Feeding is only allowed once the counter is down to the window
+        let window = counter - early;
+
+        rcc.apb1enr.modify(|_, w| w.wwdgen().enabled());
+        wwdg.cfr
+            .write(|w| unsafe { w.bits(wdgtb << WDGTB | window) });
+        wwdg.cr.write(|w| unsafe { w.bits(WDGA | counter) });
+        Ok(WindowWatchdog { wwdg, counter })
+    }
+
+    /// Restarts the countdown, which resets the device if the window has not
+    /// opened yet
+    pub fn feed(&mut self) {
+        self.wwdg
+            .cr
+            .write(|w| unsafe { w.bits(WDGA | self.counter) });
+    }
+
+    /// Counts down to 0x3F, where the device resets
+    pub fn counter(&self) -> u8 {
+        (self.wwdg.cr.read().bits() & 0x7F) as u8
+    }
+
+    /// Raises the `WWDG` interrupt when the counter reaches 0x40, one tick
+    /// before the reset, and calls `callback` from it. Only a reset can turn
+    /// it off again.
+    pub fn enable_early_wakeup(&mut self, callback: Option<fn()>) {
+        cortex_m::interrupt::free(|cs| CALLBACK.borrow(cs).set(callback));
+        self.wwdg.sr.write(|w| unsafe { w.bits(!EWIF) });
+        self.wwdg
+            .cfr
+            .modify(|r, w| unsafe { w.bits(r.bits() | EWI) });
+        unsafe { NVIC::unmask(Interrupt::WWDG) };
+    }
+}